@@ -21,7 +21,7 @@ async fn main(spawner: Spawner) {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let config = config.with_psram({
         let mut config = esp_hal::psram::PsramConfig::default();
-        config.size = esp_hal::psram::PsramSize::Size(16 * 1024 * 1024);
+        config.size = esp_hal::psram::PsramSize::Size(app::board::PSRAM_SIZE);
         config
     });
     let peripherals = esp_hal::init(config);
@@ -33,8 +33,39 @@ async fn main(spawner: Spawner) {
     defmt::info!("RAM: {}", esp_alloc::HEAP.stats());
     let rng = esp_hal::rng::Rng::new();
     let wifi = unsafe { peripherals.WIFI.clone_unchecked() };
-    let camera = app::cam::init_cam(peripherals).await.unwrap();
-    match app::wifi::init(rng, wifi, &spawner, camera).await {
+    let board = app::board_pins!(peripherals);
+    info!("Board: {}", board.name);
+    let card = board
+        .sd
+        .and_then(|pins| app::record::init_card(peripherals.SPI2, peripherals.DMA_CH1, pins));
+    let camera = app::cam::init_cam(
+        board.cam,
+        peripherals.I2C0,
+        peripherals.LCD_CAM,
+        peripherals.DMA_CH0,
+    )
+    .await
+    .unwrap();
+    spawner.spawn(app::cam::capture_task(camera)).ok();
+    spawner.spawn(app::cam::watchdog::watchdog_task()).ok();
+    if let Some(pin) = board.flash {
+        spawner
+            .spawn(app::flash::flash_task(peripherals.LEDC, pin))
+            .ok();
+    }
+    spawner.spawn(app::motion::motion_task()).ok();
+    spawner.spawn(app::cam::rate::rate_task()).ok();
+    spawner.spawn(app::timelapse::timelapse_task()).ok();
+    spawner.spawn(app::clip::clip_task()).ok();
+    if let Some(pin) = board.trigger {
+        spawner.spawn(app::clip::input_task(pin)).ok();
+    }
+    if let Some(card) = card {
+        spawner.spawn(app::record::record_task(card)).ok();
+    }
+    app::store::init(peripherals.FLASH).await;
+    app::cam::settings::restore().await;
+    match app::wifi::init(rng, wifi, &spawner).await {
         Ok(stack) => {
             info!("Waiting to get IP address...");
             loop {
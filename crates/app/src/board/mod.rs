@@ -0,0 +1,228 @@
+//!
+//! Board profiles
+//!
+//! The wiring is picked with one `board-*` cargo feature, `board-devkit` by
+//! default:
+//!
+//! ```text
+//! cargo build --release --no-default-features --features board-xiao-sense
+//! ```
+//!
+//! [board_pins!](crate::board_pins) moves the pins of the selected board out of
+//! `Peripherals`, everything else stays available to the caller.
+//!
+
+use esp_hal::gpio::AnyPin;
+
+const _: () = assert!(
+    cfg!(feature = "board-devkit") as u8
+        + cfg!(feature = "board-s3-eye") as u8
+        + cfg!(feature = "board-freenove") as u8
+        + cfg!(feature = "board-xiao-sense") as u8
+        == 1,
+    "select exactly one board-* feature"
+);
+
+/// PSRAM fitted to the module
+#[cfg(feature = "board-devkit")]
+pub const PSRAM_SIZE: usize = 16 * 1024 * 1024;
+#[cfg(not(feature = "board-devkit"))]
+pub const PSRAM_SIZE: usize = 8 * 1024 * 1024;
+
+/// Camera connector
+pub struct CamPins {
+    /// Master clock output, `None` if the module has its own oscillator
+    pub xclk: Option<AnyPin<'static>>,
+    /// Power down, high puts the sensor into standby
+    pub pwdn: Option<AnyPin<'static>>,
+    /// Reset, low resets the sensor
+    pub reset: Option<AnyPin<'static>>,
+    pub sda: AnyPin<'static>,
+    pub scl: AnyPin<'static>,
+    pub vsync: AnyPin<'static>,
+    pub href: AnyPin<'static>,
+    pub pclk: AnyPin<'static>,
+    /// D0 to D7, often labelled Y2 to Y9
+    pub data: [AnyPin<'static>; 8],
+}
+
+/// SD card wired for SPI
+pub struct SdPins {
+    pub sck: AnyPin<'static>,
+    pub mosi: AnyPin<'static>,
+    pub miso: AnyPin<'static>,
+    pub cs: AnyPin<'static>,
+}
+
+pub struct Board {
+    pub name: &'static str,
+    pub cam: CamPins,
+    /// Flash LED, driven by LEDC PWM
+    pub flash: Option<AnyPin<'static>>,
+    /// `None` if the card slot has no chip select wired for SPI
+    pub sd: Option<SdPins>,
+    /// Clip trigger input, active low
+    pub trigger: Option<AnyPin<'static>>,
+}
+
+/// Our own wiring of an OV2640 module with oscillator to an ESP32-S3 DevKit
+///
+/// ```text
+/// SCL   13    SDA   12    PCLK   3    VSYNC  4    HREF   5
+/// D0    11    D1     7    D2    10    D3    15    D4     9
+/// D5    16    D6    46    D7    17    PWDN   8    RST    6
+/// FLASH 18    SD CLK 39, MOSI 38, MISO 40, CS 41    TRIGGER 14
+/// ```
+#[cfg(feature = "board-devkit")]
+#[macro_export]
+macro_rules! board_pins {
+    ($p:ident) => {
+        $crate::board::Board {
+            name: "devkit",
+            cam: $crate::board::CamPins {
+                xclk: None,
+                pwdn: Some($p.GPIO8.into()),
+                reset: Some($p.GPIO6.into()),
+                sda: $p.GPIO12.into(),
+                scl: $p.GPIO13.into(),
+                vsync: $p.GPIO4.into(),
+                href: $p.GPIO5.into(),
+                pclk: $p.GPIO3.into(),
+                data: [
+                    $p.GPIO11.into(),
+                    $p.GPIO7.into(),
+                    $p.GPIO10.into(),
+                    $p.GPIO15.into(),
+                    $p.GPIO9.into(),
+                    $p.GPIO16.into(),
+                    $p.GPIO46.into(),
+                    $p.GPIO17.into(),
+                ],
+            },
+            flash: Some($p.GPIO18.into()),
+            sd: Some($crate::board::SdPins {
+                sck: $p.GPIO39.into(),
+                mosi: $p.GPIO38.into(),
+                miso: $p.GPIO40.into(),
+                cs: $p.GPIO41.into(),
+            }),
+            trigger: Some($p.GPIO14.into()),
+        }
+    };
+}
+
+/// Espressif ESP32-S3-EYE
+///
+/// The card slot is wired for 1-bit SDMMC without chip select and there is no
+/// flash LED. The BOOT button triggers clips.
+#[cfg(feature = "board-s3-eye")]
+#[macro_export]
+macro_rules! board_pins {
+    ($p:ident) => {
+        $crate::board::Board {
+            name: "esp32-s3-eye",
+            cam: $crate::board::CamPins {
+                xclk: Some($p.GPIO15.into()),
+                pwdn: None,
+                reset: None,
+                sda: $p.GPIO4.into(),
+                scl: $p.GPIO5.into(),
+                vsync: $p.GPIO6.into(),
+                href: $p.GPIO7.into(),
+                pclk: $p.GPIO13.into(),
+                data: [
+                    $p.GPIO11.into(),
+                    $p.GPIO9.into(),
+                    $p.GPIO8.into(),
+                    $p.GPIO10.into(),
+                    $p.GPIO12.into(),
+                    $p.GPIO18.into(),
+                    $p.GPIO17.into(),
+                    $p.GPIO16.into(),
+                ],
+            },
+            flash: None,
+            sd: None,
+            trigger: Some($p.GPIO0.into()),
+        }
+    };
+}
+
+/// Freenove ESP32-S3-WROOM CAM, same camera pinout as the ESP32-S3-EYE
+///
+/// The card slot is wired for 1-bit SDMMC without chip select, the blue LED on
+/// GPIO 2 stands in for a flash.
+#[cfg(feature = "board-freenove")]
+#[macro_export]
+macro_rules! board_pins {
+    ($p:ident) => {
+        $crate::board::Board {
+            name: "freenove-s3",
+            cam: $crate::board::CamPins {
+                xclk: Some($p.GPIO15.into()),
+                pwdn: None,
+                reset: None,
+                sda: $p.GPIO4.into(),
+                scl: $p.GPIO5.into(),
+                vsync: $p.GPIO6.into(),
+                href: $p.GPIO7.into(),
+                pclk: $p.GPIO13.into(),
+                data: [
+                    $p.GPIO11.into(),
+                    $p.GPIO9.into(),
+                    $p.GPIO8.into(),
+                    $p.GPIO10.into(),
+                    $p.GPIO12.into(),
+                    $p.GPIO18.into(),
+                    $p.GPIO17.into(),
+                    $p.GPIO16.into(),
+                ],
+            },
+            flash: Some($p.GPIO2.into()),
+            sd: None,
+            trigger: Some($p.GPIO14.into()),
+        }
+    };
+}
+
+/// Seeed Studio XIAO ESP32S3 Sense
+///
+/// The user LED shares GPIO 21 with the card's chip select, so there is no
+/// flash. The BOOT button triggers clips.
+#[cfg(feature = "board-xiao-sense")]
+#[macro_export]
+macro_rules! board_pins {
+    ($p:ident) => {
+        $crate::board::Board {
+            name: "xiao-esp32s3-sense",
+            cam: $crate::board::CamPins {
+                xclk: Some($p.GPIO10.into()),
+                pwdn: None,
+                reset: None,
+                sda: $p.GPIO40.into(),
+                scl: $p.GPIO39.into(),
+                vsync: $p.GPIO38.into(),
+                href: $p.GPIO47.into(),
+                pclk: $p.GPIO13.into(),
+                data: [
+                    $p.GPIO15.into(),
+                    $p.GPIO17.into(),
+                    $p.GPIO18.into(),
+                    $p.GPIO16.into(),
+                    $p.GPIO14.into(),
+                    $p.GPIO12.into(),
+                    $p.GPIO11.into(),
+                    $p.GPIO48.into(),
+                ],
+            },
+            flash: None,
+            sd: Some($crate::board::SdPins {
+                sck: $p.GPIO7.into(),
+                mosi: $p.GPIO9.into(),
+                miso: $p.GPIO8.into(),
+                cs: $p.GPIO21.into(),
+            }),
+            trigger: Some($p.GPIO0.into()),
+        }
+    };
+}
@@ -0,0 +1,301 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    sync::atomic::Ordering,
+};
+use defmt::{info, warn};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Delay, Duration};
+use media::raw::RawFormat;
+use ov2640::{Configuration, ImageFormat, Resolution};
+
+use super::{
+    pool::FRAME_POOL,
+    rate,
+    sensor::{Sensor, SENSOR},
+};
+use crate::metrics::METRICS;
+
+/// How long a reconfiguration waits for the capture task to stop its transfer
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Smallest JPEG buffer, small frames of detailed scenes compress badly
+const MIN_JPEG_CAPACITY: usize = 48 * 1024;
+/// The sensor only delivers raw frames in the QVGA window
+pub const RAW_RESOLUTION: Resolution = Resolution::R320x240;
+
+/// What the sensor puts out
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PixelFormat {
+    Jpeg,
+    /// Little endian RGB565
+    Rgb565,
+    /// YUYV
+    Yuv422,
+}
+
+impl PixelFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "jpeg" => Some(PixelFormat::Jpeg),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            "yuv422" => Some(PixelFormat::Yuv422),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::Jpeg => "jpeg",
+            PixelFormat::Rgb565 => "rgb565",
+            PixelFormat::Yuv422 => "yuv422",
+        }
+    }
+
+    /// Pixel layout of the raw formats
+    pub fn raw(self) -> Option<RawFormat> {
+        match self {
+            PixelFormat::Jpeg => None,
+            PixelFormat::Rgb565 => Some(RawFormat::Rgb565),
+            PixelFormat::Yuv422 => Some(RawFormat::Yuv422),
+        }
+    }
+
+    fn of(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::JPEG => PixelFormat::Jpeg,
+            ImageFormat::QVGA => PixelFormat::Rgb565,
+            ImageFormat::YUV422 => PixelFormat::Yuv422,
+        }
+    }
+
+    fn sensor(self) -> ImageFormat {
+        match self {
+            PixelFormat::Jpeg => ImageFormat::JPEG,
+            PixelFormat::Rgb565 => ImageFormat::QVGA,
+            PixelFormat::Yuv422 => ImageFormat::YUV422,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub format: PixelFormat,
+    /// Always [RAW_RESOLUTION] for the raw formats
+    pub resolution: Resolution,
+    /// JPEG quality scale, kept for the raw formats
+    pub quality: u8,
+}
+
+impl CaptureConfig {
+    fn of(sensor: &Sensor) -> Self {
+        Self::from_configuration(sensor.configuration())
+    }
+
+    /// The capture part of a sensor configuration
+    pub fn from_configuration(config: &Configuration) -> Self {
+        let format = PixelFormat::of(config.image_format);
+        CaptureConfig {
+            format,
+            resolution: match format {
+                PixelFormat::Jpeg => config.resolution,
+                _ => RAW_RESOLUTION,
+            },
+            quality: config.quality,
+        }
+    }
+
+    /// Largest frame the pool has to hold
+    pub fn frame_capacity(&self) -> usize {
+        let (width, height) = self.resolution.size();
+        let pixels = width as usize * height as usize;
+        match self.format {
+            // even fine quality scales stay well below 3 pixels per byte
+            PixelFormat::Jpeg => (pixels / 3).max(MIN_JPEG_CAPACITY),
+            PixelFormat::Rgb565 | PixelFormat::Yuv422 => pixels * 2,
+        }
+    }
+}
+
+/// How the capture task frames what the DMA delivers
+#[derive(Clone, Copy)]
+pub(super) struct CaptureMode {
+    pub format: PixelFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Buffer size for JPEG, exact frame size for the raw formats
+    pub frame_len: usize,
+}
+
+impl CaptureMode {
+    fn of(config: &CaptureConfig) -> Self {
+        let (width, height) = config.resolution.size();
+        CaptureMode {
+            format: config.format,
+            width,
+            height,
+            frame_len: config.frame_capacity(),
+        }
+    }
+}
+
+/// Serialises reconfigurations
+static CONTROL: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+static PAUSE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static PAUSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static RESUME: Signal<CriticalSectionRawMutex, CaptureMode> = Signal::new();
+/// Outcome of the last reconfiguration
+static LAST_ERROR: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<&'static str>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+/// The setting the sensor runs with
+pub async fn config() -> Option<CaptureConfig> {
+    SENSOR.lock().await.as_ref().map(CaptureConfig::of)
+}
+
+/// Size the frame pool for the sensor's setting, returns how to capture
+pub(super) async fn init_pool() -> Result<CaptureMode, ()> {
+    let config = config().await.ok_or(())?;
+    FRAME_POOL.init(config.frame_capacity())?;
+    Ok(CaptureMode::of(&config))
+}
+
+/// Whether the capture task should stop its transfer and call [paused]
+pub(super) fn pause_requested() -> bool {
+    PAUSE.signaled()
+}
+
+/// Called by the capture task once its transfer is stopped, returns how to
+/// capture after the sensor was reprogrammed
+pub(super) async fn paused() -> CaptureMode {
+    PAUSE.reset();
+    PAUSED.signal(());
+    RESUME.wait().await
+}
+
+/// Switch format, resolution and quality while the capture runs
+///
+/// The capture is paused around the change, the frame pool is resized for the
+/// new largest frame and the sensor reprogrammed. On failure the previous
+/// setting is restored. Consumers waiting for frames simply get the next one
+/// in the new setting.
+pub async fn apply(config: CaptureConfig) -> Result<(), &'static str> {
+    let result = reconfigure(config).await;
+    LAST_ERROR.lock(|e| e.replace(result.err()));
+    result
+}
+
+async fn reconfigure(mut config: CaptureConfig) -> Result<(), &'static str> {
+    if !(ov2640::MIN_QUALITY..=ov2640::MAX_QUALITY).contains(&config.quality) {
+        return Err("quality out of range");
+    }
+    if config.format != PixelFormat::Jpeg && config.resolution != RAW_RESOLUTION {
+        return Err("raw formats are 320x240 only");
+    }
+    let _control = CONTROL.lock().await;
+    // holding the sensor keeps the rate controller and the watchdog off it
+    let mut sensor = SENSOR.lock().await;
+    let sensor = sensor.as_mut().ok_or("no sensor")?;
+    let previous = CaptureConfig::of(sensor);
+    if config == previous {
+        return Ok(());
+    }
+    if config.format == previous.format && config.resolution == previous.resolution {
+        // the frame size bound doesn't change, no need to stop the capture
+        sensor
+            .set_quality(config.quality)
+            .map_err(|_| "can't set the quality")?;
+        update_rate(&config);
+        return Ok(());
+    }
+
+    PAUSED.reset();
+    PAUSE.signal(());
+    if with_timeout(PAUSE_TIMEOUT, PAUSED.wait()).await.is_err() {
+        PAUSE.reset();
+        return Err("capture did not pause");
+    }
+    let mut result = Ok(());
+    if FRAME_POOL.resize(config.frame_capacity()).is_err() {
+        result = Err("not enough PSRAM for the frame pool");
+        config = previous;
+        // shrinking back frees what the failed attempt got
+        let _ = FRAME_POOL.resize(config.frame_capacity());
+    } else if program(sensor, &config).is_err() {
+        result = Err("can't program the sensor");
+        config = previous;
+        let _ = FRAME_POOL.resize(config.frame_capacity());
+        if program(sensor, &config).is_err() {
+            warn!("Camera: can't restore the previous setting");
+        }
+    }
+    RESUME.signal(CaptureMode::of(&config));
+    if result.is_ok() {
+        let (width, height) = config.resolution.size();
+        info!(
+            "Camera: {} {}x{}, quality {}, {} bytes per frame buffer",
+            config.format,
+            width,
+            height,
+            config.quality,
+            config.frame_capacity()
+        );
+        update_rate(&config);
+    }
+    result
+}
+
+fn program(sensor: &mut Sensor, config: &CaptureConfig) -> Result<(), ()> {
+    let current = *sensor.configuration();
+    let format = config.format.sensor();
+    if current.image_format != format {
+        // switching formats soft resets the sensor, so everything is written
+        // again
+        let configuration = Configuration {
+            image_format: format,
+            resolution: config.resolution,
+            quality: config.quality,
+            ..current
+        };
+        return sensor
+            .set_configuration(configuration, &mut Delay)
+            .map_err(|_| ());
+    }
+    if format == ImageFormat::JPEG {
+        sensor.set_resolution(config.resolution).map_err(|_| ())?;
+    }
+    sensor.set_quality(config.quality).map_err(|_| ())
+}
+
+fn update_rate(config: &CaptureConfig) {
+    if config.format == PixelFormat::Jpeg {
+        rate::init(config.quality, config.resolution);
+    }
+    let (width, height) = config.resolution.size();
+    METRICS.frame_width.store(width as u32, Ordering::Relaxed);
+    METRICS.frame_height.store(height as u32, Ordering::Relaxed);
+}
+
+/// Write the capture setting, the frame buffer size and the last error as JSON
+pub async fn render_json(out: &mut impl Write) -> fmt::Result {
+    let Some(config) = config().await else {
+        return write!(out, "{{\"error\":\"no sensor\"}}");
+    };
+    let (width, height) = config.resolution.size();
+    write!(
+        out,
+        "{{\"format\":\"{}\",\"resolution\":\"{}x{}\",\"quality\":{},\"frame_buffer\":{},\"error\":",
+        config.format.name(),
+        width,
+        height,
+        config.quality,
+        FRAME_POOL.capacity()
+    )?;
+    match LAST_ERROR.lock(|e| *e.borrow()) {
+        Some(e) => write!(out, "\"{}\"}}", e),
+        None => write!(out, "null}}"),
+    }
+}
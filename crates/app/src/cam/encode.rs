@@ -0,0 +1,113 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use defmt::warn;
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use media::{
+    jpeg::{Encoder, EncoderConfig, Subsampling},
+    raw::RawImage,
+};
+
+use super::pool::FrameMeta;
+use crate::{
+    mem::{self, PsramBuf},
+    metrics::{inc, METRICS},
+};
+
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<EncoderConfig>> =
+    Mutex::new(RefCell::new(EncoderConfig {
+        quality: 80,
+        subsampling: Subsampling::S420,
+    }));
+
+pub fn config() -> EncoderConfig {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+/// Applies from the next encoded frame on
+pub fn set_config(config: EncoderConfig) {
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+}
+
+/// Software JPEG encoding of raw frames for one consumer
+///
+/// The output buffer is allocated in PSRAM on first use, as large as the raw
+/// frame. A scene that doesn't compress into it (noise at quality 100) drops
+/// the frame.
+pub struct RawEncoder {
+    encoder: Option<Encoder>,
+    buffer: PsramBuf,
+    /// Size of the last JPEG, 0 before the first one
+    last_len: usize,
+}
+
+impl RawEncoder {
+    pub const fn new() -> Self {
+        Self {
+            encoder: None,
+            buffer: PsramBuf::EMPTY,
+            last_len: 0,
+        }
+    }
+
+    pub fn last_len(&self) -> usize {
+        self.last_len
+    }
+
+    /// JPEG of a raw frame, `None` for JPEG frames or if encoding failed
+    ///
+    /// Yields after every row of MCUs so the capture task keeps draining the
+    /// DMA while a frame is encoded.
+    pub async fn encode(&mut self, frame: &[u8], meta: &FrameMeta) -> Option<&[u8]> {
+        let image = RawImage::new(meta.format.raw()?, meta.width, meta.height, frame)?;
+        let config = config();
+        if self.encoder.as_ref().map(|e| e.config()) != Some(config) {
+            self.encoder = Some(Encoder::new(config));
+        }
+        if self.buffer.len() < image.data().len() {
+            // drop the old buffer first, PSRAM may not hold both
+            self.buffer = PsramBuf::EMPTY;
+            self.buffer = mem::psram_buf(image.data().len())?;
+        }
+        let encoder = self.encoder.as_ref()?;
+        let result = async {
+            let mut scan = encoder.start(&image, &mut self.buffer)?;
+            while scan.encode_row()? {
+                yield_now().await;
+            }
+            scan.finish()
+        }
+        .await;
+        match result {
+            Ok(len) => {
+                inc(&METRICS.frames_encoded);
+                self.last_len = len;
+                Some(&self.buffer[..len])
+            }
+            Err(e) => {
+                warn!("Can't encode frame {}: {}", meta.seq, e);
+                inc(&METRICS.encode_failures);
+                None
+            }
+        }
+    }
+}
+
+impl Default for RawEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write quality and chroma subsampling as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    let config = config();
+    write!(
+        out,
+        "{{\"quality\":{},\"subsampling\":\"{}\"}}",
+        config.quality,
+        config.subsampling.name()
+    )
+}
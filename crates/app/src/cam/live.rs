@@ -0,0 +1,343 @@
+use core::{cell::RefCell, sync::atomic::Ordering};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+
+use super::pool::{Frame, FrameWriter};
+use crate::metrics::{inc, METRICS};
+
+/// Live stream clients served at the same time
+pub const MAX_LIVE_CLIENTS: usize = 2;
+/// Chunk size line (fixed 4 hex digits) and the CRLF after the data
+const CHUNK_OVERHEAD: usize = 8;
+/// Largest payload of one HTTP chunk with a 4 digit size
+const MAX_CHUNK: usize = 0xFFFF;
+/// Ends the previous part and starts the frame with its SOI, which the framer
+/// leaves out of its data
+const PART_HEADER: &[u8] = b"\r\n--frame\r\nContent-Type: image/jpeg\r\n\r\n\xFF\xD8";
+/// Bytes of a frame the part header already carries
+const SOI_LEN: usize = 2;
+/// Closes a frame that was cut off, so decoders stop there
+const EOI: &[u8] = b"\xFF\xD9";
+
+struct Client {
+    active: bool,
+    /// Room in the socket for a whole frame, set by the client
+    ready: bool,
+    /// Sending a frame, the one being captured unless `done` is set
+    in_frame: bool,
+    /// Written before the next data, whole or not at all
+    prefix: &'static [u8],
+    /// Bytes of the frame sent, SOI included
+    taken: usize,
+    /// The frame once it is complete, keeps its buffer while the rest goes out
+    done: Option<Frame>,
+}
+
+impl Client {
+    const IDLE: Client = Client {
+        active: false,
+        ready: false,
+        in_frame: false,
+        prefix: &[],
+        taken: 0,
+        done: None,
+    };
+
+    /// Done with a complete frame once all of it went out
+    fn settle(&mut self) {
+        if self.prefix.is_empty() && self.done.as_ref().is_some_and(|f| self.taken == f.len()) {
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.in_frame = false;
+        self.done = None;
+        inc(&METRICS.frames_sent);
+    }
+
+    /// Stop sending the current frame, close it if any of it went out
+    fn cut(&mut self) {
+        if self.in_frame {
+            self.in_frame = false;
+            self.done = None;
+            self.prefix = match self.prefix.is_empty() {
+                true => EOI,
+                // the part header never went out, nothing to close
+                false => &[],
+            };
+        }
+    }
+
+    /// Sending the frame being captured
+    fn capturing(&self) -> bool {
+        self.in_frame && self.done.is_none()
+    }
+}
+
+struct State {
+    /// Changes whenever the DMA chunk or the frame being captured does, a
+    /// copy out of them made across a change is thrown away
+    generation: u32,
+    /// Bytes of the frame being captured so far, SOI included
+    frame_len: usize,
+    /// Address and length of its copy in the frame pool, 0 length without one
+    pool: (usize, usize),
+    /// Address, offset in the frame and length of the DMA chunk lent by the
+    /// capture task, 0 length while none is
+    chunk: (usize, usize, usize),
+    clients: [Client; MAX_LIVE_CLIENTS],
+}
+
+impl State {
+    /// Address and length of what `client` can send next
+    ///
+    /// The DMA chunk comes first, the pool copy is there for clients that
+    /// fell behind it.
+    fn source(&self, client: &Client) -> (usize, usize) {
+        let taken = client.taken;
+        if let Some(frame) = &client.done {
+            return (frame.as_ptr() as usize + taken, frame.len() - taken);
+        }
+        let (addr, start, len) = self.chunk;
+        match client.in_frame {
+            true if (start..start + len).contains(&taken) => {
+                (addr + taken - start, start + len - taken)
+            }
+            true if taken < self.pool.1 => (self.pool.0 + taken, self.pool.1 - taken),
+            _ => (0, 0),
+        }
+    }
+
+    /// Start over with no DMA chunk and no pool copy
+    fn reset_frame(&mut self, frame_len: usize) {
+        self.generation = self.generation.wrapping_add(1);
+        self.frame_len = frame_len;
+        self.pool = (0, 0);
+        self.chunk = (0, 0, 0);
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    generation: 0,
+    frame_len: 0,
+    pool: (0, 0),
+    chunk: (0, 0, 0),
+    clients: [Client::IDLE; MAX_LIVE_CLIENTS],
+}));
+static WAKE: [Signal<CriticalSectionRawMutex, ()>; MAX_LIVE_CLIENTS] =
+    [const { Signal::new() }; MAX_LIVE_CLIENTS];
+
+fn wake_all() {
+    for wake in &WAKE {
+        wake.signal(());
+    }
+}
+
+/// Whether any live client is connected
+pub fn active() -> bool {
+    STATE.lock(|s| s.borrow().clients.iter().any(|c| c.active))
+}
+
+/// A JPEG starts, clients with room join it
+///
+/// A client still sending the last frame has it cut off and skips this one,
+/// so it never holds more than one frame buffer.
+pub fn start_frame() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.reset_frame(SOI_LEN);
+        for client in s.clients.iter_mut().filter(|c| c.active) {
+            client.settle();
+            client.cut();
+            if client.ready && client.prefix.is_empty() {
+                client.in_frame = true;
+                client.prefix = PART_HEADER;
+                client.taken = SOI_LEN;
+            } else {
+                inc(&METRICS.frames_skipped);
+            }
+        }
+    });
+    wake_all();
+}
+
+/// The pool copy of the current frame grew to what is in `writer`
+pub fn written(writer: &FrameWriter) {
+    let data = writer.data();
+    STATE.lock(|s| s.borrow_mut().pool = (data.as_ptr() as usize, data.len()));
+}
+
+/// Lend `data`, the next bytes of the current frame in the DMA buffer, to the
+/// clients in it until [release]
+///
+/// Never waits for them. Clients that missed the last chunk and have no pool
+/// copy to catch up from are cut off. Returns whether any client is waiting
+/// for the data.
+pub fn lend(data: &[u8]) -> bool {
+    let waiting = STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        let start = s.frame_len;
+        s.generation = s.generation.wrapping_add(1);
+        s.chunk = (data.as_ptr() as usize, start, data.len());
+        s.frame_len += data.len();
+        let pool_len = s.pool.1;
+        for client in s.clients.iter_mut().filter(|c| c.capturing()) {
+            if client.taken < start && client.taken >= pool_len {
+                client.cut();
+            }
+        }
+        s.clients.iter().any(|c| c.capturing())
+    });
+    if waiting {
+        wake_all();
+    }
+    waiting
+}
+
+/// The capture task is about to give the lent DMA chunk back
+pub fn release() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        if s.chunk.2 > 0 {
+            s.generation = s.generation.wrapping_add(1);
+            s.chunk = (0, 0, 0);
+        }
+    });
+}
+
+/// The current frame is complete, `frame` is it as committed to the pool
+///
+/// Clients in it keep a reference until they sent the rest. Without a pool
+/// copy only clients that sent all of it from the DMA chunks finish it.
+pub fn end_frame(frame: Option<Frame>) {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        let frame_len = s.frame_len;
+        s.reset_frame(0);
+        for client in s.clients.iter_mut().filter(|c| c.capturing()) {
+            match &frame {
+                Some(frame) => {
+                    client.done = Some(frame.clone());
+                    client.settle();
+                }
+                None if client.prefix.is_empty() && client.taken == frame_len => client.finish(),
+                None => client.cut(),
+            }
+        }
+    });
+    wake_all();
+}
+
+/// The current frame is dropped, clients in it get it cut off
+///
+/// Must be called before its [FrameWriter] is dropped.
+pub fn abort_frame() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.reset_frame(0);
+        for client in s.clients.iter_mut().filter(|c| c.capturing()) {
+            client.cut();
+        }
+    });
+    wake_all();
+}
+
+/// A connected live stream client
+pub struct LiveClient {
+    id: usize,
+}
+
+impl LiveClient {
+    /// Take a free slot, `None` if [MAX_LIVE_CLIENTS] are connected
+    pub fn register() -> Option<Self> {
+        STATE.lock(|s| {
+            let mut s = s.borrow_mut();
+            let id = s.clients.iter().position(|c| !c.active)?;
+            s.clients[id] = Client {
+                active: true,
+                ..Client::IDLE
+            };
+            WAKE[id].reset();
+            Some(LiveClient { id })
+        })
+    }
+
+    /// Whether the socket has room for a frame of `free` bytes, checked when
+    /// the next frame starts
+    pub fn set_ready(&self, free: usize) {
+        let ready = free >= METRICS.frame_size_avg.load(Ordering::Relaxed) as usize;
+        STATE.lock(|s| s.borrow_mut().clients[self.id].ready = ready);
+    }
+
+    /// Wait until there may be something to send
+    pub async fn wait(&self) {
+        WAKE[self.id].wait().await
+    }
+
+    /// Write what is pending for this client as one HTTP chunk into `buf`,
+    /// the free part of the socket's send buffer. Returns the bytes written,
+    /// 0 if nothing is pending or `buf` is too small for it.
+    ///
+    /// Only where to copy from is looked up under the lock, the copy is made
+    /// outside and kept if the source didn't change meanwhile.
+    pub fn drain(&self, buf: &mut [u8]) -> usize {
+        let (generation, from_done, prefix, (addr, available)) = STATE.lock(|s| {
+            let s = s.borrow();
+            let client = &s.clients[self.id];
+            let from_done = client.done.is_some();
+            (s.generation, from_done, client.prefix, s.source(client))
+        });
+        let room = buf.len().saturating_sub(CHUNK_OVERHEAD).min(MAX_CHUNK);
+        if prefix.len() > room {
+            return 0;
+        }
+        let take = available.min(room - prefix.len());
+        let len = prefix.len() + take;
+        if len == 0 {
+            return 0;
+        }
+        write_hex(&mut buf[..4], len as u16);
+        buf[4..6].copy_from_slice(b"\r\n");
+        buf[6..6 + prefix.len()].copy_from_slice(prefix);
+        if take > 0 {
+            // SAFETY: a complete frame stays put while this client holds it,
+            // the DMA chunk and the pool copy while the generation is the same,
+            // a copy made across a change is not sent
+            let data = unsafe { core::slice::from_raw_parts(addr as *const u8, take) };
+            buf[6 + prefix.len()..6 + len].copy_from_slice(data);
+        }
+        buf[6 + len..8 + len].copy_from_slice(b"\r\n");
+        STATE.lock(|s| {
+            let mut s = s.borrow_mut();
+            let unchanged = s.generation == generation;
+            let client = &mut s.clients[self.id];
+            let kept = match from_done {
+                true => client.done.is_some(),
+                false => unchanged,
+            };
+            if !kept {
+                return 0;
+            }
+            client.prefix = &[];
+            client.taken += take;
+            client.settle();
+            CHUNK_OVERHEAD + len
+        })
+    }
+}
+
+impl Drop for LiveClient {
+    fn drop(&mut self) {
+        STATE.lock(|s| s.borrow_mut().clients[self.id] = Client::IDLE);
+    }
+}
+
+fn write_hex(out: &mut [u8], value: u16) {
+    for (i, byte) in out.iter_mut().enumerate() {
+        let nibble = (value >> (12 - 4 * i)) as u8 & 0xF;
+        *byte = b"0123456789abcdef"[nibble as usize];
+    }
+}
@@ -1,69 +1,84 @@
-use alloc::vec::Vec;
-use defmt::{error, info, warn};
+use core::sync::atomic::Ordering;
+use defmt::{debug, error, info, warn};
+use embassy_futures::yield_now;
 use embassy_net::tcp::TcpSocket;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_hal::{
     delay::Delay,
-    dma::DmaRxStreamBuf,
+    dma_rx_stream_buffer,
     gpio::{Level, Output, OutputConfig},
     i2c,
     lcd_cam::{
         cam::{Camera, Config, EofMode},
         LcdCam,
     },
-    peripherals::Peripherals,
+    peripherals::{DMA_CH0, I2C0, LCD_CAM},
     time::Rate,
 };
+use http::{Response, Status};
+use media::{
+    jpeg::{self, Event, FrameError, JpegFramer},
+    raw::{Export, RawImage},
+};
 
-use crate::wifi::write_all;
+use crate::{
+    board::CamPins,
+    flash, metadata,
+    metrics::{inc, METRICS},
+    wifi::{self, write_all},
+};
 
-pub enum CamEvent {
-    FrameStart,
-    Data(Vec<u8>),
-    FrameEnd,
-}
+pub mod control;
+use control::{CaptureMode, PixelFormat};
+pub mod encode;
+use encode::RawEncoder;
+pub mod live;
+use live::LiveClient;
+pub mod pool;
+pub mod power;
+use pool::{Frame, FrameMeta, FrameWriter, FRAME_POOL};
+pub mod rate;
+pub mod sensor;
+pub mod settings;
+pub mod watchdog;
+use sensor::{SensorState, SENSOR};
 
-pub static CAM_CHANNEL: Channel<CriticalSectionRawMutex, CamEvent, 5> = Channel::new();
+/// How long a consumer waits for a new frame before giving up
+pub(crate) const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many frames a snapshot looks at to find a valid one
+const SNAPSHOT_ATTEMPTS: usize = 3;
+/// Converted rows are sent in pieces of up to this size
+const RAW_CHUNK: usize = 2048;
+/// How long a stream client may keep its send buffer full before it is dropped
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often exposure and gain are read back from the sensor
+const SENSOR_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-/// GND
-/// SCL    ->   13
-/// SDA    ->   12
-/// D0     ->   11
-/// D2     ->   10
-/// D4     ->   9
-/// D6     ->   46
-/// PCLK   ->   3
-/// PWDN   ->   8
-/// 3.3V
-/// VSYNC   ->  4
-/// HREF   ->   5
-/// RST    ->   6
-/// D1     ->   7
-/// D3     ->   15
-/// D5     ->   16
-/// D7     ->   17
-/// FLASH  ->   18
-pub async fn init_cam(peripherals: Peripherals) -> Result<Camera<'static>, ()> {
+/// Bring up the sensor on `pins` and the LCD_CAM receiver
+pub async fn init_cam(
+    pins: CamPins,
+    i2c: I2C0<'static>,
+    lcd_cam: LCD_CAM<'static>,
+    dma: DMA_CH0<'static>,
+) -> Result<Camera<'static>, ()> {
     let mut delay = Delay::new();
 
-    let _pwdn = Output::new(peripherals.GPIO8, Level::Low, OutputConfig::default());
-    let mut rst = Output::new(peripherals.GPIO6, Level::Low, OutputConfig::default());
-
-    delay.delay_millis(10);
-    rst.set_high();
+    if let Some(pwdn) = pins.pwdn {
+        power::init(Output::new(pwdn, Level::Low, OutputConfig::default()));
+    }
+    if let Some(reset) = pins.reset {
+        let mut rst = Output::new(reset, Level::Low, OutputConfig::default());
+        delay.delay_millis(10);
+        rst.set_high();
+        power::init_reset(rst);
+    }
     delay.delay_millis(10);
 
     let i2c_config = i2c::master::Config::default();
-    let i2c = i2c::master::I2c::new(peripherals.I2C0, i2c_config)
+    let i2c = i2c::master::I2c::new(i2c, i2c_config)
         .unwrap()
-        .with_scl(peripherals.GPIO13)
-        .with_sda(peripherals.GPIO12);
-
-    let vsync_pin = peripherals.GPIO4;
-    let href_pin = peripherals.GPIO5;
-    let pclk_pin = peripherals.GPIO3;
+        .with_scl(pins.scl)
+        .with_sda(pins.sda);
 
     let config = Config::default()
         .with_frequency(Rate::from_mhz(20))
@@ -71,20 +86,24 @@ pub async fn init_cam(peripherals: Peripherals) -> Result<Camera<'static>, ()> {
         .with_invert_vsync(false)
         .with_invert_h_enable(false);
 
-    let lcd_cam = LcdCam::new(peripherals.LCD_CAM);
-    let camera = Camera::new(lcd_cam.cam, peripherals.DMA_CH0, config)
+    let lcd_cam = LcdCam::new(lcd_cam);
+    let [d0, d1, d2, d3, d4, d5, d6, d7] = pins.data;
+    let mut camera = Camera::new(lcd_cam.cam, dma, config)
         .unwrap()
-        .with_data0(peripherals.GPIO11)
-        .with_data1(peripherals.GPIO7)
-        .with_data2(peripherals.GPIO10)
-        .with_data3(peripherals.GPIO15)
-        .with_data4(peripherals.GPIO9)
-        .with_data5(peripherals.GPIO16)
-        .with_data6(peripherals.GPIO46)
-        .with_data7(peripherals.GPIO17)
-        .with_pixel_clock(pclk_pin)
-        .with_vsync(vsync_pin)
-        .with_h_enable(href_pin);
+        .with_data0(d0)
+        .with_data1(d1)
+        .with_data2(d2)
+        .with_data3(d3)
+        .with_data4(d4)
+        .with_data5(d5)
+        .with_data6(d6)
+        .with_data7(d7)
+        .with_pixel_clock(pins.pclk)
+        .with_vsync(pins.vsync)
+        .with_h_enable(pins.href);
+    if let Some(xclk) = pins.xclk {
+        camera = camera.with_master_clock(xclk);
+    }
 
     let mut ov = ov2640::OV2640::new(i2c);
     match ov.init(&mut delay) {
@@ -115,53 +134,61 @@ pub async fn init_cam(peripherals: Peripherals) -> Result<Camera<'static>, ()> {
         Ok(_) => defmt::info!("ov2640 set_special_effect ok"),
         Err(e) => defmt::warn!("ov2640 set_special_effect failed {:?}", e),
     };
+    rate::init(ov.configuration().quality, ov.configuration().resolution);
+    *SENSOR.lock().await = Some(ov);
     Ok(camera)
 }
 
-pub async fn stream_camera(
-    mut camera: Camera<'static>,
-    mut dma_buf: DmaRxStreamBuf,
-    socket: &mut TcpSocket<'_>,
-) -> (Camera<'static>, DmaRxStreamBuf) {
-    if let Err(e) = write_all(
-        socket,
-        b"HTTP/1.1 200 OK\r\n\
-          Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
-          Cache-Control: no-cache\r\n\
-          Connection: keep-alive\r\n\r\n",
-    )
-    .await
-    {
-        warn!("Failed to send HTTP headers: {}", e);
-        return (camera, dma_buf);
-    }
-    let mut jpeg_buffer = unsafe {
-        let p = esp_alloc::HEAP.alloc_caps(
-            esp_alloc::MemoryCapability::External.into(),
-            core::alloc::Layout::from_size_align(40960 * 10, 4).unwrap(),
-        );
-        alloc::vec::Vec::from_raw_parts(p, 40960 * 10, 40960 * 10)
+/// Move frames from the DMA into the frame pool
+///
+/// The DMA ring is a stream buffer that never holds a whole frame, so only the
+/// frame pool and the framer follow the frame size when [control] switches the
+/// setting.
+#[embassy_executor::task]
+pub async fn capture_task(mut camera: Camera<'static>) {
+    let Ok(mut mode) = control::init_pool().await else {
+        error!("Failed to allocate frame pool");
+        return;
     };
-    let mut jpeg_len = 0;
-    let mut frame_count = 0;
-    let mut in_frame = false;
+    let mut dma_buf = dma_rx_stream_buffer!(65536, 1024);
+    let mut framer = JpegFramer::with_max_len(mode.frame_len);
+    let mut writer = None;
+    // raw frames have no markers, one starts with the first byte after VSYNC
+    let mut raw_start;
+    let mut frame_count: u32 = 0;
     let mut fps_count = 0;
     let mut last_fps_instant = Instant::now();
+    let mut sensor_state = SensorState::default();
+    let mut last_sensor_read = Instant::from_ticks(0);
 
     loop {
+        if control::pause_requested() {
+            mode = control::paused().await;
+            framer = JpegFramer::with_max_len(mode.frame_len);
+            info!("Capturing {} {}x{}", mode.format, mode.width, mode.height);
+        }
+        let mut vsync = Instant::now();
+        raw_start = false;
+        watchdog::RESTART.reset();
         let mut transfer = match camera.receive(dma_buf) {
             Ok(t) => t,
             Err((e, cam, buf)) => {
                 error!("Camera receive error: {:?}", e);
-                return (cam, buf);
+                (camera, dma_buf) = (cam, buf);
+                Timer::after(Duration::from_millis(100)).await;
+                continue;
             }
         };
         loop {
             let (data, eof) = transfer.peek_until_eof();
             let len = data.len();
             if data.is_empty() {
+                if watchdog::RESTART.signaled() || control::pause_requested() {
+                    break;
+                }
                 if transfer.is_done() {
                     warn!("Too slow!");
+                    inc(&METRICS.dma_too_slow);
                     break;
                 }
                 if eof {
@@ -170,129 +197,588 @@ pub async fn stream_camera(
                 Timer::after_micros(100).await;
                 continue;
             }
-            if let Err(_) = process_jpeg_data(
-                data,
-                &mut jpeg_buffer,
-                &mut jpeg_len,
-                &mut in_frame,
-                &mut frame_count,
-                socket,
-            )
-            .await
-            {
-                warn!("process error");
-                return transfer.stop();
+            match mode.format {
+                PixelFormat::Jpeg => {
+                    let lent = process_jpeg_data(
+                        data,
+                        &mut framer,
+                        &mut writer,
+                        vsync,
+                        &sensor_state,
+                        &mut frame_count,
+                    );
+                    // one turn for the live clients to send from the DMA
+                    // buffer, without waiting for them
+                    if lent {
+                        yield_now().await;
+                    }
+                    live::release();
+                }
+                _ => process_raw_data(data, &mode, &mut writer, vsync, &mut raw_start),
             }
             transfer.consume(len);
             if eof {
+                if let Some(e) = framer.finish() {
+                    warn!("Dropping JPEG frame at VSYNC: {}", e);
+                    inc(&METRICS.frames_dropped);
+                    live::abort_frame();
+                    writer = None;
+                }
+                if mode.format != PixelFormat::Jpeg {
+                    if let Some(w) = writer.take() {
+                        if w.len() == mode.frame_len {
+                            watchdog::frame();
+                            commit_frame(
+                                w,
+                                mode.width,
+                                mode.height,
+                                &sensor_state,
+                                &mut frame_count,
+                            );
+                        } else {
+                            debug!("Dropping raw frame of {} bytes", w.len());
+                            inc(&METRICS.frames_dropped);
+                        }
+                    }
+                    raw_start = true;
+                }
                 let now = Instant::now();
+                vsync = now;
+                watchdog::vsync();
+                if control::pause_requested() {
+                    break;
+                }
+                if now - last_sensor_read >= SENSOR_POLL_INTERVAL {
+                    if let Some(state) = sensor::try_read_state() {
+                        sensor_state = state;
+                    }
+                    last_sensor_read = now;
+                }
                 if now - last_fps_instant >= Duration::from_secs(1) {
-                    let frames_sent = frame_count - fps_count;
-                    info!("FPS: {}", frames_sent);
+                    let frames_captured = frame_count - fps_count;
+                    info!("FPS: {}", frames_captured);
                     fps_count = frame_count;
                     last_fps_instant = now;
                     defmt::info!("HEAP: {:?}", esp_alloc::HEAP.stats());
                 }
             }
         }
+        if framer.finish().is_some() {
+            live::abort_frame();
+        }
+        writer = None;
         (camera, dma_buf) = transfer.stop();
     }
 }
 
-async fn process_jpeg_data(
+/// Copy raw pixels into the frame started at the last VSYNC, it is committed
+/// at the next VSYNC if it came out at the expected size
+fn process_raw_data(
     data: &[u8],
-    jpeg_buffer: &mut [u8],
-    jpeg_len: &mut usize,
-    in_frame: &mut bool,
+    mode: &CaptureMode,
+    writer: &mut Option<FrameWriter>,
+    vsync: Instant,
+    start: &mut bool,
+) {
+    if core::mem::take(start) {
+        *writer = FRAME_POOL.writer();
+        match writer {
+            Some(w) => {
+                let meta = w.meta_mut();
+                meta.captured_at = vsync;
+                meta.format = mode.format;
+            }
+            None => {
+                debug!("No free frame buffer, dropping frame");
+                inc(&METRICS.frames_dropped);
+                return;
+            }
+        }
+    }
+    if let Some(w) = writer {
+        if w.extend(data).is_err() {
+            warn!("Raw frame longer than {} bytes, dropping", mode.frame_len);
+            inc(&METRICS.frames_overflowed);
+            *writer = None;
+        }
+    }
+}
+
+/// Fill in what is known at the end of a frame and publish it
+fn commit_frame(
+    mut writer: FrameWriter,
+    width: u16,
+    height: u16,
+    sensor_state: &SensorState,
     frame_count: &mut u32,
-    socket: &mut TcpSocket<'_>,
-) -> Result<(), ()> {
-    let mut i = 0;
-    while i < data.len() {
-        if !*in_frame {
-            if let Some(pos) = data[i..].windows(2).position(|w| w == [0xFF, 0xD8]) {
-                *in_frame = true;
-                *jpeg_len = 0;
-                i += pos;
+) {
+    let meta = writer.meta_mut();
+    meta.width = width;
+    meta.height = height;
+    meta.exposure = sensor_state.exposure;
+    meta.gain = sensor_state.gain;
+    METRICS.frame_width.store(width as u32, Ordering::Relaxed);
+    METRICS.frame_height.store(height as u32, Ordering::Relaxed);
+    METRICS.frame_captured(writer.len());
+    writer.commit();
+    *frame_count = frame_count.wrapping_add(1);
+}
 
-                if jpeg_buffer.len() >= 2 {
-                    jpeg_buffer[..2].copy_from_slice(&[0xFF, 0xD8]);
-                    *jpeg_len = 2;
-                    i += 2;
-                } else {
-                    warn!("buf to small, buf size: {}", jpeg_buffer.len());
-                    return Err(());
+/// Copy a JPEG into the frame pool and lend its data to live stream clients
+///
+/// Returns whether the last chunk lent is waiting to be sent.
+fn process_jpeg_data(
+    data: &[u8],
+    framer: &mut JpegFramer,
+    writer: &mut Option<FrameWriter>,
+    vsync: Instant,
+    sensor_state: &SensorState,
+    frame_count: &mut u32,
+) -> bool {
+    let mut lent = false;
+    for event in framer.feed(data) {
+        match event {
+            Event::Start => {
+                // clients behind on the last frame give its buffer back first
+                live::start_frame();
+                *writer = FRAME_POOL.writer();
+                match writer {
+                    Some(w) => {
+                        let _ = w.extend(&[0xFF, jpeg::SOI]);
+                        w.meta_mut().captured_at = vsync;
+                        live::written(w);
+                    }
+                    // live clients can still get it out of the DMA buffer
+                    None => {
+                        debug!("No free frame buffer, dropping frame");
+                        inc(&METRICS.frames_dropped);
+                    }
                 }
-            } else {
+            }
+            Event::Data(bytes) => {
+                if let Some(w) = writer {
+                    if w.extend(bytes).is_err() {
+                        warn!(
+                            "JPEG buffer overflow, dropping frame (size: {})",
+                            w.len() + bytes.len()
+                        );
+                        inc(&METRICS.frames_overflowed);
+                        live::abort_frame();
+                        *writer = None;
+                        continue;
+                    }
+                    live::written(w);
+                }
+                lent = live::lend(bytes);
+            }
+            Event::End(info) => {
+                watchdog::frame();
+                lent = false;
+                match writer.take() {
+                    Some(w) => {
+                        commit_frame(w, info.width, info.height, sensor_state, frame_count);
+                        live::end_frame(FRAME_POOL.latest());
+                    }
+                    None => live::end_frame(None),
+                }
+            }
+            Event::Error(FrameError::TooLarge) => {
+                live::abort_frame();
+                lent = false;
+                let size = writer.as_ref().map_or(0, |w| w.len());
+                warn!("JPEG buffer overflow, dropping frame (size: {})", size);
+                inc(&METRICS.frames_overflowed);
+                *writer = None;
+            }
+            Event::Error(e) => {
+                live::abort_frame();
+                lent = false;
+                warn!("Dropping JPEG frame: {}", e);
+                inc(&METRICS.frames_dropped);
+                *writer = None;
+            }
+        }
+    }
+    lent
+}
+
+/// Stream frames as `multipart/x-mixed-replace`
+///
+/// `fps` limits the frame rate for this client. Frames are skipped while the
+/// socket still holds a full frame of unsent data, so a slow client always gets
+/// the newest frame instead of a growing backlog.
+///
+/// Raw frames are encoded to JPEG in software for this client. Only the head
+/// is sent if `head_only`.
+pub async fn stream_camera(socket: &mut TcpSocket<'_>, fps: Option<u32>, head_only: bool) {
+    let head = Response::<256>::new(Status::Ok)
+        .content_type("multipart/x-mixed-replace; boundary=frame")
+        .no_cache();
+    if wifi::send_head(socket, head).await.is_err() {
+        warn!("Failed to send HTTP headers");
+        return;
+    }
+    if head_only {
+        return;
+    }
+    let _client = METRICS.client();
+    power::wake().await;
+    let interval = fps.map(|fps| Duration::from_micros(1_000_000 / fps.max(1) as u64));
+    let mut last_seq = 0;
+    let mut next_due = Instant::now();
+    let mut last_progress = Instant::now();
+    let mut sent: u32 = 0;
+    let mut skipped: u32 = 0;
+    let mut encoder = RawEncoder::new();
+    loop {
+        if interval.is_some() {
+            Timer::at(next_due).await;
+        }
+        let frame = match with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(last_seq)).await {
+            Ok(frame) => frame,
+            Err(_) => {
+                warn!("No frame within {} ms", FRAME_TIMEOUT.as_millis());
+                break;
+            }
+        };
+        let mut skip = 0;
+        if last_seq != 0 {
+            skip = frame.seq().wrapping_sub(last_seq).saturating_sub(1);
+        }
+        last_seq = frame.seq();
+        let frame_len = match frame.meta().format {
+            PixelFormat::Jpeg => frame.len(),
+            // judged by the last encoded frame, not worth encoding otherwise
+            _ => encoder.last_len(),
+        };
+        let backlogged = frame_len > 0 && socket.send_queue() >= frame_len;
+        if backlogged {
+            skip += 1;
+            inc(&METRICS.frames_backlogged);
+        }
+        skipped += skip;
+        METRICS.frames_skipped.fetch_add(skip, Ordering::Relaxed);
+        if backlogged {
+            if Instant::now() - last_progress >= STREAM_STALL_TIMEOUT {
+                warn!("Stream client stalled, closing");
                 break;
             }
             continue;
         }
+        let jpeg = match frame.meta().format {
+            PixelFormat::Jpeg => &frame[..],
+            _ => match encoder.encode(&frame, frame.meta()).await {
+                Some(jpeg) => jpeg,
+                None => continue,
+            },
+        };
+        if let Err(e) = send_jpeg_frame(socket, jpeg, frame.meta()).await {
+            warn!("Failed to send frame: {}", e);
+            break;
+        }
+        sent += 1;
+        inc(&METRICS.frames_sent);
+        last_progress = Instant::now();
+        if let Some(interval) = interval {
+            // don't let a slow period turn into a burst afterwards
+            next_due = (next_due + interval).max(last_progress);
+        }
+    }
+    info!("Stream closed: {} frames sent, {} skipped", sent, skipped);
+}
 
-        let remaining = &data[i..];
-        if let Some(eoi_pos) = remaining.windows(2).position(|w| w == [0xFF, 0xD9]) {
-            let bytes_to_copy = eoi_pos + 2;
-            if *jpeg_len + bytes_to_copy <= jpeg_buffer.len() {
-                jpeg_buffer[*jpeg_len..*jpeg_len + bytes_to_copy]
-                    .copy_from_slice(&remaining[..bytes_to_copy]);
-                *jpeg_len += bytes_to_copy;
-                if let Err(e) =
-                    send_jpeg_frame(socket, &jpeg_buffer[..*jpeg_len], *frame_count).await
-                {
-                    warn!("Failed to send frame: {}", e);
-                    return Err(());
+/// Stream JPEGs from the frame buffer they are captured into as chunked
+/// `multipart/x-mixed-replace`
+///
+/// Parts carry no length or per-frame headers, frames go out while they are
+/// captured. A frame only starts when the socket has room for an average one,
+/// a frame not sent by the time the next one starts is cut off and closed
+/// with EOI. Raw formats, rate limits and metadata need complete frames, see
+/// [stream_camera]. Only the head is sent if `head_only`, no client slot is
+/// taken for it.
+pub async fn stream_live(socket: &mut TcpSocket<'_>, head_only: bool) {
+    let head = Response::<256>::new(Status::Ok)
+        .content_type("multipart/x-mixed-replace; boundary=frame")
+        .header("Transfer-Encoding", "chunked")
+        .no_cache();
+    if head_only {
+        _ = wifi::send_head(socket, head).await;
+        return;
+    }
+    let Some(client) = LiveClient::register() else {
+        _ = wifi::send_response(
+            socket,
+            Status::ServiceUnavailable,
+            "text/plain",
+            b"Too many clients\n",
+            false,
+        )
+        .await;
+        return;
+    };
+    if wifi::send_head(socket, head).await.is_err() {
+        warn!("Failed to send HTTP headers");
+        return;
+    }
+    let _client = METRICS.client();
+    power::wake().await;
+    'stream: loop {
+        client.set_ready(socket.send_capacity() - socket.send_queue());
+        if with_timeout(FRAME_TIMEOUT, client.wait()).await.is_err() {
+            warn!("No live frame within {} ms", FRAME_TIMEOUT.as_millis());
+            break;
+        }
+        loop {
+            match socket
+                .write_with(|buf| {
+                    let n = client.drain(buf);
+                    (n, n)
+                })
+                .await
+            {
+                Ok(0) => break,
+                Ok(n) => {
+                    METRICS.bytes_sent.fetch_add(n as u32, Ordering::Relaxed);
                 }
-                *frame_count += 1;
-            } else {
-                warn!(
-                    "JPEG buffer overflow, dropping frame (size: {})",
-                    *jpeg_len + bytes_to_copy
-                );
+                Err(_) => break 'stream,
             }
-            *in_frame = false;
-            *jpeg_len = 0;
-            i += bytes_to_copy;
-        } else {
-            let bytes_to_copy = remaining.len();
-            let available_space = jpeg_buffer.len().saturating_sub(*jpeg_len);
+        }
+    }
+    // the last chunk
+    _ = write_all(socket, b"0\r\n\r\n").await;
+    info!("Live stream closed");
+}
 
-            if bytes_to_copy <= available_space {
-                jpeg_buffer[*jpeg_len..*jpeg_len + bytes_to_copy].copy_from_slice(remaining);
-                *jpeg_len += bytes_to_copy;
-            } else {
-                warn!("JPEG buffer full at {} bytes, dropping frame", *jpeg_len);
-                *in_frame = false;
-                *jpeg_len = 0;
+/// Pick a valid JPEG for a snapshot
+///
+/// Without `fresh` the latest cached frame is used. With `with_flash` the
+/// flash LED is lit and the first frame exposed entirely with it is taken,
+/// unless it is cooling down.
+pub async fn snapshot(fresh: bool, with_flash: bool) -> Option<Frame> {
+    if control::config()
+        .await
+        .is_some_and(|c| c.format != PixelFormat::Jpeg)
+    {
+        return None;
+    }
+    // the cached frame is stale if the sensor was in standby
+    let fresh = fresh || power::is_standby();
+    if !power::wake().await {
+        return None;
+    }
+    let mut seq = match FRAME_POOL.latest() {
+        Some(frame) if !fresh && !with_flash && jpeg::validate(&frame).is_ok() => {
+            return Some(frame)
+        }
+        Some(frame) => frame.seq(),
+        None => 0,
+    };
+    let lit_at = match with_flash {
+        true => flash::acquire().await,
+        false => None,
+    };
+    if let Some(lit_at) = lit_at {
+        // rows are exposed while the frame before is read out, so the first
+        // frame lit all over follows the first one started with the flash on
+        loop {
+            let Ok(frame) = with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(seq)).await else {
+                flash::release();
+                return None;
+            };
+            seq = frame.seq();
+            if frame.meta().captured_at > lit_at {
+                break;
             }
+        }
+    }
+    let mut snapshot = None;
+    for _ in 0..SNAPSHOT_ATTEMPTS {
+        let Ok(frame) = with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(seq)).await else {
             break;
+        };
+        seq = frame.seq();
+        match jpeg::validate(&frame) {
+            Ok(_) => {
+                snapshot = Some(frame);
+                break;
+            }
+            Err(e) => warn!("Invalid snapshot frame {}: {}", seq, e),
         }
     }
+    if with_flash {
+        flash::release();
+    }
+    snapshot
+}
 
-    Ok(())
+pub async fn send_snapshot(
+    socket: &mut TcpSocket<'_>,
+    fresh: bool,
+    with_flash: bool,
+    head_only: bool,
+) {
+    let Some(frame) = snapshot(fresh, with_flash).await else {
+        warn!("No frame for snapshot");
+        _ = wifi::send_response(
+            socket,
+            Status::ServiceUnavailable,
+            "text/plain",
+            b"No Image",
+            head_only,
+        )
+        .await;
+        return;
+    };
+    let jpeg = metadata::tag(&frame, frame.meta());
+    let head = Response::<512>::new(Status::Ok)
+        .content_type("image/jpeg")
+        .content_length(jpeg.len())
+        .no_cache()
+        .headers_with(|out| write_meta_headers(out, frame.meta()));
+    if wifi::send_head(socket, head).await.is_err() || head_only {
+        return;
+    }
+    for part in jpeg.parts() {
+        if let Err(e) = write_all(socket, part).await {
+            warn!("Failed to send snapshot: {}", e);
+            return;
+        }
+    }
+    inc(&METRICS.frames_sent);
+}
+
+/// Latest raw frame, `None` while the sensor delivers JPEG
+async fn raw_frame() -> Option<Frame> {
+    if control::config()
+        .await
+        .is_none_or(|c| c.format == PixelFormat::Jpeg)
+    {
+        return None;
+    }
+    let fresh = power::is_standby();
+    if !power::wake().await {
+        return None;
+    }
+    let mut seq = match FRAME_POOL.latest() {
+        Some(frame) if !fresh && frame.meta().format != PixelFormat::Jpeg => return Some(frame),
+        Some(frame) => frame.seq(),
+        None => 0,
+    };
+    // right after a switch the pool may still hand out JPEG frames
+    for _ in 0..SNAPSHOT_ATTEMPTS {
+        let frame = with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(seq))
+            .await
+            .ok()?;
+        if frame.meta().format != PixelFormat::Jpeg {
+            return Some(frame);
+        }
+        seq = frame.seq();
+    }
+    None
+}
+
+/// Send the latest raw frame uncompressed as `export`
+///
+/// Rows are converted while they are sent, nothing but a chunk is buffered.
+/// Only the head is sent if `head_only`.
+pub async fn send_raw(socket: &mut TcpSocket<'_>, export: Export, head_only: bool) {
+    let Some(frame) = raw_frame().await else {
+        warn!("No raw frame");
+        _ = wifi::send_response(
+            socket,
+            Status::Conflict,
+            "text/plain",
+            b"No raw frame",
+            head_only,
+        )
+        .await;
+        return;
+    };
+    let meta = frame.meta();
+    let Some(image) = meta
+        .format
+        .raw()
+        .and_then(|format| RawImage::new(format, meta.width, meta.height, &frame))
+    else {
+        warn!("Raw frame doesn't match its format and size");
+        _ = wifi::send_response(
+            socket,
+            Status::InternalServerError,
+            "text/plain",
+            b"Bad raw frame",
+            head_only,
+        )
+        .await;
+        return;
+    };
+    let head = Response::<512>::new(Status::Ok)
+        .content_type(export.content_type())
+        .content_length(export.file_len(&image))
+        .header(
+            "Content-Disposition",
+            format_args!(
+                "inline; filename=\"frame-{}.{}\"",
+                meta.seq,
+                export.extension()
+            ),
+        )
+        .header("X-Pixel-Format", meta.format.name())
+        .no_cache()
+        .headers_with(|out| write_meta_headers(out, meta));
+    if wifi::send_head(socket, head).await.is_err() || head_only {
+        return;
+    }
+
+    let mut chunk = [0u8; RAW_CHUNK];
+    let len = export.write_header(&image, meta.seq, meta.captured_at.as_micros(), &mut chunk);
+    if write_all(socket, &chunk[..len]).await.is_err() {
+        return;
+    }
+    let row_len = export.row_len(&image);
+    let mut len = 0;
+    for row in 0..image.height() as usize {
+        if len + row_len > chunk.len() {
+            if write_all(socket, &chunk[..len]).await.is_err() {
+                return;
+            }
+            len = 0;
+        }
+        len += export.write_row(&image, row, &mut chunk[len..]);
+    }
+    if let Err(e) = write_all(socket, &chunk[..len]).await {
+        warn!("Failed to send raw frame: {}", e);
+        return;
+    }
+    inc(&METRICS.frames_sent);
 }
 
 async fn send_jpeg_frame(
     socket: &mut TcpSocket<'_>,
     jpeg_data: &[u8],
-    _frame_count: u32,
+    meta: &FrameMeta,
 ) -> Result<(), ()> {
-    let mut header = heapless::String::<256>::new();
+    let jpeg = metadata::tag(jpeg_data, meta);
+    let mut header = heapless::String::<512>::new();
     use core::fmt::Write;
 
     let _ = write!(
         &mut header,
-        "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
-        jpeg_data.len()
+        "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n",
+        jpeg.len()
     );
+    let _ = write_meta_headers(&mut header, meta);
+    let _ = write!(&mut header, "\r\n");
 
     if let Err(e) = write_all(socket, header.as_bytes()).await {
         warn!("Failed to send frame header: {}", e);
         return Err(());
     }
 
-    if let Err(e) = write_all(socket, jpeg_data).await {
-        warn!("Failed to send JPEG data: {}", e);
-        return Err(());
+    for part in jpeg.parts() {
+        if let Err(e) = write_all(socket, part).await {
+            warn!("Failed to send JPEG data: {}", e);
+            return Err(());
+        }
     }
 
     if let Err(e) = write_all(socket, b"\r\n").await {
@@ -302,3 +788,19 @@ async fn send_jpeg_frame(
 
     Ok(())
 }
+
+/// `X-Frame-Seq`, `X-Timestamp` (capture time since boot) and the sensor state
+fn write_meta_headers(header: &mut dyn core::fmt::Write, meta: &FrameMeta) -> core::fmt::Result {
+    let micros = meta.captured_at.as_micros();
+    write!(
+        header,
+        "X-Frame-Seq: {}\r\nX-Timestamp: {}.{:06}\r\nX-Resolution: {}x{}\r\nX-Exposure: {}\r\nX-Gain: {}\r\n",
+        meta.seq,
+        micros / 1_000_000,
+        micros % 1_000_000,
+        meta.width,
+        meta.height,
+        meta.exposure,
+        meta.gain
+    )
+}
@@ -0,0 +1,358 @@
+use core::{cell::RefCell, future::poll_fn, ops::Deref, task::Poll};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    waitqueue::MultiWakerRegistration,
+};
+use embassy_time::Instant;
+
+use super::control::PixelFormat;
+use crate::mem::{psram_buf, PsramBuf};
+
+/// Number of frame buffers in the pool
+pub const POOL_SLOTS: usize = 4;
+const MAX_WAITERS: usize = 8;
+
+pub static FRAME_POOL: FramePool = FramePool::new();
+
+/// What is known about a captured frame
+#[derive(Clone, Copy)]
+pub struct FrameMeta {
+    /// Sequence number, increases by one for every published frame
+    pub seq: u32,
+    /// VSYNC that started the frame
+    pub captured_at: Instant,
+    /// Frame size in bytes
+    pub len: usize,
+    pub format: PixelFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Exposure time in line periods
+    pub exposure: u16,
+    /// Raw 10 bit AGC value
+    pub gain: u16,
+}
+
+impl FrameMeta {
+    pub const EMPTY: FrameMeta = FrameMeta {
+        seq: 0,
+        captured_at: Instant::from_ticks(0),
+        len: 0,
+        format: PixelFormat::Jpeg,
+        width: 0,
+        height: 0,
+        exposure: 0,
+        gain: 0,
+    };
+}
+
+struct Slot {
+    buf: PsramBuf,
+    meta: FrameMeta,
+    refs: u16,
+    writing: bool,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        buf: PsramBuf::EMPTY,
+        meta: FrameMeta::EMPTY,
+        refs: 0,
+        writing: false,
+    };
+
+    /// Bring the buffer to `capacity` bytes, only while nothing references it
+    fn reallocate(&mut self, capacity: usize) -> Result<(), ()> {
+        if self.buf.len() == capacity {
+            return if capacity > 0 { Ok(()) } else { Err(()) };
+        }
+        // free the old buffer first, PSRAM may not hold both
+        self.buf = PsramBuf::EMPTY;
+        self.buf = psram_buf(capacity).ok_or(())?;
+        Ok(())
+    }
+}
+
+struct State {
+    slots: [Slot; POOL_SLOTS],
+    latest: Option<usize>,
+    seq: u32,
+    /// Size the slots are (re)allocated with
+    capacity: usize,
+    waiters: MultiWakerRegistration<MAX_WAITERS>,
+}
+
+/// Frame buffers in PSRAM shared between the capture task and any number of
+/// consumers.
+///
+/// The capture task fills a free slot and publishes it as the latest frame.
+/// Consumers only ever see the latest frame, older frames stay alive as long
+/// as a [Frame] references them.
+///
+/// A slot is only reallocated while nothing references it, so [Self::resize]
+/// takes effect slot by slot as consumers let go of older frames.
+pub struct FramePool {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl FramePool {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                slots: [Slot::EMPTY; POOL_SLOTS],
+                latest: None,
+                seq: 0,
+                capacity: 0,
+                waiters: MultiWakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Allocate `capacity` bytes of PSRAM for every slot
+    pub fn init(&self, capacity: usize) -> Result<(), ()> {
+        self.resize(capacity)
+    }
+
+    /// Change the size of the frame buffers
+    ///
+    /// Idle slots are reallocated right away, the latest frame and frames still
+    /// referenced keep their buffer until the capture task claims the slot
+    /// again. Fails if PSRAM runs out, the slots without a buffer are retried
+    /// for every frame.
+    pub fn resize(&self, capacity: usize) -> Result<(), ()> {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.capacity = capacity;
+            let latest = s.latest;
+            let mut result = Ok(());
+            for (index, slot) in s.slots.iter_mut().enumerate() {
+                if Some(index) != latest && slot.refs == 0 && !slot.writing {
+                    if slot.reallocate(capacity).is_err() {
+                        result = Err(());
+                    }
+                }
+            }
+            result
+        })
+    }
+
+    /// Size of the frame buffers
+    pub fn capacity(&self) -> usize {
+        self.state.lock(|s| s.borrow().capacity)
+    }
+
+    /// Claim a free slot for the next frame
+    ///
+    /// Returns `None` while every slot is either the latest frame or still
+    /// referenced by a consumer, or if the slot can't be brought to the pool's
+    /// capacity.
+    pub fn writer(&'static self) -> Option<FrameWriter> {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            let (latest, capacity) = (s.latest, s.capacity);
+            let (index, slot) = s
+                .slots
+                .iter_mut()
+                .enumerate()
+                .find(|(i, slot)| Some(*i) != latest && slot.refs == 0 && !slot.writing)?;
+            slot.reallocate(capacity).ok()?;
+            slot.writing = true;
+            Some(FrameWriter {
+                pool: self,
+                slot: index,
+                buf: slot.buf.as_mut_ptr(),
+                capacity: slot.buf.len(),
+                len: 0,
+                meta: FrameMeta::EMPTY,
+            })
+        })
+    }
+
+    /// Sequence number of the latest frame, it counts every captured frame
+    pub fn seq(&self) -> u32 {
+        self.state.lock(|s| s.borrow().seq)
+    }
+
+    /// The latest complete frame
+    pub fn latest(&'static self) -> Option<Frame> {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            let index = s.latest?;
+            Some(self.acquire(&mut s, index))
+        })
+    }
+
+    /// Wait for a frame newer than sequence number `after`
+    pub async fn next(&'static self, after: u32) -> Frame {
+        poll_fn(|cx| {
+            self.state.lock(|s| {
+                let mut s = s.borrow_mut();
+                match s.latest {
+                    Some(index) if s.slots[index].meta.seq != after => {
+                        Poll::Ready(self.acquire(&mut s, index))
+                    }
+                    _ => {
+                        s.waiters.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+
+    fn acquire(&'static self, s: &mut State, index: usize) -> Frame {
+        let slot = &mut s.slots[index];
+        slot.refs += 1;
+        Frame {
+            pool: self,
+            slot: index,
+            meta: slot.meta,
+            data: slot.buf.as_ptr(),
+        }
+    }
+
+    fn publish(&self, index: usize, meta: FrameMeta) -> u32 {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.seq = s.seq.wrapping_add(1).max(1);
+            let seq = s.seq;
+            let slot = &mut s.slots[index];
+            slot.writing = false;
+            slot.meta = FrameMeta { seq, ..meta };
+            s.latest = Some(index);
+            s.waiters.wake();
+            seq
+        })
+    }
+
+    fn abort(&self, index: usize) {
+        self.state
+            .lock(|s| s.borrow_mut().slots[index].writing = false);
+    }
+
+    fn retain(&self, index: usize) {
+        self.state.lock(|s| s.borrow_mut().slots[index].refs += 1);
+    }
+
+    fn release(&self, index: usize) {
+        self.state.lock(|s| s.borrow_mut().slots[index].refs -= 1);
+    }
+}
+
+impl Default for FramePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exclusive access to a slot while the capture task fills it
+pub struct FrameWriter {
+    pool: &'static FramePool,
+    slot: usize,
+    buf: *mut u8,
+    capacity: usize,
+    len: usize,
+    meta: FrameMeta,
+}
+
+impl FrameWriter {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// What was written so far
+    pub fn data(&self) -> &[u8] {
+        // SAFETY: the first `len` bytes are written and stay put
+        unsafe { core::slice::from_raw_parts(self.buf, self.len) }
+    }
+
+    /// Append `data`, fails if the slot is full
+    pub fn extend(&mut self, data: &[u8]) -> Result<(), ()> {
+        if self.len + data.len() > self.capacity {
+            return Err(());
+        }
+        // SAFETY: the slot is marked as writing, nobody else touches the buffer
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.buf.add(self.len), data.len());
+        }
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Metadata published with the frame, `seq` and `len` are filled in on
+    /// [Self::commit]
+    pub fn meta_mut(&mut self) -> &mut FrameMeta {
+        &mut self.meta
+    }
+
+    /// Publish the frame as the latest one and return its sequence number
+    pub fn commit(self) -> u32 {
+        let meta = FrameMeta {
+            len: self.len,
+            ..self.meta
+        };
+        let seq = self.pool.publish(self.slot, meta);
+        core::mem::forget(self);
+        seq
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        self.pool.abort(self.slot);
+    }
+}
+
+/// Reference counted handle to a frame in the pool
+pub struct Frame {
+    pool: &'static FramePool,
+    slot: usize,
+    meta: FrameMeta,
+    data: *const u8,
+}
+
+impl Frame {
+    /// Sequence number, increases by one for every captured frame
+    pub fn seq(&self) -> u32 {
+        self.meta.seq
+    }
+
+    pub fn meta(&self) -> &FrameMeta {
+        &self.meta
+    }
+}
+
+// SAFETY: the slot stays allocated and unwritten while a frame refers to it,
+// references are counted under the pool's lock
+unsafe impl Send for Frame {}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the slot is not written to while it is referenced
+        unsafe { core::slice::from_raw_parts(self.data, self.meta.len) }
+    }
+}
+
+impl Clone for Frame {
+    fn clone(&self) -> Self {
+        self.pool.retain(self.slot);
+        Self {
+            pool: self.pool,
+            slot: self.slot,
+            meta: self.meta,
+            data: self.data,
+        }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        self.pool.release(self.slot);
+    }
+}
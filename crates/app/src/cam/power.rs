@@ -0,0 +1,92 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Timer};
+use esp_hal::gpio::Output;
+
+use super::{pool::FRAME_POOL, FRAME_TIMEOUT};
+
+/// Frames dropped after waking up while exposure and white balance settle
+const WARMUP_FRAMES: usize = 5;
+/// How long PWDN and RST are held for a power cycle
+const CYCLE_HOLD: Duration = Duration::from_millis(50);
+/// Time the sensor needs after reset before it takes I2C writes
+const CYCLE_SETTLE: Duration = Duration::from_millis(20);
+
+static PWDN: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+    Mutex::new(RefCell::new(None));
+static RESET: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Take over the sensor power down pin
+pub fn init(pin: Output<'static>) {
+    PWDN.lock(|p| p.replace(Some(pin)));
+}
+
+/// Take over the sensor reset pin
+pub fn init_reset(pin: Output<'static>) {
+    RESET.lock(|r| r.replace(Some(pin)));
+}
+
+fn set_reset(reset: bool) {
+    RESET.lock(|r| {
+        if let Some(pin) = r.borrow_mut().as_mut() {
+            if reset {
+                pin.set_low();
+            } else {
+                pin.set_high();
+            }
+        }
+    });
+}
+
+/// Take the sensor through standby and a hardware reset, with whichever of
+/// PWDN and RST the board wires
+///
+/// The registers are lost, the sensor has to be configured again. Returns
+/// false if neither pin is wired, a soft reset is all that is left then.
+pub async fn power_cycle() -> bool {
+    let wired = PWDN.lock(|p| p.borrow().is_some()) || RESET.lock(|r| r.borrow().is_some());
+    set_standby(true);
+    set_reset(true);
+    Timer::after(CYCLE_HOLD).await;
+    set_standby(false);
+    Timer::after(CYCLE_HOLD).await;
+    set_reset(false);
+    Timer::after(CYCLE_SETTLE).await;
+    wired
+}
+
+/// Put the sensor into standby or wake it up, registers are kept either way
+pub fn set_standby(standby: bool) {
+    PWDN.lock(|p| {
+        if let Some(pin) = p.borrow_mut().as_mut() {
+            if standby {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    });
+}
+
+pub fn is_standby() -> bool {
+    PWDN.lock(|p| p.borrow().as_ref().is_some_and(|pin| pin.is_set_high()))
+}
+
+/// Wake the sensor if it is in standby and wait until its frames are usable
+///
+/// Returns false if no frames arrive after waking up.
+pub async fn wake() -> bool {
+    if !is_standby() {
+        return true;
+    }
+    set_standby(false);
+    let mut seq = FRAME_POOL.latest().map_or(0, |frame| frame.seq());
+    for _ in 0..WARMUP_FRAMES {
+        match with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(seq)).await {
+            Ok(frame) => seq = frame.seq(),
+            Err(_) => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,292 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    sync::atomic::Ordering,
+};
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use ov2640::Resolution;
+
+use super::{
+    control::{self, CaptureConfig, PixelFormat},
+    pool::FRAME_POOL,
+};
+use crate::metrics::{inc, METRICS};
+
+/// Length of one throughput measurement
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+/// Uncongested windows in a row before the quality is raised again
+const IMPROVE_AFTER: u8 = 3;
+/// Quality scale steps, degrading is faster than recovering
+const DEGRADE_STEP: u8 = 6;
+const IMPROVE_STEP: u8 = 2;
+/// Worst quality scale used before the resolution is lowered
+const WORST_QUALITY: u8 = 40;
+
+/// What the controller tries to hold
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RateTarget {
+    Off,
+    /// Frames per second captured while clients stream
+    Fps(u32),
+    /// Kilobits per second sent to HTTP clients
+    Kbps(u32),
+}
+
+#[derive(Clone, Copy)]
+pub struct RateConfig {
+    pub target: RateTarget,
+    /// Best quality scale the controller goes back up to
+    pub best_quality: u8,
+    /// Step the resolution once the quality scale is exhausted
+    pub adapt_resolution: bool,
+    /// Largest resolution the controller steps up to
+    pub max_resolution: Resolution,
+}
+
+struct State {
+    config: RateConfig,
+    quality: u8,
+    resolution: Resolution,
+    /// Measured over the last window
+    fps_milli: u32,
+    kbps: u32,
+}
+
+static RATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    config: RateConfig {
+        target: RateTarget::Off,
+        best_quality: 10,
+        adapt_resolution: false,
+        max_resolution: Resolution::R640x480,
+    },
+    quality: ov2640::DEFAULT_QUALITY,
+    resolution: Resolution::R320x240,
+    fps_milli: 0,
+    kbps: 0,
+}));
+
+pub fn config() -> RateConfig {
+    RATE.lock(|r| r.borrow().config)
+}
+
+/// Takes effect with the next measurement window
+pub fn set_config(config: RateConfig) {
+    RATE.lock(|r| r.borrow_mut().config = config);
+}
+
+/// Switch the controller off, quality and resolution set by hand stay as they
+/// are instead of being adapted away
+pub fn set_manual() {
+    RATE.lock(|r| {
+        let mut r = r.borrow_mut();
+        if r.config.target != RateTarget::Off {
+            info!("Rate: off, the capture setting was changed by hand");
+            r.config.target = RateTarget::Off;
+        }
+    });
+}
+
+/// Parse a resolution written as `640x480`
+pub fn parse_resolution(value: &str) -> Option<Resolution> {
+    let (width, height) = value.split_once('x')?;
+    let size = (width.parse().ok()?, height.parse().ok()?);
+    Resolution::ALL.into_iter().find(|r| r.size() == size)
+}
+
+#[derive(PartialEq, Eq)]
+enum Decision {
+    Degrade,
+    Improve,
+    Hold,
+}
+
+/// Traffic in one measurement window
+struct Sample {
+    /// Captured frames, the same for any number of clients
+    fps_milli: u32,
+    kbps: u32,
+    /// Frames skipped because a client's send buffer was still full
+    backlogged: u32,
+}
+
+fn decide(target: RateTarget, sample: &Sample) -> Decision {
+    let (congested, spare) = match target {
+        RateTarget::Off => return Decision::Hold,
+        RateTarget::Fps(fps) => (
+            sample.backlogged > 0,
+            sample.fps_milli >= fps.saturating_mul(1000),
+        ),
+        RateTarget::Kbps(kbps) => (
+            sample.backlogged > 0 || sample.kbps > kbps.saturating_mul(11) / 10,
+            sample.kbps < kbps.saturating_mul(7) / 10,
+        ),
+    };
+    if congested {
+        Decision::Degrade
+    } else if spare {
+        Decision::Improve
+    } else {
+        Decision::Hold
+    }
+}
+
+/// Quality and resolution after one step in the direction of `decision`
+fn next_setting(
+    config: &RateConfig,
+    quality: u8,
+    resolution: Resolution,
+    decision: Decision,
+) -> Option<(u8, Resolution)> {
+    let middle = (config.best_quality + WORST_QUALITY) / 2;
+    match decision {
+        Decision::Degrade if quality < WORST_QUALITY => {
+            Some(((quality + DEGRADE_STEP).min(WORST_QUALITY), resolution))
+        }
+        Decision::Degrade if config.adapt_resolution => {
+            // a setting made by hand may be above the limit; a step lands under it
+            resolution
+                .smaller()
+                .map(|smaller| (middle, smaller.min(config.max_resolution)))
+        }
+        Decision::Improve if quality > config.best_quality => Some((
+            quality
+                .saturating_sub(IMPROVE_STEP)
+                .max(config.best_quality),
+            resolution,
+        )),
+        Decision::Improve if config.adapt_resolution && resolution < config.max_resolution => {
+            resolution.larger().map(|larger| (middle, larger))
+        }
+        _ => None,
+    }
+}
+
+/// Adjust the JPEG quality scale, and optionally the resolution, to what the
+/// network delivers to stream clients
+#[embassy_executor::task]
+pub async fn rate_task() {
+    let get = |c: &core::sync::atomic::AtomicU32| c.load(Ordering::Relaxed);
+    let mut last = (
+        get(&METRICS.bytes_sent),
+        FRAME_POOL.seq(),
+        get(&METRICS.frames_backlogged),
+    );
+    let mut last_at = Instant::now();
+    let mut good_windows = 0;
+    loop {
+        Timer::after(RATE_WINDOW).await;
+        let now = Instant::now();
+        let elapsed = (now - last_at).as_millis().max(1);
+        let counters = (
+            get(&METRICS.bytes_sent),
+            FRAME_POOL.seq(),
+            get(&METRICS.frames_backlogged),
+        );
+        let sample = Sample {
+            fps_milli: (counters.1.wrapping_sub(last.1) as u64 * 1_000_000 / elapsed) as u32,
+            kbps: (counters.0.wrapping_sub(last.0) as u64 * 8 / elapsed) as u32,
+            backlogged: counters.2.wrapping_sub(last.2),
+        };
+        (last, last_at) = (counters, now);
+        let config = RATE.lock(|r| {
+            let mut r = r.borrow_mut();
+            r.fps_milli = sample.fps_milli;
+            r.kbps = sample.kbps;
+            r.config
+        });
+
+        let streaming = get(&METRICS.active_clients) > 0 && sample.fps_milli > 0;
+        let decision = match decide(config.target, &sample) {
+            _ if !streaming => Decision::Hold,
+            Decision::Improve => {
+                good_windows += 1;
+                if good_windows < IMPROVE_AFTER {
+                    continue;
+                }
+                Decision::Improve
+            }
+            decision => decision,
+        };
+        good_windows = 0;
+        if decision == Decision::Hold {
+            continue;
+        }
+
+        let Some(current) = control::config().await else {
+            continue;
+        };
+        // raw frames have no quality scale to adapt
+        if current.format != PixelFormat::Jpeg {
+            continue;
+        }
+        let Some((new_quality, new_resolution)) =
+            next_setting(&config, current.quality, current.resolution, decision)
+        else {
+            continue;
+        };
+        let setting = CaptureConfig {
+            quality: new_quality,
+            resolution: new_resolution,
+            ..current
+        };
+        if let Err(e) = control::apply(setting).await {
+            warn!("Rate: {}", e);
+            continue;
+        }
+        info!(
+            "Rate: {} kbps, {}.{:03} fps, {} backlogged -> quality {}, {}",
+            sample.kbps,
+            sample.fps_milli / 1000,
+            sample.fps_milli % 1000,
+            sample.backlogged,
+            new_quality,
+            new_resolution
+        );
+        inc(&METRICS.rate_changes);
+    }
+}
+
+/// Record the sensor setting the controller starts from, and goes on from
+/// after a change
+pub fn init(quality: u8, resolution: Resolution) {
+    METRICS
+        .jpeg_quality
+        .store(quality as u32, Ordering::Relaxed);
+    RATE.lock(|r| {
+        let mut r = r.borrow_mut();
+        r.quality = quality;
+        r.resolution = resolution;
+    });
+}
+
+/// Write the controller configuration and the last measurement as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    RATE.lock(|r| {
+        let r = r.borrow();
+        let (target, value) = match r.config.target {
+            RateTarget::Off => ("off", 0),
+            RateTarget::Fps(fps) => ("fps", fps),
+            RateTarget::Kbps(kbps) => ("kbps", kbps),
+        };
+        let max = r.config.max_resolution.size();
+        let current = r.resolution.size();
+        write!(
+            out,
+            "{{\"target\":\"{}\",\"value\":{},\"best_quality\":{},\"adapt_resolution\":{},\"max_resolution\":\"{}x{}\",\"quality\":{},\"resolution\":\"{}x{}\",\"fps\":{}.{:03},\"kbps\":{}}}",
+            target,
+            value,
+            r.config.best_quality,
+            r.config.adapt_resolution,
+            max.0,
+            max.1,
+            r.quality,
+            current.0,
+            current.1,
+            r.fps_milli / 1000,
+            r.fps_milli % 1000,
+            r.kbps
+        )
+    })
+}
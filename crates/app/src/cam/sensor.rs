@@ -0,0 +1,26 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use esp_hal::{i2c::master::I2c, Blocking};
+
+pub type Sensor = ov2640::OV2640<I2c<'static, Blocking>>;
+
+/// The OV2640 control interface, shared by everything that talks to the sensor
+pub static SENSOR: Mutex<CriticalSectionRawMutex, Option<Sensor>> = Mutex::new(None);
+
+/// Exposure and gain as last read from the sensor
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct SensorState {
+    /// Exposure time in line periods
+    pub exposure: u16,
+    /// Raw 10 bit AGC value
+    pub gain: u16,
+}
+
+/// Read the exposure and gain, `None` if the sensor is busy or unavailable
+pub fn try_read_state() -> Option<SensorState> {
+    let mut sensor = SENSOR.try_lock().ok()?;
+    let sensor = sensor.as_mut()?;
+    Some(SensorState {
+        exposure: sensor.exposure().ok()?,
+        gain: sensor.gain().ok()?,
+    })
+}
@@ -0,0 +1,324 @@
+use core::fmt::{self, Write};
+use defmt::{info, warn};
+use http::json::{Object, Value};
+use ov2640::{Brightness, Configuration, Contrast, LightMode, Saturation, SpecialEffect};
+use storage::kv::MAX_VALUE_LEN;
+
+use super::{
+    control::{self, CaptureConfig, PixelFormat, RAW_RESOLUTION},
+    rate,
+    sensor::{Sensor, SENSOR},
+};
+use crate::store;
+
+/// Key of the camera settings in the config partition
+const STORE_KEY: &str = "camera.settings";
+
+const LIGHT_MODES: [(LightMode, &str); 5] = [
+    (LightMode::Auto, "auto"),
+    (LightMode::Sunny, "sunny"),
+    (LightMode::Cloudy, "cloudy"),
+    (LightMode::Office, "office"),
+    (LightMode::Home, "home"),
+];
+
+const SPECIAL_EFFECTS: [(SpecialEffect, &str); 8] = [
+    (SpecialEffect::Normal, "normal"),
+    (SpecialEffect::Antique, "antique"),
+    (SpecialEffect::Bluish, "bluish"),
+    (SpecialEffect::Greenish, "greenish"),
+    (SpecialEffect::Reddish, "reddish"),
+    (SpecialEffect::BlackWhite, "black_white"),
+    (SpecialEffect::Negative, "negative"),
+    (SpecialEffect::BlackWhiteNegative, "black_white_negative"),
+];
+
+// levels -2..2, the driver counts down from the strongest setting
+const SATURATIONS: [Saturation; 5] = [
+    Saturation::Saturation4,
+    Saturation::Saturation3,
+    Saturation::Saturation2,
+    Saturation::Saturation1,
+    Saturation::Saturation0,
+];
+const BRIGHTNESSES: [Brightness; 5] = [
+    Brightness::Brightness4,
+    Brightness::Brightness3,
+    Brightness::Brightness2,
+    Brightness::Brightness1,
+    Brightness::Brightness0,
+];
+const CONTRASTS: [Contrast; 5] = [
+    Contrast::Contrast4,
+    Contrast::Contrast3,
+    Contrast::Contrast2,
+    Contrast::Contrast1,
+    Contrast::Contrast0,
+];
+
+/// Why a change of the settings was refused
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SettingsError<'a> {
+    /// The body is no JSON object, at this offset
+    Syntax(usize),
+    /// A member that isn't a setting
+    Unknown(&'a str),
+    /// A setting and what it takes
+    Invalid(&'a str, &'static str),
+    /// The sensor couldn't be programmed
+    Sensor(&'static str),
+}
+
+impl SettingsError<'_> {
+    /// Whether the request is at fault rather than the sensor
+    pub fn is_client_error(&self) -> bool {
+        !matches!(self, SettingsError::Sensor(_))
+    }
+
+    pub fn render_json(&self, out: &mut impl Write) -> fmt::Result {
+        match self {
+            SettingsError::Syntax(offset) => {
+                write!(out, "{{\"error\":\"invalid JSON\",\"offset\":{}}}", offset)
+            }
+            // the parser takes no quotes or escapes in names
+            SettingsError::Unknown(field) => {
+                write!(
+                    out,
+                    "{{\"error\":\"unknown setting\",\"field\":\"{}\"}}",
+                    field
+                )
+            }
+            SettingsError::Invalid(field, expected) => write!(
+                out,
+                "{{\"error\":\"invalid value\",\"field\":\"{}\",\"expected\":\"{}\"}}",
+                field, expected
+            ),
+            SettingsError::Sensor(message) => write!(out, "{{\"error\":\"{}\"}}", message),
+        }
+    }
+}
+
+fn name<T: PartialEq + Copy>(table: &[(T, &'static str)], value: T) -> &'static str {
+    table
+        .iter()
+        .find(|(v, _)| *v == value)
+        .map_or("", |(_, name)| name)
+}
+
+fn by_name<T: Copy>(table: &[(T, &'static str)], value: Value) -> Option<T> {
+    let Value::Str(value) = value else {
+        return None;
+    };
+    table
+        .iter()
+        .find(|(_, name)| *name == value)
+        .map(|(v, _)| *v)
+}
+
+fn level<T: PartialEq>(levels: &[T; 5], value: T) -> i32 {
+    levels.iter().position(|l| *l == value).unwrap_or(2) as i32 - 2
+}
+
+fn by_level<T: Copy>(levels: &[T; 5], value: Value) -> Option<T> {
+    let Value::Int(value) = value else {
+        return None;
+    };
+    levels
+        .get(usize::try_from(value.checked_add(2)?).ok()?)
+        .copied()
+}
+
+/// Apply the members of `body`, settings it doesn't name stay as they are
+///
+/// Every member is checked before anything changes. The image settings are
+/// written to the sensor first, then format, resolution and quality go
+/// through [control::apply]. If any of it fails what was already written is
+/// put back, so the settings change whole or not at all. The result is saved
+/// for [restore]. Strings with escapes are decoded into `strings`.
+pub async fn apply_json<'a>(
+    body: &'a [u8],
+    strings: &'a mut [u8],
+) -> Result<(), SettingsError<'a>> {
+    apply(body, strings, true).await?;
+    let mut saved = heapless::String::<MAX_VALUE_LEN>::new();
+    if render_json(&mut saved).await.is_err()
+        || store::set(STORE_KEY, saved.as_bytes()).await.is_err()
+    {
+        warn!("Settings: can't save the camera settings");
+    }
+    Ok(())
+}
+
+/// Apply the settings saved by the last [apply_json], if there are any
+///
+/// Unlike a change by hand they leave the rate controller on.
+pub async fn restore() {
+    let mut saved = [0; MAX_VALUE_LEN];
+    let Some(len) = store::get(STORE_KEY, &mut saved).await else {
+        return;
+    };
+    let mut strings = [0; MAX_VALUE_LEN];
+    match apply(&saved[..len], &mut strings, false).await {
+        Ok(()) => info!("Settings: camera settings restored"),
+        Err(e) => warn!("Settings: can't restore the camera settings: {}", e),
+    }
+}
+
+/// [apply_json], `by_hand` switches the rate controller off if resolution or
+/// quality are named
+async fn apply<'a>(
+    body: &'a [u8],
+    strings: &'a mut [u8],
+    by_hand: bool,
+) -> Result<(), SettingsError<'a>> {
+    let object = Object::parse(body, strings).map_err(|e| SettingsError::Syntax(e.offset))?;
+    let current = SENSOR
+        .lock()
+        .await
+        .as_ref()
+        .map(|sensor| *sensor.configuration())
+        .ok_or(SettingsError::Sensor("no sensor"))?;
+    let mut next = current;
+    let mut capture = CaptureConfig::from_configuration(&current);
+    let mut resolution = None;
+    for (key, value) in object.members() {
+        let invalid = |expected| SettingsError::Invalid(key, expected);
+        match key {
+            "image_format" => {
+                capture.format = match value {
+                    Value::Str(value) => PixelFormat::parse(value),
+                    _ => None,
+                }
+                .ok_or(invalid("jpeg, rgb565 or yuv422"))?
+            }
+            "resolution" => {
+                let parsed = match value {
+                    Value::Str(value) => rate::parse_resolution(value),
+                    _ => None,
+                };
+                resolution = Some(parsed.ok_or(invalid("160x120 to 1600x1200"))?);
+            }
+            "light_mode" => {
+                next.light_mode = by_name(&LIGHT_MODES, value)
+                    .ok_or(invalid("auto, sunny, cloudy, office or home"))?
+            }
+            "special_effect" => {
+                next.special_effect =
+                    by_name(&SPECIAL_EFFECTS, value).ok_or(invalid("an effect name"))?
+            }
+            "saturation" => {
+                next.saturation = by_level(&SATURATIONS, value).ok_or(invalid("-2..2"))?
+            }
+            "brightness" => {
+                next.brightness = by_level(&BRIGHTNESSES, value).ok_or(invalid("-2..2"))?
+            }
+            "contrast" => next.contrast = by_level(&CONTRASTS, value).ok_or(invalid("-2..2"))?,
+            "quality" => {
+                let range = i32::from(ov2640::MIN_QUALITY)..=i32::from(ov2640::MAX_QUALITY);
+                capture.quality = match value {
+                    Value::Int(q) if range.contains(&q) => q as u8,
+                    _ => return Err(invalid("2..63")),
+                }
+            }
+            _ => return Err(SettingsError::Unknown(key)),
+        }
+    }
+    match (capture.format, resolution) {
+        (PixelFormat::Jpeg, Some(resolution)) => capture.resolution = resolution,
+        (PixelFormat::Jpeg, None) => {}
+        (_, Some(resolution)) if resolution != RAW_RESOLUTION => {
+            return Err(SettingsError::Invalid(
+                "resolution",
+                "320x240 for raw formats",
+            ))
+        }
+        (_, _) => capture.resolution = RAW_RESOLUTION,
+    }
+
+    if let Err(e) = write_image_settings(&next).await {
+        put_back(&current).await;
+        return Err(SettingsError::Sensor(e));
+    }
+    let rate = rate::config();
+    if by_hand && (object.get("resolution").is_some() || object.get("quality").is_some()) {
+        rate::set_manual();
+    }
+    // control::apply puts the capture settings back itself when it fails
+    if let Err(e) = control::apply(capture).await {
+        rate::set_config(rate);
+        put_back(&current).await;
+        return Err(SettingsError::Sensor(e));
+    }
+    Ok(())
+}
+
+async fn write_image_settings(config: &Configuration) -> Result<(), &'static str> {
+    let mut sensor = SENSOR.lock().await;
+    let sensor = sensor.as_mut().ok_or("no sensor")?;
+    program(sensor, config)
+}
+
+/// Put the image settings of `config` back after a failed change
+async fn put_back(config: &Configuration) {
+    if write_image_settings(config).await.is_err() {
+        warn!("Settings: can't restore the image settings");
+    }
+}
+
+/// Write the image settings of `config` that differ from the sensor's
+fn program(sensor: &mut Sensor, config: &Configuration) -> Result<(), &'static str> {
+    let current = *sensor.configuration();
+    if config.light_mode != current.light_mode {
+        sensor
+            .set_light_mode(config.light_mode)
+            .map_err(|_| "can't set the light mode")?;
+    }
+    if config.saturation != current.saturation {
+        sensor
+            .set_saturation(config.saturation)
+            .map_err(|_| "can't set the saturation")?;
+    }
+    if config.brightness != current.brightness {
+        sensor
+            .set_brightness(config.brightness)
+            .map_err(|_| "can't set the brightness")?;
+    }
+    if config.contrast != current.contrast {
+        sensor
+            .set_contrast(config.contrast)
+            .map_err(|_| "can't set the contrast")?;
+    }
+    if config.special_effect != current.special_effect {
+        sensor
+            .set_special_effect(config.special_effect)
+            .map_err(|_| "can't set the special effect")?;
+    }
+    Ok(())
+}
+
+/// Write the full sensor configuration as JSON
+pub async fn render_json(out: &mut impl Write) -> fmt::Result {
+    let Some(config) = SENSOR
+        .lock()
+        .await
+        .as_ref()
+        .map(|sensor| *sensor.configuration())
+    else {
+        return write!(out, "{{\"error\":\"no sensor\"}}");
+    };
+    let capture = CaptureConfig::from_configuration(&config);
+    let (width, height) = capture.resolution.size();
+    write!(
+        out,
+        "{{\"image_format\":\"{}\",\"resolution\":\"{}x{}\",\"quality\":{},\"light_mode\":\"{}\",\"saturation\":{},\"brightness\":{},\"contrast\":{},\"special_effect\":\"{}\"}}",
+        capture.format.name(),
+        width,
+        height,
+        capture.quality,
+        name(&LIGHT_MODES, config.light_mode),
+        level(&SATURATIONS, config.saturation),
+        level(&BRIGHTNESSES, config.brightness),
+        level(&CONTRASTS, config.contrast),
+        name(&SPECIAL_EFFECTS, config.special_effect)
+    )
+}
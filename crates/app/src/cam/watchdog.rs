@@ -0,0 +1,125 @@
+use core::cell::RefCell;
+use defmt::{error, info, warn};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+
+use super::{pool::FRAME_POOL, power, sensor::SENSOR, FRAME_TIMEOUT};
+use crate::metrics::{inc, METRICS};
+
+/// How long the capture may go without VSYNC or a valid frame
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait after a failed recovery, doubled for every further failure
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+struct Health {
+    last_vsync: Instant,
+    last_frame: Instant,
+}
+
+static HEALTH: Mutex<CriticalSectionRawMutex, RefCell<Health>> = Mutex::new(RefCell::new(Health {
+    last_vsync: Instant::from_ticks(0),
+    last_frame: Instant::from_ticks(0),
+}));
+
+/// Asks the capture task to drop its DMA transfer and start a new one
+pub(super) static RESTART: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Called by the capture task at every VSYNC
+pub(super) fn vsync() {
+    HEALTH.lock(|h| h.borrow_mut().last_vsync = Instant::now());
+}
+
+/// Called by the capture task for every complete frame, buffered or not
+pub(super) fn frame() {
+    HEALTH.lock(|h| h.borrow_mut().last_frame = Instant::now());
+}
+
+/// Start the timeouts over, e.g. after the sensor was woken up
+fn reset(now: Instant) {
+    HEALTH.lock(|h| {
+        let mut h = h.borrow_mut();
+        h.last_vsync = now;
+        h.last_frame = now;
+    });
+}
+
+/// What is missing, `None` while the capture is healthy
+fn stall(now: Instant) -> Option<&'static str> {
+    HEALTH.lock(|h| {
+        let h = h.borrow();
+        if now.saturating_duration_since(h.last_vsync) > STALL_TIMEOUT {
+            Some("no VSYNC")
+        } else if now.saturating_duration_since(h.last_frame) > STALL_TIMEOUT {
+            Some("no valid frame")
+        } else {
+            None
+        }
+    })
+}
+
+/// Watch the capture and bring the sensor back when it stalls
+///
+/// A stall is VSYNC or valid frames missing for [STALL_TIMEOUT] while the
+/// sensor is not in standby. The sensor is power cycled, configured with the
+/// settings it had and the capture restarted.
+#[embassy_executor::task]
+pub async fn watchdog_task() {
+    reset(Instant::now());
+    let mut retry_delay = RETRY_DELAY;
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+        let now = Instant::now();
+        // frames stop on purpose in standby
+        if power::is_standby() {
+            reset(now);
+            continue;
+        }
+        let Some(reason) = stall(now) else {
+            continue;
+        };
+        warn!("Camera stalled: {}, recovering", reason);
+        inc(&METRICS.camera_recoveries);
+        if recover().await {
+            info!("Camera recovered");
+            retry_delay = RETRY_DELAY;
+        } else {
+            error!(
+                "Camera recovery failed, next attempt in {} s",
+                retry_delay.as_secs()
+            );
+            inc(&METRICS.camera_recovery_failures);
+            Timer::after(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+        }
+        reset(Instant::now());
+    }
+}
+
+/// Power cycle and configure the sensor, true once a frame arrives again
+async fn recover() -> bool {
+    let seq = FRAME_POOL.latest().map_or(0, |frame| frame.seq());
+    {
+        // holding the sensor keeps the rate controller off it meanwhile
+        let mut sensor = SENSOR.lock().await;
+        let Some(sensor) = sensor.as_mut() else {
+            return false;
+        };
+        if !power::power_cycle().await {
+            warn!("Camera: no PWDN or RST wired, soft reset only");
+        }
+        // writes the last configuration, including quality and resolution
+        if let Err(e) = sensor.init(&mut Delay) {
+            warn!("Camera: configuring the sensor failed: {:?}", e);
+            return false;
+        }
+    }
+    RESTART.signal(());
+    with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(seq))
+        .await
+        .is_ok()
+}
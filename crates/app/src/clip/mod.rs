@@ -0,0 +1,465 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use defmt::{error, info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::gpio::{AnyPin, Input, InputConfig, Pull};
+use http::Status;
+use media::{avi::AviInfo, ring::FrameRing};
+use storage::ShortName;
+
+use crate::{
+    cam::{control::PixelFormat, pool::FRAME_POOL, power, FRAME_TIMEOUT},
+    clock,
+    mem::{psram_buf, psram_free, PsramBuf},
+    metadata, record, wifi,
+};
+
+/// PSRAM wanted for the pre-event ring
+const PRE_ROLL_SIZE: usize = 3 * 1024 * 1024;
+/// PSRAM wanted for the last clip, pre-roll and post-roll together
+const CLIP_SIZE: usize = 4 * 1024 * 1024;
+/// PSRAM left for everything allocated later
+const PSRAM_RESERVE: usize = 1024 * 1024;
+/// A smaller pre-event ring holds too few frames to be of use
+const MIN_RING_SIZE: usize = 512 * 1024;
+/// A GPIO trigger has to stay low this long
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// What started a clip
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Trigger {
+    Motion,
+    Api,
+    Input,
+}
+
+impl Trigger {
+    fn name(self) -> &'static str {
+        match self {
+            Trigger::Motion => "motion",
+            Trigger::Api => "api",
+            Trigger::Input => "input",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ClipConfig {
+    pub enabled: bool,
+    /// Seconds kept in front of an event
+    pub pre_secs: u32,
+    /// Seconds recorded after the last event
+    pub post_secs: u32,
+    pub fps: u32,
+    /// Start clips on motion events, not just on the API and the input
+    pub on_motion: bool,
+}
+
+/// A buffered frame
+#[derive(Clone, Copy)]
+pub struct ClipFrame {
+    pub seq: u32,
+    pub captured_at: Instant,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// The last clip, kept in memory until the next event
+pub struct Clip {
+    pub frames: FrameRing<ClipFrame, PsramBuf>,
+    pub complete: bool,
+}
+
+/// Summary of the last clip for the HTTP API
+#[derive(Clone, Copy)]
+struct LastClip {
+    trigger: Trigger,
+    triggered_at: Instant,
+    frames: usize,
+    bytes: usize,
+    span: Duration,
+    complete: bool,
+    /// Where the clip went on the card, or why it didn't
+    saved: Option<Result<ShortName, &'static str>>,
+}
+
+struct Status {
+    error: Option<&'static str>,
+    capacity: usize,
+    frames: usize,
+    bytes: usize,
+    /// Time between the oldest and the newest buffered frame
+    span: Duration,
+    /// End of the post-roll while a clip is running
+    until: Option<Instant>,
+    clips: u32,
+    last: Option<LastClip>,
+}
+
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<ClipConfig>> =
+    blocking_mutex::Mutex::new(RefCell::new(ClipConfig {
+        enabled: false,
+        pre_secs: 10,
+        post_secs: 10,
+        fps: 10,
+        on_motion: true,
+    }));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static TRIGGER: Signal<CriticalSectionRawMutex, Trigger> = Signal::new();
+
+static STATUS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Status>> =
+    blocking_mutex::Mutex::new(RefCell::new(Status {
+        error: None,
+        capacity: 0,
+        frames: 0,
+        bytes: 0,
+        span: Duration::from_ticks(0),
+        until: None,
+        clips: 0,
+        last: None,
+    }));
+
+pub static CLIP: Mutex<CriticalSectionRawMutex, Option<Clip>> = Mutex::new(None);
+
+pub fn config() -> ClipConfig {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+/// A running clip keeps its post-roll end until the next event
+pub fn set_config(config: ClipConfig) {
+    CONFIG.lock(|c| {
+        *c.borrow_mut() = ClipConfig {
+            fps: config.fps.clamp(1, 30),
+            ..config
+        }
+    });
+    CHANGED.signal(());
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG.lock(|c| c.borrow().enabled)
+}
+
+/// Start a clip, or extend the post-roll of the running one
+pub fn trigger(trigger: Trigger) {
+    let config = config();
+    if config.enabled && (trigger != Trigger::Motion || config.on_motion) {
+        TRIGGER.signal(trigger);
+    }
+}
+
+/// Split what the heap can spare between the two rings
+fn allocate() -> Option<(
+    FrameRing<ClipFrame, PsramBuf>,
+    FrameRing<ClipFrame, PsramBuf>,
+)> {
+    let free = psram_free();
+    let budget = free.saturating_sub(PSRAM_RESERVE);
+    let wanted = PRE_ROLL_SIZE + CLIP_SIZE;
+    let share = |size: usize| (size as u64 * budget.min(wanted) as u64 / wanted as u64) as usize;
+    let (pre_size, clip_size) = (share(PRE_ROLL_SIZE), share(CLIP_SIZE));
+    if pre_size < MIN_RING_SIZE {
+        error!("Clips: only {} bytes of PSRAM free", free);
+        STATUS.lock(|s| s.borrow_mut().error = Some("not enough PSRAM"));
+        return None;
+    }
+    if budget < wanted {
+        warn!(
+            "Clips: {} bytes of PSRAM free, rings cut to {} and {} bytes",
+            free, pre_size, clip_size
+        );
+    }
+    let (Some(pre), Some(clip)) = (psram_buf(pre_size), psram_buf(clip_size)) else {
+        error!("Failed to allocate the clip rings");
+        STATUS.lock(|s| s.borrow_mut().error = Some("PSRAM allocation failed"));
+        return None;
+    };
+    STATUS.lock(|s| s.borrow_mut().capacity = pre_size);
+    Some((FrameRing::new(pre), FrameRing::new(clip)))
+}
+
+/// Keep the last `pre_secs` in PSRAM and turn them into a clip on events
+#[embassy_executor::task]
+pub async fn clip_task() {
+    let Some((mut pre, clip)) = allocate() else {
+        return;
+    };
+    *CLIP.lock().await = Some(Clip {
+        frames: clip,
+        complete: false,
+    });
+    let mut last_seq = 0;
+    let mut next_due = Instant::now();
+    let mut until: Option<Instant> = None;
+    loop {
+        let config = config();
+        if !config.enabled {
+            if until.take().is_some() {
+                finish().await;
+            }
+            pre.clear();
+            update_status(&pre, None);
+            CHANGED.wait().await;
+            TRIGGER.reset();
+            next_due = Instant::now();
+            continue;
+        }
+        power::wake().await;
+        Timer::at(next_due).await;
+        let interval = Duration::from_micros(1_000_000 / config.fps as u64);
+        next_due = (next_due + interval).max(Instant::now());
+        let Ok(frame) = with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(last_seq)).await else {
+            warn!("Clips: no frame");
+            continue;
+        };
+        last_seq = frame.seq();
+        let meta = *frame.meta();
+        if meta.format != PixelFormat::Jpeg {
+            continue;
+        }
+        let clip_frame = ClipFrame {
+            seq: meta.seq,
+            captured_at: meta.captured_at,
+            width: meta.width,
+            height: meta.height,
+        };
+        // frames are stored as they will be saved and sent
+        if let Err(e) = pre.push_parts(&metadata::tag(&frame, &meta).parts(), clip_frame) {
+            warn!("Clips: {}", e);
+            continue;
+        }
+        let keep = Duration::from_secs(config.pre_secs as u64);
+        while pre
+            .get(0)
+            .is_some_and(|(f, _)| meta.captured_at.saturating_duration_since(f.captured_at) > keep)
+        {
+            pre.pop();
+        }
+
+        let post_end = meta.captured_at + Duration::from_secs(config.post_secs as u64);
+        match (until, TRIGGER.try_take()) {
+            (None, Some(trigger)) => {
+                info!("Clip started by {}, frame {}", trigger, meta.seq);
+                let mut clip = CLIP.lock().await;
+                if let Some(clip) = clip.as_mut() {
+                    clip.frames.clear();
+                    clip.complete = false;
+                    for (f, jpeg) in pre.iter() {
+                        let _ = clip.frames.push(jpeg, *f);
+                    }
+                }
+                STATUS.lock(|s| {
+                    s.borrow_mut().last = Some(LastClip {
+                        trigger,
+                        triggered_at: meta.captured_at,
+                        frames: 0,
+                        bytes: 0,
+                        span: Duration::from_ticks(0),
+                        complete: false,
+                        saved: None,
+                    })
+                });
+                until = Some(post_end);
+            }
+            (Some(_), trigger) => {
+                if let Some(trigger) = trigger {
+                    info!("Clip extended by {}, frame {}", trigger, meta.seq);
+                    until = Some(post_end);
+                }
+                // the oldest frames of the clip make way if it outgrows its ring
+                if let Some(clip) = CLIP.lock().await.as_mut() {
+                    let _ = clip
+                        .frames
+                        .push_parts(&metadata::tag(&frame, &meta).parts(), clip_frame);
+                }
+            }
+            (None, None) => {}
+        }
+        drop(frame);
+        if until.is_some_and(|until| meta.captured_at >= until) {
+            until = None;
+            // the pre-event ring pauses while the clip goes to the card
+            finish().await;
+        }
+        update_status(&pre, until);
+    }
+}
+
+fn update_status(pre: &FrameRing<ClipFrame, PsramBuf>, until: Option<Instant>) {
+    let span = span(pre);
+    let (frames, bytes) = (pre.len(), pre.bytes());
+    STATUS.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.frames = frames;
+        s.bytes = bytes;
+        s.span = span;
+        s.until = until;
+    });
+}
+
+/// Time between the oldest and the newest frame
+fn span(frames: &FrameRing<ClipFrame, PsramBuf>) -> Duration {
+    let mut iter = frames.iter();
+    match (iter.next(), iter.next_back()) {
+        (Some((first, _)), Some((last, _))) => last
+            .captured_at
+            .saturating_duration_since(first.captured_at),
+        _ => Duration::from_ticks(0),
+    }
+}
+
+/// Close the running clip and save it to the card
+async fn finish() {
+    let mut clip = CLIP.lock().await;
+    let Some(clip) = clip.as_mut() else {
+        return;
+    };
+    clip.complete = true;
+    let span = span(&clip.frames);
+    let (frames, bytes) = (clip.frames.len(), clip.frames.bytes());
+    STATUS.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.clips += 1;
+        if let Some(last) = s.last.as_mut() {
+            last.frames = frames;
+            last.bytes = bytes;
+            last.span = span;
+            last.complete = true;
+        }
+    });
+    info!("Clip complete: {} frames, {} bytes", frames, bytes);
+    if frames == 0 {
+        return;
+    }
+    let saved = record::save_avi(avi_info(&clip.frames), clip.frames.iter().map(|(_, j)| j)).await;
+    if let Err(e) = saved {
+        warn!("Clip not saved: {}", e);
+    }
+    STATUS.lock(|s| {
+        if let Some(last) = s.borrow_mut().last.as_mut() {
+            last.saved = Some(saved);
+        }
+    });
+}
+
+/// Frames play at the rate they were taken
+fn avi_info(frames: &FrameRing<ClipFrame, PsramBuf>) -> AviInfo {
+    let (width, height) = frames
+        .iter()
+        .fold((0, 0), |(w, h), (f, _)| (f.width.max(w), f.height.max(h)));
+    let micros = AviInfo::frame_duration(frames.len() as u32, span(frames).as_micros());
+    AviInfo::new(width, height, micros)
+}
+
+/// Start a clip when `pin` is pulled low, by a button or an open collector
+/// output of a sensor
+#[embassy_executor::task]
+pub async fn input_task(pin: AnyPin<'static>) {
+    let mut input = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+    loop {
+        input.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE).await;
+        if input.is_low() {
+            trigger(Trigger::Input);
+            input.wait_for_high().await;
+        }
+    }
+}
+
+/// Send the last complete clip as an AVI file
+///
+/// New clips wait until the download is done. Only the head is sent if
+/// `head_only`.
+pub async fn send_avi(socket: &mut TcpSocket<'_>, head_only: bool) {
+    let clip = CLIP.lock().await;
+    let Some(clip) = clip
+        .as_ref()
+        .filter(|clip| clip.complete && !clip.frames.is_empty())
+    else {
+        _ = wifi::send_response(
+            socket,
+            Status::NotFound,
+            "text/plain",
+            b"No clip",
+            head_only,
+        )
+        .await;
+        return;
+    };
+    let frames = clip.frames.iter().map(|(_, jpeg)| jpeg);
+    if wifi::send_avi(
+        socket,
+        "clip.avi",
+        avi_info(&clip.frames),
+        frames,
+        head_only,
+    )
+    .await
+    .is_err()
+    {
+        warn!("Clip download aborted");
+    }
+}
+
+/// Write the configuration, the pre-event ring and the last clip as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    let config = config();
+    write!(
+        out,
+        "{{\"enabled\":{},\"pre\":{},\"post\":{},\"fps\":{},\"on_motion\":{},\"error\":",
+        config.enabled, config.pre_secs, config.post_secs, config.fps, config.on_motion
+    )?;
+    STATUS.lock(|s| {
+        let s = s.borrow();
+        match s.error {
+            Some(error) => write!(out, "\"{}\"", error)?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"buffer\":{{\"capacity\":{},\"frames\":{},\"bytes\":{},\"seconds\":{}.{:03}}},\"recording\":{},\"clips\":{},\"last\":",
+            s.capacity,
+            s.frames,
+            s.bytes,
+            s.span.as_millis() / 1000,
+            s.span.as_millis() % 1000,
+            s.until.is_some(),
+            s.clips
+        )?;
+        let Some(last) = s.last else {
+            return write!(out, "null}}");
+        };
+        write!(
+            out,
+            "{{\"trigger\":\"{}\",\"time\":",
+            last.trigger.name()
+        )?;
+        match clock::to_unix_micros(last.triggered_at) {
+            Some(micros) => write!(out, "{}.{:06}", micros / 1_000_000, micros % 1_000_000)?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"complete\":{},\"frames\":{},\"bytes\":{},\"seconds\":{}.{:03},\"file\":",
+            last.complete,
+            last.frames,
+            last.bytes,
+            last.span.as_millis() / 1000,
+            last.span.as_millis() % 1000
+        )?;
+        match last.saved {
+            Some(Ok(name)) => write!(out, "\"{}\",\"save_error\":null", name)?,
+            Some(Err(error)) => write!(out, "null,\"save_error\":\"{}\"", error)?,
+            None => write!(out, "null,\"save_error\":null")?,
+        }
+        write!(out, "}}}}")
+    })
+}
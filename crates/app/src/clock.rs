@@ -0,0 +1,127 @@
+use core::cell::RefCell;
+use defmt::{info, warn};
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+/// Seconds from 1900-01-01 (NTP) to 1970-01-01 (Unix)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Clock {
+    /// Unix time in microseconds at boot, once synchronized
+    boot_unix_micros: Option<u64>,
+    /// Local time zone offset from UTC
+    utc_offset_minutes: i32,
+}
+
+static CLOCK: Mutex<CriticalSectionRawMutex, RefCell<Clock>> = Mutex::new(RefCell::new(Clock {
+    boot_unix_micros: None,
+    utc_offset_minutes: 0,
+}));
+
+/// Unix time in microseconds, `None` until the first synchronization
+pub fn unix_micros() -> Option<u64> {
+    to_unix_micros(Instant::now())
+}
+
+/// Unix time in microseconds of an instant since boot
+pub fn to_unix_micros(instant: Instant) -> Option<u64> {
+    CLOCK
+        .lock(|c| c.borrow().boot_unix_micros)
+        .map(|boot| boot + instant.as_micros())
+}
+
+pub fn set_unix_micros(now: u64) {
+    let boot = now.saturating_sub(Instant::now().as_micros());
+    CLOCK.lock(|c| c.borrow_mut().boot_unix_micros = Some(boot));
+}
+
+pub fn utc_offset_minutes() -> i32 {
+    CLOCK.lock(|c| c.borrow().utc_offset_minutes)
+}
+
+pub fn set_utc_offset_minutes(offset: i32) {
+    CLOCK.lock(|c| c.borrow_mut().utc_offset_minutes = offset.clamp(-14 * 60, 14 * 60));
+}
+
+/// Minutes since local midnight, `None` until the first synchronization
+pub fn local_minute_of_day() -> Option<u32> {
+    let secs = unix_micros()? as i64 / 1_000_000 + utc_offset_minutes() as i64 * 60;
+    Some((secs.rem_euclid(86_400) / 60) as u32)
+}
+
+/// Keep the clock synchronized with SNTP
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 128];
+    let mut tx_buffer = [0u8; 128];
+    loop {
+        stack.wait_config_up().await;
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let delay = match sync(stack, &mut socket).await {
+            Ok(()) => SYNC_INTERVAL,
+            Err(e) => {
+                warn!("SNTP failed: {}", e);
+                RETRY_INTERVAL
+            }
+        };
+        drop(socket);
+        Timer::after(delay).await;
+    }
+}
+
+async fn sync(stack: Stack<'static>, socket: &mut UdpSocket<'_>) -> Result<(), &'static str> {
+    let server = *stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .map_err(|_| "DNS lookup failed")?
+        .first()
+        .ok_or("no address")?;
+    socket.bind(0).map_err(|_| "bind failed")?;
+
+    // LI 0, version 4, mode 3 (client)
+    let mut packet = [0u8; 48];
+    packet[0] = 0x23;
+    let sent_at = Instant::now();
+    socket
+        .send_to(&packet, (server, NTP_PORT))
+        .await
+        .map_err(|_| "send failed")?;
+    let (len, _) = with_timeout(REPLY_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| "no reply")?
+        .map_err(|_| "receive failed")?;
+    let received_at = Instant::now();
+    if len < 48 || packet[0] & 0x07 != 4 {
+        return Err("bad reply");
+    }
+
+    // transmit timestamp, corrected by half the round trip
+    let secs = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    let fraction = u32::from_be_bytes([packet[44], packet[45], packet[46], packet[47]]) as u64;
+    if secs < NTP_UNIX_OFFSET {
+        return Err("bad timestamp");
+    }
+    let micros = (secs - NTP_UNIX_OFFSET) * 1_000_000 + ((fraction * 1_000_000) >> 32);
+    let now = micros + (received_at - sent_at).as_micros() / 2;
+    set_unix_micros(now);
+    info!("SNTP: unix time {}", now / 1_000_000);
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use defmt::{error, warn};
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::{
+    gpio::{AnyPin, DriveMode},
+    ledc::{
+        channel::{self, ChannelIFace},
+        timer::{self, TimerIFace},
+        LSGlobalClkSource, Ledc, LowSpeed,
+    },
+    peripherals::LEDC,
+    time::Rate,
+};
+
+/// PWM frequency, far above the line rate so it doesn't band the image
+const PWM_FREQUENCY: Rate = Rate::from_khz(20);
+/// Longest time the LED may stay lit in one go before it overheats
+pub const MAX_ON_TIME: Duration = Duration::from_secs(60);
+/// How long the LED stays dark after hitting the limit
+const COOL_DOWN: Duration = Duration::from_secs(30);
+/// How long a snapshot waits for the flash task to light the LED
+const LIGHT_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlashMode {
+    Off,
+    /// Lit continuously as an illuminator, up to [MAX_ON_TIME]
+    On,
+    /// Lit for snapshots
+    Auto,
+}
+
+impl FlashMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(FlashMode::Off),
+            "on" => Some(FlashMode::On),
+            "auto" => Some(FlashMode::Auto),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FlashMode::Off => "off",
+            FlashMode::On => "on",
+            FlashMode::Auto => "auto",
+        }
+    }
+}
+
+struct State {
+    mode: FlashMode,
+    /// Duty cycle in percent
+    brightness: u8,
+    /// Snapshots holding the light
+    snapshots: u8,
+    /// When the flash task switched the LED on
+    lit_since: Option<Instant>,
+    /// End of the forced pause after the LED was on for too long
+    cool_until: Option<Instant>,
+}
+
+impl State {
+    /// Apply the safety limit and decide on the duty cycle, returns it and
+    /// when to look again
+    fn update(&mut self, now: Instant) -> (u8, Option<Instant>) {
+        if self.cool_until.is_some_and(|until| now >= until) {
+            self.cool_until = None;
+        }
+        if self
+            .lit_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= MAX_ON_TIME)
+        {
+            warn!("Flash: on for too long, cooling down");
+            self.cool_until = Some(now + COOL_DOWN);
+            if self.mode == FlashMode::On {
+                self.mode = FlashMode::Off;
+            }
+        }
+        let on = self.cool_until.is_none()
+            && self.brightness > 0
+            && (self.mode == FlashMode::On || self.snapshots > 0);
+        self.lit_since = on.then(|| self.lit_since.unwrap_or(now));
+        let duty = if on { self.brightness } else { 0 };
+        (
+            duty,
+            self.lit_since
+                .map(|since| since + MAX_ON_TIME)
+                .or(self.cool_until),
+        )
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    mode: FlashMode::Off,
+    brightness: 100,
+    snapshots: 0,
+    lit_since: None,
+    cool_until: None,
+}));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn mode() -> FlashMode {
+    STATE.lock(|s| s.borrow().mode)
+}
+
+pub fn set_mode(mode: FlashMode) {
+    STATE.lock(|s| s.borrow_mut().mode = mode);
+    CHANGED.signal(());
+}
+
+pub fn brightness() -> u8 {
+    STATE.lock(|s| s.borrow().brightness)
+}
+
+/// Duty cycle in percent, takes effect right away if the LED is lit
+pub fn set_brightness(brightness: u8) {
+    STATE.lock(|s| s.borrow_mut().brightness = brightness.min(100));
+    CHANGED.signal(());
+}
+
+/// Light the LED for a snapshot
+///
+/// Returns when it went on, `None` while it cools down or is dimmed to 0.
+/// Every call needs a [release] afterwards.
+pub async fn acquire() -> Option<Instant> {
+    STATE.lock(|s| s.borrow_mut().snapshots += 1);
+    CHANGED.signal(());
+    let lit = with_timeout(LIGHT_TIMEOUT, async {
+        loop {
+            let (lit_since, dark) = STATE.lock(|s| {
+                let s = s.borrow();
+                (s.lit_since, s.cool_until.is_some() || s.brightness == 0)
+            });
+            if lit_since.is_some() || dark {
+                return lit_since;
+            }
+            Timer::after_millis(1).await;
+        }
+    })
+    .await;
+    lit.ok().flatten()
+}
+
+pub fn release() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.snapshots = s.snapshots.saturating_sub(1);
+    });
+    CHANGED.signal(());
+}
+
+/// Drive the LED through LEDC PWM and enforce the on-time limit
+#[embassy_executor::task]
+pub async fn flash_task(ledc: LEDC<'static>, pin: AnyPin<'static>) {
+    let mut ledc = Ledc::new(ledc);
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    let mut timer = ledc.timer::<LowSpeed>(timer::Number::Timer0);
+    let timer_config = timer::config::Config {
+        duty: timer::config::Duty::Duty8Bit,
+        clock_source: timer::LSClockSource::APBClk,
+        frequency: PWM_FREQUENCY,
+    };
+    if let Err(e) = timer.configure(timer_config) {
+        error!("Flash: can't configure the LEDC timer: {}", e);
+        return;
+    }
+    let mut channel = ledc.channel(channel::Number::Channel0, pin);
+    let channel_config = channel::config::Config {
+        timer: &timer,
+        duty_pct: 0,
+        drive_mode: DriveMode::PushPull,
+    };
+    if let Err(e) = channel.configure(channel_config) {
+        error!("Flash: can't configure the LEDC channel: {}", e);
+        return;
+    }
+    loop {
+        let (duty, wake_at) = STATE.lock(|s| s.borrow_mut().update(Instant::now()));
+        if let Err(e) = channel.set_duty(duty) {
+            warn!("Flash: can't set the duty cycle: {}", e);
+        }
+        match wake_at {
+            Some(at) => {
+                select(CHANGED.wait(), Timer::at(at)).await;
+            }
+            None => CHANGED.wait().await,
+        }
+    }
+}
+
+/// Write the mode, the brightness and the LED state as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    let now = Instant::now();
+    STATE.lock(|s| {
+        let s = s.borrow();
+        let on_time = s
+            .lit_since
+            .map_or(0, |since| now.saturating_duration_since(since).as_millis());
+        write!(
+            out,
+            "{{\"mode\":\"{}\",\"brightness\":{},\"lit\":{},\"on_time\":{}.{:03},\"max_on_time\":{},\"cooling\":{}}}",
+            s.mode.name(),
+            s.brightness,
+            s.lit_since.is_some(),
+            on_time / 1000,
+            on_time % 1000,
+            MAX_ON_TIME.as_secs(),
+            s.cool_until.is_some()
+        )
+    })
+}
@@ -1,8 +1,19 @@
 #![no_std]
 #![feature(type_alias_impl_trait)]
 extern crate alloc;
+pub mod board;
 pub mod cam;
+pub mod clip;
+pub mod clock;
 pub mod errors;
+pub mod flash;
+pub mod mem;
+pub mod metadata;
+pub mod metrics;
+pub mod motion;
+pub mod record;
+pub mod store;
+pub mod timelapse;
 pub mod wifi;
 
 #[macro_export]
@@ -0,0 +1,81 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+/// Zeroed buffer in PSRAM, given back to the heap with the layout it was
+/// allocated with
+pub struct PsramBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// the buffer is owned like a `Vec`
+unsafe impl Send for PsramBuf {}
+
+impl PsramBuf {
+    /// Buffer of no bytes, allocates nothing
+    pub const EMPTY: PsramBuf = PsramBuf {
+        ptr: NonNull::dangling(),
+        len: 0,
+    };
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, 4).unwrap()
+    }
+}
+
+impl Deref for PsramBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for PsramBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for PsramBuf {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { esp_alloc::HEAP.dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+        }
+    }
+}
+
+/// Allocate a zeroed `len` bytes buffer in PSRAM
+pub fn psram_buf(len: usize) -> Option<PsramBuf> {
+    if len == 0 {
+        return Some(PsramBuf::EMPTY);
+    }
+    unsafe {
+        let p = esp_alloc::HEAP.alloc_caps(
+            esp_alloc::MemoryCapability::External.into(),
+            PsramBuf::layout(len),
+        );
+        let ptr = NonNull::new(p)?;
+        p.write_bytes(0, len);
+        Some(PsramBuf { ptr, len })
+    }
+}
+
+/// Free PSRAM according to the heap statistics, not necessarily in one piece
+pub fn psram_free() -> usize {
+    esp_alloc::HEAP
+        .stats()
+        .region_stats
+        .iter()
+        .flatten()
+        .filter(|region| {
+            region
+                .capabilities
+                .contains(esp_alloc::MemoryCapability::External)
+        })
+        .map(|region| region.free)
+        .sum()
+}
@@ -0,0 +1,227 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+use media::jpeg::exif::{self, DateTime, Exif};
+
+use crate::{cam::pool::FrameMeta, clock};
+
+/// Firmware name and version written to every frame
+const SOFTWARE: &str = concat!("esp_rs_cam_app ", env!("CARGO_PKG_VERSION"));
+const MAKE: &str = "Espressif";
+/// Room for the segment, the strings are bounded so this always fits
+const SEGMENT_CAPACITY: usize = 384;
+pub const MAX_NAME_LEN: usize = 32;
+/// Device name until one is configured
+const DEFAULT_NAME: &str = "esp-cam";
+
+/// Which segment goes into the frames
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MetadataMode {
+    Off,
+    /// APP1 with EXIF tags
+    Exif,
+    /// COM with the same facts as one line of text
+    Comment,
+}
+
+impl MetadataMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(MetadataMode::Off),
+            "exif" => Some(MetadataMode::Exif),
+            "com" => Some(MetadataMode::Comment),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MetadataMode::Off => "off",
+            MetadataMode::Exif => "exif",
+            MetadataMode::Comment => "com",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct MetadataConfig {
+    pub mode: MetadataMode,
+    /// Written as the camera model, empty for the default
+    pub device_name: String<MAX_NAME_LEN>,
+}
+
+impl MetadataConfig {
+    fn device_name(&self) -> &str {
+        match self.device_name.as_str() {
+            "" => DEFAULT_NAME,
+            name => name,
+        }
+    }
+}
+
+/// A device name of letters, digits, `-`, `_` and `.`
+pub fn parse_name(value: &str) -> Option<String<MAX_NAME_LEN>> {
+    let valid = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return None;
+    }
+    value.try_into().ok()
+}
+
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<MetadataConfig>> =
+    Mutex::new(RefCell::new(MetadataConfig {
+        mode: MetadataMode::Off,
+        device_name: String::new(),
+    }));
+
+pub fn config() -> MetadataConfig {
+    CONFIG.lock(|c| c.borrow().clone())
+}
+
+/// Applies to frames sent or stored from now on
+pub fn set_config(config: MetadataConfig) {
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+}
+
+/// A JPEG with a metadata segment in front of the sensor data
+///
+/// The frame itself is not copied, [Self::parts] hands out the pieces in
+/// order. Without a segment the frame goes out as it is.
+pub struct Tagged<'a> {
+    jpeg: &'a [u8],
+    offset: usize,
+    segment: heapless::Vec<u8, SEGMENT_CAPACITY>,
+}
+
+impl<'a> Tagged<'a> {
+    /// The frame unchanged
+    pub fn plain(jpeg: &'a [u8]) -> Self {
+        Self {
+            jpeg,
+            offset: 0,
+            segment: heapless::Vec::new(),
+        }
+    }
+
+    /// Length of the frame with the segment
+    pub fn len(&self) -> usize {
+        self.jpeg.len() + self.segment.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Everything up to the segment, the segment and the rest of the frame
+    pub fn parts(&self) -> [&[u8]; 3] {
+        [
+            &self.jpeg[..self.offset],
+            &self.segment,
+            &self.jpeg[self.offset..],
+        ]
+    }
+}
+
+/// Put the configured segment into `jpeg`
+///
+/// `jpeg` comes back unchanged if metadata is off or it doesn't start with SOI.
+pub fn tag<'a>(jpeg: &'a [u8], meta: &FrameMeta) -> Tagged<'a> {
+    let config = config();
+    let Some(offset) = exif::insert_offset(jpeg).filter(|_| config.mode != MetadataMode::Off)
+    else {
+        return Tagged::plain(jpeg);
+    };
+    let mut segment = heapless::Vec::new();
+    // the capacity is checked by the segment writers
+    let _ = segment.resize_default(SEGMENT_CAPACITY);
+    let len = match config.mode {
+        MetadataMode::Off => Ok(0),
+        MetadataMode::Exif => write_exif(&config, meta, &mut segment),
+        MetadataMode::Comment => write_comment(&config, meta, &mut segment),
+    };
+    match len {
+        Ok(len) => {
+            segment.truncate(len);
+            Tagged {
+                jpeg,
+                offset,
+                segment,
+            }
+        }
+        Err(_) => Tagged::plain(jpeg),
+    }
+}
+
+/// Local capture time and its milliseconds, `None` until the clock is set
+fn captured(meta: &FrameMeta) -> Option<(DateTime, u16)> {
+    let micros = clock::to_unix_micros(meta.captured_at)? as i64;
+    let local = micros / 1_000_000 + clock::utc_offset_minutes() as i64 * 60;
+    Some((DateTime::from_unix(local), (micros / 1000 % 1000) as u16))
+}
+
+fn write_exif(
+    config: &MetadataConfig,
+    meta: &FrameMeta,
+    out: &mut [u8],
+) -> Result<usize, exif::TooSmall> {
+    let mut description = String::<48>::new();
+    let _ = write!(description, "exposure={} gain={}", meta.exposure, meta.gain);
+    let captured = captured(meta);
+    let exif = Exif {
+        captured: captured.map(|(date_time, _)| date_time),
+        subsec_millis: captured.map_or(0, |(_, millis)| millis),
+        make: MAKE,
+        model: config.device_name(),
+        software: SOFTWARE,
+        description: &description,
+        width: meta.width,
+        height: meta.height,
+    };
+    exif::write_exif(&exif, out)
+}
+
+/// `name firmware time WxH exposure=E gain=G`, the time is the uptime in
+/// seconds until the clock is set
+fn write_comment(
+    config: &MetadataConfig,
+    meta: &FrameMeta,
+    out: &mut [u8],
+) -> Result<usize, exif::TooSmall> {
+    let mut text = String::<160>::new();
+    let _ = write!(text, "{} {} ", config.device_name(), SOFTWARE);
+    let _ = match captured(meta) {
+        Some((date_time, millis)) => write!(
+            text,
+            "{}.{:03}",
+            core::str::from_utf8(&date_time.ascii()).unwrap_or(""),
+            millis
+        ),
+        None => {
+            let millis = meta.captured_at.as_millis();
+            write!(text, "uptime={}.{:03}", millis / 1000, millis % 1000)
+        }
+    };
+    let _ = write!(
+        text,
+        " {}x{} exposure={} gain={}",
+        meta.width, meta.height, meta.exposure, meta.gain
+    );
+    exif::write_comment(text.as_bytes(), out)
+}
+
+/// Write the mode and the device name as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    let config = config();
+    write!(
+        out,
+        "{{\"mode\":\"{}\",\"device_name\":\"{}\",\"software\":\"{}\"}}",
+        config.mode.name(),
+        config.device_name(),
+        SOFTWARE
+    )
+}
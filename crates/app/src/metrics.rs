@@ -0,0 +1,248 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use esp_alloc::MemoryCapability;
+
+/// Counters and gauges exposed at `/metrics`
+///
+/// Counters are 32 bit since the ESP32-S3 has no 64 bit atomics, Prometheus
+/// treats the wrap around like a restart.
+pub struct Metrics {
+    pub frames_captured: AtomicU32,
+    pub frames_sent: AtomicU32,
+    pub frames_dropped: AtomicU32,
+    pub frames_skipped: AtomicU32,
+    pub frames_overflowed: AtomicU32,
+    pub frames_backlogged: AtomicU32,
+    pub dma_too_slow: AtomicU32,
+    pub frames_encoded: AtomicU32,
+    pub encode_failures: AtomicU32,
+    pub frame_size_avg: AtomicU32,
+    pub frame_width: AtomicU32,
+    pub frame_height: AtomicU32,
+    pub jpeg_quality: AtomicU32,
+    pub rate_changes: AtomicU32,
+    pub active_clients: AtomicU32,
+    pub bytes_sent: AtomicU32,
+    pub wifi_reconnects: AtomicU32,
+    pub motion_events: AtomicU32,
+    pub camera_recoveries: AtomicU32,
+    pub camera_recovery_failures: AtomicU32,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            frames_captured: AtomicU32::new(0),
+            frames_sent: AtomicU32::new(0),
+            frames_dropped: AtomicU32::new(0),
+            frames_skipped: AtomicU32::new(0),
+            frames_overflowed: AtomicU32::new(0),
+            frames_backlogged: AtomicU32::new(0),
+            dma_too_slow: AtomicU32::new(0),
+            frames_encoded: AtomicU32::new(0),
+            encode_failures: AtomicU32::new(0),
+            frame_size_avg: AtomicU32::new(0),
+            frame_width: AtomicU32::new(0),
+            frame_height: AtomicU32::new(0),
+            jpeg_quality: AtomicU32::new(0),
+            rate_changes: AtomicU32::new(0),
+            active_clients: AtomicU32::new(0),
+            bytes_sent: AtomicU32::new(0),
+            wifi_reconnects: AtomicU32::new(0),
+            motion_events: AtomicU32::new(0),
+            camera_recoveries: AtomicU32::new(0),
+            camera_recovery_failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Count a captured frame and fold its size into the moving average
+    pub fn frame_captured(&self, len: usize) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+        let avg = self.frame_size_avg.load(Ordering::Relaxed);
+        let avg = if avg == 0 {
+            len as u32
+        } else {
+            avg - avg / 16 + len as u32 / 16
+        };
+        self.frame_size_avg.store(avg, Ordering::Relaxed);
+    }
+
+    /// Track an active client until the guard is dropped
+    pub fn client(&'static self) -> ClientGuard {
+        self.active_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self)
+    }
+
+    /// Write all metrics in the Prometheus text format
+    pub fn render(&self, out: &mut impl Write) -> fmt::Result {
+        let get = |c: &AtomicU32| c.load(Ordering::Relaxed);
+        let counters = [
+            (
+                "camera_frames_captured_total",
+                "Complete JPEG frames captured",
+                get(&self.frames_captured),
+            ),
+            (
+                "camera_frames_sent_total",
+                "Frames sent to stream and snapshot clients",
+                get(&self.frames_sent),
+            ),
+            (
+                "camera_frames_dropped_total",
+                "Truncated, corrupt or unbuffered frames dropped by the capture task",
+                get(&self.frames_dropped),
+            ),
+            (
+                "camera_frames_skipped_total",
+                "Frames skipped for slow or rate limited stream clients",
+                get(&self.frames_skipped),
+            ),
+            (
+                "camera_frames_overflowed_total",
+                "Frames dropped because they exceeded the frame buffer",
+                get(&self.frames_overflowed),
+            ),
+            (
+                "camera_frames_backlogged_total",
+                "Frames skipped because a stream client's send buffer was still full",
+                get(&self.frames_backlogged),
+            ),
+            (
+                "camera_dma_too_slow_total",
+                "DMA transfers stopped because the buffer was not drained in time",
+                get(&self.dma_too_slow),
+            ),
+            (
+                "camera_frames_encoded_total",
+                "Raw frames JPEG encoded in software for stream clients",
+                get(&self.frames_encoded),
+            ),
+            (
+                "camera_encode_failures_total",
+                "Raw frames whose JPEG did not fit the encoder buffer",
+                get(&self.encode_failures),
+            ),
+            (
+                "camera_rate_changes_total",
+                "JPEG quality or resolution changes made by the rate controller",
+                get(&self.rate_changes),
+            ),
+            (
+                "net_bytes_sent_total",
+                "Bytes written to HTTP clients",
+                get(&self.bytes_sent),
+            ),
+            (
+                "wifi_reconnects_total",
+                "Wi-Fi station disconnects followed by a reconnect",
+                get(&self.wifi_reconnects),
+            ),
+            (
+                "motion_events_total",
+                "Motion periods detected in the camera image",
+                get(&self.motion_events),
+            ),
+            (
+                "camera_recoveries_total",
+                "Sensor power cycles after the capture stalled",
+                get(&self.camera_recoveries),
+            ),
+            (
+                "camera_recovery_failures_total",
+                "Power cycles after which the sensor delivered no frame",
+                get(&self.camera_recovery_failures),
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_metric(out, name, help, "counter", value as usize)?;
+        }
+        write_metric(
+            out,
+            "camera_frame_size_bytes",
+            "Moving average of the JPEG frame size",
+            "gauge",
+            get(&self.frame_size_avg) as usize,
+        )?;
+        let gauges = [
+            (
+                "camera_frame_width_pixels",
+                "Width of the latest frame",
+                get(&self.frame_width),
+            ),
+            (
+                "camera_frame_height_pixels",
+                "Height of the latest frame",
+                get(&self.frame_height),
+            ),
+            (
+                "camera_jpeg_quality",
+                "OV2640 JPEG quality scale, lower is better",
+                get(&self.jpeg_quality),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            write_metric(out, name, help, "gauge", value as usize)?;
+        }
+        write_metric(
+            out,
+            "http_active_clients",
+            "Connected stream clients",
+            "gauge",
+            get(&self.active_clients) as usize,
+        )?;
+
+        let (mut internal, mut psram) = ((0, 0), (0, 0));
+        for region in esp_alloc::HEAP.stats().region_stats.iter().flatten() {
+            let heap = if region.capabilities.contains(MemoryCapability::External) {
+                &mut psram
+            } else {
+                &mut internal
+            };
+            heap.0 += region.used;
+            heap.1 += region.free;
+        }
+        writeln!(out, "# HELP heap_used_bytes Allocated heap memory")?;
+        writeln!(out, "# TYPE heap_used_bytes gauge")?;
+        writeln!(out, "heap_used_bytes{{region=\"internal\"}} {}", internal.0)?;
+        writeln!(out, "heap_used_bytes{{region=\"psram\"}} {}", psram.0)?;
+        writeln!(out, "# HELP heap_free_bytes Free heap memory")?;
+        writeln!(out, "# TYPE heap_free_bytes gauge")?;
+        writeln!(out, "heap_free_bytes{{region=\"internal\"}} {}", internal.1)?;
+        writeln!(out, "heap_free_bytes{{region=\"psram\"}} {}", psram.1)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ClientGuard(&'static Metrics);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.active_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn write_metric(
+    out: &mut impl Write,
+    name: &str,
+    help: &str,
+    kind: &str,
+    value: usize,
+) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+/// Shorthand to bump a counter by one
+pub fn inc(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,221 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Deque;
+use media::{
+    jpeg,
+    motion::{CellMask, MotionConfig, MotionDetector, Region, GRID_ROWS},
+};
+
+use crate::{
+    cam::{control::PixelFormat, pool::FRAME_POOL},
+    clip::{self, Trigger},
+    mem::psram_buf,
+    metrics::{inc, METRICS},
+};
+
+/// How often a frame is analysed
+const ANALYSIS_INTERVAL: Duration = Duration::from_millis(200);
+/// How long the scene has to be still before new motion counts as a new event
+const MOTION_HOLD: Duration = Duration::from_secs(2);
+/// Luma map for up to 1600x1200, one byte per 8x8 block
+const MAX_LUMA_MAP: usize = 200 * 150;
+/// Events kept for the HTTP API
+const MAX_EVENTS: usize = 16;
+
+/// Start of a motion period
+#[derive(Clone, Copy)]
+pub struct MotionEvent {
+    pub seq: u32,
+    pub captured_at: Instant,
+    /// Permille of the watched area that changed
+    pub score: u16,
+    /// Bounding box in image pixels
+    pub region: Option<Region>,
+}
+
+struct State {
+    enabled: bool,
+    config: MotionConfig,
+    /// Sequence number of the last analysed frame
+    seq: u32,
+    motion: bool,
+    score: u16,
+    cells: CellMask,
+    region: Option<Region>,
+    events: Deque<MotionEvent, MAX_EVENTS>,
+}
+
+static MOTION: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    enabled: true,
+    config: MotionConfig::DEFAULT,
+    seq: 0,
+    motion: false,
+    score: 0,
+    cells: [0; GRID_ROWS],
+    region: None,
+    events: Deque::new(),
+}));
+
+pub fn is_enabled() -> bool {
+    MOTION.lock(|m| m.borrow().enabled)
+}
+
+pub fn set_enabled(enabled: bool) {
+    MOTION.lock(|m| m.borrow_mut().enabled = enabled);
+}
+
+pub fn config() -> MotionConfig {
+    MOTION.lock(|m| m.borrow().config)
+}
+
+/// Takes effect with the next analysed frame
+pub fn set_config(config: MotionConfig) {
+    MOTION.lock(|m| m.borrow_mut().config = config);
+}
+
+/// Parse zones written as comma separated hex rows, e.g. `ffff,00ff,...`
+///
+/// Missing rows are left disabled.
+pub fn parse_zones(zones: &str) -> Option<CellMask> {
+    let mut mask = [0; GRID_ROWS];
+    for (i, row) in zones.split(',').enumerate() {
+        *mask.get_mut(i)? = u16::from_str_radix(row, 16).ok()?;
+    }
+    Some(mask)
+}
+
+/// Run the motion detector on the frame pool
+#[embassy_executor::task]
+pub async fn motion_task() {
+    let Some(mut luma) = psram_buf(MAX_LUMA_MAP) else {
+        error!("Failed to allocate the motion luma map");
+        return;
+    };
+    let mut detector = MotionDetector::new(config());
+    let mut last_seq = 0;
+    let mut last_motion: Option<Instant> = None;
+    loop {
+        Timer::after(ANALYSIS_INTERVAL).await;
+        if !is_enabled() {
+            detector.reset();
+            last_motion = None;
+            continue;
+        }
+        detector.set_config(config());
+        let Ok(frame) = with_timeout(ANALYSIS_INTERVAL * 10, FRAME_POOL.next(last_seq)).await
+        else {
+            continue;
+        };
+        last_seq = frame.seq();
+        let meta = *frame.meta();
+        if meta.format != PixelFormat::Jpeg {
+            continue;
+        }
+        let size = match jpeg::decode_dc_luma(&frame, &mut luma) {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("Motion: can't decode frame {}: {}", meta.seq, e);
+                continue;
+            }
+        };
+        drop(frame);
+        let Some(result) = detector.update(&luma, size.width, size.height) else {
+            continue;
+        };
+
+        let region = result.region.map(|r| r.scale(8));
+        let now = Instant::now();
+        let new_event = result.motion && last_motion.is_none_or(|at| now - at >= MOTION_HOLD);
+        if result.motion {
+            last_motion = Some(now);
+        }
+        MOTION.lock(|m| {
+            let mut m = m.borrow_mut();
+            m.seq = meta.seq;
+            m.motion = result.motion;
+            m.score = result.score;
+            m.cells = result.cells;
+            m.region = region;
+            if new_event {
+                if m.events.is_full() {
+                    m.events.pop_front();
+                }
+                let _ = m.events.push_back(MotionEvent {
+                    seq: meta.seq,
+                    captured_at: meta.captured_at,
+                    score: result.score,
+                    region,
+                });
+            }
+        });
+        if new_event {
+            info!(
+                "Motion in frame {}: score {}, {:?}",
+                meta.seq, result.score, region
+            );
+            inc(&METRICS.motion_events);
+            clip::trigger(Trigger::Motion);
+        }
+    }
+}
+
+/// Write the detector state, its configuration and the recent events as JSON
+pub fn render_json(out: &mut impl Write) -> fmt::Result {
+    MOTION.lock(|m| {
+        let m = m.borrow();
+        write!(
+            out,
+            "{{\"enabled\":{},\"sensitivity\":{},\"min_cells\":{},\"zones\":",
+            m.enabled, m.config.sensitivity, m.config.min_cells
+        )?;
+        write_cells(out, &m.config.zones)?;
+        write!(
+            out,
+            ",\"seq\":{},\"motion\":{},\"score\":{},\"cells\":",
+            m.seq, m.motion, m.score
+        )?;
+        write_cells(out, &m.cells)?;
+        write!(out, ",\"region\":")?;
+        write_region(out, m.region)?;
+        write!(out, ",\"events\":[")?;
+        for (i, event) in m.events.iter().enumerate() {
+            let micros = event.captured_at.as_micros();
+            write!(
+                out,
+                "{}{{\"seq\":{},\"timestamp\":{}.{:06},\"score\":{},\"region\":",
+                if i > 0 { "," } else { "" },
+                event.seq,
+                micros / 1_000_000,
+                micros % 1_000_000,
+                event.score
+            )?;
+            write_region(out, event.region)?;
+            write!(out, "}}")?;
+        }
+        write!(out, "]}}")
+    })
+}
+
+fn write_cells(out: &mut impl Write, cells: &CellMask) -> fmt::Result {
+    write!(out, "[")?;
+    for (i, row) in cells.iter().enumerate() {
+        write!(out, "{}\"{:04x}\"", if i > 0 { "," } else { "" }, row)?;
+    }
+    write!(out, "]")
+}
+
+fn write_region(out: &mut impl Write, region: Option<Region>) -> fmt::Result {
+    match region {
+        Some(r) => write!(
+            out,
+            "{{\"x\":{},\"y\":{},\"width\":{},\"height\":{}}}",
+            r.x, r.y, r.width, r.height
+        ),
+        None => write!(out, "null"),
+    }
+}
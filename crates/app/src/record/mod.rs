@@ -0,0 +1,609 @@
+use alloc::{vec, vec::Vec};
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+};
+use defmt::{error, info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+use esp_hal::{
+    dma::{DmaRxBuf, DmaTxBuf},
+    dma_buffers,
+    gpio::{Level, Output, OutputConfig},
+    peripherals::{DMA_CH1, SPI2},
+    spi::{
+        master::{Config, Spi, SpiDmaBus},
+        Mode,
+    },
+    time::Rate,
+    Async,
+};
+use http::{Response, Status};
+use media::avi::{AviInfo, AviWriter, HEADER_LEN};
+use storage::{BlockDevice, Dir, FatError, FatTime, SdCard, ShortName, Volume};
+
+use crate::{
+    board::SdPins,
+    cam::{control::PixelFormat, pool::FRAME_POOL, power, FRAME_TIMEOUT},
+    clock, metadata,
+    wifi::{self, write_all},
+};
+
+pub type Card = SdCard<SpiDmaBus<'static, Async>, Output<'static>, Delay>;
+type Error = FatError<<Card as BlockDevice>::Error>;
+
+const DIR_NAME: &str = "REC";
+/// Free space kept on the card, the oldest recordings are deleted for it
+const RESERVE_BYTES: u64 = 32 * 1024 * 1024;
+/// AVI files beyond 1 GiB don't play everywhere, longer recordings are split
+const MAX_FILE_BYTES: u32 = 1 << 30;
+/// How often the file size is written to the card, what a power loss costs
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const INIT_FREQUENCY: Rate = Rate::from_khz(400);
+const FREQUENCY: Rate = Rate::from_mhz(20);
+const INIT_ATTEMPTS: usize = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Bytes read from the card per lock during a download
+const DOWNLOAD_CHUNK: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RecordConfig {
+    pub enabled: bool,
+    /// Length of a file, 0 records one file until stopped or 1 GiB
+    pub segment_secs: u32,
+    pub fps: u32,
+}
+
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<RecordConfig>> =
+    blocking_mutex::Mutex::new(RefCell::new(RecordConfig {
+        enabled: false,
+        segment_secs: 300,
+        fps: 10,
+    }));
+
+struct Status {
+    current: Option<ShortName>,
+    error: Option<&'static str>,
+}
+
+static STATUS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Status>> =
+    blocking_mutex::Mutex::new(RefCell::new(Status {
+        current: None,
+        error: None,
+    }));
+
+/// The mounted card, shared by the recorder and downloads
+pub struct Storage {
+    volume: Volume<Card>,
+    dir: Dir,
+    next_number: u32,
+    /// Files being downloaded, kept when making room
+    reading: Vec<ShortName>,
+}
+
+pub static STORAGE: Mutex<CriticalSectionRawMutex, Option<Storage>> = Mutex::new(None);
+
+pub fn config() -> RecordConfig {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+/// A running segment is closed and a new one started with the new settings
+pub fn set_config(config: RecordConfig) {
+    CONFIG.lock(|c| {
+        *c.borrow_mut() = RecordConfig {
+            fps: config.fps.clamp(1, 30),
+            ..config
+        }
+    });
+}
+
+pub fn is_recording() -> bool {
+    STATUS.lock(|s| s.borrow().current.is_some())
+}
+
+fn set_error(error: Option<&'static str>) {
+    STATUS.lock(|s| s.borrow_mut().error = error);
+}
+
+/// Local time for directory entries
+fn local_time() -> FatTime {
+    match clock::unix_micros() {
+        Some(micros) => {
+            let local = micros as i64 / 1_000_000 + clock::utc_offset_minutes() as i64 * 60;
+            FatTime::from_unix(local.max(0) as u64)
+        }
+        None => FatTime::EPOCH,
+    }
+}
+
+/// CLK -> SCK, CMD -> MOSI, D0 -> MISO, D3 -> CS
+pub fn init_card(spi: SPI2<'static>, dma: DMA_CH1<'static>, pins: SdPins) -> Option<Card> {
+    let (rx_buffer, rx_descriptors, tx_buffer, tx_descriptors) = dma_buffers!(4096);
+    let rx = DmaRxBuf::new(rx_descriptors, rx_buffer).ok()?;
+    let tx = DmaTxBuf::new(tx_descriptors, tx_buffer).ok()?;
+    let config = Config::default()
+        .with_frequency(INIT_FREQUENCY)
+        .with_mode(Mode::_0);
+    let spi = Spi::new(spi, config)
+        .ok()?
+        .with_sck(pins.sck)
+        .with_mosi(pins.mosi)
+        .with_miso(pins.miso)
+        .with_dma(dma)
+        .with_buffers(rx, tx)
+        .into_async();
+    let cs = Output::new(pins.cs, Level::High, OutputConfig::default());
+    Some(SdCard::new(spi, cs, Delay))
+}
+
+/// Mount the card and record while enabled
+#[embassy_executor::task]
+pub async fn record_task(card: Card) {
+    let Some(storage) = mount(card).await else {
+        return;
+    };
+    *STORAGE.lock().await = Some(storage);
+    loop {
+        if !config().enabled {
+            Timer::after(Duration::from_millis(500)).await;
+            continue;
+        }
+        let result = record_segment().await;
+        STATUS.lock(|s| s.borrow_mut().current = None);
+        match result {
+            Ok(()) => set_error(None),
+            Err(e) => {
+                warn!("Recording failed: {}", e);
+                set_error(Some(describe(&e)));
+                Timer::after(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn mount(mut card: Card) -> Option<Storage> {
+    let mut kind = None;
+    for _ in 0..INIT_ATTEMPTS {
+        match card.init().await {
+            Ok(k) => {
+                kind = Some(k);
+                break;
+            }
+            Err(e) => warn!("SD card init failed: {}", e),
+        }
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    let Some(kind) = kind else {
+        set_error(Some("no card"));
+        return None;
+    };
+    let fast = Config::default()
+        .with_frequency(FREQUENCY)
+        .with_mode(Mode::_0);
+    if let Err(e) = card.spi().apply_config(&fast) {
+        warn!("SD card stays at the init clock: {}", e);
+    }
+    info!("SD card: {}, {} MiB", kind, card.block_count() / 2048);
+
+    let mut volume = match Volume::mount(card).await {
+        Ok(volume) => volume,
+        Err(e) => {
+            error!("SD card: no usable FAT file system: {}", e);
+            set_error(Some("no FAT file system"));
+            return None;
+        }
+    };
+    let name = ShortName::new(DIR_NAME)?;
+    let root = volume.root();
+    let dir = match volume.open_or_create_dir(root, &name, local_time()).await {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("SD card: can't open {}: {}", DIR_NAME, e);
+            set_error(Some("file system error"));
+            return None;
+        }
+    };
+    let next_number = match volume.list(dir).await {
+        Ok(entries) => entries
+            .iter()
+            .filter_map(|e| recording_number(&e.name))
+            .max()
+            .map_or(1, |n| n + 1),
+        Err(_) => 1,
+    };
+    info!(
+        "SD card: {}, {} of {} MiB free",
+        volume.kind(),
+        volume.free_bytes() >> 20,
+        volume.total_bytes() >> 20
+    );
+    Some(Storage {
+        volume,
+        dir,
+        next_number,
+        reading: Vec::new(),
+    })
+}
+
+fn describe(error: &Error) -> &'static str {
+    match error {
+        FatError::DiskFull => "card full",
+        FatError::Device(_) => "card error",
+        _ => "file system error",
+    }
+}
+
+/// Recordings are named after a counter, `00000042.AVI`
+fn next_name(storage: &mut Storage) -> Result<ShortName, Error> {
+    let name = ShortName::new(&alloc::format!("{:08}.AVI", storage.next_number))
+        .ok_or(FatError::InvalidName)?;
+    storage.next_number += 1;
+    Ok(name)
+}
+
+fn recording_number(name: &ShortName) -> Option<u32> {
+    if name.extension() != "AVI" {
+        return None;
+    }
+    name.base().parse().ok()
+}
+
+/// Delete the oldest recordings until the reserve is free again
+async fn make_room(storage: &mut Storage, keep: &ShortName) -> Result<(), Error> {
+    while storage.volume.free_bytes() < RESERVE_BYTES {
+        let entries = storage.volume.list(storage.dir).await?;
+        let oldest = entries
+            .iter()
+            .filter(|e| e.name != *keep && !storage.reading.contains(&e.name))
+            .filter_map(|e| Some((recording_number(&e.name)?, e.name)))
+            .min_by_key(|(number, _)| *number);
+        let Some((_, name)) = oldest else {
+            return Err(FatError::DiskFull);
+        };
+        info!("Recording: deleting {} for space", name);
+        storage.volume.delete(storage.dir, &name).await?;
+    }
+    Ok(())
+}
+
+/// Record one file, until the segment ends or the configuration changes
+async fn record_segment() -> Result<(), Error> {
+    let config = config();
+    let (name, mut file) = {
+        let mut storage = STORAGE.lock().await;
+        let Some(storage) = storage.as_mut() else {
+            return Ok(());
+        };
+        let name = next_name(storage)?;
+        make_room(storage, &name).await?;
+        let mut file = storage
+            .volume
+            .create(storage.dir, &name, local_time())
+            .await?;
+        // the real header follows once the frame count is known
+        storage.volume.write(&mut file, &[0; HEADER_LEN]).await?;
+        (name, file)
+    };
+    info!("Recording to {}", name);
+    STATUS.lock(|s| s.borrow_mut().current = Some(name));
+    power::wake().await;
+
+    let interval = Duration::from_micros(1_000_000 / config.fps as u64);
+    let started = Instant::now();
+    let mut next_due = started;
+    let mut last_sync = started;
+    let mut last_seq = 0;
+    let mut info = AviInfo::new(0, 0, interval.as_micros() as u32);
+    let mut sizes: Vec<u32> = Vec::new();
+    let mut span: Option<(Instant, Instant)> = None;
+    let mut result = Ok(());
+    while self::config() == config {
+        let now = Instant::now();
+        if config.segment_secs > 0
+            && now - started >= Duration::from_secs(config.segment_secs as u64)
+        {
+            break;
+        }
+        Timer::at(next_due).await;
+        next_due = (next_due + interval).max(Instant::now());
+        let Ok(frame) = with_timeout(FRAME_TIMEOUT, FRAME_POOL.next(last_seq)).await else {
+            warn!("Recording: no frame");
+            continue;
+        };
+        last_seq = frame.seq();
+        let meta = *frame.meta();
+        if meta.format != PixelFormat::Jpeg {
+            continue;
+        }
+        let jpeg = metadata::tag(&frame, &meta);
+        // chunk header, padding and index entry come on top of the frame
+        let index_len = 8 + 16 * (sizes.len() as u64 + 1);
+        if file.size() as u64 + jpeg.len() as u64 + 9 + index_len > MAX_FILE_BYTES as u64 {
+            break;
+        }
+
+        let mut storage = STORAGE.lock().await;
+        let Some(storage) = storage.as_mut() else {
+            break;
+        };
+        // a frame written only partly is cut off again
+        let frame_start = file.position();
+        let write = async {
+            make_room(storage, &name).await?;
+            let volume = &mut storage.volume;
+            volume
+                .write(&mut file, &AviWriter::frame_header(jpeg.len()))
+                .await?;
+            for part in jpeg.parts() {
+                volume.write(&mut file, part).await?;
+            }
+            volume
+                .write(&mut file, AviWriter::frame_padding(jpeg.len()))
+                .await?;
+            if now - last_sync >= SYNC_INTERVAL {
+                volume.flush_file(&mut file, local_time()).await?;
+                last_sync = now;
+            }
+            Ok::<(), Error>(())
+        };
+        if let Err(e) = write.await {
+            file.seek(frame_start);
+            if let Err(e) = storage.volume.truncate(&mut file).await {
+                warn!("Recording: can't cut off the last frame: {}", e);
+            }
+            result = Err(e);
+            break;
+        }
+        info.add_frame(jpeg.len());
+        info.width = info.width.max(meta.width);
+        info.height = info.height.max(meta.height);
+        sizes.push(jpeg.len() as u32);
+        span = Some((
+            span.map_or(meta.captured_at, |(first, _)| first),
+            meta.captured_at,
+        ));
+    }
+
+    // frames play at the rate they were taken
+    if let Some((first, last)) = span {
+        info.micros_per_frame = AviInfo::frame_duration(info.frames, (last - first).as_micros());
+    }
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage.as_mut() else {
+        return result;
+    };
+    if sizes.is_empty() {
+        let deleted = storage.volume.delete(storage.dir, &name).await;
+        return result.and(deleted);
+    }
+    let avi = AviWriter::new(info);
+    let volume = &mut storage.volume;
+    let finish = async {
+        volume.write(&mut file, &avi.index_header()).await?;
+        let mut index = avi.index();
+        for &len in &sizes {
+            volume.write(&mut file, &index.entry(len as usize)).await?;
+        }
+        file.seek(0);
+        volume.write(&mut file, &avi.header()).await?;
+        volume.flush_file(&mut file, local_time()).await
+    };
+    if let Err(e) = finish.await {
+        warn!("Finishing {} failed: {}", name, e);
+        let _ = storage.volume.delete(storage.dir, &name).await;
+        // the error that stopped the recording comes first
+        return result.and(Err(e));
+    }
+    info!(
+        "Recorded {}: {} frames, {} bytes",
+        name,
+        info.frames,
+        file.size()
+    );
+    result
+}
+
+/// Save frames held in memory as the next recording
+///
+/// The card stays locked until the file is complete, a running recording
+/// waits for it.
+pub async fn save_avi<'a>(
+    mut info: AviInfo,
+    frames: impl Iterator<Item = &'a [u8]> + Clone,
+) -> Result<ShortName, &'static str> {
+    for jpeg in frames.clone() {
+        info.add_frame(jpeg.len());
+    }
+    let avi = AviWriter::new(info);
+    let mut storage = STORAGE.lock().await;
+    let storage = storage.as_mut().ok_or("no card")?;
+    let name = next_name(storage).map_err(|e| describe(&e))?;
+    let dir = storage.dir;
+    let write = async {
+        make_room(storage, &name).await?;
+        let volume = &mut storage.volume;
+        let mut file = volume.create(dir, &name, local_time()).await?;
+        volume.write(&mut file, &avi.header()).await?;
+        for jpeg in frames.clone() {
+            volume
+                .write(&mut file, &AviWriter::frame_header(jpeg.len()))
+                .await?;
+            volume.write(&mut file, jpeg).await?;
+            volume
+                .write(&mut file, AviWriter::frame_padding(jpeg.len()))
+                .await?;
+        }
+        volume.write(&mut file, &avi.index_header()).await?;
+        let mut index = avi.index();
+        for jpeg in frames {
+            volume.write(&mut file, &index.entry(jpeg.len())).await?;
+        }
+        volume.flush_file(&mut file, local_time()).await?;
+        Ok::<u32, Error>(file.size())
+    };
+    match write.await {
+        Ok(size) => {
+            info!("Saved {}: {} frames, {} bytes", name, info.frames, size);
+            Ok(name)
+        }
+        Err(e) => {
+            warn!("Saving {} failed: {}", name, e);
+            let _ = storage.volume.delete(dir, &name).await;
+            Err(describe(&e))
+        }
+    }
+}
+
+/// Send a recording from the card as a file download, only the head if
+/// `head_only`
+pub async fn send_recording(socket: &mut TcpSocket<'_>, name: &str, head_only: bool) {
+    let name = ShortName::new(name).filter(|name| recording_number(name).is_some());
+    let opened = match name {
+        Some(name) if STATUS.lock(|s| s.borrow().current) == Some(name) => Err(Status::Conflict),
+        Some(name) => {
+            let mut storage = STORAGE.lock().await;
+            match storage.as_mut() {
+                Some(storage) => match storage.volume.open(storage.dir, &name).await {
+                    Ok(file) => {
+                        storage.reading.push(name);
+                        Ok((name, file))
+                    }
+                    Err(FatError::NotFound) => Err(Status::NotFound),
+                    Err(_) => Err(Status::InternalServerError),
+                },
+                None => Err(Status::ServiceUnavailable),
+            }
+        }
+        None => Err(Status::NotFound),
+    };
+    let (name, mut file) = match opened {
+        Ok(opened) => opened,
+        Err(status) => {
+            _ = wifi::send_error(socket, status, &[], head_only).await;
+            return;
+        }
+    };
+
+    let head = Response::<256>::new(Status::Ok)
+        .content_type("video/x-msvideo")
+        .header(
+            "Content-Disposition",
+            format_args!("attachment; filename=\"{}\"", name),
+        )
+        .content_length(file.size() as usize);
+    if wifi::send_head(socket, head).await.is_ok() && !head_only {
+        let mut buf = vec![0; DOWNLOAD_CHUNK];
+        loop {
+            let read = match STORAGE.lock().await.as_mut() {
+                Some(storage) => storage.volume.read(&mut file, &mut buf).await,
+                None => break,
+            };
+            match read {
+                Ok(0) => break,
+                Ok(len) => {
+                    if write_all(socket, &buf[..len]).await.is_err() {
+                        warn!("Download of {} aborted", name);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Reading {} failed: {}", name, e);
+                    break;
+                }
+            }
+        }
+    }
+    if let Some(storage) = STORAGE.lock().await.as_mut() {
+        if let Some(i) = storage.reading.iter().position(|n| *n == name) {
+            storage.reading.swap_remove(i);
+        }
+    }
+}
+
+/// Delete a recording, the one being written or downloaded is kept
+pub async fn delete(name: &str) -> Result<(), Status> {
+    let Some(name) = ShortName::new(name).filter(|name| recording_number(name).is_some()) else {
+        return Err(Status::NotFound);
+    };
+    if STATUS.lock(|s| s.borrow().current) == Some(name) {
+        return Err(Status::Conflict);
+    }
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage.as_mut() else {
+        return Err(Status::ServiceUnavailable);
+    };
+    if storage.reading.contains(&name) {
+        return Err(Status::Conflict);
+    }
+    match storage.volume.delete(storage.dir, &name).await {
+        Ok(()) => {}
+        Err(FatError::NotFound) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+    storage
+        .volume
+        .flush()
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Write the configuration, the card and the recordings on it as JSON
+pub async fn render_json(out: &mut impl Write) -> fmt::Result {
+    let config = config();
+    let (current, error) = STATUS.lock(|s| {
+        let s = s.borrow();
+        (s.current, s.error)
+    });
+    write!(
+        out,
+        "{{\"enabled\":{},\"segment\":{},\"fps\":{},\"current\":",
+        config.enabled, config.segment_secs, config.fps
+    )?;
+    match current {
+        Some(name) => write!(out, "\"{}\"", name)?,
+        None => write!(out, "null")?,
+    }
+    write!(out, ",\"error\":")?;
+    match error {
+        Some(error) => write!(out, "\"{}\"", error)?,
+        None => write!(out, "null")?,
+    }
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage.as_mut() else {
+        return write!(out, ",\"card\":null,\"files\":[]}}");
+    };
+    write!(
+        out,
+        ",\"card\":{{\"type\":\"{}\",\"total\":{},\"free\":{}}},\"files\":[",
+        match storage.volume.kind() {
+            storage::FatKind::Fat16 => "FAT16",
+            storage::FatKind::Fat32 => "FAT32",
+        },
+        storage.volume.total_bytes(),
+        storage.volume.free_bytes()
+    )?;
+    let mut entries = storage.volume.list(storage.dir).await.unwrap_or_default();
+    entries.retain(|e| recording_number(&e.name).is_some());
+    entries.sort_unstable_by_key(|e| recording_number(&e.name));
+    let offset = clock::utc_offset_minutes() as i64 * 60;
+    for (i, entry) in entries.iter().enumerate() {
+        write!(
+            out,
+            "{}{{\"name\":\"{}\",\"size\":{},\"time\":",
+            if i > 0 { "," } else { "" },
+            entry.name,
+            entry.size
+        )?;
+        // entries carry local time, 1980-01-01 means the clock was not set
+        match entry.modified {
+            FatTime::EPOCH => write!(out, "null")?,
+            time => write!(out, "{}", time.to_unix() as i64 - offset)?,
+        }
+        write!(out, "}}")?;
+    }
+    write!(out, "]}}")
+}
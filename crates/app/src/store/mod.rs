@@ -0,0 +1,92 @@
+//!
+//! Settings kept across reboots in the `config` partition
+//!
+//! The partition holds a [storage::kv] log, so rewriting a setting spreads
+//! over all of its sectors instead of wearing out one.
+//!
+
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::{nor_flash::NorFlash, ReadStorage};
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use storage::kv::{Flash, Store, SECTOR_LEN};
+
+/// Where the `config` partition of partitions.csv starts
+const PARTITION_OFFSET: u32 = 0x21_0000;
+const PARTITION_LEN: u32 = 0x1_0000;
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct FlashError;
+
+/// The `config` partition, offsets are relative to its start
+struct Partition {
+    flash: FlashStorage<'static>,
+}
+
+impl Flash for Partition {
+    type Error = FlashError;
+
+    fn capacity(&self) -> u32 {
+        PARTITION_LEN
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        // the byte wise interface handles unaligned reads
+        ReadStorage::read(&mut self.flash, PARTITION_OFFSET + offset, buf).map_err(|_| FlashError)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        NorFlash::write(&mut self.flash, PARTITION_OFFSET + offset, data).map_err(|_| FlashError)
+    }
+
+    fn erase(&mut self, offset: u32) -> Result<(), FlashError> {
+        let start = PARTITION_OFFSET + offset;
+        NorFlash::erase(&mut self.flash, start, start + SECTOR_LEN).map_err(|_| FlashError)
+    }
+}
+
+/// `None` until [init] mounted the partition
+static STORE: Mutex<CriticalSectionRawMutex, Option<Store<Partition>>> = Mutex::new(None);
+
+/// Mount the settings, a partition that holds none is formatted
+pub async fn init(flash: FLASH<'static>) {
+    let partition = Partition {
+        flash: FlashStorage::new(flash),
+    };
+    match Store::mount(partition) {
+        Ok(store) => {
+            info!("Settings: mounted");
+            *STORE.lock().await = Some(store);
+        }
+        Err(e) => warn!("Settings: can't mount the config partition: {}", e),
+    }
+}
+
+/// Copy the value of `key` into `buf`, returns its length
+pub async fn get(key: &str, buf: &mut [u8]) -> Option<usize> {
+    let mut store = STORE.lock().await;
+    match store.as_mut()?.get(key, buf) {
+        Ok(len) => len,
+        Err(e) => {
+            warn!("Settings: can't read {}: {}", key, e);
+            None
+        }
+    }
+}
+
+pub async fn set(key: &str, value: &[u8]) -> Result<(), ()> {
+    let mut store = STORE.lock().await;
+    let store = store.as_mut().ok_or(())?;
+    store.set(key, value).map_err(|e| {
+        warn!("Settings: can't write {}: {}", key, e);
+    })
+}
+
+pub async fn remove(key: &str) -> Result<(), ()> {
+    let mut store = STORE.lock().await;
+    let store = store.as_mut().ok_or(())?;
+    store.remove(key).map_err(|e| {
+        warn!("Settings: can't remove {}: {}", key, e);
+    })
+}
@@ -0,0 +1,323 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    future::pending,
+};
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use http::{Response, Status};
+use media::{avi::AviInfo, ring::FrameRing};
+
+use crate::{
+    cam::{power, snapshot},
+    clip, clock,
+    mem::{psram_buf, PsramBuf},
+    metadata,
+    metrics::METRICS,
+    record,
+    wifi::{self, write_all},
+};
+
+/// PSRAM set aside for time-lapse frames
+const RING_SIZE: usize = 4 * 1024 * 1024;
+/// Shorter intervals keep the sensor running, waking it up takes a few frames
+const MIN_STANDBY_INTERVAL: u32 = 5;
+
+#[derive(Clone, Copy)]
+pub struct TimelapseConfig {
+    pub enabled: bool,
+    pub interval_secs: u32,
+    /// Daily window as local minutes since midnight, may wrap past midnight
+    pub window: Option<(u16, u16)>,
+    /// Put the sensor into standby between shots while nobody is streaming or
+    /// recording
+    pub power_save: bool,
+}
+
+/// A stored time-lapse frame
+#[derive(Clone, Copy)]
+pub struct Shot {
+    pub seq: u32,
+    pub captured_at: Instant,
+    pub width: u16,
+    pub height: u16,
+}
+
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<TimelapseConfig>> =
+    blocking_mutex::Mutex::new(RefCell::new(TimelapseConfig {
+        enabled: false,
+        interval_secs: 60,
+        window: None,
+        power_save: true,
+    }));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The recorded sequence, oldest frame first
+pub static SHOTS: Mutex<CriticalSectionRawMutex, Option<FrameRing<Shot, PsramBuf>>> =
+    Mutex::new(None);
+
+pub fn config() -> TimelapseConfig {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+/// Takes effect right away, the next shot is due one interval after the last
+pub fn set_config(config: TimelapseConfig) {
+    CONFIG.lock(|c| {
+        *c.borrow_mut() = TimelapseConfig {
+            interval_secs: config.interval_secs.max(1),
+            ..config
+        }
+    });
+    CHANGED.signal(());
+}
+
+/// Parse a daily window written as `07:00-19:30`
+pub fn parse_window(value: &str) -> Option<(u16, u16)> {
+    let minutes = |time: &str| -> Option<u16> {
+        let (hours, minutes) = time.split_once(':')?;
+        let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    };
+    let (start, end) = value.split_once('-')?;
+    Some((minutes(start)?, minutes(end)?))
+}
+
+fn in_window((start, end): (u16, u16), minute: u16) -> bool {
+    if start <= end {
+        (start..end).contains(&minute)
+    } else {
+        minute >= start || minute < end
+    }
+}
+
+/// Take a frame every `interval_secs` into the PSRAM ring
+#[embassy_executor::task]
+pub async fn timelapse_task() {
+    let Some(buf) = psram_buf(RING_SIZE) else {
+        error!("Failed to allocate the time-lapse ring");
+        return;
+    };
+    *SHOTS.lock().await = Some(FrameRing::new(buf));
+    let mut last_slot: Option<Instant> = None;
+    loop {
+        let config = config();
+        if !config.enabled {
+            last_slot = None;
+        }
+        if !config.enabled || !config.power_save {
+            power::set_standby(false);
+        }
+        let due = match (config.enabled, last_slot) {
+            (false, _) => None,
+            (true, None) => Some(Instant::now()),
+            (true, Some(last)) => Some(last + Duration::from_secs(config.interval_secs as u64)),
+        };
+        let wait = async {
+            match due {
+                Some(due) => Timer::at(due).await,
+                None => pending().await,
+            }
+        };
+        if let Either::Second(()) = select(wait, CHANGED.wait()).await {
+            continue;
+        }
+        let Some(due) = due else { continue };
+        let now = Instant::now();
+        // keep the schedule, unless a slot was missed entirely
+        last_slot = Some(
+            if now - due > Duration::from_secs(config.interval_secs as u64) {
+                now
+            } else {
+                due
+            },
+        );
+
+        if let Some(window) = config.window {
+            match clock::local_minute_of_day() {
+                Some(minute) if in_window(window, minute as u16) => {}
+                Some(_) => {
+                    standby(&config);
+                    continue;
+                }
+                None => {
+                    warn!("Time-lapse: no time yet, ignoring the daily window");
+                }
+            }
+        }
+
+        match snapshot(true, false).await {
+            Some(frame) => {
+                let meta = *frame.meta();
+                let mut shots = SHOTS.lock().await;
+                if let Some(shots) = shots.as_mut() {
+                    let shot = Shot {
+                        seq: meta.seq,
+                        captured_at: meta.captured_at,
+                        width: meta.width,
+                        height: meta.height,
+                    };
+                    match shots.push_parts(&metadata::tag(&frame, &meta).parts(), shot) {
+                        Ok(()) => info!(
+                            "Time-lapse: frame {} stored, {} frames in {} bytes",
+                            meta.seq,
+                            shots.len(),
+                            shots.bytes()
+                        ),
+                        Err(e) => warn!("Time-lapse: {}", e),
+                    }
+                }
+            }
+            None => warn!("Time-lapse: no frame"),
+        }
+        standby(&config);
+    }
+}
+
+fn standby(config: &TimelapseConfig) {
+    let clients = METRICS
+        .active_clients
+        .load(core::sync::atomic::Ordering::Relaxed);
+    if config.power_save
+        && config.interval_secs > MIN_STANDBY_INTERVAL
+        && clients == 0
+        && !record::is_recording()
+        && !clip::is_enabled()
+    {
+        power::set_standby(true);
+    }
+}
+
+/// Send the whole sequence as one MJPEG file, a plain series of JPEGs. Only
+/// the head is sent if `head_only`.
+pub async fn send_mjpeg(socket: &mut TcpSocket<'_>, head_only: bool) {
+    let shots = SHOTS.lock().await;
+    let Some(shots) = shots.as_ref().filter(|shots| !shots.is_empty()) else {
+        send_no_frames(socket, head_only).await;
+        return;
+    };
+    let head = Response::<256>::new(Status::Ok)
+        .content_type("video/x-motion-jpeg")
+        .header(
+            "Content-Disposition",
+            "attachment; filename=\"timelapse.mjpeg\"",
+        )
+        .content_length(shots.bytes());
+    if wifi::send_head(socket, head).await.is_err() || head_only {
+        return;
+    }
+    for (_, jpeg) in shots.iter() {
+        if write_all(socket, jpeg).await.is_err() {
+            warn!("Time-lapse download aborted");
+            return;
+        }
+    }
+}
+
+/// Send the whole sequence as an AVI file playing at `fps`, only the head if
+/// `head_only`
+pub async fn send_avi(socket: &mut TcpSocket<'_>, fps: u32, head_only: bool) {
+    let shots = SHOTS.lock().await;
+    let Some(shots) = shots.as_ref().filter(|shots| !shots.is_empty()) else {
+        send_no_frames(socket, head_only).await;
+        return;
+    };
+    let (width, height) = shots.iter().fold((0, 0), |(w, h), (shot, _)| {
+        (shot.width.max(w), shot.height.max(h))
+    });
+    let info = AviInfo::new(width, height, 1_000_000 / fps.clamp(1, 120));
+    let frames = shots.iter().map(|(_, jpeg)| jpeg);
+    if wifi::send_avi(socket, "timelapse.avi", info, frames, head_only)
+        .await
+        .is_err()
+    {
+        warn!("Time-lapse download aborted");
+    }
+}
+
+async fn send_no_frames(socket: &mut TcpSocket<'_>, head_only: bool) {
+    _ = wifi::send_response(
+        socket,
+        Status::NotFound,
+        "text/plain",
+        b"No frames",
+        head_only,
+    )
+    .await;
+}
+
+pub async fn clear() {
+    if let Some(shots) = SHOTS.lock().await.as_mut() {
+        shots.clear();
+    }
+}
+
+/// Write the configuration and the recorded sequence as JSON
+pub async fn render_json(out: &mut impl Write) -> fmt::Result {
+    let config = config();
+    write!(
+        out,
+        "{{\"enabled\":{},\"interval\":{},\"power_save\":{},\"window\":",
+        config.enabled, config.interval_secs, config.power_save
+    )?;
+    match config.window {
+        Some((start, end)) => write!(
+            out,
+            "\"{:02}:{:02}-{:02}:{:02}\"",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        )?,
+        None => write!(out, "null")?,
+    }
+    write!(
+        out,
+        ",\"utc_offset\":{},\"time\":",
+        clock::utc_offset_minutes()
+    )?;
+    write_unix(out, clock::unix_micros())?;
+    let shots = SHOTS.lock().await;
+    let (frames, bytes, capacity) = shots
+        .as_ref()
+        .map_or((0, 0, 0), |s| (s.len(), s.bytes(), s.capacity()));
+    write!(
+        out,
+        ",\"frames\":{},\"bytes\":{},\"capacity\":{},\"first\":",
+        frames, bytes, capacity
+    )?;
+    let mut iter = shots.as_ref().into_iter().flat_map(|s| s.iter());
+    let first = iter.next().map(|(shot, _)| *shot);
+    let last = iter.next_back().map(|(shot, _)| *shot).or(first);
+    write_shot(out, first)?;
+    write!(out, ",\"last\":")?;
+    write_shot(out, last)?;
+    write!(out, "}}")
+}
+
+fn write_shot(out: &mut impl Write, shot: Option<Shot>) -> fmt::Result {
+    let Some(shot) = shot else {
+        return write!(out, "null");
+    };
+    write!(
+        out,
+        "{{\"seq\":{},\"resolution\":\"{}x{}\",\"time\":",
+        shot.seq, shot.width, shot.height
+    )?;
+    write_unix(out, clock::to_unix_micros(shot.captured_at))?;
+    write!(out, "}}")
+}
+
+/// Unix time in seconds, `null` while the clock is not synchronized
+fn write_unix(out: &mut impl Write, micros: Option<u64>) -> fmt::Result {
+    match micros {
+        Some(micros) => write!(out, "{}.{:06}", micros / 1_000_000, micros % 1_000_000),
+        None => write!(out, "null"),
+    }
+}
@@ -1,21 +1,88 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_net::{tcp::TcpSocket, IpListenEndpoint, Runner, Stack, StackResources};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Timer};
-use esp_hal::{dma_rx_stream_buffer, lcd_cam::cam::Camera, peripherals::WIFI, rng::Rng};
+use esp_hal::{peripherals::WIFI, rng::Rng};
 use esp_radio::{
     wifi::{self, ClientConfig, WifiController, WifiDevice, WifiEvent, WifiMode},
     Controller,
 };
 extern crate alloc;
-use alloc::{boxed::Box, string::String};
+use alloc::string::String;
+use http::{
+    json::{self, Object, Value},
+    query_flag, query_param, Method, ParseError, Request, Response, Status,
+};
+use media::{
+    avi::{AviInfo, AviWriter},
+    jpeg::Subsampling,
+    raw::Export,
+};
+
+use crate::{
+    cam::{
+        control::{self, PixelFormat, RAW_RESOLUTION},
+        encode,
+        rate::{self, RateTarget},
+        send_raw, send_snapshot, settings, stream_camera, stream_live,
+    },
+    clip::{self, Trigger},
+    clock,
+    errors::RuntimeError,
+    flash::{self, FlashMode},
+    mem::{self, PsramBuf},
+    metadata::{self, MetadataMode},
+    metrics::{inc, METRICS},
+    mk_static, motion, record, store, timelapse,
+};
+
+pub mod portal;
+
+/// Keys of the station credentials in the settings store
+const SSID_KEY: &str = "wifi.ssid";
+const PASSWORD_KEY: &str = "wifi.password";
 
-use crate::{cam::stream_camera, errors::RuntimeError, mk_static};
+/// Set when the stored credentials change
+static CREDENTIALS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Most HTTP connections served at the same time
+pub const MAX_CONNECTIONS: usize = 4;
+/// PSRAM the connection buffers may take together
+const CONNECTION_BUDGET: usize = 1024 * 1024;
+/// Receive buffer of a connection, requests are small
+const RX_BUFFER: usize = 4 * 1024;
+/// Send buffer of a connection, room for a couple of frames of a stream
+const TX_BUFFER: usize = 200 * 1024;
+/// HTTP listeners plus the DHCP client, DNS and SNTP sockets
+const SOCKETS: usize = MAX_CONNECTIONS + 3;
+/// Failed connection attempts in a row before the setup access point opens
+const CONNECT_ATTEMPTS: u32 = 3;
+/// Buffers of the listener on the setup access point, it only serves the
+/// setup page
+const PORTAL_RX_BUFFER: usize = 2 * 1024;
+const PORTAL_TX_BUFFER: usize = 16 * 1024;
+/// Room for the strings with escapes of a JSON body, credentials take up to
+/// 95 bytes
+const JSON_STRINGS: usize = 256;
+
+/// Which pages a listener serves
+#[derive(Clone, Copy)]
+enum Site {
+    Camera,
+    /// The setup page on the access point
+    Portal,
+}
 
 pub async fn init(
     rng: Rng,
     wifi_peripheral: WIFI<'static>,
     spawner: &Spawner,
-    camera: Camera<'static>,
 ) -> Result<Stack<'static>, RuntimeError> {
     let init = esp_radio::init()?;
     let init = mk_static!(Controller, init);
@@ -23,36 +90,642 @@ pub async fn init(
     let device = interface.sta;
     let config = embassy_net::Config::dhcpv4(Default::default());
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
-    let stack_resources = mk_static!(StackResources::<6>, StackResources::<6>::new());
+    let stack_resources = mk_static!(StackResources::<SOCKETS>, StackResources::<SOCKETS>::new());
     let (stack, runner) = embassy_net::new(device, config, stack_resources, seed);
-    spawner.spawn(connection(control)).ok();
+
+    let ap_ssid = portal::ssid(interface.ap.mac_address());
+    let ap_resources = mk_static!(
+        StackResources::<{ portal::SOCKETS }>,
+        StackResources::<{ portal::SOCKETS }>::new()
+    );
+    let (ap_stack, ap_runner) =
+        embassy_net::new(interface.ap, portal::config(), ap_resources, seed ^ 1);
+
+    spawner.spawn(connection(control, ap_ssid)).ok();
     spawner.spawn(net_task(runner)).ok();
-    spawner.spawn(http_handle(stack, camera)).ok();
+    spawner.spawn(net_task(ap_runner)).ok();
+    spawn_listeners(stack, spawner);
+    portal::spawn(ap_stack, spawner);
+    match (
+        mem::psram_buf(PORTAL_RX_BUFFER),
+        mem::psram_buf(PORTAL_TX_BUFFER),
+    ) {
+        (Some(rx_buffer), Some(tx_buffer)) => {
+            let listener = http_handle(
+                ap_stack,
+                MAX_CONNECTIONS,
+                Site::Portal,
+                rx_buffer,
+                tx_buffer,
+            );
+            spawner.spawn(listener).ok();
+        }
+        _ => defmt::warn!("No PSRAM for the setup page listener"),
+    }
+    spawner.spawn(clock::sntp_task(stack)).ok();
     Ok(stack)
 }
 
+/// Spawn as many HTTP listeners as [CONNECTION_BUDGET] and the free PSRAM
+/// allow, at least one
+fn spawn_listeners(stack: Stack<'static>, spawner: &Spawner) {
+    let budget = CONNECTION_BUDGET.min(mem::psram_free());
+    let count = (budget / (RX_BUFFER + TX_BUFFER)).clamp(1, MAX_CONNECTIONS);
+    let mut spawned = 0;
+    for id in 0..count {
+        let (Some(rx_buffer), Some(tx_buffer)) =
+            (mem::psram_buf(RX_BUFFER), mem::psram_buf(TX_BUFFER))
+        else {
+            defmt::warn!("No PSRAM for HTTP listener {}", id);
+            break;
+        };
+        if spawner
+            .spawn(http_handle(stack, id, Site::Camera, rx_buffer, tx_buffer))
+            .is_err()
+        {
+            break;
+        }
+        spawned += 1;
+    }
+    defmt::info!("{} HTTP listeners", spawned);
+}
+
 pub async fn write_all(socket: &mut TcpSocket<'_>, buf: &[u8]) -> Result<(), ()> {
     let mut offset = 0;
     while offset < buf.len() {
         match socket.write(&buf[offset..]).await {
             Ok(0) => return Err(()),
-            Ok(n) => offset += n,
+            Ok(n) => {
+                offset += n;
+                METRICS.bytes_sent.fetch_add(n as u32, Ordering::Relaxed);
+            }
             Err(_) => return Err(()),
         }
     }
     Ok(())
 }
 
-#[embassy_executor::task]
-pub async fn http_handle(stack: Stack<'static>, mut camera: Camera<'static>) {
-    let mut rx_buffer = Box::new([0u8; 4096]);
-    let mut tx_buffer = unsafe {
-        let p = esp_alloc::HEAP.alloc_caps(
-            esp_alloc::MemoryCapability::External.into(),
-            core::alloc::Layout::from_size_align(40960 * 10, 4).unwrap(),
-        );
-        alloc::vec::Vec::from_raw_parts(p, 40960 * 10, 40960 * 10)
+/// Send `frames` as an AVI download, `frames` is walked three times. Only
+/// the head is sent if `head_only`.
+pub async fn send_avi<'a>(
+    socket: &mut TcpSocket<'_>,
+    filename: &str,
+    mut info: AviInfo,
+    frames: impl Iterator<Item = &'a [u8]> + Clone,
+    head_only: bool,
+) -> Result<(), ()> {
+    for jpeg in frames.clone() {
+        info.add_frame(jpeg.len());
+    }
+    let avi = AviWriter::new(info);
+    let head = Response::<256>::new(Status::Ok)
+        .content_type("video/x-msvideo")
+        .header(
+            "Content-Disposition",
+            format_args!("attachment; filename=\"{}\"", filename),
+        )
+        .content_length(info.file_len());
+    send_head(socket, head).await?;
+    if head_only {
+        return Ok(());
+    }
+    write_all(socket, &avi.header()).await?;
+    for jpeg in frames.clone() {
+        write_all(socket, &AviWriter::frame_header(jpeg.len())).await?;
+        write_all(socket, jpeg).await?;
+        write_all(socket, AviWriter::frame_padding(jpeg.len())).await?;
+    }
+    write_all(socket, &avi.index_header()).await?;
+    let mut index = avi.index();
+    for jpeg in frames {
+        write_all(socket, &index.entry(jpeg.len())).await?;
+    }
+    Ok(())
+}
+
+/// Send a `200 OK` response with `body`
+async fn send_body(
+    socket: &mut TcpSocket<'_>,
+    content_type: &str,
+    body: &[u8],
+    head_only: bool,
+) -> Result<(), ()> {
+    send_response(socket, Status::Ok, content_type, body, head_only).await
+}
+
+/// Send a response with `status` and `body`, `head_only` answers a `HEAD`
+/// request with the head alone
+pub async fn send_response(
+    socket: &mut TcpSocket<'_>,
+    status: Status,
+    content_type: &str,
+    body: &[u8],
+    head_only: bool,
+) -> Result<(), ()> {
+    let head = Response::<256>::new(status)
+        .content_type(content_type)
+        .no_cache()
+        .content_length(body.len());
+    send_head(socket, head).await?;
+    if head_only {
+        return Ok(());
+    }
+    write_all(socket, body).await
+}
+
+/// Send [http::error_response] for `status`, its head alone if `head_only`
+pub async fn send_error(
+    socket: &mut TcpSocket<'_>,
+    status: Status,
+    allow: &[Method],
+    head_only: bool,
+) -> Result<(), ()> {
+    let response = http::error_response(status, allow);
+    let len = match head_only {
+        true => response
+            .find("\r\n\r\n")
+            .map_or(response.len(), |end| end + 4),
+        false => response.len(),
     };
+    write_all(socket, &response.as_bytes()[..len]).await
+}
+
+/// Send `head`, fails without writing anything if it didn't fit
+pub async fn send_head<const N: usize>(
+    socket: &mut TcpSocket<'_>,
+    head: Response<N>,
+) -> Result<(), ()> {
+    let head = head.finish().map_err(|_| ())?;
+    write_all(socket, head.as_bytes()).await
+}
+
+/// Apply `enabled`, `sensitivity`, `min_cells` and `zones` from a form body
+fn configure_motion(form: &str) {
+    if query_param(form, "enabled").is_some() {
+        motion::set_enabled(query_flag(form, "enabled"));
+    }
+    let mut config = motion::config();
+    if let Some(sensitivity) = query_param(form, "sensitivity").and_then(|v| v.parse().ok()) {
+        config.sensitivity = u8::clamp(sensitivity, 1, 100);
+    }
+    if let Some(min_cells) = query_param(form, "min_cells").and_then(|v| v.parse().ok()) {
+        config.min_cells = min_cells;
+    }
+    if let Some(zones) = query_param(form, "zones").and_then(|v| motion::parse_zones(&v)) {
+        config.zones = zones;
+    }
+    motion::set_config(config);
+}
+
+/// Apply `fps`, `kbps`, `off`, `best_quality`, `adapt_resolution` and
+/// `max_resolution` from a form body
+fn configure_rate(form: &str) {
+    let mut config = rate::config();
+    if let Some(fps) = query_param(form, "fps").and_then(|v| v.parse().ok()) {
+        config.target = RateTarget::Fps(fps);
+    }
+    if let Some(kbps) = query_param(form, "kbps").and_then(|v| v.parse().ok()) {
+        config.target = RateTarget::Kbps(kbps);
+    }
+    if query_flag(form, "off") {
+        config.target = RateTarget::Off;
+    }
+    if let Some(quality) = query_param(form, "best_quality").and_then(|v| v.parse().ok()) {
+        config.best_quality = u8::clamp(quality, ov2640::MIN_QUALITY, ov2640::MAX_QUALITY);
+    }
+    if query_param(form, "adapt_resolution").is_some() {
+        config.adapt_resolution = query_flag(form, "adapt_resolution");
+    }
+    if let Some(resolution) =
+        query_param(form, "max_resolution").and_then(|v| rate::parse_resolution(&v))
+    {
+        config.max_resolution = resolution;
+    }
+    rate::set_config(config);
+}
+
+/// Apply `format` (`jpeg`, `rgb565`, `yuv422`), `resolution` and `quality`
+/// from a form body
+async fn configure_capture(form: &str) {
+    let Some(mut config) = control::config().await else {
+        return;
+    };
+    let current = config;
+    if let Some(format) = query_param(form, "format").and_then(|v| PixelFormat::parse(&v)) {
+        config.format = format;
+        if format != PixelFormat::Jpeg {
+            config.resolution = RAW_RESOLUTION;
+        }
+    }
+    if let Some(resolution) =
+        query_param(form, "resolution").and_then(|v| rate::parse_resolution(&v))
+    {
+        config.resolution = resolution;
+        rate::set_manual();
+    }
+    if let Some(quality) = query_param(form, "quality").and_then(|v| v.parse().ok()) {
+        config.quality = quality;
+        rate::set_manual();
+    }
+    if config != current {
+        if let Err(e) = control::apply(config).await {
+            defmt::warn!("Can't change the capture setting: {}", e);
+        }
+    }
+}
+
+/// Apply `quality` (1..100) and `subsampling` (`444`, `422`, `420`) of the
+/// software encoder from a form body
+fn configure_encoder(form: &str) {
+    let mut config = encode::config();
+    if let Some(quality) = query_param(form, "quality").and_then(|v| v.parse::<u8>().ok()) {
+        config.quality = quality.clamp(1, 100);
+    }
+    if let Some(subsampling) = query_param(form, "subsampling").and_then(|v| Subsampling::parse(&v))
+    {
+        config.subsampling = subsampling;
+    }
+    encode::set_config(config);
+}
+
+/// Apply `enabled`, `interval`, `window` (`07:00-19:00` or `off`),
+/// `power_save`, `utc_offset` (minutes) and `clear` from a form body
+async fn configure_timelapse(form: &str) {
+    let mut config = timelapse::config();
+    if query_param(form, "enabled").is_some() {
+        config.enabled = query_flag(form, "enabled");
+    }
+    if let Some(interval) = query_param(form, "interval").and_then(|v| v.parse().ok()) {
+        config.interval_secs = interval;
+    }
+    match query_param(form, "window").as_deref() {
+        Some("off") => config.window = None,
+        Some(window) => {
+            if let Some(window) = timelapse::parse_window(window) {
+                config.window = Some(window);
+            }
+        }
+        None => {}
+    }
+    if query_param(form, "power_save").is_some() {
+        config.power_save = query_flag(form, "power_save");
+    }
+    if let Some(offset) = query_param(form, "utc_offset").and_then(|v| v.parse().ok()) {
+        clock::set_utc_offset_minutes(offset);
+    }
+    if query_flag(form, "clear") {
+        timelapse::clear().await;
+    }
+    if !form.is_empty() {
+        timelapse::set_config(config);
+    }
+}
+
+/// Apply `enabled`, `segment` (seconds, 0 for one file) and `fps` from a form
+/// body
+async fn configure_recording(form: &str) {
+    let mut config = record::config();
+    if query_param(form, "enabled").is_some() {
+        config.enabled = query_flag(form, "enabled");
+    }
+    if let Some(segment) = query_param(form, "segment").and_then(|v| v.parse().ok()) {
+        config.segment_secs = segment;
+    }
+    if let Some(fps) = query_param(form, "fps").and_then(|v| v.parse().ok()) {
+        config.fps = fps;
+    }
+    record::set_config(config);
+}
+
+/// Apply `enabled`, `pre` and `post` (seconds), `fps`, `on_motion` and
+/// `trigger` from a form body
+fn configure_clip(form: &str) {
+    let mut config = clip::config();
+    if query_param(form, "enabled").is_some() {
+        config.enabled = query_flag(form, "enabled");
+    }
+    if let Some(pre) = query_param(form, "pre").and_then(|v| v.parse().ok()) {
+        config.pre_secs = pre;
+    }
+    if let Some(post) = query_param(form, "post").and_then(|v| v.parse().ok()) {
+        config.post_secs = post;
+    }
+    if let Some(fps) = query_param(form, "fps").and_then(|v| v.parse().ok()) {
+        config.fps = fps;
+    }
+    if query_param(form, "on_motion").is_some() {
+        config.on_motion = query_flag(form, "on_motion");
+    }
+    if config != clip::config() {
+        clip::set_config(config);
+    }
+    if query_flag(form, "trigger") {
+        clip::trigger(Trigger::Api);
+    }
+}
+
+/// Apply `mode` (`off`, `on`, `auto`) and `brightness` (percent) from a
+/// form body
+fn configure_flash(form: &str) {
+    if let Some(brightness) = query_param(form, "brightness").and_then(|v| v.parse().ok()) {
+        flash::set_brightness(brightness);
+    }
+    if let Some(mode) = query_param(form, "mode").and_then(|v| FlashMode::parse(&v)) {
+        flash::set_mode(mode);
+    }
+}
+
+/// Apply `mode` (`off`, `exif`, `com`) and `name` from a form body
+fn configure_metadata(form: &str) {
+    let mut config = metadata::config();
+    if let Some(mode) = query_param(form, "mode").and_then(|v| MetadataMode::parse(&v)) {
+        config.mode = mode;
+    }
+    if let Some(value) = query_param(form, "name") {
+        match metadata::parse_name(&value) {
+            Some(name) => config.device_name = name,
+            None => defmt::warn!("Invalid device name {}", value.as_str()),
+        }
+    }
+    metadata::set_config(config);
+}
+
+/// Store `ssid` and `password` of a JSON body as the station credentials
+async fn configure_wifi(body: &[u8]) -> Result<(), (Status, &'static str)> {
+    let mut strings = [0; JSON_STRINGS];
+    let object =
+        Object::parse(body, &mut strings).map_err(|_| (Status::BadRequest, "invalid JSON"))?;
+    let (Some(Value::Str(ssid)), password) = (object.get("ssid"), object.get("password")) else {
+        return Err((Status::BadRequest, "ssid missing"));
+    };
+    let password = match password {
+        Some(Value::Str(password)) => password,
+        None | Some(Value::Null) => "",
+        Some(_) => return Err((Status::BadRequest, "password is no string")),
+    };
+    let credentials = Credentials::new(ssid, password).ok_or((
+        Status::BadRequest,
+        "ssid takes 1 to 32 bytes, password none or 8 to 63",
+    ))?;
+    set_credentials(&credentials)
+        .await
+        .map_err(|_| (Status::InternalServerError, "can't store the credentials"))
+}
+
+/// Write the SSID and whether the station is connected as JSON, never the
+/// password
+async fn render_wifi_json(out: &mut impl Write) -> fmt::Result {
+    out.write_str("{\"ssid\":")?;
+    match credentials().await {
+        Some(credentials) => json::write_str(out, &credentials.ssid)?,
+        None => out.write_str("null")?,
+    }
+    write!(
+        out,
+        ",\"connected\":{},\"access_point\":{}}}",
+        is_connected(),
+        portal::is_active()
+    )
+}
+
+/// Methods `path` accepts, `None` if there is no such resource
+fn allowed_methods(path: &str) -> Option<&'static [Method]> {
+    match path {
+        "/" | "/index" | "/index.html" | "/stream" | "/metrics" | "/clip.avi"
+        | "/timelapse.avi" | "/timelapse.mjpeg" | "/raw" | "/snapshot" => {
+            Some(&[Method::Get, Method::Head])
+        }
+        "/api/motion" | "/api/capture" | "/api/encoder" | "/api/rate" | "/api/timelapse"
+        | "/api/recordings" | "/api/clip" | "/api/flash" | "/api/metadata" => {
+            Some(&[Method::Get, Method::Head, Method::Post])
+        }
+        "/api/camera" => Some(&[Method::Get, Method::Head, Method::Put]),
+        "/api/wifi" => Some(&[Method::Get, Method::Head, Method::Put, Method::Delete]),
+        _ if path.starts_with("/recordings/") => Some(&[Method::Get, Method::Head, Method::Delete]),
+        _ => None,
+    }
+}
+
+/// Answer with a 404 or 405 unless `request` names a resource that takes its
+/// method, `methods` are those of the resource. Returns whether it does
+async fn check_method(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+    methods: Option<&[Method]>,
+) -> bool {
+    let head_only = request.method == Method::Head;
+    let Some(methods) = methods else {
+        let _ = send_error(socket, Status::NotFound, &[], head_only).await;
+        return false;
+    };
+    if methods.contains(&request.method) {
+        return true;
+    }
+    let _ = send_error(socket, Status::MethodNotAllowed, methods, head_only).await;
+    false
+}
+
+/// Send `body` as JSON, errors are only logged since the connection closes
+/// right after
+async fn send_json(socket: &mut TcpSocket<'_>, body: &str, head_only: bool) {
+    if send_body(socket, "application/json", body.as_bytes(), head_only)
+        .await
+        .is_err()
+    {
+        defmt::warn!("Failed to send response");
+    }
+}
+
+/// `/api/wifi` of both sites
+async fn serve_wifi(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let result = match request.method {
+        Method::Put => configure_wifi(request.body).await,
+        Method::Delete => clear_credentials()
+            .await
+            .map_err(|_| (Status::InternalServerError, "can't clear the credentials")),
+        _ => Ok(()),
+    };
+    let mut body = String::new();
+    let status = match result {
+        Ok(()) => {
+            let _ = render_wifi_json(&mut body).await;
+            Status::Ok
+        }
+        Err((status, message)) => {
+            let _ = write!(body, "{{\"error\":\"{}\"}}", message);
+            status
+        }
+    };
+    let head_only = request.method == Method::Head;
+    let _ = send_response(
+        socket,
+        status,
+        "application/json",
+        body.as_bytes(),
+        head_only,
+    )
+    .await;
+}
+
+async fn route(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if !check_method(socket, request, allowed_methods(request.path)).await {
+        return;
+    }
+    let query = request.query;
+    let head_only = request.method == Method::Head;
+    // settings only change with a POST, a GET with a query string just reads
+    let form = match request.method {
+        Method::Post => core::str::from_utf8(request.body).unwrap_or("").trim(),
+        _ => "",
+    };
+    let mut body = String::new();
+    match request.path {
+        "/index" | "/index.html" => {
+            let _ = send_body(
+                socket,
+                "text/html",
+                include_bytes!("../../../../stream.html"),
+                head_only,
+            )
+            .await;
+        }
+        "/stream" => {
+            let fps = query_param(query, "fps")
+                .and_then(|fps| fps.parse().ok())
+                .filter(|&fps| fps > 0);
+            if query_flag(query, "live") {
+                stream_live(socket, head_only).await;
+            } else {
+                stream_camera(socket, fps, head_only).await;
+            }
+        }
+        "/metrics" => {
+            let _ = METRICS.render(&mut body);
+            let _ = send_body(
+                socket,
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+                head_only,
+            )
+            .await;
+        }
+        "/api/motion" => {
+            configure_motion(form);
+            let _ = motion::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/capture" => {
+            configure_capture(form).await;
+            let _ = control::render_json(&mut body).await;
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/camera" => {
+            let mut status = Status::Ok;
+            if request.method == Method::Put {
+                let mut strings = [0; JSON_STRINGS];
+                if let Err(e) = settings::apply_json(request.body, &mut strings).await {
+                    defmt::warn!("Camera settings refused: {}", e);
+                    status = match e.is_client_error() {
+                        true => Status::BadRequest,
+                        false => Status::InternalServerError,
+                    };
+                    let _ = e.render_json(&mut body);
+                }
+            }
+            if status == Status::Ok {
+                let _ = settings::render_json(&mut body).await;
+            }
+            let _ = send_response(
+                socket,
+                status,
+                "application/json",
+                body.as_bytes(),
+                head_only,
+            )
+            .await;
+        }
+        "/api/wifi" => serve_wifi(socket, request).await,
+        "/api/encoder" => {
+            configure_encoder(form);
+            let _ = encode::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/rate" => {
+            configure_rate(form);
+            let _ = rate::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/timelapse" => {
+            configure_timelapse(form).await;
+            let _ = timelapse::render_json(&mut body).await;
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/recordings" => {
+            configure_recording(form).await;
+            let _ = record::render_json(&mut body).await;
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/clip" => {
+            configure_clip(form);
+            let _ = clip::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/flash" => {
+            configure_flash(form);
+            let _ = flash::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/api/metadata" => {
+            configure_metadata(form);
+            let _ = metadata::render_json(&mut body);
+            send_json(socket, &body, head_only).await;
+        }
+        "/clip.avi" => clip::send_avi(socket, head_only).await,
+        "/timelapse.avi" => {
+            let fps = query_param(query, "fps").and_then(|v| v.parse().ok());
+            timelapse::send_avi(socket, fps.unwrap_or(10), head_only).await;
+        }
+        "/timelapse.mjpeg" => timelapse::send_mjpeg(socket, head_only).await,
+        "/raw" => {
+            let export = query_param(query, "format").and_then(|v| Export::parse(&v));
+            send_raw(socket, export.unwrap_or(Export::Bmp), head_only).await;
+        }
+        "/snapshot" => {
+            let fresh = query_flag(query, "fresh");
+            let flash = match query_param(query, "flash") {
+                Some(_) => query_flag(query, "flash"),
+                None => flash::mode() == FlashMode::Auto,
+            };
+            send_snapshot(socket, fresh, flash, head_only).await;
+        }
+        path => match path.strip_prefix("/recordings/") {
+            Some(name) if request.method == Method::Delete => match record::delete(name).await {
+                Ok(()) => {
+                    let _ = record::render_json(&mut body).await;
+                    send_json(socket, &body, head_only).await;
+                }
+                Err(status) => {
+                    defmt::warn!("Can't delete recording {}", name);
+                    let _ = send_error(socket, status, &[], false).await;
+                }
+            },
+            Some(name) => record::send_recording(socket, name, head_only).await,
+            None => {
+                let html = b"<html><body><h1>ESP32 Camera</h1><img src='/stream' /></body></html>";
+                let _ = send_body(socket, "text/html", html, head_only).await;
+            }
+        },
+    }
+}
+
+#[embassy_executor::task(pool_size = MAX_CONNECTIONS + 1)]
+async fn http_handle(
+    stack: Stack<'static>,
+    id: usize,
+    site: Site,
+    mut rx_buffer: PsramBuf,
+    mut tx_buffer: PsramBuf,
+) {
     loop {
         if stack.is_link_up() {
             break;
@@ -62,102 +735,60 @@ pub async fn http_handle(stack: Stack<'static>, mut camera: Camera<'static>) {
     stack
         .config_v4()
         .inspect(|c| defmt::info!("ipv4 config: {}", c));
-    let mut dma_buf = dma_rx_stream_buffer!(65536, 1024);
     loop {
-        let mut socket = TcpSocket::new(stack, &mut *rx_buffer, &mut tx_buffer);
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(Duration::from_secs(10)));
-        defmt::info!("Wait for connection...");
+        defmt::info!("Listener {}: wait for connection...", id);
         let r = socket
             .accept(IpListenEndpoint {
                 addr: None,
                 port: 80,
             })
             .await;
-        defmt::info!("Connected...");
+        defmt::info!("Listener {}: connected", id);
         if let Err(e) = r {
             defmt::info!("connect error: {:?}", e);
             continue;
         }
         let mut buffer = [0u8; 2048];
         let mut pos = 0;
-
-        let mut attempts = 0;
-        let max_attempts = 20;
-
-        while attempts < max_attempts {
+        let complete = loop {
+            if pos > 0
+                && Request::parse(&buffer[..pos], buffer.len()).err()
+                    != Some(ParseError::Incomplete)
+            {
+                break true;
+            }
             match socket.read(&mut buffer[pos..]).await {
                 Ok(0) => {
-                    if pos > 0 {
-                        defmt::info!("Client finished sending ({} bytes)", pos);
-                        break;
-                    } else {
-                        defmt::info!("Client closed without data");
-                        break;
-                    }
-                }
-                Ok(len) => {
-                    pos += len;
-                    defmt::debug!("Read {} bytes, total {}", len, pos);
-                    let request = unsafe { core::str::from_utf8_unchecked(&buffer[..pos]) };
-                    if request.contains("\r\n\r\n") {
-                        defmt::info!("Complete request received");
-                        break;
-                    }
-
-                    if pos >= buffer.len() {
-                        defmt::warn!("Buffer full");
-                        break;
-                    }
-
-                    attempts = 0;
+                    defmt::info!("Client closed after {} bytes", pos);
+                    break false;
                 }
+                Ok(len) => pos += len,
                 Err(e) => {
                     defmt::warn!("Read error: {:?}, bytes read: {}", e, pos);
-                    if pos > 0 {
-                        break;
-                    } else {
-                        socket.close();
-                        Timer::after(Duration::from_millis(10)).await;
-                        continue;
-                    }
+                    break false;
                 }
             }
-            attempts += 1;
-        }
-
-        if pos == 0 {
-            defmt::warn!("No data received, closing");
+        };
+        if !complete {
             socket.close();
             Timer::after(Duration::from_millis(10)).await;
             continue;
         }
 
-        let request = unsafe { core::str::from_utf8_unchecked(&buffer[..pos]) };
-        defmt::info!("Request: <{}>", request);
-        if request.contains("GET /index") {
-            let buf = include_bytes!("../../../../stream.html");
-            let mut header = heapless::String::<256>::new();
-            use core::fmt::Write;
-            let _ = write!(
-                &mut header,
-                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                buf.len()
-            );
-            if write_all(&mut socket, header.as_bytes()).await.is_err() {
-                continue;
+        match Request::parse(&buffer[..pos], buffer.len()) {
+            Ok((request, _)) => {
+                defmt::info!("Request: {} {}", request.method, request.path);
+                match site {
+                    Site::Camera => route(&mut socket, &request).await,
+                    Site::Portal => portal::route(&mut socket, &request).await,
+                }
             }
-            if write_all(&mut socket, buf).await.is_err() {
-                continue;
+            Err(e) => {
+                defmt::warn!("Rejecting request: {}", e);
+                let _ = send_error(&mut socket, e.status(), &[], false).await;
             }
-        } else if request.contains("GET /stream") {
-            (camera, dma_buf) = stream_camera(camera, dma_buf, &mut socket).await;
-        } else if request.contains("GET /snapshot") {
-            _ = socket
-                .write(b"HTTP/1.1 404 Not Found\r\n\r\nNo Image")
-                .await;
-        } else {
-            let html = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n<html><body><h1>ESP32 Camera</h1><img src='/stream' /></body></html>";
-            _ = socket.write(html.as_bytes()).await;
         }
 
         let r = socket.flush().await;
@@ -166,39 +797,144 @@ pub async fn http_handle(stack: Stack<'static>, mut camera: Camera<'static>) {
         }
         Timer::after(Duration::from_millis(10)).await;
         socket.close();
-        defmt::info!("close >>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>");
+        defmt::info!("Listener {}: closed", id);
+    }
+}
+
+/// Network the station joins
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: heapless::String<32>,
+    /// Empty for open networks
+    pub password: heapless::String<64>,
+}
+
+impl Credentials {
+    /// `None` unless the SSID has 1 to 32 bytes and the password is empty or
+    /// 8 to 63 characters, what WPA2 takes
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        if ssid.is_empty() || !(password.is_empty() || (8..=63).contains(&password.len())) {
+            return None;
+        }
+        Some(Self {
+            ssid: heapless::String::try_from(ssid).ok()?,
+            password: heapless::String::try_from(password).ok()?,
+        })
     }
 }
 
+/// The stored credentials, or those set as `WIFI_SSID` and `WIFI_PASSWORD`
+/// at build time
+pub async fn credentials() -> Option<Credentials> {
+    let mut ssid = [0; 32];
+    let mut password = [0; 64];
+    let Some(ssid_len) = store::get(SSID_KEY, &mut ssid).await else {
+        return Credentials::new(
+            option_env!("WIFI_SSID")?,
+            option_env!("WIFI_PASSWORD").unwrap_or(""),
+        );
+    };
+    let password_len = store::get(PASSWORD_KEY, &mut password).await.unwrap_or(0);
+    Credentials::new(
+        core::str::from_utf8(&ssid[..ssid_len]).ok()?,
+        core::str::from_utf8(&password[..password_len]).ok()?,
+    )
+}
+
+/// Store `credentials` and reconnect with them
+pub async fn set_credentials(credentials: &Credentials) -> Result<(), ()> {
+    // the SSID goes last, without it the password isn't used
+    store::set(PASSWORD_KEY, credentials.password.as_bytes()).await?;
+    store::set(SSID_KEY, credentials.ssid.as_bytes()).await?;
+    CREDENTIALS_CHANGED.signal(());
+    Ok(())
+}
+
+/// Forget the stored credentials and disconnect
+pub async fn clear_credentials() -> Result<(), ()> {
+    store::remove(SSID_KEY).await?;
+    store::remove(PASSWORD_KEY).await?;
+    CREDENTIALS_CHANGED.signal(());
+    Ok(())
+}
+
+/// Whether the station is associated with its network
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Keep the station connected, the setup access point `ap_ssid` opens while
+/// there are no credentials or [CONNECT_ATTEMPTS] in a row failed
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>) {
+pub async fn connection(mut controller: WifiController<'static>, ap_ssid: heapless::String<16>) {
+    let mut failures = 0;
     loop {
+        CREDENTIALS_CHANGED.reset();
+        let credentials = match credentials().await {
+            Some(credentials) if failures < CONNECT_ATTEMPTS => credentials,
+            credentials => {
+                match &credentials {
+                    Some(credentials) => {
+                        defmt::warn!("Can't reach {}", credentials.ssid.as_str())
+                    }
+                    None => defmt::warn!("No Wi-Fi credentials"),
+                }
+                portal::provision(&mut controller, &ap_ssid, credentials.as_ref()).await;
+                failures = 0;
+                continue;
+            }
+        };
         if !matches!(controller.is_started(), Ok(true)) {
             controller.set_mode(WifiMode::Sta).unwrap();
             let client_config = ClientConfig::default()
-                .with_ssid(String::try_from("TP-LINK_7CBB").unwrap())
-                .with_password(String::try_from("mt.123456").unwrap());
+                .with_ssid(String::from(credentials.ssid.as_str()))
+                .with_password(String::from(credentials.password.as_str()));
             controller
                 .set_config(&wifi::ModeConfig::Client(client_config))
                 .unwrap();
             controller.start_async().await.unwrap();
         }
 
-        defmt::info!("Wifi connecting...");
-        match controller.connect_async().await {
+        defmt::info!("Wifi connecting to {}...", credentials.ssid.as_str());
+        let changed = match controller.connect_async().await {
             Ok(_) => {
                 defmt::info!("Wifi connected!");
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                failures = 0;
+                CONNECTED.store(true, Ordering::Relaxed);
+                let event = select(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    CREDENTIALS_CHANGED.wait(),
+                )
+                .await;
+                CONNECTED.store(false, Ordering::Relaxed);
+                match event {
+                    Either::First(_) => {
+                        inc(&METRICS.wifi_reconnects);
+                        false
+                    }
+                    Either::Second(_) => true,
+                }
             }
             Err(e) => {
                 defmt::warn!("Failed to connect: {:?}", e);
-                Timer::after(Duration::from_millis(5000)).await
+                failures += 1;
+                let retry = Timer::after(Duration::from_millis(5000));
+                matches!(
+                    select(retry, CREDENTIALS_CHANGED.wait()).await,
+                    Either::Second(_)
+                )
             }
+        };
+        if changed {
+            // the configuration is only applied when the controller starts
+            defmt::info!("Wifi credentials changed");
+            let _ = controller.disconnect_async().await;
+            let _ = controller.stop_async().await;
         }
     }
 }
 
-#[embassy_executor::task]
+#[embassy_executor::task(pool_size = 2)]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
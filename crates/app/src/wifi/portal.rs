@@ -0,0 +1,325 @@
+//!
+//! Access point for provisioning
+//!
+//! Opens as `ESPCAM-XXXX` when there are no credentials or the station can't
+//! reach its network. Clients get an address by DHCP, every name resolves to
+//! the device and every unknown path is redirected to the setup page, which
+//! makes phones and laptops show it as a captive portal.
+//!
+
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use edge_dhcp::{
+    io::{self as dhcp_io, DEFAULT_SERVER_PORT},
+    server::{Server, ServerOptions},
+};
+use edge_nal::UdpBind;
+use edge_nal_embassy::{Udp, UdpBuffers};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    Ipv4Cidr, Stack, StaticConfigV4,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_radio::wifi::{
+    AccessPointConfig, AuthMethod, ClientConfig, ModeConfig, ScanConfig, WifiController, WifiMode,
+};
+extern crate alloc;
+use alloc::string::String;
+use http::{dns, json, Method, Request, Response, Status};
+
+use super::{send_body, send_response, serve_wifi, Credentials, CREDENTIALS_CHANGED};
+
+/// Address of the device on its own network, a /24
+pub const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+const SETUP_URL: &str = "http://192.168.4.1/";
+/// DHCP, DNS and the HTTP listener
+pub const SOCKETS: usize = 3;
+/// Leases the DHCP server hands out
+const MAX_LEASES: usize = 8;
+/// Networks kept from a scan, the strongest
+const MAX_NETWORKS: usize = 16;
+/// How often the station tries its network while the access point is open
+const RETRY: Duration = Duration::from_secs(60);
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SCAN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SCAN_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static NETWORKS: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<Network, MAX_NETWORKS>>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// A network found by a scan
+#[derive(Clone)]
+pub struct Network {
+    pub ssid: heapless::String<32>,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    pub open: bool,
+}
+
+/// Network configuration of the access point interface
+pub fn config() -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ADDRESS, 24),
+        gateway: Some(ADDRESS),
+        dns_servers: Default::default(),
+    })
+}
+
+/// `ESPCAM-` and the last two bytes of `mac` in hex
+pub fn ssid(mac: [u8; 6]) -> heapless::String<16> {
+    let mut ssid = heapless::String::new();
+    let _ = write!(ssid, "ESPCAM-{:02X}{:02X}", mac[4], mac[5]);
+    ssid
+}
+
+/// Whether the access point is open
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Spawn the DHCP and DNS servers, they idle while the access point is down
+pub fn spawn(stack: Stack<'static>, spawner: &Spawner) {
+    spawner.spawn(dhcp_task(stack)).ok();
+    spawner.spawn(dns_task(stack)).ok();
+}
+
+/// Run the access point next to the station until credentials are set or,
+/// with `credentials` known, the station gets through to its network
+///
+/// The station only tries every [RETRY], joining a network moves the access
+/// point to its channel and drops the clients for a moment. If the access
+/// point fails to start this returns after [RETRY], to be called again.
+pub async fn provision(
+    controller: &mut WifiController<'static>,
+    ssid: &str,
+    credentials: Option<&Credentials>,
+) {
+    let _ = controller.stop_async().await;
+    let client_config = match credentials {
+        Some(credentials) => ClientConfig::default()
+            .with_ssid(String::from(credentials.ssid.as_str()))
+            .with_password(String::from(credentials.password.as_str())),
+        None => ClientConfig::default(),
+    };
+    let ap_config = AccessPointConfig::default().with_ssid(String::from(ssid));
+    let started = async {
+        controller.set_mode(WifiMode::ApSta)?;
+        controller.set_config(&ModeConfig::ApSta(client_config, ap_config))?;
+        controller.start_async().await
+    }
+    .await;
+    if let Err(e) = started {
+        defmt::error!("Setup access point failed to start: {:?}", e);
+        Timer::after(RETRY).await;
+        return;
+    }
+    defmt::info!("Setup access point {} open at {}", ssid, SETUP_URL);
+    ACTIVE.store(true, Ordering::Relaxed);
+
+    loop {
+        match select3(
+            CREDENTIALS_CHANGED.wait(),
+            SCAN_REQUEST.wait(),
+            Timer::after(RETRY),
+        )
+        .await
+        {
+            Either3::First(_) => {
+                // let the setup page get its answer before the network goes
+                Timer::after(Duration::from_secs(1)).await;
+                break;
+            }
+            Either3::Second(_) => {
+                scan(controller).await;
+                SCAN_DONE.signal(());
+            }
+            Either3::Third(_) => {
+                if credentials.is_some() && controller.connect_async().await.is_ok() {
+                    defmt::info!("Station reached its network, closing the access point");
+                    break;
+                }
+            }
+        }
+    }
+
+    ACTIVE.store(false, Ordering::Relaxed);
+    let _ = controller.disconnect_async().await;
+    let _ = controller.stop_async().await;
+}
+
+/// Scan with the station interface and keep the strongest networks
+async fn scan(controller: &mut WifiController<'static>) {
+    let mut networks = heapless::Vec::<Network, MAX_NETWORKS>::new();
+    match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(mut found) => {
+            found.sort_unstable_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+            for ap in found {
+                if ap.ssid.is_empty() || networks.iter().any(|n| n.ssid == ap.ssid.as_str()) {
+                    continue;
+                }
+                let Ok(ssid) = heapless::String::try_from(ap.ssid.as_str()) else {
+                    continue;
+                };
+                let network = Network {
+                    ssid,
+                    rssi: ap.signal_strength,
+                    open: ap.auth_method == Some(AuthMethod::None),
+                };
+                if networks.push(network).is_err() {
+                    break;
+                }
+            }
+        }
+        Err(e) => defmt::warn!("Scan failed: {:?}", e),
+    }
+    NETWORKS.lock(|n| *n.borrow_mut() = networks);
+}
+
+/// Scan again and wait for the result, the last one stays if it takes too
+/// long
+pub async fn refresh_networks() {
+    if !is_active() {
+        return;
+    }
+    SCAN_DONE.reset();
+    SCAN_REQUEST.signal(());
+    let _ = with_timeout(SCAN_TIMEOUT, SCAN_DONE.wait()).await;
+}
+
+/// Write the networks of the last scan as JSON
+pub fn render_networks_json(out: &mut impl Write) -> fmt::Result {
+    NETWORKS.lock(|networks| {
+        out.write_str("{\"networks\":[")?;
+        for (i, network) in networks.borrow().iter().enumerate() {
+            out.write_str(if i == 0 { "{\"ssid\":" } else { ",{\"ssid\":" })?;
+            json::write_str(out, &network.ssid)?;
+            write!(
+                out,
+                ",\"rssi\":{},\"open\":{}}}",
+                network.rssi, network.open
+            )?;
+        }
+        out.write_str("]}")
+    })
+}
+
+/// Serve the setup page and what it uses, everything else is redirected to it
+pub async fn route(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let methods: &[Method] = match request.path {
+        "/" | "/index.html" | "/api/scan" => &[Method::Get, Method::Head],
+        "/api/wifi" => &[Method::Get, Method::Head, Method::Put],
+        _ => {
+            let response = Response::<128>::new(Status::Found)
+                .header("Location", SETUP_URL)
+                .content_length(0)
+                .finish();
+            if let Ok(response) = response {
+                let _ = super::write_all(socket, response.as_bytes()).await;
+            }
+            return;
+        }
+    };
+    if !super::check_method(socket, request, Some(methods)).await {
+        return;
+    }
+    let head_only = request.method == Method::Head;
+    match request.path {
+        "/api/scan" => {
+            refresh_networks().await;
+            let mut body = String::new();
+            let _ = render_networks_json(&mut body);
+            let _ = send_response(
+                socket,
+                Status::Ok,
+                "application/json",
+                body.as_bytes(),
+                head_only,
+            )
+            .await;
+        }
+        "/api/wifi" => serve_wifi(socket, request).await,
+        _ => {
+            let _ = send_body(
+                socket,
+                "text/html",
+                include_bytes!("../../../../setup.html"),
+                head_only,
+            )
+            .await;
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn dhcp_task(stack: Stack<'static>) {
+    let buffers = UdpBuffers::<1, 1024, 1024, 4>::new();
+    let udp = Udp::new(stack, &buffers);
+    let endpoint = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        DEFAULT_SERVER_PORT,
+    ));
+    let mut socket = match udp.bind(endpoint).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            defmt::error!("DHCP server can't bind: {:?}", defmt::Debug2Format(&e));
+            return;
+        }
+    };
+    let mut gateways = [ADDRESS];
+    let dns_servers = [ADDRESS];
+    let mut options = ServerOptions::new(ADDRESS, Some(&mut gateways));
+    options.dns = &dns_servers;
+    let mut server = Server::<_, MAX_LEASES>::new(|| Instant::now().as_secs(), ADDRESS);
+    let mut buffer = [0u8; 1500];
+    loop {
+        if let Err(e) = dhcp_io::server::run(&mut server, &options, &mut socket, &mut buffer).await
+        {
+            defmt::warn!("DHCP server: {:?}", defmt::Debug2Format(&e));
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn dns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(53) {
+        defmt::error!("DNS server can't bind: {:?}", e);
+        return;
+    }
+    let mut query = [0u8; 512];
+    let mut answer = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = dns::answer(&query[..len], ADDRESS.octets(), &mut answer) {
+            let _ = socket.send_to(&answer[..len], meta).await;
+        }
+    }
+}
@@ -0,0 +1,24 @@
+#![no_main]
+
+use http::dns::answer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte sizes the output buffer, so short ones are hit too
+    let Some((&size, query)) = data.split_first() else {
+        return;
+    };
+    let mut out = vec![0; size as usize * 2];
+    let Some(len) = answer(query, [192, 168, 4, 1], &mut out) else {
+        return;
+    };
+    assert!(len <= out.len());
+    // the question is echoed, at most one answer follows it
+    assert_eq!(out[..2], query[..2]);
+    assert!(len >= 12 && len <= query.len() + 16);
+    assert!(out[7] <= 1);
+    // a larger buffer gives the same answer
+    let mut larger = vec![0; 512];
+    assert_eq!(answer(query, [192, 168, 4, 1], &mut larger), Some(len));
+    assert_eq!(larger[..len], out[..len]);
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use http::json::{write_str, Object, Value};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte sizes the buffer for decoded strings
+    let Some((&size, body)) = data.split_first() else {
+        return;
+    };
+    let mut strings = vec![0; size as usize];
+    let parsed = Object::parse(body, &mut strings);
+    match parsed {
+        Ok(object) => {
+            for (key, value) in object.members() {
+                assert!(object.get(key).is_some());
+                // a decoded string written back reads the same
+                if let Value::Str(value) = value {
+                    let mut written = String::new();
+                    write_str(&mut written, value).unwrap();
+                    let again = format!("{{\"v\":{}}}", written);
+                    let mut strings = vec![0; again.len()];
+                    let object = Object::parse(again.as_bytes(), &mut strings).unwrap();
+                    assert_eq!(object.get("v"), Some(Value::Str(value)));
+                }
+            }
+        }
+        Err(e) => assert!(e.offset <= body.len()),
+    }
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use http::Request;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the buffer capacity, so the size limits are hit too
+    let Some((&capacity, data)) = data.split_first() else {
+        return;
+    };
+    if let Ok((request, len)) = Request::parse(data, capacity as usize * 8) {
+        assert!(len <= data.len());
+        assert!(data[..len].ends_with(request.body));
+        assert!(request.path.starts_with('/'));
+        for header in request.headers() {
+            assert!(request.header(header.name).is_some());
+        }
+        for pair in request.query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // decoding never grows a value
+            if let Some(decoded) = http::percent_decode::<256>(value) {
+                assert!(decoded.len() <= value.len());
+            }
+            let _ = request.query_param(key);
+        }
+    }
+});
@@ -0,0 +1,70 @@
+//!
+//! DNS answers for the captive portal
+//!
+//! Every `A` query gets the one address of the access point, other record
+//! types an empty answer, so clients that join look up the setup page whatever
+//! name they ask for.
+//!
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Short, the answers are wrong once the device is back on its network
+const TTL_SECS: u32 = 60;
+/// Name pointer to the question, type, class, TTL, length and address
+const ANSWER_LEN: usize = 16;
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *buf.get(offset)?,
+        *buf.get(offset + 1)?,
+    ]))
+}
+
+/// Write the response to `query` into `out` and return its length, `None`
+/// if `query` is no standard query with one question or `out` is too small
+pub fn answer(query: &[u8], address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    let flags = u16_at(query, 2)?;
+    // responses and opcodes other than QUERY
+    if flags & 0xf800 != 0 || u16_at(query, 4)? != 1 {
+        return None;
+    }
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // questions come first, there is nothing to point back to
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len;
+    }
+    let question_type = u16_at(query, pos)?;
+    let question_class = u16_at(query, pos + 2)?;
+    let question_end = pos + 4;
+    let answers = question_class == CLASS_IN && matches!(question_type, TYPE_A | TYPE_ANY);
+    let len = question_end + if answers { ANSWER_LEN } else { 0 };
+
+    let out = out.get_mut(..len)?;
+    out[..question_end].copy_from_slice(&query[..question_end]);
+    // response, authoritative, recursion desired as asked and available
+    out[2] = 0x84 | (query[2] & 0x01);
+    out[3] = 0x80;
+    out[6..8].copy_from_slice(&u16::from(answers).to_be_bytes());
+    // authority and additional records of the query are dropped
+    out[8..12].fill(0);
+    if answers {
+        let answer = &mut out[question_end..];
+        answer[..2].copy_from_slice(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&TTL_SECS.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&address);
+    }
+    Some(len)
+}
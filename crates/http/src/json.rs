@@ -0,0 +1,269 @@
+//!
+//! Flat JSON objects for request bodies
+//!
+//! Only what the settings endpoints take: one object whose members are
+//! strings, integers, booleans or null. Nested values and fractions are
+//! rejected. Strings with escapes are decoded into a buffer of the caller, the
+//! others point into the body. Responses are written with `write!`, strings
+//! that come from users with [write_str].
+//!
+
+use core::fmt::{self, Write};
+
+/// Members kept per object, more are rejected
+pub const MAX_MEMBERS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(i32),
+    Bool(bool),
+    Null,
+}
+
+/// Where the body stops being JSON this parser takes
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct SyntaxError {
+    pub offset: usize,
+}
+
+pub struct Object<'a> {
+    members: heapless::Vec<(&'a str, Value<'a>), MAX_MEMBERS>,
+}
+
+impl<'a> Object<'a> {
+    /// Parse a whole body holding one object, white space around it is fine
+    ///
+    /// Strings with escapes are decoded into `strings`, one that doesn't fit
+    /// anymore is an error at its opening quote.
+    pub fn parse(body: &'a [u8], strings: &'a mut [u8]) -> Result<Self, SyntaxError> {
+        let mut parser = Parser {
+            src: body,
+            pos: 0,
+            strings,
+        };
+        let mut members = heapless::Vec::new();
+        parser.expect(b'{')?;
+        if !parser.accept(b'}') {
+            loop {
+                let offset = parser.skip_space();
+                let key = parser.string()?;
+                parser.expect(b':')?;
+                let value = parser.value()?;
+                members
+                    .push((key, value))
+                    .map_err(|_| SyntaxError { offset })?;
+                if parser.accept(b'}') {
+                    break;
+                }
+                parser.expect(b',')?;
+            }
+        }
+        if parser.skip_space() != body.len() {
+            return Err(parser.error());
+        }
+        Ok(Self { members })
+    }
+
+    /// Value of the last member called `key`
+    pub fn get(&self, key: &str) -> Option<Value<'a>> {
+        self.members
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+    }
+
+    /// Members in the order of the body
+    pub fn members(&self) -> impl Iterator<Item = (&'a str, Value<'a>)> + '_ {
+        self.members.iter().copied()
+    }
+}
+
+/// Write `value` as a JSON string, quotes included
+pub fn write_str(out: &mut impl Write, value: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    /// What is left of the buffer for decoded strings
+    strings: &'a mut [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> SyntaxError {
+        SyntaxError { offset: self.pos }
+    }
+
+    /// Skip white space, returns the position after it
+    fn skip_space(&mut self) -> usize {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.src.get(self.pos) {
+            self.pos += 1;
+        }
+        self.pos
+    }
+
+    /// Consume `byte` after white space if it is next
+    fn accept(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        if self.src.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), SyntaxError> {
+        match self.accept(byte) {
+            true => Ok(()),
+            false => Err(self.error()),
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, SyntaxError> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        let mut escaped = false;
+        loop {
+            match self.src.get(self.pos) {
+                Some(b'"') => break,
+                // checked when decoding
+                Some(b'\\') if self.pos + 1 < self.src.len() => {
+                    escaped = true;
+                    self.pos += 2;
+                }
+                Some(&b) if b < 0x20 => return Err(self.error()),
+                Some(b'\\') | None => return Err(self.error()),
+                Some(_) => self.pos += 1,
+            }
+        }
+        let value = match escaped {
+            true => self.unescape(start)?,
+            false => &self.src[start..self.pos],
+        };
+        let value = core::str::from_utf8(value).map_err(|e| SyntaxError {
+            // decoded strings are only checked as a whole
+            offset: start + if escaped { 0 } else { e.valid_up_to() },
+        })?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    /// Decode the string from `start` up to the closing quote at the current
+    /// position into [Parser::strings]
+    fn unescape(&mut self, start: usize) -> Result<&'a [u8], SyntaxError> {
+        let strings = core::mem::take(&mut self.strings);
+        let raw = &self.src[start..self.pos];
+        let mut len = 0;
+        let mut at = 0;
+        while at < raw.len() {
+            let mut utf8 = [0; 4];
+            let (bytes, used): (&[u8], usize) = match raw[at] {
+                b'\\' => match raw[at + 1] {
+                    b'"' => (b"\"", 2),
+                    b'\\' => (b"\\", 2),
+                    b'/' => (b"/", 2),
+                    b'b' => (b"\x08", 2),
+                    b'f' => (b"\x0C", 2),
+                    b'n' => (b"\n", 2),
+                    b'r' => (b"\r", 2),
+                    b't' => (b"\t", 2),
+                    b'u' => {
+                        let (c, used) =
+                            code_point(&raw[at..]).ok_or(SyntaxError { offset: start + at })?;
+                        (c.encode_utf8(&mut utf8).as_bytes(), used)
+                    }
+                    _ => return Err(SyntaxError { offset: start + at }),
+                },
+                _ => (&raw[at..at + 1], 1),
+            };
+            let out = strings
+                .get_mut(len..len + bytes.len())
+                .ok_or(SyntaxError { offset: start - 1 })?;
+            out.copy_from_slice(bytes);
+            len += bytes.len();
+            at += used;
+        }
+        let (value, rest) = strings.split_at_mut(len);
+        self.strings = rest;
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value<'a>, SyntaxError> {
+        self.skip_space();
+        let rest = &self.src[self.pos..];
+        for (word, value) in [
+            (&b"true"[..], Value::Bool(true)),
+            (b"false", Value::Bool(false)),
+            (b"null", Value::Null),
+        ] {
+            if rest.starts_with(word) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        match rest.first() {
+            Some(b'"') => self.string().map(Value::Str),
+            Some(b'-' | b'0'..=b'9') => self.integer(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value<'a>, SyntaxError> {
+        let start = self.pos;
+        if self.src[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        let digits = self.pos;
+        while self.src.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        let leading_zero = || self.src[digits] == b'0' && self.pos - digits > 1;
+        if self.pos == digits || leading_zero() {
+            return Err(SyntaxError { offset: digits });
+        }
+        if let Some(b'.' | b'e' | b'E') = self.src.get(self.pos) {
+            return Err(self.error());
+        }
+        // only ASCII digits and a sign were taken
+        let text = core::str::from_utf8(&self.src[start..self.pos]).unwrap_or("");
+        text.parse()
+            .map(Value::Int)
+            .map_err(|_| SyntaxError { offset: start })
+    }
+}
+
+/// The character of a `\uXXXX` escape at the start of `raw`, a surrogate pair
+/// takes two, and the bytes it took
+fn code_point(raw: &[u8]) -> Option<(char, usize)> {
+    let high = hex4(raw)?;
+    if !(0xD800..0xDC00).contains(&high) {
+        return char::from_u32(high).map(|c| (c, 6));
+    }
+    let low = match raw.get(6..8) {
+        Some(b"\\u") => hex4(&raw[6..])?,
+        _ => return None,
+    };
+    if !(0xDC00..0xE000).contains(&low) {
+        return None;
+    }
+    char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).map(|c| (c, 12))
+}
+
+/// Value of the four hex digits after the `\u` at the start of `raw`
+fn hex4(raw: &[u8]) -> Option<u32> {
+    raw.get(2..6)?.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | (digit as char).to_digit(16)?)
+    })
+}
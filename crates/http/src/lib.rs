@@ -0,0 +1,426 @@
+//!
+//! Bounded HTTP/1.1 request parsing and response heads
+//!
+//! Requests are parsed in place from the receive buffer, nothing is copied.
+//! Only what the camera's endpoints need is supported: origin-form targets,
+//! `Content-Length` bodies, no chunked requests and no header folding.
+//!
+//! Nothing in here touches the network, so the crate builds, is tested and
+//! fuzzed on the host. That is also why the captive portal's DNS answers live
+//! in [dns].
+//!
+
+#![no_std]
+
+use core::fmt::{self, Display, Write};
+
+pub mod dns;
+pub mod json;
+
+/// Headers kept per request, more are rejected
+pub const MAX_HEADERS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl Method {
+    pub const ALL: [Method; 6] = [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Options,
+    ];
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Status {
+    Ok,
+    Found,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub const ALL: [Status; 9] = [
+        Status::Ok,
+        Status::Found,
+        Status::BadRequest,
+        Status::NotFound,
+        Status::MethodNotAllowed,
+        Status::Conflict,
+        Status::PayloadTooLarge,
+        Status::InternalServerError,
+        Status::ServiceUnavailable,
+    ];
+
+    pub const fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::Found => 302,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::Conflict => 409,
+            Status::PayloadTooLarge => 413,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Found => "Found",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+}
+
+/// Why a request can't be parsed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ParseError {
+    /// The head or the body isn't complete yet
+    Incomplete,
+    /// Malformed, or something this parser doesn't support
+    BadRequest,
+    /// Too many headers or a body larger than the buffer
+    TooLarge,
+}
+
+impl ParseError {
+    /// Status to answer with, [ParseError::Incomplete] with a full buffer is
+    /// [Status::PayloadTooLarge]
+    pub fn status(self) -> Status {
+        match self {
+            ParseError::BadRequest => Status::BadRequest,
+            ParseError::Incomplete | ParseError::TooLarge => Status::PayloadTooLarge,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+pub struct Request<'a> {
+    pub method: Method,
+    /// Target without the query string, starts with `/`
+    pub path: &'a str,
+    /// Query string without the `?`, not percent decoded
+    pub query: &'a str,
+    headers: heapless::Vec<Header<'a>, MAX_HEADERS>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request at the start of `buf`, `capacity` is the most the
+    /// receive buffer holds. Returns the request and how many bytes it took.
+    pub fn parse(buf: &'a [u8], capacity: usize) -> Result<(Self, usize), ParseError> {
+        let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None if buf.len() >= capacity => return Err(ParseError::TooLarge),
+            None => return Err(ParseError::Incomplete),
+        };
+        let head =
+            core::str::from_utf8(&buf[..head_len - 4]).map_err(|_| ParseError::BadRequest)?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(ParseError::BadRequest);
+        };
+        let method = Method::parse(method).ok_or(ParseError::BadRequest)?;
+        if !matches!(version, "HTTP/1.1" | "HTTP/1.0") {
+            return Err(ParseError::BadRequest);
+        }
+        if !target.starts_with('/') || !target.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(ParseError::BadRequest);
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut headers = heapless::Vec::new();
+        let mut content_length = None;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest)?;
+            if name.is_empty() || !name.bytes().all(is_token) {
+                // also rejects folded lines, they start with white space
+                return Err(ParseError::BadRequest);
+            }
+            let value = value.trim_matches([' ', '\t']);
+            if name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err(ParseError::BadRequest);
+            }
+            if name.eq_ignore_ascii_case("content-length") {
+                let len = parse_length(value).ok_or(ParseError::BadRequest)?;
+                if content_length.is_some_and(|l| l != len) {
+                    return Err(ParseError::BadRequest);
+                }
+                content_length = Some(len);
+            }
+            headers
+                .push(Header { name, value })
+                .map_err(|_| ParseError::TooLarge)?;
+        }
+
+        let body_len = content_length.unwrap_or(0);
+        if body_len > capacity.saturating_sub(head_len) {
+            return Err(ParseError::TooLarge);
+        }
+        let Some(body) = buf.get(head_len..head_len + body_len) else {
+            return Err(ParseError::Incomplete);
+        };
+        let request = Request {
+            method,
+            path,
+            query,
+            headers,
+            body,
+        };
+        Ok((request, head_len + body_len))
+    }
+
+    /// Value of the first header called `name`, case insensitive
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers
+    }
+
+    /// Decoded value of `key` in the query string
+    pub fn query_param(&self, key: &str) -> Option<Param> {
+        query_param(self.query, key)
+    }
+
+    /// Whether `key` is in the query string and not switched off
+    pub fn query_flag(&self, key: &str) -> bool {
+        query_flag(self.query, key)
+    }
+}
+
+/// tchar of RFC 9110
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn parse_length(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Longest query or form value after decoding
+pub const MAX_PARAM_LEN: usize = 128;
+
+/// Decoded value of a query string or form body
+pub type Param = heapless::String<MAX_PARAM_LEN>;
+
+/// Value of `key` in a query string or `application/x-www-form-urlencoded`
+/// body, decoded. A value that can't be decoded or is longer than
+/// [MAX_PARAM_LEN] counts as missing.
+pub fn query_param(query: &str, key: &str) -> Option<Param> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| percent_decode::<MAX_PARAM_LEN>(k).is_some_and(|k| k == key))
+        .and_then(|(_, v)| percent_decode(v))
+}
+
+/// Returns true if `key` is present and not switched off (`0`, `false`, `off`)
+pub fn query_flag(query: &str, key: &str) -> bool {
+    query_param(query, key).is_some_and(|v| !matches!(v.as_str(), "0" | "false" | "off"))
+}
+
+/// Decode `%XX` escapes and `+` for a space. `None` if an escape is cut short
+/// or not hex, the result isn't UTF-8 or doesn't fit `N` bytes.
+pub fn percent_decode<const N: usize>(value: &str) -> Option<heapless::String<N>> {
+    let mut decoded = heapless::Vec::<u8, N>::new();
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        let b = match b {
+            b'+' => b' ',
+            b'%' => {
+                let high = hex_digit(bytes.next()?)?;
+                high << 4 | hex_digit(bytes.next()?)?
+            }
+            b => b,
+        };
+        decoded.push(b).ok()?;
+    }
+    heapless::String::from_utf8(decoded).ok()
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// A response head didn't fit its buffer
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct HeadTooLarge;
+
+/// Status line and headers of a response
+///
+/// Every response closes the connection. A header that doesn't fit `N` is
+/// left out whole and [Self::finish] fails.
+pub struct Response<const N: usize> {
+    head: heapless::String<N>,
+    overflowed: bool,
+}
+
+impl<const N: usize> Response<N> {
+    pub fn new(status: Status) -> Self {
+        let response = Self {
+            head: heapless::String::new(),
+            overflowed: false,
+        };
+        response.headers_with(|out| {
+            write!(
+                out,
+                "HTTP/1.1 {} {}\r\nConnection: close\r\n",
+                status.code(),
+                status.reason()
+            )
+        })
+    }
+
+    pub fn header(self, name: &str, value: impl Display) -> Self {
+        self.headers_with(|out| write!(out, "{}: {}\r\n", name, value))
+    }
+
+    pub fn content_type(self, content_type: &str) -> Self {
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn content_length(self, len: usize) -> Self {
+        self.header("Content-Length", len)
+    }
+
+    pub fn no_cache(self) -> Self {
+        self.header("Cache-Control", "no-cache")
+    }
+
+    /// Append headers written by `f`, each terminated by CRLF. If they don't
+    /// all fit none of them are kept.
+    pub fn headers_with(mut self, f: impl FnOnce(&mut dyn Write) -> fmt::Result) -> Self {
+        let len = self.head.len();
+        if self.overflowed || f(&mut self.head).is_err() {
+            self.head.truncate(len);
+            self.overflowed = true;
+        }
+        self
+    }
+
+    /// The head with the blank line that ends it, fails if anything was left
+    /// out
+    pub fn finish(mut self) -> Result<heapless::String<N>, HeadTooLarge> {
+        if self.overflowed || self.head.push_str("\r\n").is_err() {
+            return Err(HeadTooLarge);
+        }
+        Ok(self.head)
+    }
+}
+
+/// Room for the longest [error_response]
+const ERROR_RESPONSE_LEN: usize = 256;
+
+const fn longest_error_response() -> usize {
+    let mut reason = 0;
+    let mut i = 0;
+    while i < Status::ALL.len() {
+        if Status::ALL[i].reason().len() > reason {
+            reason = Status::ALL[i].reason().len();
+        }
+        i += 1;
+    }
+    let mut allow = 0;
+    let mut i = 0;
+    while i < Method::ALL.len() {
+        allow += Method::ALL[i].name().len() + ", ".len();
+        i += 1;
+    }
+    // the status line and the body both carry the code and the reason
+    let fixed = "HTTP/1.1 000 \r\nConnection: close\r\nContent-Type: text/plain\r\n\
+                 Content-Length: 00\r\nAllow: \r\n\r\n000 \n";
+    fixed.len() + 2 * reason + allow
+}
+
+const _: () = assert!(longest_error_response() <= ERROR_RESPONSE_LEN);
+
+/// Complete plain text response for an error, `allow` lists the methods of a
+/// [Status::MethodNotAllowed] resource
+pub fn error_response(status: Status, allow: &[Method]) -> heapless::String<ERROR_RESPONSE_LEN> {
+    let mut body = heapless::String::<40>::new();
+    let _ = writeln!(body, "{} {}", status.code(), status.reason());
+    let mut response = Response::new(status)
+        .content_type("text/plain")
+        .content_length(body.len());
+    if !allow.is_empty() {
+        response = response.headers_with(|out| {
+            out.write_str("Allow: ")?;
+            for (i, method) in allow.iter().enumerate() {
+                out.write_str(if i == 0 { "" } else { ", " })?;
+                out.write_str(method.name())?;
+            }
+            out.write_str("\r\n")
+        });
+    }
+    // fits, see longest_error_response
+    let mut out = response.finish().unwrap_or_default();
+    let _ = out.push_str(&body);
+    out
+}
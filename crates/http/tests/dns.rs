@@ -0,0 +1,144 @@
+use http::dns::answer;
+
+const ADDRESS: [u8; 4] = [192, 168, 4, 1];
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+
+/// Standard query with recursion desired for `name`, `additional` is appended
+/// as one additional record
+fn query(name: &str, qtype: u16, additional: &[u8]) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query[11] = u8::from(!additional.is_empty());
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    query.extend_from_slice(additional);
+    query
+}
+
+/// EDNS OPT record as sent by most resolvers
+const OPT: [u8; 11] = [0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0];
+
+#[test]
+fn a_query_gets_the_address() {
+    let query = query("connectivitycheck.gstatic.com", TYPE_A, &OPT);
+    let question_end = query.len() - OPT.len();
+    let mut out = [0; 512];
+    let len = answer(&query, ADDRESS, &mut out).unwrap();
+    assert_eq!(len, question_end + 16);
+    assert_eq!(out[..2], query[..2]);
+    // response, authoritative, recursion desired and available, one answer and
+    // the additional record dropped
+    assert_eq!(out[2..12], [0x85, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(out[12..question_end], query[12..question_end]);
+    assert_eq!(
+        out[question_end..len],
+        [0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 4, 1]
+    );
+
+    let any = self::query("setup.local", TYPE_ANY, &[]);
+    let len = answer(&any, ADDRESS, &mut out).unwrap();
+    assert_eq!(len, any.len() + 16);
+    assert_eq!(out[len - 4..len], ADDRESS);
+}
+
+#[test]
+fn other_types_get_no_answer() {
+    for qtype in [TYPE_AAAA, 5, 15, 16, 33, 65] {
+        let query = query("example.com", qtype, &[]);
+        let mut out = [0xff; 512];
+        let len = answer(&query, ADDRESS, &mut out).unwrap();
+        assert_eq!(len, query.len());
+        assert_eq!(out[2..12], [0x85, 0x80, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(out[12..len], query[12..]);
+    }
+    // nor do other classes
+    let mut chaos = query("version.bind", TYPE_A, &[]);
+    let class = chaos.len() - 1;
+    chaos[class] = 3;
+    let mut out = [0; 512];
+    assert_eq!(answer(&chaos, ADDRESS, &mut out), Some(chaos.len()));
+    assert_eq!(out[6..8], [0, 0]);
+}
+
+#[test]
+fn recursion_desired_is_echoed() {
+    let mut query = query("a.b", TYPE_A, &[]);
+    query[2] = 0x00;
+    let mut out = [0; 512];
+    answer(&query, ADDRESS, &mut out).unwrap();
+    assert_eq!(out[2..4], [0x84, 0x80]);
+}
+
+#[test]
+fn truncated_questions_are_refused() {
+    let query = query("captive.apple.com", TYPE_A, &[]);
+    let mut out = [0; 512];
+    for len in 0..query.len() {
+        assert_eq!(answer(&query[..len], ADDRESS, &mut out), None, "{}", len);
+    }
+    // a label running past the end
+    let mut long_label = query.clone();
+    long_label[12] = 63;
+    assert_eq!(answer(&long_label, ADDRESS, &mut out), None);
+}
+
+#[test]
+fn compression_pointers_are_refused() {
+    let mut out = [0; 512];
+    let mut pointer = query("a.b", TYPE_A, &[]);
+    pointer.splice(12.., [0xc0, 12, 0, 1, 0, 1]);
+    assert_eq!(answer(&pointer, ADDRESS, &mut out), None);
+    // behind a label, and with only one of the two high bits set
+    for first in [0xc0, 0x80, 0x40] {
+        let mut query = query("a.b", TYPE_A, &[]);
+        query[14] = first;
+        assert_eq!(answer(&query, ADDRESS, &mut out), None, "{:#x}", first);
+    }
+}
+
+#[test]
+fn only_standard_queries_with_one_question() {
+    let mut out = [0; 512];
+    let mut response = query("a.b", TYPE_A, &[]);
+    response[2] |= 0x80;
+    assert_eq!(answer(&response, ADDRESS, &mut out), None);
+    for opcode in 1..16u8 {
+        let mut other = query("a.b", TYPE_A, &[]);
+        other[2] |= opcode << 3;
+        assert_eq!(answer(&other, ADDRESS, &mut out), None);
+    }
+    for count in [0u8, 2] {
+        let mut questions = query("a.b", TYPE_A, &[]);
+        questions[5] = count;
+        assert_eq!(answer(&questions, ADDRESS, &mut out), None);
+    }
+}
+
+#[test]
+fn short_out_is_refused() {
+    let query = query("a.b", TYPE_A, &[]);
+    let len = query.len() + 16;
+    let mut out = [0; 512];
+    for short in 0..len {
+        assert_eq!(
+            answer(&query, ADDRESS, &mut out[..short]),
+            None,
+            "{}",
+            short
+        );
+    }
+    assert_eq!(answer(&query, ADDRESS, &mut out[..len]), Some(len));
+
+    let empty = self::query("a.b", TYPE_AAAA, &[]);
+    assert_eq!(answer(&empty, ADDRESS, &mut out[..empty.len() - 1]), None);
+    assert_eq!(
+        answer(&empty, ADDRESS, &mut out[..empty.len()]),
+        Some(empty.len())
+    );
+}
@@ -0,0 +1,160 @@
+use http::json::{write_str, Object, SyntaxError, Value, MAX_MEMBERS};
+
+fn error_at(body: &str) -> usize {
+    match Object::parse(body.as_bytes(), &mut [0; 64]) {
+        Ok(_) => panic!("accepted {:?}", body),
+        Err(SyntaxError { offset }) => offset,
+    }
+}
+
+#[test]
+fn members() {
+    let body = br#" { "quality": 12, "hmirror": true, "vflip":false,
+        "effect" : "sepia", "gain": null, "level": -2 } "#;
+    let mut strings = [0; 64];
+    let object = Object::parse(body, &mut strings).unwrap();
+    assert_eq!(object.get("quality"), Some(Value::Int(12)));
+    assert_eq!(object.get("hmirror"), Some(Value::Bool(true)));
+    assert_eq!(object.get("vflip"), Some(Value::Bool(false)));
+    assert_eq!(object.get("effect"), Some(Value::Str("sepia")));
+    assert_eq!(object.get("gain"), Some(Value::Null));
+    assert_eq!(object.get("level"), Some(Value::Int(-2)));
+    assert_eq!(object.get("missing"), None);
+    let keys: Vec<_> = object.members().map(|(k, _)| k).collect();
+    assert_eq!(
+        keys,
+        ["quality", "hmirror", "vflip", "effect", "gain", "level"]
+    );
+
+    assert_eq!(
+        Object::parse(b"{}", &mut [0; 64])
+            .unwrap()
+            .members()
+            .count(),
+        0
+    );
+    // the last of a repeated member wins
+    let mut strings = [0; 64];
+    let object = Object::parse(br#"{"a":1,"a":2}"#, &mut strings).unwrap();
+    assert_eq!(object.get("a"), Some(Value::Int(2)));
+}
+
+#[test]
+fn integers() {
+    let mut strings = [0; 64];
+    let object = Object::parse(
+        br#"{"max":2147483647,"min":-2147483648,"zero":0}"#,
+        &mut strings,
+    )
+    .unwrap();
+    assert_eq!(object.get("max"), Some(Value::Int(i32::MAX)));
+    assert_eq!(object.get("min"), Some(Value::Int(i32::MIN)));
+    assert_eq!(object.get("zero"), Some(Value::Int(0)));
+
+    assert_eq!(error_at(r#"{"a":2147483648}"#), 5);
+    assert_eq!(error_at(r#"{"a":01}"#), 5);
+    assert_eq!(error_at(r#"{"a":-}"#), 6);
+    assert_eq!(error_at(r#"{"a":1.5}"#), 6);
+    assert_eq!(error_at(r#"{"a":1e3}"#), 6);
+}
+
+#[test]
+fn errors_point_at_the_offending_byte() {
+    assert_eq!(error_at(""), 0);
+    assert_eq!(error_at("[]"), 0);
+    assert_eq!(error_at(r#"{"a":1"#), 6);
+    assert_eq!(error_at(r#"{"a":1,}"#), 7);
+    assert_eq!(error_at(r#"{"a" 1}"#), 5);
+    assert_eq!(error_at(r#"{a:1}"#), 1);
+    assert_eq!(error_at(r#"{"a":1} x"#), 8);
+    assert_eq!(error_at(r#"{"a":{}}"#), 5);
+    assert_eq!(error_at(r#"{"a":[1]}"#), 5);
+    assert_eq!(error_at(r#"{"a":tru}"#), 5);
+    // control characters and bad escapes in strings
+    assert_eq!(error_at("{\"a\":\"x\ny\"}"), 7);
+    assert_eq!(error_at(r#"{"a":"x\qy"}"#), 7);
+    assert_eq!(error_at(r#"{"a":"x\u12g4"}"#), 7);
+    assert_eq!(error_at(r#"{"a":"x\u12"}"#), 7);
+    assert_eq!(error_at(r#"{"a":"x\"}"#), 10);
+    // surrogates only come in pairs
+    assert_eq!(error_at(r#"{"a":"\ud83d"}"#), 6);
+    assert_eq!(error_at(r#"{"a":"\ud83d\u0041"}"#), 6);
+    assert_eq!(error_at(r#"{"a":"\ude00"}"#), 6);
+    assert_eq!(error_at(r#"{"a":"open}"#), 11);
+    assert_eq!(
+        Object::parse(b"{\"a\":\"\xff\"}", &mut [0; 64]).err(),
+        Some(SyntaxError { offset: 6 })
+    );
+}
+
+#[test]
+fn member_limit() {
+    let members: Vec<_> = (0..MAX_MEMBERS)
+        .map(|i| format!("\"m{}\":{}", i, i))
+        .collect();
+    let body = format!("{{{}}}", members.join(","));
+    assert_eq!(
+        Object::parse(body.as_bytes(), &mut [0; 64])
+            .unwrap()
+            .members()
+            .count(),
+        MAX_MEMBERS
+    );
+    let over = format!("{{{},\"last\":1}}", members.join(","));
+    let offset = over.find("\"last\"").unwrap();
+    assert_eq!(error_at(&over), offset);
+}
+
+#[test]
+fn strings_are_escaped() {
+    let mut out = String::new();
+    write_str(&mut out, "say \"hi\"\\\n\u{1}é").unwrap();
+    assert_eq!(out, r#""say \"hi\"\\\u000a\u0001é""#);
+}
+
+#[test]
+fn escapes_are_decoded() {
+    let body = br#"{"ssid":"my \"home\"","password":"back\\slash\/\b\f\n\r\t","\u0070ass":"\u00e9\u20AC\ud83d\ude00"}"#;
+    let mut strings = [0; 64];
+    let object = Object::parse(body, &mut strings).unwrap();
+    assert_eq!(object.get("ssid"), Some(Value::Str("my \"home\"")));
+    assert_eq!(
+        object.get("password"),
+        Some(Value::Str("back\\slash/\u{8}\u{c}\n\r\t"))
+    );
+    assert_eq!(object.get("pass"), Some(Value::Str("é€😀")));
+}
+
+#[test]
+fn credentials_from_json_stringify() {
+    // what the setup page sends for awkward credentials
+    let body = br#"{"ssid":"Caf\u00e9 \"5G\"","password":"p\\a\"ss w\/ord"}"#;
+    let mut strings = [0; 64];
+    let object = Object::parse(body, &mut strings).unwrap();
+    assert_eq!(object.get("ssid"), Some(Value::Str("Café \"5G\"")));
+    assert_eq!(object.get("password"), Some(Value::Str("p\\a\"ss w/ord")));
+    // the written string reads back the same
+    let mut written = String::new();
+    write_str(&mut written, "p\\a\"ss\n").unwrap();
+    let body = format!("{{\"password\":{}}}", written);
+    assert_eq!(
+        Object::parse(body.as_bytes(), &mut [0; 64])
+            .unwrap()
+            .get("password"),
+        Some(Value::Str("p\\a\"ss\n"))
+    );
+}
+
+#[test]
+fn decoded_strings_must_fit() {
+    let body = br#"{"a":"plain is free","b":"\"12345678\""}"#;
+    let mut strings = [0; 10];
+    let object = Object::parse(body, &mut strings).unwrap();
+    assert_eq!(object.get("a"), Some(Value::Str("plain is free")));
+    assert_eq!(object.get("b"), Some(Value::Str("\"12345678\"")));
+    let mut strings = [0; 9];
+    assert_eq!(
+        Object::parse(body, &mut strings).err(),
+        Some(SyntaxError { offset: 25 })
+    );
+}
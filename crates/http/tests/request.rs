@@ -0,0 +1,201 @@
+use http::{Method, ParseError, Request, MAX_HEADERS};
+
+const CAPACITY: usize = 1024;
+
+fn parse(raw: &[u8]) -> Result<(Request<'_>, usize), ParseError> {
+    Request::parse(raw, CAPACITY)
+}
+
+fn rejected(raw: &[u8]) -> ParseError {
+    match parse(raw) {
+        Ok(_) => panic!("accepted {:?}", String::from_utf8_lossy(raw)),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn request_line() {
+    let raw = b"GET /stream?live=1&fps=5 HTTP/1.1\r\nHost: cam\r\n\r\n";
+    let (request, len) = parse(raw).unwrap();
+    assert_eq!(len, raw.len());
+    assert_eq!(request.method, Method::Get);
+    assert_eq!(request.path, "/stream");
+    assert_eq!(request.query, "live=1&fps=5");
+    assert_eq!(request.query_param("fps").as_deref(), Some("5"));
+    assert!(request.query_flag("live"));
+    assert!(!request.query_flag("fresh"));
+    assert!(request.body.is_empty());
+
+    for method in Method::ALL {
+        let raw = format!("{} / HTTP/1.0\r\n\r\n", method.name());
+        assert_eq!(parse(raw.as_bytes()).unwrap().0.method, method);
+    }
+}
+
+#[test]
+fn bad_request_lines() {
+    for raw in [
+        &b"GET  / HTTP/1.1\r\n\r\n"[..],
+        b"GET / HTTP/1.1 extra\r\n\r\n",
+        b"GET /\r\n\r\n",
+        b"get / HTTP/1.1\r\n\r\n",
+        b"PATCH / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/2\r\n\r\n",
+        b"GET http://cam/ HTTP/1.1\r\n\r\n",
+        b"GET * HTTP/1.1\r\n\r\n",
+        b"GET /a\x01b HTTP/1.1\r\n\r\n",
+        b"GET /\xff HTTP/1.1\r\n\r\n",
+        b"\r\n\r\n",
+    ] {
+        assert_eq!(rejected(raw), ParseError::BadRequest);
+    }
+}
+
+#[test]
+fn headers() {
+    let raw = b"GET / HTTP/1.1\r\nHost: cam\r\nX-Empty:\r\nAccept:  \t*/* \t\r\n\r\n";
+    let (request, _) = parse(raw).unwrap();
+    assert_eq!(request.header("host"), Some("cam"));
+    assert_eq!(request.header("ACCEPT"), Some("*/*"));
+    assert_eq!(request.header("x-empty"), Some(""));
+    assert_eq!(request.header("missing"), None);
+    assert_eq!(request.headers().len(), 3);
+
+    for raw in [
+        &b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"[..],
+        b"GET / HTTP/1.1\r\n: empty name\r\n\r\n",
+        b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: cam\r\n folded\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost : cam\r\n\r\n",
+    ] {
+        assert_eq!(rejected(raw), ParseError::BadRequest);
+    }
+}
+
+#[test]
+fn header_limits() {
+    let mut raw = String::from("GET / HTTP/1.1\r\n");
+    for i in 0..MAX_HEADERS {
+        raw += &format!("X-{}: {}\r\n", i, i);
+    }
+    let full = raw.clone() + "\r\n";
+    assert_eq!(
+        parse(full.as_bytes()).unwrap().0.headers().len(),
+        MAX_HEADERS
+    );
+    let over = raw + "X-Last: 1\r\n\r\n";
+    assert_eq!(rejected(over.as_bytes()), ParseError::TooLarge);
+
+    // a head that fills the buffer without ending
+    let long = format!("GET /{} HTTP/1.1\r\n", "a".repeat(CAPACITY));
+    assert_eq!(rejected(long.as_bytes()), ParseError::TooLarge);
+    assert_eq!(rejected(&long.as_bytes()[..100]), ParseError::Incomplete);
+}
+
+#[test]
+fn bodies() {
+    let raw = b"POST /api/motion HTTP/1.1\r\nContent-Length: 9\r\n\r\nenabled=1GET";
+    let (request, len) = parse(raw).unwrap();
+    assert_eq!(request.body, b"enabled=1");
+    assert_eq!(len, raw.len() - 3);
+
+    // the body is waited for, or refused if it can't fit
+    assert_eq!(
+        rejected(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\nenab"),
+        ParseError::Incomplete
+    );
+    let huge = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", CAPACITY);
+    assert_eq!(rejected(huge.as_bytes()), ParseError::TooLarge);
+    assert_eq!(ParseError::TooLarge.status().code(), 413);
+    assert_eq!(ParseError::BadRequest.status().code(), 400);
+}
+
+#[test]
+fn content_length_conflicts() {
+    // repeated with the same value is fine
+    let raw = b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nok";
+    assert_eq!(parse(raw).unwrap().0.body, b"ok");
+
+    for raw in [
+        &b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nok!"[..],
+        b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nok",
+        b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nok",
+        b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+        b"POST / HTTP/1.1\r\nContent-Length:\r\n\r\n",
+        b"POST / HTTP/1.1\r\nContent-Length: 0x2\r\n\r\nok",
+        b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+    ] {
+        assert_eq!(rejected(raw), ParseError::BadRequest);
+    }
+}
+
+#[test]
+fn transfer_encoding_is_rejected() {
+    for raw in [
+        &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n"[..],
+        b"POST / HTTP/1.1\r\ntransfer-encoding: identity\r\n\r\n",
+        b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\nok",
+    ] {
+        assert_eq!(rejected(raw), ParseError::BadRequest);
+    }
+}
+
+#[test]
+fn pipelined_requests() {
+    let raw = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+    let (first, len) = parse(raw).unwrap();
+    assert_eq!(first.path, "/a");
+    let (second, rest) = parse(&raw[len..]).unwrap();
+    assert_eq!(second.path, "/b");
+    assert_eq!(len + rest, raw.len());
+}
+
+#[test]
+fn query_strings() {
+    let param = |query, key| http::query_param(query, key).map(|v| v.as_str().to_owned());
+    assert_eq!(param("a=1&b=&c", "b").as_deref(), Some(""));
+    assert_eq!(param("a=1&b=&c", "c").as_deref(), Some(""));
+    assert_eq!(param("a=1&b=&c", "d"), None);
+    assert_eq!(param("a=1&a=2", "a").as_deref(), Some("1"));
+    assert!(http::query_flag("on&x=1", "on"));
+    for off in ["0", "false", "off"] {
+        assert!(!http::query_flag(&format!("flag={}", off), "flag"));
+    }
+}
+
+#[test]
+fn query_values_are_decoded() {
+    let param = |query, key| http::query_param(query, key).map(|v| v.as_str().to_owned());
+    assert_eq!(
+        param("name=front%2Ddoor", "name").as_deref(),
+        Some("front-door")
+    );
+    assert_eq!(
+        param("zones=00ff%2C0F0f", "zones").as_deref(),
+        Some("00ff,0F0f")
+    );
+    assert_eq!(param("q=a+b%20c%2B", "q").as_deref(), Some("a b c+"));
+    assert_eq!(param("caf%C3%A9=%E2%82%AC", "café").as_deref(), Some("€"));
+    assert!(http::query_flag("live=%31", "live"));
+    assert!(!http::query_flag("live=of%66", "live"));
+
+    // malformed escapes, invalid UTF-8 and overlong values count as missing
+    for bad in ["v=%", "v=%4", "v=%G1", "v=%4g", "v=%FF", "v=%C3"] {
+        assert_eq!(param(bad, "v"), None, "{}", bad);
+    }
+    let long = format!("v={}", "x".repeat(http::MAX_PARAM_LEN + 1));
+    assert_eq!(param(&long, "v"), None);
+    let fits = format!("v={}", "%41".repeat(http::MAX_PARAM_LEN));
+    assert_eq!(
+        param(&fits, "v").map(|v| v.len()),
+        Some(http::MAX_PARAM_LEN)
+    );
+}
+
+#[test]
+fn percent_decode_bounds() {
+    assert_eq!(http::percent_decode::<4>("abcd").as_deref(), Some("abcd"));
+    assert_eq!(http::percent_decode::<4>("abcde"), None);
+    assert_eq!(http::percent_decode::<1>("%7e").as_deref(), Some("~"));
+    assert_eq!(http::percent_decode::<0>("").as_deref(), Some(""));
+}
@@ -0,0 +1,58 @@
+use http::{error_response, HeadTooLarge, Method, Response, Status};
+
+#[test]
+fn head() {
+    let head = Response::<128>::new(Status::Ok)
+        .content_type("image/jpeg")
+        .content_length(1234)
+        .no_cache()
+        .headers_with(|out| write!(out, "X-Frame: {}\r\n", 7))
+        .finish()
+        .unwrap();
+    assert_eq!(
+        head.as_str(),
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: image/jpeg\r\n\
+         Content-Length: 1234\r\nCache-Control: no-cache\r\nX-Frame: 7\r\n\r\n"
+    );
+}
+
+#[test]
+fn overflow_is_an_error() {
+    let fits = Response::<64>::new(Status::Found)
+        .header("Location", "/")
+        .finish()
+        .unwrap();
+    assert!(fits.ends_with("\r\n\r\n"));
+    assert_eq!(
+        Response::<64>::new(Status::Found)
+            .header("Location", "http://192.168.4.1/a/long/path")
+            .finish(),
+        Err(HeadTooLarge)
+    );
+    // only the blank line doesn't fit
+    assert_eq!(fits.len(), 54);
+    assert_eq!(
+        Response::<53>::new(Status::Found)
+            .header("Location", "/")
+            .finish(),
+        Err(HeadTooLarge)
+    );
+    // nor does the status line
+    assert_eq!(Response::<8>::new(Status::Ok).finish(), Err(HeadTooLarge));
+}
+
+#[test]
+fn error_responses() {
+    for status in Status::ALL {
+        for allow in [&[][..], &Method::ALL] {
+            let response = error_response(status, allow);
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert_eq!(body, format!("{} {}\n", status.code(), status.reason()));
+            assert!(head.starts_with(&format!("HTTP/1.1 {} ", status.code())));
+            assert!(head.contains(&format!("\r\nContent-Length: {}", body.len())));
+            assert_eq!(head.contains("\r\nAllow: "), !allow.is_empty());
+        }
+    }
+    let response = error_response(Status::MethodNotAllowed, &[Method::Get, Method::Post]);
+    assert!(response.contains("\r\nAllow: GET, POST\r\n"));
+}
@@ -0,0 +1,242 @@
+//!
+//! Streaming AVI (MJPEG) writer
+//!
+//! The file is produced front to back without seeking, so every size in the
+//! headers has to be known up front. [AviInfo] collects the frame sizes in a
+//! first pass, [AviWriter] then hands out the header, the chunk headers and the
+//! `idx1` index in file order. Writers that can seek, like a file on an SD
+//! card, write a provisional header first and rewrite it once the frames are
+//! in.
+//!
+
+/// Length of everything in front of the first frame chunk
+pub const HEADER_LEN: usize = 12 + HDRL_LEN + 12;
+/// Length of an index entry
+pub const INDEX_ENTRY_LEN: usize = 16;
+
+const HDRL_LEN: usize = 8 + 4 + (8 + 56) + STRL_LEN;
+const STRL_LEN: usize = 8 + 4 + (8 + 56) + (8 + 40);
+const CHUNK_ID: &[u8; 4] = b"00dc";
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Stream parameters and frame totals
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AviInfo {
+    pub width: u16,
+    pub height: u16,
+    /// Playback duration of a frame
+    pub micros_per_frame: u32,
+    pub frames: u32,
+    /// Sum of the JPEG sizes
+    pub data_len: u32,
+    /// Number of odd sized frames, their chunks carry a pad byte
+    pub odd_frames: u32,
+    pub max_frame_len: u32,
+}
+
+impl AviInfo {
+    pub fn new(width: u16, height: u16, micros_per_frame: u32) -> Self {
+        Self {
+            width,
+            height,
+            micros_per_frame: micros_per_frame.max(1),
+            ..Default::default()
+        }
+    }
+
+    /// Account for a frame of `len` bytes
+    pub fn add_frame(&mut self, len: usize) {
+        self.frames += 1;
+        self.data_len += len as u32;
+        self.odd_frames += (len & 1) as u32;
+        self.max_frame_len = self.max_frame_len.max(len as u32);
+    }
+
+    /// Frame duration for `frames` frames spread over `micros`
+    pub fn frame_duration(frames: u32, micros: u64) -> u32 {
+        match frames {
+            0 | 1 => 1_000_000,
+            n => (micros / (n as u64 - 1)).clamp(1, u32::MAX as u64) as u32,
+        }
+    }
+
+    /// Size of the `movi` list content, including the `movi` FourCC
+    fn movi_len(&self) -> u32 {
+        4 + self.frames * 8 + self.data_len + self.odd_frames
+    }
+
+    fn index_len(&self) -> u32 {
+        self.frames * INDEX_ENTRY_LEN as u32
+    }
+
+    /// Size of the whole file
+    pub fn file_len(&self) -> usize {
+        HEADER_LEN - 4 + self.movi_len() as usize + 8 + self.index_len() as usize
+    }
+}
+
+/// Produces the pieces of an AVI file in order
+pub struct AviWriter {
+    info: AviInfo,
+}
+
+impl AviWriter {
+    pub fn new(info: AviInfo) -> Self {
+        Self { info }
+    }
+
+    pub fn info(&self) -> &AviInfo {
+        &self.info
+    }
+
+    /// RIFF header, `hdrl` list and the start of the `movi` list
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let info = &self.info;
+        let mut out = Out::<HEADER_LEN>::new();
+        out.fourcc(b"RIFF");
+        out.u32(info.file_len() as u32 - 8);
+        out.fourcc(b"AVI ");
+
+        out.fourcc(b"LIST");
+        out.u32(HDRL_LEN as u32 - 8);
+        out.fourcc(b"hdrl");
+        out.fourcc(b"avih");
+        out.u32(56);
+        out.u32(info.micros_per_frame);
+        let bytes_per_sec = info.max_frame_len as u64 * 1_000_000 / info.micros_per_frame as u64;
+        out.u32(bytes_per_sec.min(u32::MAX as u64) as u32);
+        out.u32(0); // padding granularity
+        out.u32(AVIF_HASINDEX);
+        out.u32(info.frames);
+        out.u32(0); // initial frames
+        out.u32(1); // streams
+        out.u32(info.max_frame_len + 8);
+        out.u32(info.width as u32);
+        out.u32(info.height as u32);
+        out.zeros(16);
+
+        out.fourcc(b"LIST");
+        out.u32(STRL_LEN as u32 - 8);
+        out.fourcc(b"strl");
+        out.fourcc(b"strh");
+        out.u32(56);
+        out.fourcc(b"vids");
+        out.fourcc(b"MJPG");
+        out.u32(0); // flags
+        out.u32(0); // priority and language
+        out.u32(0); // initial frames
+        out.u32(info.micros_per_frame); // scale
+        out.u32(1_000_000); // rate, frames per second = rate / scale
+        out.u32(0); // start
+        out.u32(info.frames);
+        out.u32(info.max_frame_len + 8);
+        out.u32(u32::MAX); // default quality
+        out.u32(0); // sample size, varies
+        out.u16(0);
+        out.u16(0);
+        out.u16(info.width);
+        out.u16(info.height);
+        out.fourcc(b"strf");
+        out.u32(40);
+        out.u32(40);
+        out.u32(info.width as u32);
+        out.u32(info.height as u32);
+        out.u16(1); // planes
+        out.u16(24); // bit count
+        out.fourcc(b"MJPG");
+        out.u32(info.width as u32 * info.height as u32 * 3);
+        out.zeros(16);
+
+        out.fourcc(b"LIST");
+        out.u32(info.movi_len());
+        out.fourcc(b"movi");
+        out.finish()
+    }
+
+    /// Chunk header in front of the next frame of `len` bytes
+    pub fn frame_header(len: usize) -> [u8; 8] {
+        chunk(CHUNK_ID, len as u32)
+    }
+
+    /// Bytes that go behind a frame of `len` bytes
+    pub fn frame_padding(len: usize) -> &'static [u8] {
+        &[0][..len & 1]
+    }
+
+    /// Header of the `idx1` chunk, goes behind the last frame
+    pub fn index_header(&self) -> [u8; 8] {
+        chunk(b"idx1", self.info.index_len())
+    }
+
+    /// Walks the frames again and produces their index entries
+    pub fn index(&self) -> IndexWriter {
+        IndexWriter { offset: 4 }
+    }
+}
+
+/// Index entries for the frames, in the same order as they were written
+pub struct IndexWriter {
+    /// Offset of the next chunk, relative to the `movi` FourCC
+    offset: u32,
+}
+
+impl IndexWriter {
+    pub fn entry(&mut self, len: usize) -> [u8; INDEX_ENTRY_LEN] {
+        let mut out = Out::<INDEX_ENTRY_LEN>::new();
+        out.fourcc(CHUNK_ID);
+        out.u32(AVIIF_KEYFRAME);
+        out.u32(self.offset);
+        out.u32(len as u32);
+        self.offset += 8 + len as u32 + (len & 1) as u32;
+        out.finish()
+    }
+}
+
+fn chunk(id: &[u8; 4], len: u32) -> [u8; 8] {
+    let mut out = Out::<8>::new();
+    out.fourcc(id);
+    out.u32(len);
+    out.finish()
+}
+
+/// Little endian writer into a fixed size array
+struct Out<const N: usize> {
+    buf: [u8; N],
+    pos: usize,
+}
+
+impl<const N: usize> Out<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            pos: 0,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn fourcc(&mut self, fourcc: &[u8; 4]) {
+        self.bytes(fourcc);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn zeros(&mut self, len: usize) {
+        self.pos += len;
+    }
+
+    fn finish(self) -> [u8; N] {
+        debug_assert_eq!(self.pos, N);
+        self.buf
+    }
+}
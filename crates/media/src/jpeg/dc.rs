@@ -0,0 +1,466 @@
+//!
+//! DC-only decoding of baseline JPEGs
+//!
+//! The DC coefficient of every 8x8 block is its average value. Decoding just
+//! the DC terms of the luma component gives a 1/8 scale grayscale image for the
+//! price of the Huffman decoding, without any IDCT.
+//!
+
+use super::{is_sof, EOI, RST0, RST7, SOI, SOS};
+
+const DHT: u8 = 0xC4;
+const DQT: u8 = 0xDB;
+const DRI: u8 = 0xDD;
+const SOF0: u8 = 0xC0;
+const SOF1: u8 = 0xC1;
+
+/// Why a JPEG could not be decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DecodeError {
+    /// Progressive, lossless, arithmetic coded or 12 bit JPEGs
+    Unsupported,
+    /// Broken headers or entropy coded data
+    Invalid,
+    /// The data ends before the scan is complete
+    Truncated,
+    /// The output buffer can't hold the luma map
+    BufferTooSmall,
+}
+
+/// Size of a decoded luma map, one pixel per 8x8 block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct LumaSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl LumaSize {
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Component {
+    id: u8,
+    h: u8,
+    v: u8,
+    tq: u8,
+}
+
+struct Huffman {
+    maxcode: [i32; 17],
+    mincode: [i32; 17],
+    valptr: [u16; 17],
+    vals: [u8; 256],
+    /// `len << 8 | value` for codes up to 8 bits, indexed by the next 8 bits
+    lookup: [u16; 256],
+}
+
+impl Huffman {
+    const EMPTY: Huffman = Huffman {
+        maxcode: [-1; 17],
+        mincode: [0; 17],
+        valptr: [0; 17],
+        vals: [0; 256],
+        lookup: [0; 256],
+    };
+
+    /// Build a table from the DHT counts and symbols
+    fn build(&mut self, counts: &[u8], symbols: &[u8]) -> Result<(), DecodeError> {
+        *self = Self::EMPTY;
+        self.vals[..symbols.len()].copy_from_slice(symbols);
+        let mut code: i32 = 0;
+        let mut k = 0usize;
+        for len in 1..=16 {
+            let count = counts[len - 1] as usize;
+            self.valptr[len] = k as u16;
+            self.mincode[len] = code;
+            for _ in 0..count {
+                if code >= 1 << len {
+                    return Err(DecodeError::Invalid);
+                }
+                if len <= 8 {
+                    let prefix = (code as usize) << (8 - len);
+                    let entry = (len as u16) << 8 | symbols[k] as u16;
+                    self.lookup[prefix..prefix + (1 << (8 - len))].fill(entry);
+                }
+                code += 1;
+                k += 1;
+            }
+            self.maxcode[len] = if count > 0 { code - 1 } else { -1 };
+            code <<= 1;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u8, DecodeError> {
+        let entry = self.lookup[bits.peek8()];
+        if entry != 0 {
+            bits.consume((entry >> 8) as u32);
+            return Ok(entry as u8);
+        }
+        let mut code: i32 = 0;
+        for len in 1..=16 {
+            code = (code << 1) | bits.take(1)? as i32;
+            if code <= self.maxcode[len] {
+                let index = self.valptr[len] as i32 + code - self.mincode[len];
+                return Ok(self.vals[index as usize]);
+            }
+        }
+        Err(DecodeError::Invalid)
+    }
+}
+
+/// Bit reader over entropy coded data, removes byte stuffing and stops at
+/// markers
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    count: u32,
+    /// Zero bytes fed in after a marker or the end of data
+    padding: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self {
+            data,
+            pos,
+            acc: 0,
+            count: 0,
+            padding: 0,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.count <= 24 {
+            let mut byte = 0;
+            match self.data.get(self.pos..self.pos + 2) {
+                Some([0xFF, 0x00]) => {
+                    byte = 0xFF;
+                    self.pos += 2;
+                }
+                Some([0xFF, _]) => self.padding += 1,
+                _ => match self.data.get(self.pos) {
+                    Some(&b) if b != 0xFF => {
+                        byte = b;
+                        self.pos += 1;
+                    }
+                    _ => self.padding += 1,
+                },
+            }
+            self.acc |= (byte as u32) << (24 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn peek8(&mut self) -> usize {
+        self.fill();
+        (self.acc >> 24) as usize
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.acc <<= n;
+        self.count -= n;
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32, DecodeError> {
+        if n == 0 {
+            return Ok(0);
+        }
+        self.fill();
+        // allow a few bytes of padding for the last block before EOI
+        if self.padding > 4 {
+            return Err(DecodeError::Truncated);
+        }
+        let value = self.acc >> (32 - n);
+        self.consume(n);
+        Ok(value)
+    }
+
+    /// Skip to the next restart marker and start over behind it
+    fn restart(&mut self) -> Result<(), DecodeError> {
+        self.acc = 0;
+        self.count = 0;
+        self.padding = 0;
+        match self.data.get(self.pos..self.pos + 2) {
+            Some(&[0xFF, m]) if (RST0..=RST7).contains(&m) => {
+                self.pos += 2;
+                Ok(())
+            }
+            Some(_) => Err(DecodeError::Invalid),
+            None => Err(DecodeError::Truncated),
+        }
+    }
+}
+
+/// Sign extend a `size` bit coefficient
+fn extend(value: u32, size: u32) -> i32 {
+    if value < 1 << (size - 1) {
+        value as i32 - (1 << size) + 1
+    } else {
+        value as i32
+    }
+}
+
+/// Decode the luma DC coefficients of `jpeg` into `out`
+///
+/// Every output byte is the average luma of one 8x8 block, row by row.
+pub fn decode_dc_luma(jpeg: &[u8], out: &mut [u8]) -> Result<LumaSize, DecodeError> {
+    if jpeg.get(..2) != Some(&[0xFF, SOI]) {
+        return Err(DecodeError::Invalid);
+    }
+    let mut dc_tables = [Huffman::EMPTY, Huffman::EMPTY];
+    let mut ac_tables = [Huffman::EMPTY, Huffman::EMPTY];
+    let mut quant_dc = [1u16; 4];
+    let mut components = [Component::default(); 4];
+    let mut component_count = 0;
+    let (mut width, mut height) = (0usize, 0usize);
+    let mut restart_interval = 0usize;
+
+    let mut pos = 2;
+    loop {
+        // skip fill bytes in front of the marker
+        while jpeg.get(pos + 1) == Some(&0xFF) && jpeg[pos] == 0xFF {
+            pos += 1;
+        }
+        let (marker, len) = match jpeg.get(pos..pos + 4) {
+            Some(&[0xFF, marker, hi, lo]) => (marker, u16::from_be_bytes([hi, lo]) as usize),
+            Some(_) => return Err(DecodeError::Invalid),
+            None => return Err(DecodeError::Truncated),
+        };
+        if marker == EOI || len < 2 {
+            return Err(DecodeError::Invalid);
+        }
+        let body = jpeg
+            .get(pos + 4..pos + 2 + len)
+            .ok_or(DecodeError::Truncated)?;
+        pos += 2 + len;
+        match marker {
+            SOF0 | SOF1 => {
+                if body.len() < 6 || body[0] != 8 {
+                    return Err(DecodeError::Unsupported);
+                }
+                height = u16::from_be_bytes([body[1], body[2]]) as usize;
+                width = u16::from_be_bytes([body[3], body[4]]) as usize;
+                component_count = body[5] as usize;
+                if component_count == 0
+                    || component_count > 4
+                    || body.len() < 6 + 3 * component_count
+                {
+                    return Err(DecodeError::Invalid);
+                }
+                for (c, spec) in components.iter_mut().zip(body[6..].chunks(3)) {
+                    *c = Component {
+                        id: spec[0],
+                        h: spec[1] >> 4,
+                        v: spec[1] & 0x0F,
+                        tq: spec[2] & 0x03,
+                    };
+                    if !(1..=4).contains(&c.h) || !(1..=4).contains(&c.v) {
+                        return Err(DecodeError::Invalid);
+                    }
+                }
+            }
+            m if is_sof(m) => return Err(DecodeError::Unsupported),
+            DHT => {
+                let mut rest = body;
+                while !rest.is_empty() {
+                    if rest.len() < 17 {
+                        return Err(DecodeError::Invalid);
+                    }
+                    let (class, id) = (rest[0] >> 4, (rest[0] & 0x0F) as usize);
+                    let counts = &rest[1..17];
+                    let n: usize = counts.iter().map(|&c| c as usize).sum();
+                    let symbols = rest.get(17..17 + n).ok_or(DecodeError::Invalid)?;
+                    let table = match class {
+                        0 => dc_tables.get_mut(id),
+                        1 => ac_tables.get_mut(id),
+                        _ => None,
+                    };
+                    table
+                        .ok_or(DecodeError::Unsupported)?
+                        .build(counts, symbols)?;
+                    rest = &rest[17 + n..];
+                }
+            }
+            DQT => {
+                let mut rest = body;
+                while !rest.is_empty() {
+                    let (precision, id) = (rest[0] >> 4, (rest[0] & 0x03) as usize);
+                    let size = if precision == 0 { 64 } else { 128 };
+                    let table = rest.get(1..1 + size).ok_or(DecodeError::Invalid)?;
+                    quant_dc[id] = if precision == 0 {
+                        table[0] as u16
+                    } else {
+                        u16::from_be_bytes([table[0], table[1]])
+                    };
+                    rest = &rest[1 + size..];
+                }
+            }
+            DRI => {
+                if body.len() < 2 {
+                    return Err(DecodeError::Invalid);
+                }
+                restart_interval = u16::from_be_bytes([body[0], body[1]]) as usize;
+            }
+            SOS => {
+                if component_count == 0 {
+                    return Err(DecodeError::Invalid);
+                }
+                let comps = &components[..component_count];
+                let scan = Scan::parse(body, comps)?;
+                return scan.decode(
+                    jpeg,
+                    pos,
+                    comps,
+                    (width, height),
+                    restart_interval,
+                    quant_dc[comps[0].tq as usize],
+                    &dc_tables,
+                    &ac_tables,
+                    out,
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Scan {
+    /// (index into the frame components, DC table, AC table)
+    components: [(usize, usize, usize); 4],
+    count: usize,
+}
+
+impl Scan {
+    fn parse(body: &[u8], frame: &[Component]) -> Result<Self, DecodeError> {
+        let count = *body.first().ok_or(DecodeError::Invalid)? as usize;
+        if count == 0 || count > frame.len() || body.len() < 1 + 2 * count + 3 {
+            return Err(DecodeError::Invalid);
+        }
+        let mut components = [(0, 0, 0); 4];
+        for (slot, spec) in components.iter_mut().zip(body[1..].chunks(2).take(count)) {
+            let index = frame
+                .iter()
+                .position(|c| c.id == spec[0])
+                .ok_or(DecodeError::Invalid)?;
+            let (td, ta) = ((spec[1] >> 4) as usize, (spec[1] & 0x0F) as usize);
+            if td > 1 || ta > 1 {
+                return Err(DecodeError::Unsupported);
+            }
+            *slot = (index, td, ta);
+        }
+        // the luma has to be part of the first scan
+        if components[0].0 != 0 {
+            return Err(DecodeError::Unsupported);
+        }
+        Ok(Self { components, count })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn decode(
+        &self,
+        jpeg: &[u8],
+        pos: usize,
+        frame: &[Component],
+        (width, height): (usize, usize),
+        restart_interval: usize,
+        quant: u16,
+        dc_tables: &[Huffman; 2],
+        ac_tables: &[Huffman; 2],
+        out: &mut [u8],
+    ) -> Result<LumaSize, DecodeError> {
+        let h_max = frame.iter().map(|c| c.h as usize).max().unwrap_or(1);
+        let v_max = frame.iter().map(|c| c.v as usize).max().unwrap_or(1);
+        let luma = frame[0];
+        let (h, v) = (luma.h as usize, luma.v as usize);
+        let size = LumaSize {
+            width: (width * h).div_ceil(h_max).div_ceil(8),
+            height: (height * v).div_ceil(v_max).div_ceil(8),
+        };
+        if out.len() < size.len() {
+            return Err(DecodeError::BufferTooSmall);
+        }
+
+        // a single component scan isn't interleaved and has 1x1 block MCUs
+        let (mcu_cols, mcu_rows, blocks) = if self.count == 1 {
+            (size.width, size.height, (1, 1))
+        } else {
+            (
+                width.div_ceil(8 * h_max),
+                height.div_ceil(8 * v_max),
+                (h, v),
+            )
+        };
+
+        let mut bits = Bits::new(jpeg, pos);
+        let mut pred = [0i32; 4];
+        let mut store = |bx: usize, by: usize, dc: i32| {
+            if bx < size.width && by < size.height {
+                let value = dc * quant as i32 / 8 + 128;
+                out[by * size.width + bx] = value.clamp(0, 255) as u8;
+            }
+        };
+        for mcu in 0..mcu_cols * mcu_rows {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                bits.restart()?;
+                pred = [0; 4];
+            }
+            let (mx, my) = (mcu % mcu_cols, mcu / mcu_cols);
+            for (n, &(index, td, ta)) in self.components[..self.count].iter().enumerate() {
+                let (ch, cv) = if self.count == 1 {
+                    (1, 1)
+                } else {
+                    (frame[index].h as usize, frame[index].v as usize)
+                };
+                for by in 0..cv {
+                    for bx in 0..ch {
+                        let dc = decode_block(&mut bits, &dc_tables[td], &ac_tables[ta])?;
+                        pred[n] += dc;
+                        if index == 0 {
+                            store(mx * blocks.0 + bx, my * blocks.1 + by, pred[n]);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// Decode one block and return its DC difference, the AC terms are skipped
+fn decode_block(bits: &mut Bits, dc: &Huffman, ac: &Huffman) -> Result<i32, DecodeError> {
+    let size = dc.decode(bits)? as u32;
+    if size > 11 {
+        return Err(DecodeError::Invalid);
+    }
+    let diff = match size {
+        0 => 0,
+        _ => extend(bits.take(size)?, size),
+    };
+    let mut k = 1;
+    while k < 64 {
+        let rs = ac.decode(bits)?;
+        let (run, size) = ((rs >> 4) as usize, (rs & 0x0F) as u32);
+        if size == 0 {
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        bits.take(size)?;
+        k += run + 1;
+    }
+    if k > 64 {
+        return Err(DecodeError::Invalid);
+    }
+    Ok(diff)
+}
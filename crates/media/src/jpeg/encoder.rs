@@ -0,0 +1,582 @@
+//!
+//! Baseline JPEG encoder for raw frames
+//!
+//! Sequential DCT with the standard Huffman tables of ITU T.81 Annex K and the
+//! Annex K quantization tables scaled like libjpeg. The forward DCT is the AAN
+//! float algorithm, the ESP32-S3 has a single precision FPU and the output
+//! scale is folded into the quantization step.
+//!
+//! The encoder needs no memory besides its tables and the output buffer. A
+//! [Scan] encodes one row of MCUs per call so a caller can yield in between.
+//!
+
+use super::{EOI, SOI, SOS};
+use crate::raw::{RawFormat, RawImage};
+
+const APP0: u8 = 0xE0;
+const DQT: u8 = 0xDB;
+const SOF0: u8 = 0xC0;
+const DHT: u8 = 0xC4;
+
+/// Natural order index of each zigzag position
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Annex K.1, natural order
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// Annex K.2, natural order
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Output scale of the AAN DCT for each frequency
+const AAN_SCALE: [f32; 8] = [
+    1.0,
+    1.387_039_8,
+    1.306_563,
+    1.175_875_6,
+    1.0,
+    0.785_694_96,
+    0.541_196_1,
+    0.275_899_38,
+];
+
+// Annex K.3, code counts per length 1..16 and the symbols in code order
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+static LUMA_DC: Huffman = Huffman::build(&LUMA_DC_BITS, &DC_VALUES);
+static CHROMA_DC: Huffman = Huffman::build(&CHROMA_DC_BITS, &DC_VALUES);
+static LUMA_AC: Huffman = Huffman::build(&LUMA_AC_BITS, &LUMA_AC_VALUES);
+static CHROMA_AC: Huffman = Huffman::build(&CHROMA_AC_BITS, &CHROMA_AC_VALUES);
+
+/// Code and length of every symbol
+struct Huffman {
+    code: [u16; 256],
+    size: [u8; 256],
+}
+
+impl Huffman {
+    /// Canonical codes, Annex C
+    const fn build(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut table = Huffman {
+            code: [0; 256],
+            size: [0; 256],
+        };
+        let mut code: u32 = 0;
+        let mut k = 0;
+        let mut len = 0;
+        while len < 16 {
+            let mut i = 0;
+            while i < bits[len] {
+                let symbol = values[k] as usize;
+                table.code[symbol] = code as u16;
+                table.size[symbol] = len as u8 + 1;
+                code += 1;
+                k += 1;
+                i += 1;
+            }
+            code <<= 1;
+            len += 1;
+        }
+        table
+    }
+}
+
+/// Chroma resolution relative to luma
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Subsampling {
+    /// Full chroma resolution
+    S444,
+    /// Half horizontally
+    S422,
+    /// Half in both directions
+    S420,
+}
+
+impl Subsampling {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "444" => Some(Subsampling::S444),
+            "422" => Some(Subsampling::S422),
+            "420" => Some(Subsampling::S420),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsampling::S444 => "444",
+            Subsampling::S422 => "422",
+            Subsampling::S420 => "420",
+        }
+    }
+
+    /// Luma blocks per MCU horizontally and vertically
+    fn factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::S444 => (1, 1),
+            Subsampling::S422 => (2, 1),
+            Subsampling::S420 => (2, 2),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct EncoderConfig {
+    /// 1 (smallest) to 100 (best), as libjpeg counts
+    pub quality: u8,
+    /// Ignored for gray frames
+    pub subsampling: Subsampling,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            quality: 80,
+            subsampling: Subsampling::S420,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum EncodeError {
+    /// The output buffer is full
+    BufferTooSmall,
+}
+
+/// Quantization for one quality setting
+pub struct Encoder {
+    config: EncoderConfig,
+    /// Written to DQT, natural order
+    luma_quant: [u8; 64],
+    chroma_quant: [u8; 64],
+    /// Reciprocals of the quantization steps with the DCT scale folded in
+    luma_divisors: [f32; 64],
+    chroma_divisors: [f32; 64],
+}
+
+impl Encoder {
+    pub fn new(config: EncoderConfig) -> Self {
+        let quality = config.quality.clamp(1, 100) as u32;
+        let scale = match quality {
+            q if q < 50 => 5000 / q,
+            q => 200 - 2 * q,
+        };
+        let quant =
+            |table: &[u8; 64]| table.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u8);
+        let divisors = |table: &[u8; 64]| {
+            let mut out = [0.0; 64];
+            for (i, d) in out.iter_mut().enumerate() {
+                *d = 1.0 / (table[i] as f32 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8] * 8.0);
+            }
+            out
+        };
+        let luma_quant = quant(&LUMA_QUANT);
+        let chroma_quant = quant(&CHROMA_QUANT);
+        Self {
+            config,
+            luma_divisors: divisors(&luma_quant),
+            chroma_divisors: divisors(&chroma_quant),
+            luma_quant,
+            chroma_quant,
+        }
+    }
+
+    pub fn config(&self) -> EncoderConfig {
+        self.config
+    }
+
+    /// Encode `image` into `out`, returns the length of the JPEG
+    pub fn encode(&self, image: &RawImage, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let mut scan = self.start(image, out)?;
+        while scan.encode_row()? {}
+        scan.finish()
+    }
+
+    /// Write the headers, the entropy coded data follows with [Scan::encode_row]
+    pub fn start<'a>(
+        &'a self,
+        image: &RawImage<'a>,
+        out: &'a mut [u8],
+    ) -> Result<Scan<'a>, EncodeError> {
+        let gray = image.format() == RawFormat::Gray8;
+        let (h, v) = match gray {
+            true => (1, 1),
+            false => self.config.subsampling.factors(),
+        };
+        let mut writer = BitWriter::new(out);
+        self.write_headers(&mut writer, image, gray, (h, v));
+        if writer.overflow {
+            return Err(EncodeError::BufferTooSmall);
+        }
+        Ok(Scan {
+            encoder: self,
+            image: *image,
+            writer,
+            gray,
+            factors: (h, v),
+            row: 0,
+            rows: (image.height() as usize).div_ceil(8 * v),
+            predictors: [0; 3],
+        })
+    }
+
+    fn write_headers(
+        &self,
+        w: &mut BitWriter,
+        image: &RawImage,
+        gray: bool,
+        (h, v): (usize, usize),
+    ) {
+        w.bytes(&[0xFF, SOI]);
+        w.bytes(&[0xFF, APP0, 0, 16]);
+        w.bytes(b"JFIF\0");
+        // version 1.1, aspect ratio 1:1, no thumbnail
+        w.bytes(&[1, 1, 0, 0, 1, 0, 1, 0, 0]);
+
+        let tables: &[(u8, &[u8; 64])] = match gray {
+            true => &[(0, &self.luma_quant)],
+            false => &[(0, &self.luma_quant), (1, &self.chroma_quant)],
+        };
+        w.bytes(&[0xFF, DQT]);
+        w.bytes(&(2 + 65 * tables.len() as u16).to_be_bytes());
+        for (id, table) in tables {
+            w.bytes(&[*id]);
+            for i in ZIGZAG {
+                w.bytes(&[table[i]]);
+            }
+        }
+
+        let components: u8 = if gray { 1 } else { 3 };
+        w.bytes(&[0xFF, SOF0]);
+        w.bytes(&(8 + 3 * components as u16).to_be_bytes());
+        w.bytes(&[8]);
+        w.bytes(&image.height().to_be_bytes());
+        w.bytes(&image.width().to_be_bytes());
+        w.bytes(&[components, 1, (h << 4 | v) as u8, 0]);
+        if !gray {
+            w.bytes(&[2, 0x11, 1, 3, 0x11, 1]);
+        }
+
+        let huffman: &[(u8, &[u8; 16], &[u8])] = match gray {
+            true => &[
+                (0x00, &LUMA_DC_BITS, &DC_VALUES),
+                (0x10, &LUMA_AC_BITS, &LUMA_AC_VALUES),
+            ],
+            false => &[
+                (0x00, &LUMA_DC_BITS, &DC_VALUES),
+                (0x10, &LUMA_AC_BITS, &LUMA_AC_VALUES),
+                (0x01, &CHROMA_DC_BITS, &DC_VALUES),
+                (0x11, &CHROMA_AC_BITS, &CHROMA_AC_VALUES),
+            ],
+        };
+        let len: usize = huffman.iter().map(|(_, _, values)| 17 + values.len()).sum();
+        w.bytes(&[0xFF, DHT]);
+        w.bytes(&(2 + len as u16).to_be_bytes());
+        for (class_id, bits, values) in huffman {
+            w.bytes(&[*class_id]);
+            w.bytes(*bits);
+            w.bytes(values);
+        }
+
+        w.bytes(&[0xFF, SOS]);
+        w.bytes(&(6 + 2 * components as u16).to_be_bytes());
+        w.bytes(&[components, 1, 0x00]);
+        if !gray {
+            w.bytes(&[2, 0x11, 3, 0x11]);
+        }
+        // full spectral range, no successive approximation
+        w.bytes(&[0, 63, 0]);
+    }
+}
+
+/// An image being encoded
+pub struct Scan<'a> {
+    encoder: &'a Encoder,
+    image: RawImage<'a>,
+    writer: BitWriter<'a>,
+    gray: bool,
+    factors: (usize, usize),
+    row: usize,
+    rows: usize,
+    /// Last DC value of each component
+    predictors: [i32; 3],
+}
+
+impl Scan<'_> {
+    /// Encode the next row of MCUs, returns whether rows are left
+    pub fn encode_row(&mut self) -> Result<bool, EncodeError> {
+        if self.row >= self.rows {
+            return Ok(false);
+        }
+        let (h, v) = self.factors;
+        let (mcu_width, mcu_height) = (8 * h, 8 * v);
+        let width = self.image.width() as usize;
+        let height = self.image.height() as usize;
+        let y0 = self.row * mcu_height;
+        let mut pixels = [[0u8; 3]; 256];
+        for x0 in (0..width).step_by(mcu_width) {
+            // edge MCUs repeat the last column and row
+            for y in 0..mcu_height {
+                for x in 0..mcu_width {
+                    pixels[y * mcu_width + x] = self
+                        .image
+                        .ycbcr((x0 + x).min(width - 1), (y0 + y).min(height - 1));
+                }
+            }
+            for by in 0..v {
+                for bx in 0..h {
+                    let mut block = [0.0f32; 64];
+                    for (i, sample) in block.iter_mut().enumerate() {
+                        let (y, x) = (by * 8 + i / 8, bx * 8 + i % 8);
+                        *sample = pixels[y * mcu_width + x][0] as f32 - 128.0;
+                    }
+                    self.block(&mut block, 0);
+                }
+            }
+            if self.gray {
+                continue;
+            }
+            for component in [1, 2] {
+                let mut block = [0.0f32; 64];
+                for (i, sample) in block.iter_mut().enumerate() {
+                    let (y, x) = (i / 8 * v, i % 8 * h);
+                    let mut sum = 0;
+                    for dy in 0..v {
+                        for dx in 0..h {
+                            sum += pixels[(y + dy) * mcu_width + x + dx][component] as u32;
+                        }
+                    }
+                    *sample = sum as f32 / (h * v) as f32 - 128.0;
+                }
+                self.block(&mut block, component);
+            }
+        }
+        self.row += 1;
+        match self.writer.overflow {
+            true => Err(EncodeError::BufferTooSmall),
+            false => Ok(self.row < self.rows),
+        }
+    }
+
+    /// Pad the last byte and write EOI, returns the length of the JPEG
+    pub fn finish(mut self) -> Result<usize, EncodeError> {
+        while self.encode_row()? {}
+        self.writer.flush();
+        self.writer.bytes(&[0xFF, EOI]);
+        match self.writer.overflow {
+            true => Err(EncodeError::BufferTooSmall),
+            false => Ok(self.writer.pos),
+        }
+    }
+
+    fn block(&mut self, block: &mut [f32; 64], component: usize) {
+        let (divisors, dc, ac) = match component {
+            0 => (&self.encoder.luma_divisors, &LUMA_DC, &LUMA_AC),
+            _ => (&self.encoder.chroma_divisors, &CHROMA_DC, &CHROMA_AC),
+        };
+        fdct(block);
+        let mut coefficients = [0i32; 64];
+        for (k, &i) in ZIGZAG.iter().enumerate() {
+            let value = block[i] * divisors[i];
+            // round half away from zero, baseline allows 11 bit magnitudes
+            let value = match value < 0.0 {
+                true => value - 0.5,
+                false => value + 0.5,
+            } as i32;
+            coefficients[k] = value.clamp(-1023, 1023);
+        }
+
+        let w = &mut self.writer;
+        let diff = coefficients[0] - self.predictors[component];
+        self.predictors[component] = coefficients[0];
+        let (bits, size) = magnitude(diff);
+        w.put(dc.code[size as usize] as u32, dc.size[size as usize] as u32);
+        w.put(bits, size);
+
+        let mut run = 0;
+        for &value in &coefficients[1..] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run >= 16 {
+                // ZRL, sixteen zeros
+                w.put(ac.code[0xF0] as u32, ac.size[0xF0] as u32);
+                run -= 16;
+            }
+            let (bits, size) = magnitude(value);
+            let symbol = (run << 4 | size) as usize;
+            w.put(ac.code[symbol] as u32, ac.size[symbol] as u32);
+            w.put(bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            // EOB
+            w.put(ac.code[0] as u32, ac.size[0] as u32);
+        }
+    }
+}
+
+/// Additional bits and their count for a coefficient, negative values are
+/// written as their one's complement
+fn magnitude(value: i32) -> (u32, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = match value < 0 {
+        true => (value - 1) as u32 & ((1 << size) - 1),
+        false => value as u32,
+    };
+    (bits, size)
+}
+
+/// AAN forward DCT in place, rows then columns, outputs are scaled by
+/// [AAN_SCALE] of row and column times 8
+fn fdct(block: &mut [f32; 64]) {
+    for row in 0..8 {
+        fdct_1d(block, row * 8, 1);
+    }
+    for column in 0..8 {
+        fdct_1d(block, column, 8);
+    }
+}
+
+fn fdct_1d(d: &mut [f32; 64], start: usize, stride: usize) {
+    let at = |i: usize| start + i * stride;
+    let tmp0 = d[at(0)] + d[at(7)];
+    let tmp7 = d[at(0)] - d[at(7)];
+    let tmp1 = d[at(1)] + d[at(6)];
+    let tmp6 = d[at(1)] - d[at(6)];
+    let tmp2 = d[at(2)] + d[at(5)];
+    let tmp5 = d[at(2)] - d[at(5)];
+    let tmp3 = d[at(3)] + d[at(4)];
+    let tmp4 = d[at(3)] - d[at(4)];
+
+    // even part
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+    d[at(0)] = tmp10 + tmp11;
+    d[at(4)] = tmp10 - tmp11;
+    let z1 = (tmp12 + tmp13) * 0.707_106_77;
+    d[at(2)] = tmp13 + z1;
+    d[at(6)] = tmp13 - z1;
+
+    // odd part
+    let tmp10 = tmp4 + tmp5;
+    let tmp11 = tmp5 + tmp6;
+    let tmp12 = tmp6 + tmp7;
+    let z5 = (tmp10 - tmp12) * 0.382_683_43;
+    let z2 = 0.541_196_1 * tmp10 + z5;
+    let z4 = 1.306_563 * tmp12 + z5;
+    let z3 = tmp11 * 0.707_106_77;
+    let z11 = tmp7 + z3;
+    let z13 = tmp7 - z3;
+    d[at(5)] = z13 + z2;
+    d[at(3)] = z13 - z2;
+    d[at(1)] = z11 + z4;
+    d[at(7)] = z11 - z4;
+}
+
+/// Writes bytes and bit fields into the output, records instead of failing
+/// when it runs out of room
+struct BitWriter<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+    /// Pending bits, right aligned
+    acc: u32,
+    bits: u32,
+    overflow: bool,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            pos: 0,
+            acc: 0,
+            bits: 0,
+            overflow: false,
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        match self.out.get_mut(self.pos) {
+            Some(slot) => {
+                *slot = byte;
+                self.pos += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    /// Marker segments, written as they are
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.byte(byte);
+        }
+    }
+
+    /// Append the low `size` bits of `code`, stuffing a 0 after every 0xFF
+    fn put(&mut self, code: u32, size: u32) {
+        self.acc = self.acc << size | code;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.acc >> self.bits) as u8;
+            self.byte(byte);
+            if byte == 0xFF {
+                self.byte(0);
+            }
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    /// Fill the last byte with 1 bits
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.put((1 << pad) - 1, pad);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//!
+//! EXIF and COM segments for frames straight from the sensor
+//!
+//! The segments are built on their own and go out in front of the untouched
+//! entropy coded data, at [insert_offset]. Nothing is decoded or re-encoded.
+//!
+
+use super::SOI;
+
+/// Application segment 0, JFIF
+pub const APP0: u8 = 0xE0;
+/// Application segment 1, EXIF
+pub const APP1: u8 = 0xE1;
+/// Comment
+pub const COM: u8 = 0xFE;
+
+/// Largest payload of a segment, the length field counts itself
+const MAX_SEGMENT_LEN: usize = 0xFFFF;
+const EXIF_HEADER: &[u8; 6] = b"Exif\0\0";
+/// Offset of the TIFF header in an APP1 segment
+const TIFF_START: usize = 4 + EXIF_HEADER.len();
+/// EXIF 2.32
+const EXIF_VERSION: &[u8; 4] = b"0232";
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_UNDEFINED: u16 = 7;
+
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010E;
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_EXIF_VERSION: u16 = 0x9000;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_SUBSEC_TIME_ORIGINAL: u16 = 0x9291;
+const TAG_PIXEL_X_DIMENSION: u16 = 0xA002;
+const TAG_PIXEL_Y_DIMENSION: u16 = 0xA003;
+
+/// The output buffer can't hold the segment, or the segment exceeds 64 KiB
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TooSmall;
+
+/// Calendar date and time of day, as EXIF writes it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn from_unix(secs: i64) -> Self {
+        let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+        let secs = secs.rem_euclid(86_400);
+        Self {
+            year: year.clamp(0, 9999) as u16,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// `YYYY:MM:DD HH:MM:SS`
+    pub fn ascii(&self) -> [u8; 19] {
+        let mut out = *b"0000:00:00 00:00:00";
+        let digits = |out: &mut [u8], mut value: u16| {
+            for byte in out.iter_mut().rev() {
+                *byte = b'0' + (value % 10) as u8;
+                value /= 10;
+            }
+        };
+        digits(&mut out[0..4], self.year);
+        digits(&mut out[5..7], self.month as u16);
+        digits(&mut out[8..10], self.day as u16);
+        digits(&mut out[11..13], self.hour as u16);
+        digits(&mut out[14..16], self.minute as u16);
+        digits(&mut out[17..19], self.second as u16);
+        out
+    }
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// What goes into the EXIF segment, empty strings are left out
+#[derive(Clone, Copy, Debug)]
+pub struct Exif<'a> {
+    /// Local capture time, `None` while the clock is not set
+    pub captured: Option<DateTime>,
+    /// Milliseconds of the capture time
+    pub subsec_millis: u16,
+    pub make: &'a str,
+    /// Device name
+    pub model: &'a str,
+    /// Firmware name and version
+    pub software: &'a str,
+    /// Free text, e.g. the sensor state
+    pub description: &'a str,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    /// Written with the terminating NUL
+    Ascii(&'a [u8]),
+    Short(u16),
+    Long(u32),
+    Undefined(&'a [u8]),
+}
+
+impl Value<'_> {
+    fn kind(&self) -> u16 {
+        match self {
+            Value::Ascii(_) => TYPE_ASCII,
+            Value::Short(_) => TYPE_SHORT,
+            Value::Long(_) => TYPE_LONG,
+            Value::Undefined(_) => TYPE_UNDEFINED,
+        }
+    }
+
+    fn count(&self) -> u32 {
+        match self {
+            Value::Ascii(s) => s.len() as u32 + 1,
+            Value::Short(_) | Value::Long(_) => 1,
+            Value::Undefined(b) => b.len() as u32,
+        }
+    }
+
+    /// Bytes in the data area, 0 if the value fits the entry
+    fn data_len(&self) -> usize {
+        match self {
+            Value::Short(_) | Value::Long(_) => 0,
+            Value::Ascii(_) | Value::Undefined(_) => match self.count() as usize {
+                len if len <= 4 => 0,
+                // offsets are kept even
+                len => len + len % 2,
+            },
+        }
+    }
+}
+
+type Entry<'a> = Option<(u16, Value<'a>)>;
+
+fn ascii(text: &str) -> Option<Value<'_>> {
+    (!text.is_empty()).then_some(Value::Ascii(text.as_bytes()))
+}
+
+/// Where metadata segments go, behind SOI and a JFIF APP0 segment if there is
+/// one. `None` if `jpeg` doesn't start with SOI.
+pub fn insert_offset(jpeg: &[u8]) -> Option<usize> {
+    if jpeg.get(..2)? != [0xFF, SOI] {
+        return None;
+    }
+    match jpeg.get(2..6) {
+        Some(&[0xFF, APP0, high, low]) => {
+            let end = 4 + u16::from_be_bytes([high, low]) as usize;
+            (end <= jpeg.len()).then_some(end)
+        }
+        _ => Some(2),
+    }
+}
+
+/// Write a COM segment holding `text`, returns its length including the marker
+pub fn write_comment(text: &[u8], out: &mut [u8]) -> Result<usize, TooSmall> {
+    let len = 2 + text.len();
+    if len > MAX_SEGMENT_LEN || out.len() < 2 + len {
+        return Err(TooSmall);
+    }
+    out[..2].copy_from_slice(&[0xFF, COM]);
+    out[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    out[4..2 + len].copy_from_slice(text);
+    Ok(2 + len)
+}
+
+/// Write an APP1 segment with a little endian TIFF structure of IFD0 and the
+/// EXIF IFD, returns its length including the marker
+pub fn write_exif(exif: &Exif, out: &mut [u8]) -> Result<usize, TooSmall> {
+    let date_time = exif.captured.map(|c| c.ascii());
+    let mut subsec = [b'0'; 3];
+    let mut millis = exif.subsec_millis.min(999);
+    for byte in subsec.iter_mut().rev() {
+        *byte = b'0' + (millis % 10) as u8;
+        millis /= 10;
+    }
+    let date_time = date_time.as_ref().map(|d| Value::Ascii(d));
+    // tags in ascending order, the IFD pointer is filled in below
+    let mut ifd0: [Entry; 6] = [
+        ascii(exif.description).map(|v| (TAG_IMAGE_DESCRIPTION, v)),
+        ascii(exif.make).map(|v| (TAG_MAKE, v)),
+        ascii(exif.model).map(|v| (TAG_MODEL, v)),
+        ascii(exif.software).map(|v| (TAG_SOFTWARE, v)),
+        date_time.map(|v| (TAG_DATE_TIME, v)),
+        Some((TAG_EXIF_IFD, Value::Long(0))),
+    ];
+    let exif_ifd: [Entry; 5] = [
+        Some((TAG_EXIF_VERSION, Value::Undefined(EXIF_VERSION))),
+        date_time.map(|v| (TAG_DATE_TIME_ORIGINAL, v)),
+        date_time.map(|_| (TAG_SUBSEC_TIME_ORIGINAL, Value::Ascii(&subsec))),
+        Some((TAG_PIXEL_X_DIMENSION, Value::Short(exif.width))),
+        Some((TAG_PIXEL_Y_DIMENSION, Value::Short(exif.height))),
+    ];
+
+    let ifd_len = |entries: &[Entry]| 2 + 12 * entries.iter().flatten().count() + 4;
+    let exif_ifd_at = 8 + ifd_len(&ifd0);
+    let data_at = exif_ifd_at + ifd_len(&exif_ifd);
+    let data_len: usize = ifd0
+        .iter()
+        .chain(exif_ifd.iter())
+        .flatten()
+        .map(|(_, v)| v.data_len())
+        .sum();
+    let len = TIFF_START + data_at + data_len;
+    if len - 2 > MAX_SEGMENT_LEN || out.len() < len {
+        return Err(TooSmall);
+    }
+    ifd0[5] = Some((TAG_EXIF_IFD, Value::Long(exif_ifd_at as u32)));
+
+    out[..2].copy_from_slice(&[0xFF, APP1]);
+    out[2..4].copy_from_slice(&((len - 2) as u16).to_be_bytes());
+    out[4..TIFF_START].copy_from_slice(EXIF_HEADER);
+    let tiff = &mut out[TIFF_START..len];
+    tiff[..8].copy_from_slice(&[b'I', b'I', 0x2A, 0x00, 8, 0, 0, 0]);
+    let mut data = data_at;
+    write_ifd(tiff, 8, &ifd0, &mut data);
+    write_ifd(tiff, exif_ifd_at, &exif_ifd, &mut data);
+    Ok(len)
+}
+
+/// Write the entries and their out of line values, `tiff` is sized to fit
+fn write_ifd(tiff: &mut [u8], at: usize, entries: &[Entry], data: &mut usize) {
+    let count = entries.iter().flatten().count();
+    tiff[at..at + 2].copy_from_slice(&(count as u16).to_le_bytes());
+    let mut entry_at = at + 2;
+    for (tag, value) in entries.iter().flatten() {
+        tiff[entry_at..entry_at + 2].copy_from_slice(&tag.to_le_bytes());
+        tiff[entry_at + 2..entry_at + 4].copy_from_slice(&value.kind().to_le_bytes());
+        tiff[entry_at + 4..entry_at + 8].copy_from_slice(&value.count().to_le_bytes());
+        let field = entry_at + 8;
+        tiff[field..field + 4].fill(0);
+        match *value {
+            Value::Short(v) => tiff[field..field + 2].copy_from_slice(&v.to_le_bytes()),
+            Value::Long(v) => tiff[field..field + 4].copy_from_slice(&v.to_le_bytes()),
+            Value::Ascii(bytes) | Value::Undefined(bytes) => {
+                let target = match value.data_len() {
+                    0 => field,
+                    len => {
+                        tiff[field..field + 4].copy_from_slice(&(*data as u32).to_le_bytes());
+                        let target = *data;
+                        tiff[target..target + len].fill(0);
+                        *data += len;
+                        target
+                    }
+                };
+                tiff[target..target + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+        entry_at += 12;
+    }
+    tiff[entry_at..entry_at + 4].fill(0);
+}
@@ -0,0 +1,452 @@
+//!
+//! Streaming JPEG frame extractor
+//!
+//! The camera delivers JPEG frames through the DMA in arbitrarily sized
+//! chunks. [JpegFramer] follows the marker segment structure of the stream
+//! byte by byte, so markers that are split across chunks are found and SOI/EOI
+//! pairs inside segments (e.g. EXIF thumbnails) don't end the frame early.
+//!
+//! After an error the framer doesn't take the next `FF D8` it finds, that
+//! could be a thumbnail in the rest of the dropped frame. A frame that grew too
+//! large is followed to its EOI without emitting data, after a corrupt one the
+//! framer skips to the next `FF D9` before it looks for SOI again.
+//!
+
+use super::{is_sof, is_standalone, EOI, RST0, RST7, SOI, SOS};
+
+/// Why a frame was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FrameError {
+    /// A new SOI or the end of the stream arrived before EOI
+    Truncated,
+    /// The marker structure is broken
+    Corrupt,
+    /// The frame grew past the configured maximum length
+    TooLarge,
+}
+
+/// Properties of a complete frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct FrameInfo {
+    /// Total length including SOI and EOI
+    pub len: usize,
+    /// Width from the SOF header, 0 if the frame had none
+    pub width: u16,
+    /// Height from the SOF header, 0 if the frame had none
+    pub height: u16,
+}
+
+/// Output of [JpegFramer::feed]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A frame started. The SOI bytes are not part of any [Event::Data], the
+    /// consumer has to emit `FF D8` itself.
+    Start,
+    /// Bytes of the current frame, in order, up to and including EOI
+    Data(&'a [u8]),
+    /// The current frame is complete
+    End(FrameInfo),
+    /// The current frame is dropped, all data of it must be discarded
+    Error(FrameError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Outside of a frame, looking for `FF`
+    Seek,
+    /// Outside of a frame, seen `FF`
+    SeekMarker,
+    /// After a corrupt frame, looking for the `FF` of its EOI
+    Resync,
+    /// After a corrupt frame, seen `FF`
+    ResyncMarker,
+    /// Expecting the `FF` of the next marker
+    Marker,
+    /// Seen `FF`, expecting the marker code
+    MarkerCode,
+    /// Expecting the high byte of the segment length
+    LengthHi(u8),
+    /// Expecting the low byte of the segment length
+    LengthLo(u8, u8),
+    /// Inside a segment body
+    Segment { marker: u8, pos: u16, len: u16 },
+    /// Inside entropy coded data
+    Entropy,
+    /// Seen `FF` inside entropy coded data
+    EntropyMarker,
+}
+
+enum Outcome {
+    Start,
+    End(FrameInfo),
+    Error(FrameError),
+    /// SOI inside a frame: the current frame is truncated and a new one starts
+    Restart,
+}
+
+/// Incremental JPEG frame parser
+pub struct JpegFramer {
+    state: State,
+    max_len: usize,
+    info: FrameInfo,
+    scanned: bool,
+    /// The frame was dropped as too large, its structure is still followed
+    dropping: bool,
+}
+
+impl JpegFramer {
+    /// Create a new framer without a size limit
+    pub const fn new() -> Self {
+        Self::with_max_len(usize::MAX)
+    }
+
+    /// Create a new framer dropping frames longer than `max_len`
+    pub const fn with_max_len(max_len: usize) -> Self {
+        Self {
+            state: State::Seek,
+            max_len,
+            info: FrameInfo {
+                len: 0,
+                width: 0,
+                height: 0,
+            },
+            scanned: false,
+            dropping: false,
+        }
+    }
+
+    /// Change the size limit, applies from the next frame on
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// Returns true while a frame is being parsed and its data emitted
+    pub fn in_frame(&self) -> bool {
+        !self.dropping && self.parsing()
+    }
+
+    /// Returns true while the framer follows the markers of a frame
+    fn parsing(&self) -> bool {
+        !matches!(
+            self.state,
+            State::Seek | State::SeekMarker | State::Resync | State::ResyncMarker
+        )
+    }
+
+    /// Parse the next chunk of the stream
+    pub fn feed<'f, 'a>(&'f mut self, data: &'a [u8]) -> Events<'f, 'a> {
+        Events {
+            framer: self,
+            data,
+            pos: 0,
+            pending: None,
+        }
+    }
+
+    /// Signal the end of the stream (e.g. VSYNC or a DMA restart)
+    ///
+    /// Returns [FrameError::Truncated] if a frame was in progress.
+    pub fn finish(&mut self) -> Option<FrameError> {
+        let in_frame = self.in_frame();
+        self.state = State::Seek;
+        self.dropping = false;
+        in_frame.then_some(FrameError::Truncated)
+    }
+
+    fn begin(&mut self) {
+        self.state = State::Marker;
+        self.info = FrameInfo {
+            len: 2,
+            width: 0,
+            height: 0,
+        };
+        self.scanned = false;
+        self.dropping = false;
+    }
+
+    /// Drop the frame as corrupt, a frame dropped before was reported already
+    fn fail(&mut self) -> Option<Outcome> {
+        self.state = State::Resync;
+        let reported = core::mem::take(&mut self.dropping);
+        (!reported).then_some(Outcome::Error(FrameError::Corrupt))
+    }
+
+    /// Consume bytes until something happens or `data` runs out
+    fn advance(&mut self, data: &[u8]) -> (usize, Option<Outcome>) {
+        let mut i = 0;
+        while i < data.len() {
+            let outcome = match self.state {
+                State::Seek | State::Resync => match data[i..].iter().position(|&b| b == 0xFF) {
+                    Some(p) => {
+                        i += p + 1;
+                        self.state = match self.state {
+                            State::Seek => State::SeekMarker,
+                            _ => State::ResyncMarker,
+                        };
+                        continue;
+                    }
+                    None => return (data.len(), None),
+                },
+                State::Segment { marker, pos, len } if !is_sof(marker) => {
+                    let n = (len - pos) as usize;
+                    let n = n.min(data.len() - i);
+                    self.info.len += n;
+                    i += n;
+                    let pos = pos + n as u16;
+                    if pos == len {
+                        self.end_segment(marker);
+                    } else {
+                        self.state = State::Segment { marker, pos, len };
+                    }
+                    None
+                }
+                State::Entropy => {
+                    let rest = &data[i..];
+                    let n = match rest.iter().position(|&b| b == 0xFF) {
+                        Some(p) => {
+                            self.state = State::EntropyMarker;
+                            p + 1
+                        }
+                        None => rest.len(),
+                    };
+                    self.info.len += n;
+                    i += n;
+                    None
+                }
+                _ => {
+                    let byte = data[i];
+                    i += 1;
+                    if self.in_frame() {
+                        self.info.len += 1;
+                    }
+                    self.step(byte)
+                }
+            };
+            // the EOI counts as well, it may be the byte that crosses the limit
+            let too_large = match &outcome {
+                None => self.in_frame() && self.info.len > self.max_len,
+                Some(Outcome::End(info)) => info.len > self.max_len,
+                Some(_) => false,
+            };
+            let outcome = match too_large {
+                true => {
+                    self.dropping = self.in_frame();
+                    Some(Outcome::Error(FrameError::TooLarge))
+                }
+                false => outcome,
+            };
+            if outcome.is_some() {
+                return (i, outcome);
+            }
+        }
+        (i, None)
+    }
+
+    fn step(&mut self, byte: u8) -> Option<Outcome> {
+        match self.state {
+            State::Seek => {
+                if byte == 0xFF {
+                    self.state = State::SeekMarker;
+                }
+                None
+            }
+            State::SeekMarker => match byte {
+                SOI => {
+                    self.begin();
+                    Some(Outcome::Start)
+                }
+                0xFF => None,
+                _ => {
+                    self.state = State::Seek;
+                    None
+                }
+            },
+            State::Resync => {
+                if byte == 0xFF {
+                    self.state = State::ResyncMarker;
+                }
+                None
+            }
+            State::ResyncMarker => {
+                self.state = match byte {
+                    EOI => State::Seek,
+                    0xFF => State::ResyncMarker,
+                    _ => State::Resync,
+                };
+                None
+            }
+            State::Marker => {
+                if byte == 0xFF {
+                    self.state = State::MarkerCode;
+                    None
+                } else {
+                    self.fail()
+                }
+            }
+            State::MarkerCode => self.marker(byte, false),
+            State::LengthHi(marker) => {
+                self.state = State::LengthLo(marker, byte);
+                None
+            }
+            State::LengthLo(marker, hi) => {
+                let len = u16::from_be_bytes([hi, byte]);
+                if len < 2 {
+                    return self.fail();
+                }
+                if len == 2 {
+                    self.end_segment(marker);
+                } else {
+                    self.state = State::Segment {
+                        marker,
+                        pos: 0,
+                        len: len - 2,
+                    };
+                }
+                None
+            }
+            State::Segment { marker, pos, len } => {
+                // SOF: precision, height (2), width (2), ...
+                match pos {
+                    1 => self.info.height = (byte as u16) << 8,
+                    2 => self.info.height |= byte as u16,
+                    3 => self.info.width = (byte as u16) << 8,
+                    4 => self.info.width |= byte as u16,
+                    _ => {}
+                }
+                if pos + 1 == len {
+                    self.end_segment(marker);
+                } else {
+                    self.state = State::Segment {
+                        marker,
+                        pos: pos + 1,
+                        len,
+                    };
+                }
+                None
+            }
+            State::Entropy => {
+                if byte == 0xFF {
+                    self.state = State::EntropyMarker;
+                }
+                None
+            }
+            State::EntropyMarker => match byte {
+                // byte stuffing and restart markers continue the scan
+                0x00 | RST0..=RST7 => {
+                    self.state = State::Entropy;
+                    None
+                }
+                _ => self.marker(byte, true),
+            },
+        }
+    }
+
+    fn marker(&mut self, code: u8, in_scan: bool) -> Option<Outcome> {
+        match code {
+            // fill bytes
+            0xFF => None,
+            SOI => {
+                let reported = self.dropping;
+                self.begin();
+                match reported {
+                    true => Some(Outcome::Start),
+                    false => Some(Outcome::Restart),
+                }
+            }
+            EOI if self.scanned => {
+                let info = self.info;
+                self.state = State::Seek;
+                match core::mem::take(&mut self.dropping) {
+                    true => None,
+                    false => Some(Outcome::End(info)),
+                }
+            }
+            EOI | 0x00 => self.fail(),
+            RST0..=RST7 if !in_scan => self.fail(),
+            _ if is_standalone(code) => {
+                self.state = if in_scan {
+                    State::Entropy
+                } else {
+                    State::Marker
+                };
+                None
+            }
+            _ => {
+                self.state = State::LengthHi(code);
+                None
+            }
+        }
+    }
+
+    fn end_segment(&mut self, marker: u8) {
+        if marker == SOS {
+            self.scanned = true;
+            self.state = State::Entropy;
+        } else {
+            self.state = State::Marker;
+        }
+    }
+}
+
+impl Default for JpegFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterator over the [Event]s of one chunk, see [JpegFramer::feed]
+pub struct Events<'f, 'a> {
+    framer: &'f mut JpegFramer,
+    data: &'a [u8],
+    pos: usize,
+    pending: Option<Event<'a>>,
+}
+
+impl<'a> Iterator for Events<'_, 'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.take() {
+            return Some(event);
+        }
+        while self.pos < self.data.len() {
+            let in_frame = self.framer.in_frame();
+            let start = self.pos;
+            let (n, outcome) = self.framer.advance(&self.data[start..]);
+            self.pos += n;
+            let data = &self.data[start..self.pos];
+            match outcome {
+                None if in_frame => return Some(Event::Data(data)),
+                None => {}
+                Some(Outcome::Start) => return Some(Event::Start),
+                Some(Outcome::End(info)) => {
+                    self.pending = Some(Event::End(info));
+                    return Some(Event::Data(data));
+                }
+                Some(Outcome::Error(e)) => return Some(Event::Error(e)),
+                Some(Outcome::Restart) => {
+                    self.pending = Some(Event::Start);
+                    return Some(Event::Error(FrameError::Truncated));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Check that `frame` holds exactly one complete JPEG
+pub fn validate(frame: &[u8]) -> Result<FrameInfo, FrameError> {
+    let mut framer = JpegFramer::new();
+    if frame.len() < 2 || frame[..2] != [0xFF, SOI] {
+        return Err(FrameError::Corrupt);
+    }
+    for event in framer.feed(frame) {
+        match event {
+            Event::End(info) if info.len == frame.len() => return Ok(info),
+            Event::End(_) | Event::Error(FrameError::Truncated) => return Err(FrameError::Corrupt),
+            Event::Error(e) => return Err(e),
+            Event::Start | Event::Data(_) => {}
+        }
+    }
+    Err(FrameError::Truncated)
+}
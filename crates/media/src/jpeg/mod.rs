@@ -0,0 +1,34 @@
+//!
+//! JPEG bitstream helpers
+//!
+
+mod dc;
+mod encoder;
+pub mod exif;
+mod framer;
+pub use dc::{decode_dc_luma, DecodeError, LumaSize};
+pub use encoder::{EncodeError, Encoder, EncoderConfig, Scan, Subsampling};
+pub use framer::{validate, Event, Events, FrameError, FrameInfo, JpegFramer};
+
+/// Start of image
+pub const SOI: u8 = 0xD8;
+/// End of image
+pub const EOI: u8 = 0xD9;
+/// Start of scan
+pub const SOS: u8 = 0xDA;
+/// Temporary private use marker, carries no length
+pub const TEM: u8 = 0x01;
+/// First restart marker, RST0..RST7 carry no length
+pub const RST0: u8 = 0xD0;
+/// Last restart marker
+pub const RST7: u8 = 0xD7;
+
+/// Returns true for start of frame markers (SOF0..SOF15 without DHT, JPG and DAC)
+pub fn is_sof(marker: u8) -> bool {
+    matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+/// Returns true for markers that are not followed by a length field
+pub fn is_standalone(marker: u8) -> bool {
+    matches!(marker, TEM | SOI | EOI | RST0..=RST7)
+}
@@ -0,0 +1,16 @@
+//!
+//! Platform independent image and container handling for the camera app
+//!
+//! Nothing in here touches the hardware, so the whole crate builds and runs
+//! on the host as well as on the ESP32-S3.
+//!
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod avi;
+pub mod jpeg;
+pub mod motion;
+pub mod raw;
+pub mod ring;
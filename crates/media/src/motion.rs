@@ -0,0 +1,201 @@
+//!
+//! Motion detection on low resolution luma maps
+//!
+//! Each map is compared against a slowly adapting background. Pixels that
+//! differ by more than the sensitivity threshold are counted per cell of a
+//! fixed grid, cells with enough changed pixels are reported as moving.
+//!
+
+use alloc::vec::Vec;
+
+/// Columns of the detection grid
+pub const GRID_COLS: usize = 16;
+/// Rows of the detection grid
+pub const GRID_ROWS: usize = 12;
+
+/// One bit per cell, bit `c` of row `r` is the cell in row `r`, column `c`
+pub type CellMask = [u16; GRID_ROWS];
+
+/// Every cell of the grid
+pub const ALL_CELLS: CellMask = [u16::MAX; GRID_ROWS];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MotionConfig {
+    /// 1 (large changes only) ..= 100 (smallest changes)
+    pub sensitivity: u8,
+    /// Cells that are watched, the others are ignored
+    pub zones: CellMask,
+    /// Percentage of a cell's pixels that has to change to mark it as moving
+    pub cell_threshold: u8,
+    /// Number of moving cells needed to report motion
+    pub min_cells: u8,
+    /// The background moves 1/2^n of the way towards every new frame
+    pub learn_shift: u8,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl MotionConfig {
+    pub const DEFAULT: MotionConfig = MotionConfig {
+        sensitivity: 50,
+        zones: ALL_CELLS,
+        cell_threshold: 20,
+        min_cells: 1,
+        learn_shift: 3,
+    };
+
+    /// Luma difference above which a pixel counts as changed
+    pub fn pixel_threshold(&self) -> i32 {
+        let sensitivity = self.sensitivity.clamp(1, 100) as i32;
+        4 + (100 - sensitivity) * 60 / 100
+    }
+}
+
+/// Bounding box in luma map pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Region {
+    /// The same box scaled by `factor`, e.g. 8 for maps of DC coefficients
+    pub fn scale(&self, factor: u16) -> Region {
+        Region {
+            x: self.x * factor,
+            y: self.y * factor,
+            width: self.width * factor,
+            height: self.height * factor,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MotionResult {
+    pub motion: bool,
+    /// Permille of the watched pixels that changed
+    pub score: u16,
+    /// Moving cells
+    pub cells: CellMask,
+    /// Bounding box of the moving cells
+    pub region: Option<Region>,
+}
+
+pub struct MotionDetector {
+    config: MotionConfig,
+    /// Background luma with 4 fractional bits
+    background: Vec<u16>,
+    width: usize,
+    height: usize,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        Self {
+            config,
+            background: Vec::new(),
+            width: 0,
+            height: 0,
+        }
+    }
+
+    pub fn config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MotionConfig) {
+        self.config = config;
+    }
+
+    /// Forget the background, the next map starts learning again
+    pub fn reset(&mut self) {
+        self.background.clear();
+        self.width = 0;
+        self.height = 0;
+    }
+
+    /// Compare `luma` against the background and fold it in
+    ///
+    /// Returns `None` for the first map and whenever the size changes, those
+    /// only seed the background.
+    pub fn update(&mut self, luma: &[u8], width: usize, height: usize) -> Option<MotionResult> {
+        let len = width * height;
+        let luma = &luma[..len];
+        if len == 0 || width != self.width || height != self.height {
+            self.background.clear();
+            self.background
+                .extend(luma.iter().map(|&l| (l as u16) << 4));
+            self.width = width;
+            self.height = height;
+            return None;
+        }
+
+        // exposure changes move the whole image, only count what's left
+        let offset = luma
+            .iter()
+            .zip(&self.background)
+            .map(|(&l, &b)| l as i32 - (b >> 4) as i32)
+            .sum::<i32>()
+            / len as i32;
+        let threshold = self.config.pixel_threshold();
+        let shift = self.config.learn_shift.min(8);
+
+        let mut total = [[0u32; GRID_COLS]; GRID_ROWS];
+        let mut changed = [[0u32; GRID_COLS]; GRID_ROWS];
+        for y in 0..height {
+            let row = y * GRID_ROWS / height;
+            for x in 0..width {
+                let col = x * GRID_COLS / width;
+                let l = luma[y * width + x] as i32;
+                let b = &mut self.background[y * width + x];
+                if (l - (*b >> 4) as i32 - offset).abs() > threshold {
+                    changed[row][col] += 1;
+                }
+                total[row][col] += 1;
+                *b = (*b as i32 + (((l << 4) - *b as i32) >> shift)) as u16;
+            }
+        }
+
+        let mut cells = [0u16; GRID_ROWS];
+        let (mut watched, mut moved, mut moving) = (0u32, 0u32, 0u32);
+        let (mut min, mut max) = ((GRID_COLS, GRID_ROWS), (0, 0));
+        for row in 0..GRID_ROWS {
+            for col in 0..GRID_COLS {
+                if self.config.zones[row] & (1 << col) == 0 || total[row][col] == 0 {
+                    continue;
+                }
+                watched += total[row][col];
+                moved += changed[row][col];
+                if changed[row][col] * 100 >= total[row][col] * self.config.cell_threshold as u32 {
+                    cells[row] |= 1 << col;
+                    moving += 1;
+                    min = (min.0.min(col), min.1.min(row));
+                    max = (max.0.max(col + 1), max.1.max(row + 1));
+                }
+            }
+        }
+
+        let region = (moving > 0).then(|| {
+            let x = (min.0 * width).div_ceil(GRID_COLS);
+            let y = (min.1 * height).div_ceil(GRID_ROWS);
+            Region {
+                x: x as u16,
+                y: y as u16,
+                width: ((max.0 * width).div_ceil(GRID_COLS) - x) as u16,
+                height: ((max.1 * height).div_ceil(GRID_ROWS) - y) as u16,
+            }
+        });
+        Some(MotionResult {
+            motion: moving > 0 && moving >= self.config.min_cells as u32,
+            score: (moved * 1000).checked_div(watched).unwrap_or(0) as u16,
+            cells,
+            region,
+        })
+    }
+}
@@ -0,0 +1,396 @@
+//!
+//! Uncompressed frames as the sensor delivers them in its raw modes
+//!
+
+/// Pixel layout of a raw frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum RawFormat {
+    /// Little endian RGB565
+    Rgb565,
+    /// YUYV, one U and V sample for each pair of pixels
+    Yuv422,
+    /// One luma byte per pixel
+    Gray8,
+}
+
+impl RawFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            RawFormat::Rgb565 | RawFormat::Yuv422 => 2,
+            RawFormat::Gray8 => 1,
+        }
+    }
+
+    /// Identifies the format in [Export::Binary] headers
+    pub fn code(self) -> u8 {
+        match self {
+            RawFormat::Rgb565 => 1,
+            RawFormat::Yuv422 => 2,
+            RawFormat::Gray8 => 3,
+        }
+    }
+}
+
+/// A raw frame with its dimensions
+#[derive(Clone, Copy, Debug)]
+pub struct RawImage<'a> {
+    format: RawFormat,
+    width: u16,
+    height: u16,
+    data: &'a [u8],
+}
+
+impl<'a> RawImage<'a> {
+    /// `None` if `data` is too short for the dimensions, or a YUYV frame has an
+    /// odd width
+    pub fn new(format: RawFormat, width: u16, height: u16, data: &'a [u8]) -> Option<Self> {
+        let len = width as usize * height as usize * format.bytes_per_pixel();
+        if width == 0 || height == 0 || data.len() < len {
+            return None;
+        }
+        if format == RawFormat::Yuv422 && width & 1 != 0 {
+            return None;
+        }
+        Some(Self {
+            format,
+            width,
+            height,
+            data: &data[..len],
+        })
+    }
+
+    pub fn format(&self) -> RawFormat {
+        self.format
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Sensor bytes of row `y`
+    pub fn row(&self, y: usize) -> &'a [u8] {
+        let len = self.width as usize * self.format.bytes_per_pixel();
+        &self.data[y * len..(y + 1) * len]
+    }
+
+    /// Full range YCbCr (JFIF) of the pixel at `x`, `y`, which must be inside
+    /// the frame
+    pub fn ycbcr(&self, x: usize, y: usize) -> [u8; 3] {
+        let index = y * self.width as usize + x;
+        match self.format {
+            RawFormat::Rgb565 => {
+                let [r, g, b] = rgb565(self.data[2 * index], self.data[2 * index + 1]);
+                rgb_to_ycbcr(r, g, b)
+            }
+            RawFormat::Yuv422 => {
+                // Y0 U Y1 V
+                let pair = 2 * (index & !1);
+                [
+                    self.data[2 * index],
+                    self.data[pair + 1],
+                    self.data[pair + 3],
+                ]
+            }
+            RawFormat::Gray8 => [self.data[index], 128, 128],
+        }
+    }
+}
+
+/// 8 bit RGB of a little endian RGB565 pixel, the low bits repeat the high
+/// ones so white stays white
+pub fn rgb565(low: u8, high: u8) -> [u8; 3] {
+    let value = u16::from_le_bytes([low, high]);
+    let r = (value >> 11) as u8 & 0x1F;
+    let g = (value >> 5) as u8 & 0x3F;
+    let b = value as u8 & 0x1F;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+/// BT.601 full range, as JFIF defines it
+pub fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    // coefficients scaled by 2^16, rounded
+    let y = (19_595 * r + 38_470 * g + 7_471 * b + 0x8000) >> 16;
+    let cb = (-11_056 * r - 21_712 * g + 32_768 * b + 0x80_8000) >> 16;
+    let cr = (32_768 * r - 27_440 * g - 5_328 * b + 0x80_8000) >> 16;
+    [y as u8, cb.clamp(0, 255) as u8, cr.clamp(0, 255) as u8]
+}
+
+/// RGB of full range YCbCr, the inverse of [rgb_to_ycbcr]
+pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let y = (y as i32) << 16;
+    let (cb, cr) = (cb as i32 - 128, cr as i32 - 128);
+    // coefficients scaled by 2^16, rounded
+    let r = (y + 91_881 * cr + 0x8000) >> 16;
+    let g = (y - 22_554 * cb - 46_802 * cr + 0x8000) >> 16;
+    let b = (y + 116_130 * cb + 0x8000) >> 16;
+    [
+        r.clamp(0, 255) as u8,
+        g.clamp(0, 255) as u8,
+        b.clamp(0, 255) as u8,
+    ]
+}
+
+/// Little endian RGB565 pixels to 3 bytes of RGB each
+pub fn rgb565_to_rgb888(src: &[u8], dst: &mut [u8]) {
+    for (pixel, out) in src.chunks_exact(2).zip(dst.chunks_exact_mut(3)) {
+        out.copy_from_slice(&rgb565(pixel[0], pixel[1]));
+    }
+}
+
+/// Little endian RGB565 pixels to their luma
+pub fn rgb565_to_gray(src: &[u8], dst: &mut [u8]) {
+    for (pixel, out) in src.chunks_exact(2).zip(dst.iter_mut()) {
+        let [r, g, b] = rgb565(pixel[0], pixel[1]);
+        *out = rgb_to_ycbcr(r, g, b)[0];
+    }
+}
+
+/// YUYV pixels to 3 bytes of RGB each
+pub fn yuv422_to_rgb888(src: &[u8], dst: &mut [u8]) {
+    for (pair, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(6)) {
+        let [y0, u, y1, v] = [pair[0], pair[1], pair[2], pair[3]];
+        out[..3].copy_from_slice(&ycbcr_to_rgb(y0, u, v));
+        out[3..].copy_from_slice(&ycbcr_to_rgb(y1, u, v));
+    }
+}
+
+/// YUYV pixels to their luma, chroma is dropped
+pub fn yuv422_to_gray(src: &[u8], dst: &mut [u8]) {
+    for (pixel, out) in src.chunks_exact(2).zip(dst.iter_mut()) {
+        *out = pixel[0];
+    }
+}
+
+/// Room for the header of every [Export]
+pub const MAX_HEADER_LEN: usize = 64;
+/// Start of a [Export::Binary] header
+pub const BINARY_MAGIC: &[u8; 4] = b"ECRF";
+const BINARY_VERSION: u8 = 1;
+const BINARY_HEADER_LEN: usize = 24;
+const BMP_HEADER_LEN: usize = 54;
+
+/// Uncompressed file formats a raw frame is exported as
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Export {
+    /// 24 bit Windows bitmap
+    Bmp,
+    /// Binary PGM (P5), luma only
+    Pgm,
+    /// Binary PPM (P6)
+    Ppm,
+    /// The sensor bytes as they are behind a 24 byte header, little endian:
+    /// magic `ECRF`, version, [RawFormat] code, width, height, reserved,
+    /// sequence number and capture time in microseconds
+    Binary,
+}
+
+impl Export {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "bmp" => Some(Export::Bmp),
+            "pgm" => Some(Export::Pgm),
+            "ppm" => Some(Export::Ppm),
+            "bin" => Some(Export::Binary),
+            _ => None,
+        }
+    }
+
+    /// File extension, also the name for [Self::parse]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Export::Bmp => "bmp",
+            Export::Pgm => "pgm",
+            Export::Ppm => "ppm",
+            Export::Binary => "bin",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Export::Bmp => "image/bmp",
+            Export::Pgm => "image/x-portable-graymap",
+            Export::Ppm => "image/x-portable-pixmap",
+            Export::Binary => "application/octet-stream",
+        }
+    }
+
+    /// Bytes of one output row, BMP rows are padded to 4 bytes
+    pub fn row_len(self, image: &RawImage) -> usize {
+        let width = image.width() as usize;
+        match self {
+            Export::Bmp => (3 * width).next_multiple_of(4),
+            Export::Pgm => width,
+            Export::Ppm => 3 * width,
+            Export::Binary => width * image.format().bytes_per_pixel(),
+        }
+    }
+
+    /// Size of the whole file
+    pub fn file_len(self, image: &RawImage) -> usize {
+        self.header_len(image) + self.row_len(image) * image.height() as usize
+    }
+
+    fn header_len(self, image: &RawImage) -> usize {
+        match self {
+            Export::Bmp => BMP_HEADER_LEN,
+            Export::Binary => BINARY_HEADER_LEN,
+            Export::Pgm | Export::Ppm => netpbm_header(self, image).1,
+        }
+    }
+
+    /// Write the file header, `out` holds at least [MAX_HEADER_LEN] bytes.
+    /// `seq` and `captured_micros` only go into the binary header.
+    pub fn write_header(
+        self,
+        image: &RawImage,
+        seq: u32,
+        captured_micros: u64,
+        out: &mut [u8],
+    ) -> usize {
+        let (width, height) = (image.width() as u32, image.height() as u32);
+        match self {
+            Export::Bmp => {
+                let file_len = self.file_len(image) as u32;
+                let pixels_len = file_len - BMP_HEADER_LEN as u32;
+                let mut fields = Fields(out, 0);
+                fields.bytes(b"BM");
+                fields.u32(file_len);
+                fields.u32(0);
+                fields.u32(BMP_HEADER_LEN as u32);
+                // BITMAPINFOHEADER, a positive height stores the bottom row first
+                fields.u32(40);
+                fields.u32(width);
+                fields.u32(height);
+                fields.u16(1);
+                fields.u16(24);
+                fields.u32(0);
+                fields.u32(pixels_len);
+                // 72 dpi
+                fields.u32(2835);
+                fields.u32(2835);
+                fields.u32(0);
+                fields.u32(0);
+                fields.1
+            }
+            Export::Pgm | Export::Ppm => {
+                let (header, len) = netpbm_header(self, image);
+                out[..len].copy_from_slice(&header[..len]);
+                len
+            }
+            Export::Binary => {
+                let mut fields = Fields(out, 0);
+                fields.bytes(BINARY_MAGIC);
+                fields.bytes(&[BINARY_VERSION, image.format().code()]);
+                fields.u16(width as u16);
+                fields.u16(height as u16);
+                fields.u16(0);
+                fields.u32(seq);
+                fields.bytes(&captured_micros.to_le_bytes());
+                fields.1
+            }
+        }
+    }
+
+    /// Write output row `index`, `out` holds at least [Self::row_len] bytes.
+    /// Returns the row length.
+    pub fn write_row(self, image: &RawImage, index: usize, out: &mut [u8]) -> usize {
+        let height = image.height() as usize;
+        let y = match self {
+            Export::Bmp => height - 1 - index,
+            _ => index,
+        };
+        let row = image.row(y);
+        let len = self.row_len(image);
+        let out = &mut out[..len];
+        match (self, image.format()) {
+            (Export::Binary, _) => out.copy_from_slice(row),
+            (Export::Pgm, RawFormat::Rgb565) => rgb565_to_gray(row, out),
+            (Export::Pgm, RawFormat::Yuv422) => yuv422_to_gray(row, out),
+            (Export::Pgm, RawFormat::Gray8) => out.copy_from_slice(row),
+            (Export::Bmp | Export::Ppm, format) => {
+                let width = image.width() as usize;
+                let rgb = &mut out[..3 * width];
+                match format {
+                    RawFormat::Rgb565 => rgb565_to_rgb888(row, rgb),
+                    RawFormat::Yuv422 => yuv422_to_rgb888(row, rgb),
+                    RawFormat::Gray8 => {
+                        for (gray, out) in row.iter().zip(rgb.chunks_exact_mut(3)) {
+                            out.fill(*gray);
+                        }
+                    }
+                }
+                if self == Export::Bmp {
+                    // BMP stores BGR
+                    for pixel in rgb.chunks_exact_mut(3) {
+                        pixel.swap(0, 2);
+                    }
+                    out[3 * width..].fill(0);
+                }
+            }
+        }
+        len
+    }
+}
+
+/// `P5`/`P6`, dimensions and the maximum value, each on its own line
+fn netpbm_header(export: Export, image: &RawImage) -> ([u8; MAX_HEADER_LEN], usize) {
+    let mut out = [0; MAX_HEADER_LEN];
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        out[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    push(match export {
+        Export::Pgm => b"P5\n",
+        _ => b"P6\n",
+    });
+    let (digits, count) = decimal(image.width());
+    push(&digits[5 - count..]);
+    push(b" ");
+    let (digits, count) = decimal(image.height());
+    push(&digits[5 - count..]);
+    push(b"\n255\n");
+    (out, len)
+}
+
+/// ASCII digits of `value`, right aligned, and how many are significant
+fn decimal(mut value: u16) -> ([u8; 5], usize) {
+    let mut digits = [b'0'; 5];
+    let mut count = 0;
+    for digit in digits.iter_mut().rev() {
+        *digit = b'0' + (value % 10) as u8;
+        value /= 10;
+        count += 1;
+        if value == 0 {
+            break;
+        }
+    }
+    (digits, count)
+}
+
+/// Little endian header fields written one after the other
+struct Fields<'a>(&'a mut [u8], usize);
+
+impl Fields<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0[self.1..self.1 + bytes.len()].copy_from_slice(bytes);
+        self.1 += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
@@ -0,0 +1,121 @@
+//!
+//! Ring of variable sized frames in one flat buffer
+//!
+//! Frames are stored back to back and never split. When the next frame does
+//! not fit behind the newest one, writing wraps around to the start of the
+//! buffer and the oldest frames in the way are dropped.
+//!
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::ops::DerefMut;
+
+struct Entry<T> {
+    start: usize,
+    len: usize,
+    meta: T,
+}
+
+/// Frames with metadata `T` in the storage `B`
+pub struct FrameRing<T, B = Vec<u8>> {
+    buf: B,
+    entries: VecDeque<Entry<T>>,
+    /// End of the newest frame
+    head: usize,
+}
+
+/// The frame is larger than the whole ring
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TooLarge;
+
+impl<T, B: DerefMut<Target = [u8]>> FrameRing<T, B> {
+    /// Use `buf` as storage, its length is the capacity of the ring
+    pub fn new(buf: B) -> Self {
+        Self {
+            buf,
+            entries: VecDeque::new(),
+            head: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Number of stored frames
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Sum of the stored frame sizes
+    pub fn bytes(&self) -> usize {
+        self.entries.iter().map(|e| e.len).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.head = 0;
+    }
+
+    /// Store a frame, dropping as many of the oldest frames as needed
+    pub fn push(&mut self, data: &[u8], meta: T) -> Result<(), TooLarge> {
+        self.push_parts(&[data], meta)
+    }
+
+    /// Store the concatenation of `parts` as one frame
+    pub fn push_parts(&mut self, parts: &[&[u8]], meta: T) -> Result<(), TooLarge> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len > self.buf.len() {
+            return Err(TooLarge);
+        }
+        if self.entries.is_empty() {
+            self.head = 0;
+        }
+        let start = if self.head + len <= self.buf.len() {
+            self.head
+        } else {
+            // everything behind the head is older than what's at the start
+            while self.entries.front().is_some_and(|e| e.start >= self.head) {
+                self.entries.pop_front();
+            }
+            0
+        };
+        // empty frames count as one byte so they can't shield the ones behind
+        while self
+            .entries
+            .front()
+            .is_some_and(|e| e.start < start + len && start < e.start + e.len.max(1))
+        {
+            self.entries.pop_front();
+        }
+        let mut at = start;
+        for part in parts {
+            self.buf[at..at + part.len()].copy_from_slice(part);
+            at += part.len();
+        }
+        self.entries.push_back(Entry { start, len, meta });
+        self.head = start + len;
+        Ok(())
+    }
+
+    /// Drop the oldest frame
+    pub fn pop(&mut self) -> Option<T> {
+        self.entries.pop_front().map(|e| e.meta)
+    }
+
+    /// Frame `index`, counted from the oldest one
+    pub fn get(&self, index: usize) -> Option<(&T, &[u8])> {
+        let e = self.entries.get(index)?;
+        Some((&e.meta, &self.buf[e.start..e.start + e.len]))
+    }
+
+    /// All frames, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&T, &[u8])> + Clone + '_ {
+        self.entries
+            .iter()
+            .map(|e| (&e.meta, &self.buf[e.start..e.start + e.len]))
+    }
+}
@@ -0,0 +1,109 @@
+use media::avi::{AviInfo, AviWriter, HEADER_LEN, INDEX_ENTRY_LEN};
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Frame `n` of `len` bytes, a marker in front so chunks can be told apart
+fn frame(n: u8, len: usize) -> Vec<u8> {
+    let mut frame = vec![n; len];
+    frame[..2].copy_from_slice(&[0xff, 0xd8]);
+    frame
+}
+
+/// Write `frames` the way the recorder does, sizes first, then the pieces in
+/// file order
+fn write(frames: &[Vec<u8>]) -> (AviWriter, Vec<u8>) {
+    let mut info = AviInfo::new(640, 480, 100_000);
+    for frame in frames {
+        info.add_frame(frame.len());
+    }
+    let writer = AviWriter::new(info);
+    let mut file = writer.header().to_vec();
+    for frame in frames {
+        file.extend_from_slice(&AviWriter::frame_header(frame.len()));
+        file.extend_from_slice(frame);
+        file.extend_from_slice(AviWriter::frame_padding(frame.len()));
+    }
+    file.extend_from_slice(&writer.index_header());
+    let mut index = writer.index();
+    for frame in frames {
+        file.extend_from_slice(&index.entry(frame.len()));
+    }
+    (writer, file)
+}
+
+#[test]
+fn sizes_and_offsets_match_the_data() {
+    let frames = [frame(1, 1000), frame(2, 777), frame(3, 2), frame(4, 3)];
+    let (writer, file) = write(&frames);
+    assert_eq!(writer.info().file_len(), file.len());
+    assert_eq!(writer.info().odd_frames, 2);
+
+    assert_eq!(&file[..4], b"RIFF");
+    assert_eq!(u32_at(&file, 4) as usize, file.len() - 8);
+    assert_eq!(&file[8..12], b"AVI ");
+
+    // hdrl list and its headers
+    assert_eq!(&file[12..16], b"LIST");
+    let hdrl_end = 20 + u32_at(&file, 16) as usize;
+    assert_eq!(&file[20..24], b"hdrl");
+    assert_eq!(&file[24..28], b"avih");
+    assert_eq!(u32_at(&file, 32), 100_000);
+    assert_eq!(u32_at(&file, 48), 4);
+    assert_eq!(u32_at(&file, 64), 640);
+    assert_eq!(u32_at(&file, 68), 480);
+    assert_eq!(&file[88..92], b"LIST");
+    assert_eq!(96 + u32_at(&file, 92) as usize, hdrl_end);
+    assert_eq!(&file[96..100], b"strl");
+
+    // movi list runs up to the index
+    assert_eq!(&file[hdrl_end..hdrl_end + 4], b"LIST");
+    let movi = hdrl_end + 8;
+    assert_eq!(movi + 4, HEADER_LEN);
+    assert_eq!(&file[movi..movi + 4], b"movi");
+    let movi_end = movi + u32_at(&file, hdrl_end + 4) as usize;
+    assert_eq!(&file[movi_end..movi_end + 4], b"idx1");
+    let index_len = u32_at(&file, movi_end + 4) as usize;
+    assert_eq!(index_len, frames.len() * INDEX_ENTRY_LEN);
+    assert_eq!(movi_end + 8 + index_len, file.len());
+
+    // every index entry points at its chunk relative to the movi FourCC
+    let mut chunk = HEADER_LEN;
+    for (n, frame) in frames.iter().enumerate() {
+        let entry = &file[movi_end + 8 + n * INDEX_ENTRY_LEN..][..INDEX_ENTRY_LEN];
+        assert_eq!(&entry[..4], b"00dc");
+        assert_eq!(u32_at(entry, 4), 0x10);
+        let offset = movi + u32_at(entry, 8) as usize;
+        assert_eq!(offset, chunk);
+        assert_eq!(u32_at(entry, 12) as usize, frame.len());
+
+        assert_eq!(&file[offset..offset + 4], b"00dc");
+        assert_eq!(u32_at(&file, offset + 4) as usize, frame.len());
+        assert_eq!(&file[offset + 8..offset + 8 + frame.len()], &frame[..]);
+        // chunks start on even offsets, odd frames carry a zero pad byte
+        assert_eq!(offset % 2, 0);
+        if frame.len() % 2 == 1 {
+            assert_eq!(file[offset + 8 + frame.len()], 0);
+        }
+        chunk = offset + 8 + frame.len().next_multiple_of(2);
+    }
+    assert_eq!(chunk, movi_end);
+}
+
+#[test]
+fn empty_recording_is_a_valid_file() {
+    let (writer, file) = write(&[]);
+    assert_eq!(writer.info().file_len(), file.len());
+    assert_eq!(u32_at(&file, 4) as usize, file.len() - 8);
+    assert_eq!(&file[HEADER_LEN..HEADER_LEN + 4], b"idx1");
+    assert_eq!(u32_at(&file, HEADER_LEN + 4), 0);
+}
+
+#[test]
+fn frame_duration_spreads_the_time() {
+    assert_eq!(AviInfo::frame_duration(0, 0), 1_000_000);
+    assert_eq!(AviInfo::frame_duration(1, 5_000_000), 1_000_000);
+    assert_eq!(AviInfo::frame_duration(11, 1_000_000), 100_000);
+    assert_eq!(AviInfo::frame_duration(3, 0), 1);
+}
@@ -0,0 +1,142 @@
+//! JPEGs for the tests, made with the crate's own encoder
+
+#![allow(dead_code)]
+
+use media::{
+    jpeg::{Encoder, EncoderConfig, Subsampling},
+    raw::{RawFormat, RawImage},
+};
+
+/// RGB565 test pattern, gradients with some noise from `seed`
+pub fn pattern(width: u16, height: u16, seed: u32) -> Vec<u8> {
+    let mut state = seed | 1;
+    let mut data = Vec::with_capacity(width as usize * height as usize * 2);
+    for y in 0..height as u32 {
+        for x in 0..width as u32 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let r = (x * 31 / width as u32) as u16;
+            let g = ((y * 63 / height as u32) as u16 + (state & 7) as u16).min(63);
+            let b = ((x + y) & 31) as u16;
+            data.extend_from_slice(&(r << 11 | g << 5 | b).to_le_bytes());
+        }
+    }
+    data
+}
+
+/// Encode the test pattern as a baseline JPEG
+pub fn jpeg(width: u16, height: u16, quality: u8, seed: u32) -> Vec<u8> {
+    let data = pattern(width, height, seed);
+    let image = RawImage::new(RawFormat::Rgb565, width, height, &data).unwrap();
+    let encoder = Encoder::new(EncoderConfig {
+        quality,
+        subsampling: Subsampling::S420,
+    });
+    let mut out = vec![0; 64 * 1024 + width as usize * height as usize * 4];
+    let len = encoder.encode(&image, &mut out).unwrap();
+    out.truncate(len);
+    out
+}
+
+/// `jpeg` with an APP1 segment behind SOI that carries `thumbnail` the way
+/// EXIF does, after an IFD0 without entries
+pub fn with_thumbnail(jpeg: &[u8], thumbnail: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(b"Exif\0\0II*\0");
+    body.extend_from_slice(&8u32.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(thumbnail);
+    let mut out = jpeg[..2].to_vec();
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&jpeg[2..]);
+    out
+}
+
+/// Offset of the first byte of entropy coded data, right behind the SOS
+/// header
+pub fn scan_start(jpeg: &[u8]) -> usize {
+    let mut pos = 2;
+    loop {
+        assert_eq!(jpeg[pos], 0xFF);
+        let marker = jpeg[pos + 1];
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        pos += 2 + len;
+        if marker == 0xDA {
+            return pos;
+        }
+    }
+}
+
+/// Gray scene of `width` x `height`: a textured gradient with a bright
+/// `size` square at `object`, `gain` is added to every pixel
+pub fn scene(
+    width: usize,
+    height: usize,
+    object: (usize, usize),
+    size: usize,
+    gain: i32,
+) -> Vec<u8> {
+    let mut luma = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let inside = (object.0..object.0 + size).contains(&x)
+                && (object.1..object.1 + size).contains(&y);
+            let base = match inside {
+                true => 230,
+                false => 40 + (x * 80 / width + y * 40 / height) as i32 + ((x ^ y) & 7) as i32,
+            };
+            luma.push((base + gain).clamp(0, 255) as u8);
+        }
+    }
+    luma
+}
+
+/// Encode a gray scene with an independent encoder, as RGB so that the
+/// chroma components and `sampling` are in the stream too
+pub fn reference_jpeg(
+    luma: &[u8],
+    width: usize,
+    height: usize,
+    sampling: jpeg_encoder::SamplingFactor,
+    restart_interval: Option<u16>,
+) -> Vec<u8> {
+    let rgb: Vec<u8> = luma.iter().flat_map(|&l| [l, l, l]).collect();
+    let mut out = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, 95);
+    encoder.set_sampling_factor(sampling);
+    if let Some(interval) = restart_interval {
+        encoder.set_restart_interval(interval);
+    }
+    encoder
+        .encode(
+            &rgb,
+            width as u16,
+            height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )
+        .unwrap();
+    out
+}
+
+/// Average of every 8x8 block, what the DC terms hold
+pub fn block_means(luma: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let (cols, rows) = (width.div_ceil(8), height.div_ceil(8));
+    let mut means = Vec::with_capacity(cols * rows);
+    for by in 0..rows {
+        for bx in 0..cols {
+            let mut sum = 0u32;
+            // the encoder repeats the last row and column into partial blocks
+            for y in by * 8..by * 8 + 8 {
+                for x in bx * 8..bx * 8 + 8 {
+                    sum += luma[y.min(height - 1) * width + x.min(width - 1)] as u32;
+                }
+            }
+            means.push(((sum + 32) / 64) as u8);
+        }
+    }
+    means
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::{block_means, jpeg, pattern, reference_jpeg, scene};
+use jpeg_encoder::SamplingFactor;
+use media::jpeg::{decode_dc_luma, DecodeError, LumaSize};
+
+/// Largest difference between the luma map and the block means
+fn max_error(decoded: &[u8], expected: &[u8]) -> u8 {
+    assert_eq!(decoded.len(), expected.len());
+    decoded
+        .iter()
+        .zip(expected)
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap()
+}
+
+#[test]
+fn matches_the_block_means() {
+    for (width, height) in [(128, 96), (160, 120), (100, 75), (8, 8)] {
+        let luma = scene(width, height, (width / 3, height / 4), width / 5, 0);
+        let expected = block_means(&luma, width, height);
+        for sampling in [
+            SamplingFactor::R_4_4_4,
+            SamplingFactor::R_4_2_2,
+            SamplingFactor::R_4_2_0,
+        ] {
+            for restart in [None, Some(1), Some(3)] {
+                let jpeg = reference_jpeg(&luma, width, height, sampling, restart);
+                let mut out = vec![0; 4096];
+                let size = decode_dc_luma(&jpeg, &mut out).unwrap();
+                assert_eq!(
+                    size,
+                    LumaSize {
+                        width: width.div_ceil(8),
+                        height: height.div_ceil(8)
+                    }
+                );
+                let error = max_error(&out[..size.len()], &expected);
+                assert!(
+                    error <= 2,
+                    "{}x{} {:?} restart {:?}: off by {}",
+                    width,
+                    height,
+                    sampling,
+                    restart,
+                    error
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn reads_the_own_encoder() {
+    let data = pattern(64, 48, 5);
+    let luma: Vec<u8> = data
+        .chunks(2)
+        .map(|p| {
+            let [r, g, b] = media::raw::rgb565(p[0], p[1]);
+            media::raw::rgb_to_ycbcr(r, g, b)[0]
+        })
+        .collect();
+    let mut out = [0; 48];
+    let size = decode_dc_luma(&jpeg(64, 48, 95, 5), &mut out).unwrap();
+    assert_eq!(size.len(), 48);
+    assert!(max_error(&out, &block_means(&luma, 64, 48)) <= 3);
+}
+
+#[test]
+fn rejects_what_it_cant_decode() {
+    let luma = scene(64, 64, (0, 0), 8, 0);
+    let jpeg = reference_jpeg(&luma, 64, 64, SamplingFactor::R_4_2_0, None);
+    let mut out = [0; 64];
+    assert_eq!(
+        decode_dc_luma(&jpeg, &mut out[..63]),
+        Err(DecodeError::BufferTooSmall)
+    );
+    assert_eq!(
+        decode_dc_luma(&jpeg[2..], &mut out),
+        Err(DecodeError::Invalid)
+    );
+    let sos = jpeg.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+    assert!(decode_dc_luma(&jpeg[..sos + 20], &mut out).is_err());
+
+    let mut progressive = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut progressive, 90);
+    encoder.set_progressive(true);
+    encoder
+        .encode(&luma, 64, 64, jpeg_encoder::ColorType::Luma)
+        .unwrap();
+    assert_eq!(
+        decode_dc_luma(&progressive, &mut out),
+        Err(DecodeError::Unsupported)
+    );
+}
+
+#[test]
+fn broken_data_never_panics() {
+    let luma = scene(64, 48, (8, 8), 16, 0);
+    let jpeg = reference_jpeg(&luma, 64, 48, SamplingFactor::R_4_2_0, Some(2));
+    let mut out = [0; 64];
+    let mut state = 0x1234_5678u32;
+    for _ in 0..20_000 {
+        let mut broken = jpeg.clone();
+        for _ in 0..4 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let i = state as usize % broken.len();
+            broken[i] = (state >> 24) as u8;
+        }
+        broken.truncate(state as usize % (jpeg.len() + 1));
+        let _ = decode_dc_luma(&broken, &mut out);
+    }
+}
@@ -0,0 +1,242 @@
+mod common;
+
+use std::io::Cursor;
+
+use exif::{In, Reader, Tag, Value};
+use media::jpeg::exif::{
+    insert_offset, write_comment, write_exif, DateTime, Exif, TooSmall, APP1, COM,
+};
+
+/// 2024-02-29 13:05:09
+const CAPTURED: i64 = 1_709_211_909;
+
+fn exif(captured: Option<DateTime>) -> Exif<'static> {
+    Exif {
+        captured,
+        subsec_millis: 42,
+        make: "Espressif",
+        model: "garage",
+        software: "esp-cam 0.1.0",
+        description: "exposure 1200 gain 4",
+        width: 64,
+        height: 48,
+    }
+}
+
+fn segment(exif: &Exif) -> Vec<u8> {
+    let mut out = vec![0; 1024];
+    let len = write_exif(exif, &mut out).unwrap();
+    out.truncate(len);
+    out
+}
+
+/// `jpeg` with `segments` at the place the camera puts them
+fn tagged(jpeg: &[u8], segments: &[&[u8]]) -> Vec<u8> {
+    let at = insert_offset(jpeg).unwrap();
+    let mut out = jpeg[..at].to_vec();
+    for segment in segments {
+        out.extend_from_slice(segment);
+    }
+    out.extend_from_slice(&jpeg[at..]);
+    out
+}
+
+fn ascii(exif: &exif::Exif, tag: Tag, ifd: In) -> Vec<u8> {
+    match &exif.get_field(tag, ifd).unwrap().value {
+        Value::Ascii(values) => values.concat(),
+        value => panic!("{tag} is {value:?}"),
+    }
+}
+
+fn short(exif: &exif::Exif, tag: Tag) -> u32 {
+    exif.get_field(tag, In::PRIMARY)
+        .unwrap()
+        .value
+        .get_uint(0)
+        .unwrap()
+}
+
+#[test]
+fn standard_reader_finds_every_tag() {
+    let jpeg = common::jpeg(64, 48, 80, 1);
+    let captured = DateTime::from_unix(CAPTURED);
+    let file = tagged(&jpeg, &[&segment(&exif(Some(captured)))]);
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(&file))
+        .unwrap();
+    assert!(exif.little_endian());
+
+    assert_eq!(ascii(&exif, Tag::Make, In::PRIMARY), b"Espressif");
+    assert_eq!(ascii(&exif, Tag::Model, In::PRIMARY), b"garage");
+    assert_eq!(ascii(&exif, Tag::Software, In::PRIMARY), b"esp-cam 0.1.0");
+    assert_eq!(
+        ascii(&exif, Tag::ImageDescription, In::PRIMARY),
+        b"exposure 1200 gain 4"
+    );
+    for tag in [Tag::DateTime, Tag::DateTimeOriginal] {
+        let text = ascii(&exif, tag, In::PRIMARY);
+        assert_eq!(text, b"2024:02:29 13:05:09");
+        let parsed = exif::DateTime::from_ascii(&text).unwrap();
+        assert_eq!((parsed.year, parsed.month, parsed.day), (2024, 2, 29));
+        assert_eq!((parsed.hour, parsed.minute, parsed.second), (13, 5, 9));
+    }
+    assert_eq!(ascii(&exif, Tag::SubSecTimeOriginal, In::PRIMARY), b"042");
+    let version = &exif.get_field(Tag::ExifVersion, In::PRIMARY).unwrap().value;
+    assert!(matches!(version, Value::Undefined(v, _) if v == b"0232"));
+    assert_eq!(short(&exif, Tag::PixelXDimension), 64);
+    assert_eq!(short(&exif, Tag::PixelYDimension), 48);
+    // nine tags and the pointer to the EXIF IFD
+    assert_eq!(exif.fields().len(), 10);
+}
+
+#[test]
+fn tags_without_a_value_are_left_out() {
+    let exif = Exif {
+        make: "",
+        description: "",
+        ..exif(None)
+    };
+    let segment = segment(&exif);
+    let exif = Reader::new().read_raw(segment[10..].to_vec()).unwrap();
+    for tag in [
+        Tag::Make,
+        Tag::ImageDescription,
+        Tag::DateTime,
+        Tag::DateTimeOriginal,
+        Tag::SubSecTimeOriginal,
+    ] {
+        assert!(exif.get_field(tag, In::PRIMARY).is_none(), "{tag}");
+    }
+    assert_eq!(ascii(&exif, Tag::Model, In::PRIMARY), b"garage");
+    assert_eq!(short(&exif, Tag::PixelXDimension), 64);
+}
+
+fn u16le(buf: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([buf[at], buf[at + 1]]) as usize
+}
+
+fn u32le(buf: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap()) as usize
+}
+
+/// Walk the IFDs by hand: values outside the entries start on even offsets,
+/// stay within the segment and don't overlap each other or the IFDs
+#[test]
+fn offsets_point_into_the_segment() {
+    let segment = segment(&exif(Some(DateTime::from_unix(CAPTURED))));
+    assert_eq!(&segment[..2], [0xFF, APP1]);
+    assert_eq!(
+        u16::from_be_bytes([segment[2], segment[3]]) as usize,
+        segment.len() - 2
+    );
+    assert_eq!(&segment[4..10], b"Exif\0\0");
+    let tiff = &segment[10..];
+    assert_eq!(&tiff[..4], b"II*\0");
+
+    let mut used = vec![(0, 8)];
+    let mut ifds = vec![u32le(tiff, 4)];
+    let mut date_time = None;
+    while let Some(ifd) = ifds.pop() {
+        let count = u16le(tiff, ifd);
+        let end = ifd + 2 + 12 * count + 4;
+        used.push((ifd, end));
+        assert_eq!(u32le(tiff, end - 4), 0, "one image, no IFD1");
+        let mut last_tag = 0;
+        for entry in (ifd + 2..end - 4).step_by(12) {
+            let tag = u16le(tiff, entry);
+            assert!(tag > last_tag, "tags ascend");
+            last_tag = tag;
+            let size = match u16le(tiff, entry + 2) {
+                2 | 7 => 1,
+                3 => 2,
+                4 => 4,
+                kind => panic!("type {kind}"),
+            };
+            let len = size * u32le(tiff, entry + 4);
+            if tag == 0x8769 {
+                ifds.push(u32le(tiff, entry + 8));
+            }
+            if len > 4 {
+                let offset = u32le(tiff, entry + 8);
+                assert_eq!(offset % 2, 0, "tag {tag:04x}");
+                assert!(offset + len <= tiff.len(), "tag {tag:04x}");
+                used.push((offset, offset + len));
+                if tag == 0x0132 {
+                    date_time = Some(&tiff[offset..offset + len]);
+                }
+            }
+        }
+    }
+    assert_eq!(date_time, Some(&b"2024:02:29 13:05:09\0"[..]));
+    used.sort();
+    for pair in used.windows(2) {
+        assert!(pair[0].1 <= pair[1].0, "{pair:?} overlap");
+    }
+    // nothing but word padding behind the last value
+    assert!(tiff.len() - used.last().unwrap().1 <= 1);
+}
+
+#[test]
+fn tagged_frames_still_decode() {
+    let jpeg = common::jpeg(64, 48, 80, 2);
+    let mut comment = vec![0; 64];
+    let len = write_comment(b"garage 2024-02-29 13:05:09", &mut comment).unwrap();
+    assert_eq!(&comment[..2], [0xFF, COM]);
+    let file = tagged(
+        &jpeg,
+        &[
+            &segment(&exif(Some(DateTime::from_unix(CAPTURED)))),
+            &comment[..len],
+        ],
+    );
+    let decode = |data: &[u8]| {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        (info.width, info.height, pixels)
+    };
+    let (width, height, pixels) = decode(&file);
+    assert_eq!((width, height), (64, 48));
+    assert_eq!(pixels, decode(&jpeg).2);
+}
+
+#[test]
+fn segments_go_behind_jfif() {
+    // the encoder writes a 16 byte JFIF APP0 behind SOI
+    let jfif = common::jpeg(16, 16, 80, 3);
+    assert_eq!(&jfif[2..4], [0xFF, 0xE0]);
+    assert_eq!(insert_offset(&jfif), Some(20));
+    let mut bare = jfif[..2].to_vec();
+    bare.extend_from_slice(&jfif[20..]);
+    assert_eq!(insert_offset(&bare), Some(2));
+    assert_eq!(insert_offset(&jfif[..10]), None);
+    assert_eq!(insert_offset(b"\xFF\xD9"), None);
+}
+
+#[test]
+fn too_small_buffers_are_refused() {
+    let exif = exif(Some(DateTime::from_unix(CAPTURED)));
+    let len = segment(&exif).len();
+    assert_eq!(write_exif(&exif, &mut vec![0; len - 1]), Err(TooSmall));
+    assert_eq!(write_exif(&exif, &mut vec![0; len]), Ok(len));
+    let text = vec![b'x'; 0xFFFE];
+    assert_eq!(write_comment(&text, &mut vec![0; 0x10010]), Err(TooSmall));
+    assert_eq!(
+        write_comment(&text[..0xFFFD], &mut vec![0; 0x10010]),
+        Ok(0x10001)
+    );
+}
+
+#[test]
+fn date_time_from_unix() {
+    assert_eq!(&DateTime::from_unix(0).ascii(), b"1970:01:01 00:00:00");
+    assert_eq!(
+        &DateTime::from_unix(CAPTURED).ascii(),
+        b"2024:02:29 13:05:09"
+    );
+    assert_eq!(
+        &DateTime::from_unix(CAPTURED + 11 * 3600 - 9).ascii(),
+        b"2024:03:01 00:05:00"
+    );
+    assert_eq!(&DateTime::from_unix(-1).ascii(), b"1969:12:31 23:59:59");
+}
@@ -0,0 +1,267 @@
+mod common;
+
+use common::{jpeg, scan_start, with_thumbnail};
+use media::jpeg::{validate, Event, FrameError, FrameInfo, JpegFramer};
+use proptest::prelude::*;
+
+/// What the framer made of a stream
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Complete(Vec<u8>, FrameInfo),
+    Dropped(FrameError),
+}
+
+/// Feed `stream` in chunks that end at `splits` and collect the frames the
+/// way the capture task assembles them
+fn run(framer: &mut JpegFramer, stream: &[u8], splits: &[usize]) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut bounds: Vec<usize> = splits.iter().map(|&s| s % (stream.len() + 1)).collect();
+    bounds.push(0);
+    bounds.push(stream.len());
+    bounds.sort_unstable();
+    for chunk in bounds.windows(2) {
+        for event in framer.feed(&stream[chunk[0]..chunk[1]]) {
+            match event {
+                Event::Start => {
+                    assert!(current.is_none(), "start inside a frame");
+                    current = Some(vec![0xFF, 0xD8]);
+                }
+                Event::Data(bytes) => current
+                    .as_mut()
+                    .expect("data outside of a frame")
+                    .extend_from_slice(bytes),
+                Event::End(info) => {
+                    let frame = current.take().expect("end outside of a frame");
+                    frames.push(Frame::Complete(frame, info));
+                }
+                Event::Error(e) => {
+                    current = None;
+                    frames.push(Frame::Dropped(e));
+                }
+            }
+        }
+    }
+    if let Some(e) = framer.finish() {
+        assert!(current.take().is_some());
+        frames.push(Frame::Dropped(e));
+    }
+    assert!(current.is_none(), "frame left open");
+    frames
+}
+
+fn complete(frame: &[u8], width: u16, height: u16) -> Frame {
+    Frame::Complete(
+        frame.to_vec(),
+        FrameInfo {
+            len: frame.len(),
+            width,
+            height,
+        },
+    )
+}
+
+/// Frame sizes and whether the frame carries a thumbnail
+fn frames() -> impl Strategy<Value = Vec<(u16, u16, bool, u32)>> {
+    prop::collection::vec((1u16..6, 1u16..5, any::<bool>(), any::<u32>()), 1..4)
+}
+
+fn build(specs: &[(u16, u16, bool, u32)]) -> Vec<(Vec<u8>, u16, u16)> {
+    let thumbnail = jpeg(16, 8, 50, 7);
+    specs
+        .iter()
+        .map(|&(w, h, thumb, seed)| {
+            let (width, height) = (w * 16, h * 16);
+            let mut frame = jpeg(width, height, 60, seed);
+            if thumb {
+                frame = with_thumbnail(&frame, &thumbnail);
+            }
+            (frame, width, height)
+        })
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn frames_survive_any_split(
+        specs in frames(),
+        gap in prop::collection::vec(any::<u8>().prop_filter("no marker", |b| *b != 0xFF), 0..8),
+        splits in prop::collection::vec(any::<usize>(), 0..40),
+    ) {
+        let frames = build(&specs);
+        let mut stream = Vec::new();
+        for (frame, _, _) in &frames {
+            stream.extend_from_slice(&gap);
+            stream.extend_from_slice(frame);
+        }
+        let expected: Vec<_> = frames.iter().map(|(f, w, h)| complete(f, *w, *h)).collect();
+        prop_assert_eq!(run(&mut JpegFramer::new(), &stream, &splits), expected);
+    }
+
+    #[test]
+    fn one_byte_chunks(specs in frames()) {
+        let frames = build(&specs);
+        let stream: Vec<u8> = frames.iter().flat_map(|(f, _, _)| f.clone()).collect();
+        let splits: Vec<usize> = (0..stream.len()).collect();
+        let expected: Vec<_> = frames.iter().map(|(f, w, h)| complete(f, *w, *h)).collect();
+        prop_assert_eq!(run(&mut JpegFramer::new(), &stream, &splits), expected);
+    }
+
+    #[test]
+    fn new_soi_in_the_scan_restarts(
+        specs in frames(),
+        cut in any::<prop::sample::Index>(),
+        splits in prop::collection::vec(any::<usize>(), 0..40),
+    ) {
+        let frames = build(&specs);
+        let (first, _, _) = &frames[0];
+        let start = scan_start(first);
+        let cut = start + cut.index(first.len() - 2 - start);
+        // drop a trailing FF, it would swallow the next SOI as a marker code
+        let cut = if first[cut - 1] == 0xFF { cut - 1 } else { cut };
+        let mut stream = first[..cut].to_vec();
+        let mut expected = vec![Frame::Dropped(FrameError::Truncated)];
+        for (frame, w, h) in &frames {
+            stream.extend_from_slice(frame);
+            expected.push(complete(frame, *w, *h));
+        }
+        prop_assert_eq!(run(&mut JpegFramer::new(), &stream, &splits), expected);
+    }
+
+    #[test]
+    fn too_large_is_cut_at_the_limit(
+        specs in frames(),
+        limit in 64usize..4096,
+        splits in prop::collection::vec(any::<usize>(), 0..40),
+    ) {
+        let frames = build(&specs);
+        let stream: Vec<u8> = frames.iter().flat_map(|(f, _, _)| f.clone()).collect();
+        let mut framer = JpegFramer::with_max_len(limit);
+        let mut emitted = 0;
+        let mut results = Vec::new();
+        let mut bounds: Vec<usize> = splits.iter().map(|&s| s % (stream.len() + 1)).collect();
+        bounds.extend([0, stream.len()]);
+        bounds.sort_unstable();
+        for chunk in bounds.windows(2) {
+            for event in framer.feed(&stream[chunk[0]..chunk[1]]) {
+                match event {
+                    Event::Start => emitted = 2,
+                    Event::Data(bytes) => {
+                        emitted += bytes.len();
+                        prop_assert!(emitted <= limit, "{} bytes emitted past {}", emitted, limit);
+                    }
+                    Event::End(info) => results.push(Ok(info.len)),
+                    Event::Error(e) => results.push(Err(e)),
+                }
+            }
+        }
+        let expected: Vec<_> = frames
+            .iter()
+            .map(|(f, _, _)| match f.len() <= limit {
+                true => Ok(f.len()),
+                false => Err(FrameError::TooLarge),
+            })
+            .collect();
+        prop_assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn broken_input_never_panics(
+        specs in frames(),
+        flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..16),
+        truncate in any::<prop::sample::Index>(),
+        splits in prop::collection::vec(any::<usize>(), 0..40),
+    ) {
+        let frames = build(&specs);
+        let mut stream: Vec<u8> = frames.iter().flat_map(|(f, _, _)| f.clone()).collect();
+        for (index, value) in &flips {
+            let i = index.index(stream.len());
+            stream[i] = *value;
+        }
+        stream.truncate(truncate.index(stream.len()) + 1);
+        let mut framer = JpegFramer::with_max_len(8192);
+        for frame in run(&mut framer, &stream, &splits) {
+            if let Frame::Complete(bytes, info) = frame {
+                prop_assert_eq!(bytes.len(), info.len);
+                prop_assert_eq!(validate(&bytes), Ok(info));
+            }
+        }
+        let _ = validate(&stream);
+    }
+
+    #[test]
+    fn truncated_frames_are_reported(specs in frames(), cut in any::<prop::sample::Index>()) {
+        let frames = build(&specs);
+        let (frame, _, _) = &frames[0];
+        let cut = 2 + cut.index(frame.len() - 3);
+        prop_assert!(validate(&frame[..cut]).is_err());
+        let results = run(&mut JpegFramer::new(), &frame[..cut], &[]);
+        prop_assert_eq!(results.len(), 1);
+        prop_assert!(matches!(results[0], Frame::Dropped(_)));
+    }
+}
+
+#[test]
+fn reports_the_sof_size() {
+    let frame = jpeg(48, 32, 75, 1);
+    assert_eq!(
+        validate(&frame),
+        Ok(FrameInfo {
+            len: frame.len(),
+            width: 48,
+            height: 32
+        })
+    );
+}
+
+#[test]
+fn thumbnail_doesnt_end_the_frame() {
+    let frame = with_thumbnail(&jpeg(32, 32, 75, 1), &jpeg(16, 16, 50, 2));
+    assert_eq!(validate(&frame).map(|info| info.width), Ok(32));
+}
+
+#[test]
+fn corrupt_frame_resyncs_past_its_thumbnail() {
+    let thumbnail = jpeg(16, 16, 50, 2);
+    let mut broken = with_thumbnail(&jpeg(32, 32, 75, 1), &thumbnail);
+    // the marker of the APP1 segment, the thumbnail is still in the stream
+    broken[2] = 0x00;
+    let next = jpeg(64, 48, 75, 3);
+    let stream = [broken, next.clone()].concat();
+    assert_eq!(
+        run(&mut JpegFramer::new(), &stream, &[]),
+        vec![Frame::Dropped(FrameError::Corrupt), complete(&next, 64, 48)]
+    );
+}
+
+#[test]
+fn dropped_frame_is_followed_to_its_eoi() {
+    let next = jpeg(16, 16, 50, 3);
+    // the limit is hit in the APP1 segment, before the thumbnail
+    let padding = vec![0; next.len() * 2];
+    let frame = with_thumbnail(
+        &jpeg(64, 64, 90, 1),
+        &[padding, jpeg(16, 16, 50, 2)].concat(),
+    );
+    let stream = [frame, next.clone()].concat();
+    let mut framer = JpegFramer::with_max_len(next.len() + 16);
+    let splits: Vec<usize> = (0..stream.len()).step_by(32).collect();
+    assert_eq!(
+        run(&mut framer, &stream, &splits),
+        vec![
+            Frame::Dropped(FrameError::TooLarge),
+            complete(&next, 16, 16)
+        ]
+    );
+}
+
+#[test]
+fn finish_reports_a_frame_in_progress() {
+    let frame = jpeg(16, 16, 50, 1);
+    let mut framer = JpegFramer::new();
+    assert_eq!(framer.feed(&frame[..20]).count(), 2);
+    assert_eq!(framer.finish(), Some(FrameError::Truncated));
+    assert_eq!(framer.finish(), None);
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use common::{reference_jpeg, scene};
+use jpeg_encoder::SamplingFactor;
+use media::{
+    jpeg::decode_dc_luma,
+    motion::{MotionConfig, MotionDetector, Region, GRID_ROWS},
+};
+
+/// 16x12 blocks, one per cell of the grid
+const WIDTH: usize = 128;
+const HEIGHT: usize = 96;
+
+/// Luma map of a frame the way the capture path gets it, through JPEG
+fn frame(object: (usize, usize), gain: i32) -> Vec<u8> {
+    let luma = scene(WIDTH, HEIGHT, object, 16, gain);
+    let jpeg = reference_jpeg(&luma, WIDTH, HEIGHT, SamplingFactor::R_4_2_0, None);
+    let mut map = vec![0; 16 * 12];
+    decode_dc_luma(&jpeg, &mut map).unwrap();
+    map
+}
+
+fn settled(config: MotionConfig) -> MotionDetector {
+    let mut detector = MotionDetector::new(config);
+    let still = frame((16, 16), 0);
+    assert_eq!(detector.update(&still, 16, 12), None);
+    for _ in 0..5 {
+        let result = detector.update(&still, 16, 12).unwrap();
+        assert!(!result.motion);
+        assert_eq!(result.score, 0);
+    }
+    detector
+}
+
+#[test]
+fn moving_object_is_found() {
+    let mut detector = settled(MotionConfig::default());
+    let result = detector.update(&frame((80, 48), 0), 16, 12).unwrap();
+    assert!(result.motion);
+    // the square left blocks 2..4 and covers 10..12 x 6..8
+    let mut cells = [0u16; GRID_ROWS];
+    cells[2] = 0b1100;
+    cells[3] = 0b1100;
+    cells[6] = 0b1100 << 8;
+    cells[7] = 0b1100 << 8;
+    assert_eq!(result.cells, cells);
+    assert_eq!(
+        result.region,
+        Some(Region {
+            x: 2,
+            y: 2,
+            width: 10,
+            height: 6
+        })
+    );
+    // 8 of 192 blocks
+    assert_eq!(result.score, 41);
+    assert_eq!(result.region.unwrap().scale(8).width, 80);
+}
+
+#[test]
+fn exposure_change_is_no_motion() {
+    let mut detector = settled(MotionConfig::default());
+    let result = detector.update(&frame((16, 16), 25), 16, 12).unwrap();
+    assert!(!result.motion, "{:?}", result);
+    assert_eq!(result.score, 0);
+}
+
+#[test]
+fn zones_and_min_cells_filter() {
+    let mut zones = [0u16; GRID_ROWS];
+    // only the right half
+    zones.fill(0xff00);
+    let mut detector = settled(MotionConfig {
+        zones,
+        ..MotionConfig::default()
+    });
+    let result = detector.update(&frame((80, 48), 0), 16, 12).unwrap();
+    assert!(result.motion);
+    assert_eq!(result.cells[2], 0);
+    assert_eq!(
+        result.region,
+        Some(Region {
+            x: 10,
+            y: 6,
+            width: 2,
+            height: 2
+        })
+    );
+    // 4 of the 96 watched blocks
+    assert_eq!(result.score, 41);
+
+    let mut detector = settled(MotionConfig {
+        min_cells: 9,
+        ..MotionConfig::default()
+    });
+    let result = detector.update(&frame((80, 48), 0), 16, 12).unwrap();
+    assert!(!result.motion);
+    assert!(result.region.is_some());
+}
+
+#[test]
+fn background_learns_a_new_scene() {
+    let mut detector = settled(MotionConfig::default());
+    let moved = frame((80, 48), 0);
+    let scores: Vec<u16> = (0..40)
+        .map(|_| detector.update(&moved, 16, 12).unwrap().score)
+        .collect();
+    assert!(scores[0] > 0);
+    assert!(scores.windows(2).all(|w| w[1] <= w[0]), "{:?}", scores);
+    assert_eq!(*scores.last().unwrap(), 0);
+}
+
+#[test]
+fn sensitivity_sets_the_threshold() {
+    let faint = |gain| {
+        let mut luma = scene(WIDTH, HEIGHT, (16, 16), 16, 0);
+        for y in 48..64 {
+            for x in 80..96 {
+                luma[y * WIDTH + x] = (luma[y * WIDTH + x] as i32 + gain) as u8;
+            }
+        }
+        let jpeg = reference_jpeg(&luma, WIDTH, HEIGHT, SamplingFactor::R_4_2_0, None);
+        let mut map = vec![0; 16 * 12];
+        decode_dc_luma(&jpeg, &mut map).unwrap();
+        map
+    };
+    for (sensitivity, motion) in [(100, true), (1, false)] {
+        let mut detector = settled(MotionConfig {
+            sensitivity,
+            ..MotionConfig::default()
+        });
+        let result = detector.update(&faint(20), 16, 12).unwrap();
+        assert_eq!(result.motion, motion, "sensitivity {}", sensitivity);
+    }
+}
+
+#[test]
+fn size_change_reseeds() {
+    let mut detector = settled(MotionConfig::default());
+    assert_eq!(detector.update(&frame((80, 48), 0)[..96], 8, 12), None);
+    detector.reset();
+    assert_eq!(detector.update(&frame((16, 16), 0), 16, 12), None);
+}
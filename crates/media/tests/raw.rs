@@ -0,0 +1,216 @@
+use image::{ImageFormat, RgbImage};
+use media::raw::{
+    rgb565, rgb565_to_gray, rgb565_to_rgb888, rgb_to_ycbcr, ycbcr_to_rgb, yuv422_to_gray,
+    yuv422_to_rgb888, Export, RawFormat, RawImage, BINARY_MAGIC, MAX_HEADER_LEN,
+};
+
+fn u16le(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// The whole file, header and rows the way `/raw` sends them
+fn export(export: Export, image: &RawImage) -> Vec<u8> {
+    let mut file = vec![0; MAX_HEADER_LEN];
+    let len = export.write_header(image, 7, 123_456_789, &mut file);
+    file.truncate(len);
+    let mut row = vec![0; export.row_len(image)];
+    for y in 0..image.height() as usize {
+        assert_eq!(export.write_row(image, y, &mut row), row.len());
+        file.extend_from_slice(&row);
+    }
+    assert_eq!(file.len(), export.file_len(image));
+    file
+}
+
+/// RGB565 frame of known colours, `width` x `height`
+fn rgb565_frame(width: u16, height: u16) -> (Vec<u8>, Vec<[u8; 3]>) {
+    let mut data = Vec::new();
+    let mut rgb = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let value = (x * 2) << 11 | (y * 4) << 5 | (x + y) & 0x1F;
+            data.extend_from_slice(&value.to_le_bytes());
+            rgb.push(rgb565(value as u8, (value >> 8) as u8));
+        }
+    }
+    (data, rgb)
+}
+
+#[test]
+fn rgb565_expands_to_full_range() {
+    let pixels: [(u16, [u8; 3]); 6] = [
+        (0x0000, [0, 0, 0]),
+        (0xFFFF, [255, 255, 255]),
+        (0xF800, [255, 0, 0]),
+        (0x07E0, [0, 255, 0]),
+        (0x001F, [0, 0, 255]),
+        (0x8410, [132, 130, 132]),
+    ];
+    let src: Vec<u8> = pixels.iter().flat_map(|(v, _)| v.to_le_bytes()).collect();
+    let mut rgb = vec![0; 3 * pixels.len()];
+    rgb565_to_rgb888(&src, &mut rgb);
+    let expected: Vec<u8> = pixels.iter().flat_map(|(_, rgb)| *rgb).collect();
+    assert_eq!(rgb, expected);
+
+    let mut gray = vec![0; pixels.len()];
+    rgb565_to_gray(&src, &mut gray);
+    assert_eq!(&gray[..2], [0, 255]);
+    // BT.601 weights
+    assert_eq!(&gray[2..5], [76, 150, 29]);
+}
+
+#[test]
+fn yuv422_keeps_luma_and_shares_chroma() {
+    // two pixel pairs, neutral chroma and then red
+    let src = [16, 128, 235, 128, 81, 90, 82, 240];
+    let mut gray = [0; 4];
+    yuv422_to_gray(&src, &mut gray);
+    assert_eq!(gray, [16, 235, 81, 82]);
+
+    let mut rgb = [0; 12];
+    yuv422_to_rgb888(&src, &mut rgb);
+    assert_eq!(&rgb[..6], [16, 16, 16, 235, 235, 235]);
+    let red = ycbcr_to_rgb(81, 90, 240);
+    assert_eq!(&rgb[6..9], red);
+    assert!(red[0] > 230 && red[1] < 20 && red[2] < 20, "{red:?}");
+}
+
+#[test]
+fn ycbcr_round_trips() {
+    for r in (0..=255).step_by(15) {
+        for g in (0..=255).step_by(17) {
+            for b in (0..=255).step_by(51) {
+                let [y, cb, cr] = rgb_to_ycbcr(r, g, b);
+                let back = ycbcr_to_rgb(y, cb, cr);
+                for (a, b) in [r, g, b].into_iter().zip(back) {
+                    assert!(a.abs_diff(b) <= 2, "{r} {g} {b} -> {back:?}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn image_needs_the_whole_frame() {
+    let data = [0; 24];
+    assert!(RawImage::new(RawFormat::Rgb565, 4, 3, &data).is_some());
+    assert!(RawImage::new(RawFormat::Rgb565, 4, 4, &data).is_none());
+    assert!(RawImage::new(RawFormat::Rgb565, 0, 3, &data).is_none());
+    assert!(RawImage::new(RawFormat::Yuv422, 3, 2, &data).is_none());
+    assert!(RawImage::new(RawFormat::Yuv422, 4, 3, &data).is_some());
+    // longer buffers are cut to the frame
+    let image = RawImage::new(RawFormat::Gray8, 4, 3, &data).unwrap();
+    assert_eq!(image.data().len(), 12);
+}
+
+#[test]
+fn bmp_has_a_valid_header_and_padded_rows() {
+    // 3 pixels make 9 bytes, padded to 12
+    let (data, rgb) = rgb565_frame(3, 5);
+    let image = RawImage::new(RawFormat::Rgb565, 3, 5, &data).unwrap();
+    let file = export(Export::Bmp, &image);
+    assert_eq!(Export::Bmp.row_len(&image), 12);
+    assert_eq!(&file[..2], b"BM");
+    assert_eq!(u32le(&file, 2) as usize, file.len());
+    assert_eq!(u32le(&file, 10), 54);
+    assert_eq!(u32le(&file, 14), 40);
+    assert_eq!((u32le(&file, 18), u32le(&file, 22)), (3, 5));
+    assert_eq!((u16le(&file, 26), u16le(&file, 28)), (1, 24));
+    assert_eq!(u32le(&file, 34) as usize, file.len() - 54);
+    // bottom row first, BGR, zero padding
+    let first = &file[54..66];
+    assert_eq!(&first[..3], [rgb[12][2], rgb[12][1], rgb[12][0]]);
+    assert_eq!(&first[9..], [0, 0, 0]);
+
+    let decoded = image::load_from_memory_with_format(&file, ImageFormat::Bmp)
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(decoded, expected_rgb(3, 5, &rgb));
+}
+
+fn expected_rgb(width: u32, height: u32, rgb: &[[u8; 3]]) -> RgbImage {
+    RgbImage::from_raw(width, height, rgb.concat()).unwrap()
+}
+
+#[test]
+fn ppm_and_pgm_decode() {
+    let (data, rgb) = rgb565_frame(10, 4);
+    let image = RawImage::new(RawFormat::Rgb565, 10, 4, &data).unwrap();
+
+    let ppm = export(Export::Ppm, &image);
+    assert!(ppm.starts_with(b"P6\n10 4\n255\n"));
+    let decoded = image::load_from_memory_with_format(&ppm, ImageFormat::Pnm)
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(decoded, expected_rgb(10, 4, &rgb));
+
+    let pgm = export(Export::Pgm, &image);
+    assert!(pgm.starts_with(b"P5\n10 4\n255\n"));
+    let decoded = image::load_from_memory_with_format(&pgm, ImageFormat::Pnm)
+        .unwrap()
+        .to_luma8();
+    let mut gray = vec![0; 40];
+    rgb565_to_gray(&data, &mut gray);
+    assert_eq!(decoded.into_raw(), gray);
+}
+
+#[test]
+fn yuv422_exports() {
+    let data: Vec<u8> = (0..8 * 2 * 2).map(|i| (i * 7) as u8).collect();
+    let image = RawImage::new(RawFormat::Yuv422, 8, 2, &data).unwrap();
+    let pgm = export(Export::Pgm, &image);
+    let luma: Vec<u8> = data.iter().step_by(2).copied().collect();
+    assert_eq!(&pgm[pgm.len() - 16..], luma);
+
+    let ppm = export(Export::Ppm, &image);
+    let decoded = image::load_from_memory_with_format(&ppm, ImageFormat::Pnm)
+        .unwrap()
+        .to_rgb8();
+    let mut rgb = vec![0; 48];
+    yuv422_to_rgb888(&data, &mut rgb);
+    assert_eq!(decoded.into_raw(), rgb);
+}
+
+#[test]
+fn binary_keeps_the_sensor_bytes() {
+    let (data, _) = rgb565_frame(6, 2);
+    let image = RawImage::new(RawFormat::Rgb565, 6, 2, &data).unwrap();
+    let file = export(Export::Binary, &image);
+    assert_eq!(&file[..4], BINARY_MAGIC);
+    assert_eq!(&file[4..6], [1, RawFormat::Rgb565.code()]);
+    assert_eq!(
+        (u16le(&file, 6), u16le(&file, 8), u16le(&file, 10)),
+        (6, 2, 0)
+    );
+    assert_eq!(u32le(&file, 12), 7);
+    assert_eq!(
+        u64::from_le_bytes(file[16..24].try_into().unwrap()),
+        123_456_789
+    );
+    assert_eq!(&file[24..], data);
+}
+
+#[test]
+fn large_dimensions_fit_the_header() {
+    let data = vec![0; 65_535];
+    let image = RawImage::new(RawFormat::Gray8, 65_535, 1, &data).unwrap();
+    let mut header = [0; MAX_HEADER_LEN];
+    for format in [Export::Bmp, Export::Pgm, Export::Ppm, Export::Binary] {
+        let len = format.write_header(&image, 0, 0, &mut header);
+        assert_eq!(len + format.row_len(&image), format.file_len(&image));
+    }
+    let len = Export::Pgm.write_header(&image, 0, 0, &mut header);
+    assert_eq!(&header[..len], b"P5\n65535 1\n255\n");
+}
+
+#[test]
+fn export_names() {
+    for format in [Export::Bmp, Export::Pgm, Export::Ppm, Export::Binary] {
+        assert_eq!(Export::parse(format.extension()), Some(format));
+    }
+    assert_eq!(Export::parse("png"), None);
+}
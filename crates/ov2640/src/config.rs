@@ -5,10 +5,13 @@
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ImageFormat {
     JPEG,
+    /// RGB565 at 320x240
     QVGA,
+    /// YUV 4:2:2 at 320x240, the QVGA window with the DSP output switched
+    YUV422,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
 pub enum Resolution {
     R160x120,
     R176x144,
@@ -21,6 +24,48 @@ pub enum Resolution {
     R1600x1200,
 }
 
+impl Resolution {
+    /// Every resolution, smallest first
+    pub const ALL: [Resolution; 9] = [
+        Resolution::R160x120,
+        Resolution::R176x144,
+        Resolution::R320x240,
+        Resolution::R352x288,
+        Resolution::R640x480,
+        Resolution::R800x600,
+        Resolution::R1024x768,
+        Resolution::R1280x1024,
+        Resolution::R1600x1200,
+    ];
+
+    /// Width and height in pixels
+    pub fn size(self) -> (u16, u16) {
+        match self {
+            Resolution::R160x120 => (160, 120),
+            Resolution::R176x144 => (176, 144),
+            Resolution::R320x240 => (320, 240),
+            Resolution::R352x288 => (352, 288),
+            Resolution::R640x480 => (640, 480),
+            Resolution::R800x600 => (800, 600),
+            Resolution::R1024x768 => (1024, 768),
+            Resolution::R1280x1024 => (1280, 1024),
+            Resolution::R1600x1200 => (1600, 1200),
+        }
+    }
+
+    /// The next smaller resolution
+    pub fn smaller(self) -> Option<Resolution> {
+        let index = Self::ALL.iter().position(|&r| r == self)?;
+        index.checked_sub(1).map(|i| Self::ALL[i])
+    }
+
+    /// The next larger resolution
+    pub fn larger(self) -> Option<Resolution> {
+        let index = Self::ALL.iter().position(|&r| r == self)?;
+        Self::ALL.get(index + 1).copied()
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LightMode {
     Auto,
@@ -78,8 +123,16 @@ pub struct Configuration {
     pub brightness: Brightness,
     pub contrast: Contrast,
     pub special_effect: SpecialEffect,
+    pub quality: u8,
 }
 
+/// JPEG quality scale after reset
+pub const DEFAULT_QUALITY: u8 = 12;
+/// Best JPEG quality scale
+pub const MIN_QUALITY: u8 = 2;
+/// Worst JPEG quality scale
+pub const MAX_QUALITY: u8 = 63;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ConfigurationBuilder {
     image_format: Option<ImageFormat>,
@@ -89,6 +142,7 @@ pub struct ConfigurationBuilder {
     brightness: Option<Brightness>,
     contrast: Option<Contrast>,
     special_effect: Option<SpecialEffect>,
+    quality: Option<u8>,
 }
 
 impl ConfigurationBuilder {
@@ -129,6 +183,11 @@ impl ConfigurationBuilder {
         self
     }
 
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
     pub fn build(&self) -> Configuration {
         let image_format = match self.image_format {
             Some(image_format) => image_format,
@@ -165,6 +224,11 @@ impl ConfigurationBuilder {
             None => SpecialEffect::Normal,
         };
 
+        let quality = match self.quality {
+            Some(quality) => quality,
+            None => DEFAULT_QUALITY,
+        };
+
         Configuration {
             image_format,
             resolution,
@@ -173,6 +237,7 @@ impl ConfigurationBuilder {
             brightness,
             contrast,
             special_effect,
+            quality,
         }
     }
 }
@@ -187,6 +252,7 @@ impl Default for ConfigurationBuilder {
             brightness: None,
             contrast: None,
             special_effect: None,
+            quality: None,
         }
     }
 }
\ No newline at end of file
@@ -10,4 +10,6 @@ pub enum OV2640Error<I2CErr> {
     NoI2cPeripheral,
     I2CError(I2CErr),
     NoSpiPeripheral,
+    /// JPEG quality scale out of range
+    InvalidQuality,
 }
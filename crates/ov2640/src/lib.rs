@@ -7,7 +7,7 @@
 pub mod config;
 pub use config::{
     Brightness, Configuration, ConfigurationBuilder, Contrast, ImageFormat, LightMode, Resolution,
-    Saturation, SpecialEffect,
+    Saturation, SpecialEffect, DEFAULT_QUALITY, MAX_QUALITY, MIN_QUALITY,
 };
 
 pub mod error;
@@ -61,8 +61,8 @@ where
 
     /// Initialize the OV2640 Driver with its configuration
     pub fn init(&mut self, delay: &mut dyn DelayNs) -> Result<(), OV2640Error<I2CErr>> {
+        // the raw formats come in one size only, JPEG gets its resolution here
         self.set_image_format(self.configuration.image_format, delay)?;
-        self.set_resolution(self.configuration.resolution)?;
         self.set_light_mode(self.configuration.light_mode)?;
         self.set_saturation(self.configuration.saturation)?;
         self.set_brightness(self.configuration.brightness)?;
@@ -70,6 +70,11 @@ where
         self.set_special_effect(self.configuration.special_effect)
     }
 
+    /// The configuration as last written to the module
+    pub fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+
     /// Set the configuration of the OV2640 Driver
     pub fn set_configuration(
         &mut self,
@@ -100,8 +105,17 @@ where
                 self.set_resolution(self.configuration.resolution)?;
             }
             ImageFormat::QVGA => self.write_registers(&QVGA_REGISTERS)?,
+            ImageFormat::YUV422 => {
+                self.write_registers(&QVGA_REGISTERS)?;
+                self.write_register(0xFF, 0x00)?;
+                self.write_register(IMAGE_MODE, IMAGE_MODE_YUV422)?;
+            }
         }
         self.configuration.image_format = image_format;
+        if image_format == ImageFormat::JPEG {
+            // the soft reset above restored the default quality scale
+            self.set_quality(self.configuration.quality)?;
+        }
         Ok(())
     }
 
@@ -311,6 +325,45 @@ where
         Ok(())
     }
 
+    /// Set the JPEG quality scale, from 2 (best) to 63 (smallest frames)
+    pub fn set_quality(&mut self, quality: u8) -> Result<(), OV2640Error<I2CErr>> {
+        if !(MIN_QUALITY..=MAX_QUALITY).contains(&quality) {
+            return Err(OV2640Error::InvalidQuality);
+        }
+        if self.configuration.image_format == ImageFormat::JPEG {
+            self.write_register(0xFF, 0x00)?;
+            self.write_register(QS, quality)?;
+        }
+        self.configuration.quality = quality;
+        Ok(())
+    }
+
+    /// Read the current exposure time in line periods (AEC)
+    pub fn exposure(&mut self) -> Result<u16, OV2640Error<I2CErr>> {
+        self.write_register(0xFF, 0x01)?;
+        let reg04 = self.read_register(0x04)?;
+        let aec = self.read_register(0x10)?;
+        let reg45 = self.read_register(0x45)?;
+        Ok(((reg45 as u16 & 0x3F) << 10) | ((aec as u16) << 2) | (reg04 as u16 & 0x03))
+    }
+
+    /// Read the current analog gain (AGC) as the raw 10 bit register value
+    pub fn gain(&mut self) -> Result<u16, OV2640Error<I2CErr>> {
+        self.write_register(0xFF, 0x01)?;
+        let gain = self.read_register(0x00)?;
+        let reg45 = self.read_register(0x45)?;
+        Ok(((reg45 as u16 & 0xC0) << 2) | gain as u16)
+    }
+
+    /// Read a singular register via I2C
+    fn read_register(&mut self, register: u8) -> Result<u8, OV2640Error<I2CErr>> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(I2C_ADDRESS, &[register], &mut value)
+            .map_err(OV2640Error::I2CError)?;
+        Ok(value[0])
+    }
+
     /// Write to a singular register via I2C
     fn write_register(&mut self, register: u8, value: u8) -> Result<(), OV2640Error<I2CErr>> {
         self.i2c
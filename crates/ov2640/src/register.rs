@@ -14,6 +14,11 @@ pub(crate) const FIFO_SIZE_1: u8 = 0x42;
 pub(crate) const FIFO_SIZE_2: u8 = 0x43;
 pub(crate) const FIFO_SIZE_3: u8 = 0x44;
 pub(crate) const TRIGGER: u8 = 0x41;
+/// JPEG quantization scale in the DSP bank
+pub(crate) const QS: u8 = 0x44;
+/// DSP output format in the DSP bank
+pub(crate) const IMAGE_MODE: u8 = 0xDA;
+pub(crate) const IMAGE_MODE_YUV422: u8 = 0x00;
 
 pub(crate) const QVGA_REGISTERS: [[u8; 2]; 194] = [
     [0xff, 0x0],
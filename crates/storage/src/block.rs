@@ -0,0 +1,89 @@
+//!
+//! Block device abstraction
+//!
+
+use alloc::{vec, vec::Vec};
+
+/// Size of a block, the only one SD cards and FAT volumes here use
+pub const BLOCK_LEN: usize = 512;
+
+pub type Block = [u8; BLOCK_LEN];
+
+/// A disk read and written in whole blocks
+#[allow(async_fn_in_trait)]
+pub trait BlockDevice {
+    type Error: defmt::Format;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u32;
+
+    /// Read consecutive blocks starting at `lba`
+    async fn read(&mut self, lba: u32, blocks: &mut [Block]) -> Result<(), Self::Error>;
+
+    /// Write consecutive blocks starting at `lba`
+    async fn write(&mut self, lba: u32, blocks: &[Block]) -> Result<(), Self::Error>;
+}
+
+/// The block range is beyond the end of a [RamDisk]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct OutOfRange;
+
+/// A disk held in memory, or loaded from a disk image
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(blocks: u32) -> Self {
+        Self {
+            data: vec![0; blocks as usize * BLOCK_LEN],
+        }
+    }
+
+    /// Use a disk image, its length is cut down to whole blocks
+    pub fn from_image(mut data: Vec<u8>) -> Self {
+        data.truncate(data.len() / BLOCK_LEN * BLOCK_LEN);
+        Self { data }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, lba: u32, blocks: usize) -> Result<core::ops::Range<usize>, OutOfRange> {
+        let start = lba as usize * BLOCK_LEN;
+        let end = start + blocks * BLOCK_LEN;
+        if end > self.data.len() {
+            return Err(OutOfRange);
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    type Error = OutOfRange;
+
+    fn block_count(&self) -> u32 {
+        (self.data.len() / BLOCK_LEN) as u32
+    }
+
+    async fn read(&mut self, lba: u32, blocks: &mut [Block]) -> Result<(), OutOfRange> {
+        let range = self.range(lba, blocks.len())?;
+        for (block, data) in blocks.iter_mut().zip(self.data[range].chunks(BLOCK_LEN)) {
+            block.copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u32, blocks: &[Block]) -> Result<(), OutOfRange> {
+        let range = self.range(lba, blocks.len())?;
+        for (block, data) in blocks.iter().zip(self.data[range].chunks_mut(BLOCK_LEN)) {
+            data.copy_from_slice(block);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1066 @@
+//!
+//! FAT16 and FAT32 file system
+//!
+//! Short 8.3 names only, long file name entries are skipped when listing and
+//! left alone otherwise. The volume is either the whole device or the first
+//! FAT partition in the MBR. Two sectors are cached, one of the FAT and one of
+//! directory or file data, call [Volume::flush] before the card is removed.
+//!
+
+use alloc::vec::Vec;
+use core::{fmt, slice};
+
+use crate::block::{Block, BlockDevice, BLOCK_LEN};
+
+const ENTRY_LEN: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DELETED: u8 = 0xE5;
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+const PARTITION_TYPES: [u8; 5] = [0x04, 0x06, 0x0B, 0x0C, 0x0E];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FatError<E> {
+    Device(E),
+    /// No FAT boot sector, neither at the start nor in the partition table
+    NoFilesystem,
+    /// FAT12, or sectors other than 512 bytes
+    Unsupported,
+    /// A cluster chain points outside the volume or loops
+    Corrupt,
+    NotFound,
+    Exists,
+    InvalidName,
+    NotADirectory,
+    IsADirectory,
+    DiskFull,
+    /// The fixed FAT16 root directory has no free entry
+    DirectoryFull,
+    /// Files end at 4 GiB
+    FileTooLarge,
+}
+
+impl<E> From<E> for FatError<E> {
+    fn from(error: E) -> Self {
+        FatError::Device(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FatKind {
+    Fat16,
+    Fat32,
+}
+
+/// An 8.3 name as stored in a directory entry, upper case and space padded
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ShortName([u8; 11]);
+
+impl ShortName {
+    /// Parse `NAME.EXT`, lower case letters are turned to upper case
+    pub fn new(name: &str) -> Option<Self> {
+        let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+        if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+            return None;
+        }
+        let mut raw = [b' '; 11];
+        for (i, c) in base.bytes().enumerate() {
+            raw[i] = Self::char(c)?;
+        }
+        for (i, c) in ext.bytes().enumerate() {
+            raw[8 + i] = Self::char(c)?;
+        }
+        Some(Self(raw))
+    }
+
+    fn char(c: u8) -> Option<u8> {
+        match c {
+            b'A'..=b'Z' | b'0'..=b'9' => Some(c),
+            b'a'..=b'z' => Some(c.to_ascii_uppercase()),
+            b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_'
+            | b'`' | b'{' | b'}' | b'~' => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn base(&self) -> &str {
+        trim(&self.0[..8])
+    }
+
+    pub fn extension(&self) -> &str {
+        trim(&self.0[8..])
+    }
+}
+
+fn trim(raw: &[u8]) -> &str {
+    let len = raw.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    core::str::from_utf8(&raw[..len]).unwrap_or("")
+}
+
+impl fmt::Display for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.extension() {
+            "" => write!(f, "{}", self.base()),
+            ext => write!(f, "{}.{}", self.base(), ext),
+        }
+    }
+}
+
+impl fmt::Debug for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl defmt::Format for ShortName {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}.{=str}", self.base(), self.extension())
+    }
+}
+
+/// Date and time as stored in directory entries, local time in 2 s steps
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct FatTime {
+    pub date: u16,
+    pub time: u16,
+}
+
+impl FatTime {
+    /// 1980-01-01 00:00:00, the earliest time FAT can store
+    pub const EPOCH: FatTime = FatTime {
+        date: (1 << 5) | 1,
+        time: 0,
+    };
+
+    /// From seconds since 1970-01-01, in whatever time zone the card should show
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64;
+        let (year, month, day) = civil_from_days(days);
+        if !(1980..=2107).contains(&year) {
+            return Self::EPOCH;
+        }
+        let secs = secs % 86_400;
+        Self {
+            date: (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16,
+            time: (((secs / 3600) as u16) << 11)
+                | ((((secs / 60) % 60) as u16) << 5)
+                | ((secs % 60) / 2) as u16,
+        }
+    }
+
+    /// Seconds since 1970-01-01
+    pub fn to_unix(&self) -> u64 {
+        let year = 1980 + (self.date >> 9) as i64;
+        let month = ((self.date >> 5) & 0x0F).clamp(1, 12) as u32;
+        let day = (self.date & 0x1F).max(1) as u32;
+        let days = days_from_civil(year, month, day) as u64;
+        let time = self.time as u64;
+        days * 86_400 + (time >> 11) * 3600 + ((time >> 5) & 0x3F) * 60 + (time & 0x1F) * 2
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// A directory, identified by its first cluster
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Dir {
+    /// 0 for the fixed FAT16 root directory
+    cluster: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct DirEntry {
+    pub name: ShortName,
+    pub is_dir: bool,
+    pub size: u32,
+    pub modified: FatTime,
+    cluster: u32,
+}
+
+impl DirEntry {
+    fn parse(raw: &[u8]) -> Option<Self> {
+        let attr = raw[11];
+        if raw[0] == 0 || raw[0] == DELETED || raw[0] == b'.' {
+            return None;
+        }
+        if attr == ATTR_LONG_NAME || attr & ATTR_VOLUME_ID != 0 {
+            return None;
+        }
+        let mut name = [0; 11];
+        name.copy_from_slice(&raw[..11]);
+        if name[0] == 0x05 {
+            name[0] = DELETED;
+        }
+        Some(Self {
+            name: ShortName(name),
+            is_dir: attr & ATTR_DIRECTORY != 0,
+            size: u32le(raw, 28),
+            modified: FatTime {
+                date: u16le(raw, 24),
+                time: u16le(raw, 22),
+            },
+            cluster: (u16le(raw, 20) as u32) << 16 | u16le(raw, 26) as u32,
+        })
+    }
+}
+
+/// Where a directory entry is stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+struct Slot {
+    lba: u32,
+    index: usize,
+}
+
+/// An open file, the size on disk is updated by [Volume::flush_file]
+#[derive(Debug, defmt::Format)]
+pub struct File {
+    slot: Slot,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// Cluster number `cluster_index` of the chain, 0 before the first access
+    cluster: u32,
+    cluster_index: u32,
+    dirty: bool,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+
+    /// Move to `pos`, writing past the end leaves a gap of undefined content
+    pub fn seek(&mut self, pos: u32) {
+        self.pos = pos;
+    }
+}
+
+/// One cached sector, written to `copies` places `stride` sectors apart
+struct Cache {
+    lba: Option<u32>,
+    block: Block,
+    dirty: bool,
+    copies: u32,
+    stride: u32,
+}
+
+impl Cache {
+    fn new(copies: u32, stride: u32) -> Self {
+        Self {
+            lba: None,
+            block: [0; BLOCK_LEN],
+            dirty: false,
+            copies,
+            stride,
+        }
+    }
+
+    async fn flush<D: BlockDevice>(&mut self, dev: &mut D) -> Result<(), D::Error> {
+        if let (Some(lba), true) = (self.lba, self.dirty) {
+            for copy in 0..self.copies {
+                dev.write(lba + copy * self.stride, slice::from_ref(&self.block))
+                    .await?;
+            }
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Make `lba` the cached sector, zeroed instead of read if `fresh`
+    async fn load<D: BlockDevice>(
+        &mut self,
+        dev: &mut D,
+        lba: u32,
+        fresh: bool,
+    ) -> Result<(), D::Error> {
+        if self.lba == Some(lba) {
+            return Ok(());
+        }
+        self.flush(dev).await?;
+        self.lba = None;
+        if fresh {
+            self.block = [0; BLOCK_LEN];
+        } else {
+            dev.read(lba, slice::from_mut(&mut self.block)).await?;
+        }
+        self.lba = Some(lba);
+        Ok(())
+    }
+
+    /// Flush or drop the cached sector if it is within `count` sectors from `lba`
+    async fn evict<D: BlockDevice>(
+        &mut self,
+        dev: &mut D,
+        lba: u32,
+        count: u32,
+        flush: bool,
+    ) -> Result<(), D::Error> {
+        if self
+            .lba
+            .is_some_and(|cached| (lba..lba + count).contains(&cached))
+        {
+            if flush {
+                self.flush(dev).await?;
+            }
+            self.lba = None;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Walks the sectors of a directory
+struct DirWalk {
+    /// Next and end sector of the fixed root directory
+    fixed: Option<(u32, u32)>,
+    cluster: u32,
+    sector: u32,
+    steps: u32,
+}
+
+pub struct Volume<D> {
+    dev: D,
+    kind: FatKind,
+    fat_start: u32,
+    root_start: u32,
+    root_sectors: u32,
+    root_cluster: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    clusters: u32,
+    fsinfo: Option<u32>,
+    free: u32,
+    next_free: u32,
+    fat: Cache,
+    data: Cache,
+}
+
+type Result<T, E> = core::result::Result<T, FatError<E>>;
+
+impl<D: BlockDevice> Volume<D> {
+    /// Find the FAT volume and count its free clusters
+    ///
+    /// Counting reads the whole FAT, which takes a few seconds on large cards.
+    pub async fn mount(mut dev: D) -> Result<Self, D::Error> {
+        let mut block = [0; BLOCK_LEN];
+        dev.read(0, slice::from_mut(&mut block)).await?;
+        let mut start = 0;
+        if !is_boot_sector(&block) {
+            let partition = (0..4)
+                .map(|i| &block[446 + i * 16..446 + (i + 1) * 16])
+                .find(|p| PARTITION_TYPES.contains(&p[4]))
+                .map(|p| u32le(p, 8));
+            match partition {
+                Some(lba) if block[510..512] == [0x55, 0xAA] => start = lba,
+                _ => return Err(FatError::NoFilesystem),
+            }
+            dev.read(start, slice::from_mut(&mut block)).await?;
+            if !is_boot_sector(&block) {
+                return Err(FatError::NoFilesystem);
+            }
+        }
+
+        let sectors_per_cluster = block[13] as u32;
+        let reserved = u16le(&block, 14) as u32;
+        let fats = block[16] as u32;
+        let root_entries = u16le(&block, 17) as u32;
+        let total = match u16le(&block, 19) {
+            0 => u32le(&block, 32),
+            total => total as u32,
+        };
+        // FAT32 boot sectors leave the 16 bit FAT size empty
+        let fat32 = u16le(&block, 22) == 0;
+        let fat_len = match fat32 {
+            true => u32le(&block, 36),
+            false => u16le(&block, 22) as u32,
+        };
+        let root_sectors = (root_entries * ENTRY_LEN as u32).div_ceil(BLOCK_LEN as u32);
+        let data_start = reserved + fats * fat_len + root_sectors;
+        if fat_len == 0 || total <= data_start {
+            return Err(FatError::NoFilesystem);
+        }
+        let mut clusters = (total - data_start) / sectors_per_cluster;
+        let kind = match (fat32, clusters) {
+            (true, _) => FatKind::Fat32,
+            (false, 0..4085) => return Err(FatError::Unsupported),
+            (false, _) => FatKind::Fat16,
+        };
+        let entry_len = if kind == FatKind::Fat16 { 2 } else { 4 };
+        clusters = clusters.min(fat_len * BLOCK_LEN as u32 / entry_len - 2);
+
+        let (root_cluster, fsinfo) = match kind {
+            FatKind::Fat16 => (0, None),
+            FatKind::Fat32 => (
+                u32le(&block, 44),
+                Some(u16le(&block, 48) as u32).filter(|&s| s != 0 && s < reserved),
+            ),
+        };
+        let mut volume = Self {
+            dev,
+            kind,
+            fat_start: start + reserved,
+            root_start: start + reserved + fats * fat_len,
+            root_sectors,
+            root_cluster,
+            data_start: start + data_start,
+            sectors_per_cluster,
+            clusters,
+            fsinfo: fsinfo.map(|s| start + s),
+            free: 0,
+            next_free: 2,
+            fat: Cache::new(fats, fat_len),
+            data: Cache::new(1, 0),
+        };
+        if kind == FatKind::Fat32 && !volume.is_cluster(root_cluster) {
+            return Err(FatError::Corrupt);
+        }
+        for cluster in 2..clusters + 2 {
+            if volume.fat_entry(cluster).await? == 0 {
+                volume.free += 1;
+            }
+        }
+        Ok(volume)
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.kind
+    }
+
+    pub fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_LEN as u32
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.clusters as u64 * self.cluster_bytes() as u64
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free as u64 * self.cluster_bytes() as u64
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    /// Give back the device, [Volume::flush] first
+    pub fn into_device(self) -> D {
+        self.dev
+    }
+
+    pub fn root(&self) -> Dir {
+        Dir {
+            cluster: self.root_cluster,
+        }
+    }
+
+    /// Write out the cached sectors and the free cluster count
+    pub async fn flush(&mut self) -> Result<(), D::Error> {
+        if let Some(lba) = self.fsinfo {
+            self.data.load(&mut self.dev, lba, false).await?;
+            let block = &mut self.data.block;
+            if block[..4] == *b"RRaA" && block[484..488] == *b"rrAa" {
+                block[488..492].copy_from_slice(&self.free.to_le_bytes());
+                block[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+                self.data.dirty = true;
+            }
+        }
+        self.data.flush(&mut self.dev).await?;
+        self.fat.flush(&mut self.dev).await?;
+        Ok(())
+    }
+
+    /// All files and directories in `dir`
+    pub async fn list(&mut self, dir: Dir) -> Result<Vec<DirEntry>, D::Error> {
+        let mut entries = Vec::new();
+        let mut walk = self.walk(dir);
+        while let Some(lba) = self.next_dir_sector(&mut walk).await? {
+            self.data.load(&mut self.dev, lba, false).await?;
+            for raw in self.data.block.chunks(ENTRY_LEN) {
+                if raw[0] == 0 {
+                    return Ok(entries);
+                }
+                entries.extend(DirEntry::parse(raw));
+            }
+        }
+        Ok(entries)
+    }
+
+    pub async fn find(&mut self, dir: Dir, name: &ShortName) -> Result<DirEntry, D::Error> {
+        let (_, entry) = self.find_slot(dir, name).await?;
+        Ok(entry)
+    }
+
+    pub async fn open_dir(&mut self, dir: Dir, name: &ShortName) -> Result<Dir, D::Error> {
+        let entry = self.find(dir, name).await?;
+        if !entry.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        Ok(self.dir_at(entry.cluster))
+    }
+
+    pub async fn create_dir(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        time: FatTime,
+    ) -> Result<Dir, D::Error> {
+        self.check_new(dir, name).await?;
+        let cluster = self.alloc(None).await?;
+        self.zero_cluster(cluster).await?;
+        let lba = self.cluster_lba(cluster);
+        self.data.load(&mut self.dev, lba, false).await?;
+        let parent = if dir.cluster == self.root_cluster {
+            0
+        } else {
+            dir.cluster
+        };
+        let block = &mut self.data.block;
+        write_entry(
+            &mut block[..ENTRY_LEN],
+            b".          ",
+            ATTR_DIRECTORY,
+            cluster,
+            time,
+        );
+        write_entry(
+            &mut block[ENTRY_LEN..2 * ENTRY_LEN],
+            b"..         ",
+            ATTR_DIRECTORY,
+            parent,
+            time,
+        );
+        self.data.dirty = true;
+        self.add_entry(dir, name, ATTR_DIRECTORY, cluster, time)
+            .await?;
+        Ok(self.dir_at(cluster))
+    }
+
+    /// Open `name` in `dir`, creating the directory if it does not exist
+    pub async fn open_or_create_dir(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        time: FatTime,
+    ) -> Result<Dir, D::Error> {
+        match self.open_dir(dir, name).await {
+            Err(FatError::NotFound) => self.create_dir(dir, name, time).await,
+            result => result,
+        }
+    }
+
+    pub async fn open(&mut self, dir: Dir, name: &ShortName) -> Result<File, D::Error> {
+        let (slot, entry) = self.find_slot(dir, name).await?;
+        if entry.is_dir {
+            return Err(FatError::IsADirectory);
+        }
+        Ok(File {
+            slot,
+            first_cluster: entry.cluster,
+            size: entry.size,
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+            dirty: false,
+        })
+    }
+
+    /// Create an empty file
+    pub async fn create(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        time: FatTime,
+    ) -> Result<File, D::Error> {
+        self.check_new(dir, name).await?;
+        let slot = self.add_entry(dir, name, ATTR_ARCHIVE, 0, time).await?;
+        Ok(File {
+            slot,
+            first_cluster: 0,
+            size: 0,
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+            dirty: false,
+        })
+    }
+
+    /// Delete a file and free its clusters
+    pub async fn delete(&mut self, dir: Dir, name: &ShortName) -> Result<(), D::Error> {
+        let (slot, entry) = self.find_slot(dir, name).await?;
+        if entry.is_dir {
+            return Err(FatError::IsADirectory);
+        }
+        self.data.load(&mut self.dev, slot.lba, false).await?;
+        self.data.block[slot.index * ENTRY_LEN] = DELETED;
+        self.data.dirty = true;
+        if entry.cluster != 0 {
+            self.free_chain(entry.cluster).await?;
+        }
+        Ok(())
+    }
+
+    /// Read from the current position, returns 0 at the end of the file
+    pub async fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, D::Error> {
+        let len = buf.len().min(file.size.saturating_sub(file.pos) as usize);
+        let mut done = 0;
+        while done < len {
+            let (lba, offset, sectors) = match self.locate(file, false).await? {
+                Some(at) => at,
+                None => return Err(FatError::Corrupt),
+            };
+            let rest = len - done;
+            let n = if offset == 0 && rest >= BLOCK_LEN {
+                let count = sectors.min((rest / BLOCK_LEN) as u32);
+                self.data.evict(&mut self.dev, lba, count, true).await?;
+                let bytes = count as usize * BLOCK_LEN;
+                self.dev
+                    .read(lba, as_blocks_mut(&mut buf[done..done + bytes]))
+                    .await?;
+                bytes
+            } else {
+                self.data.load(&mut self.dev, lba, false).await?;
+                let n = rest.min(BLOCK_LEN - offset);
+                buf[done..done + n].copy_from_slice(&self.data.block[offset..offset + n]);
+                n
+            };
+            done += n;
+            file.pos += n as u32;
+        }
+        Ok(len)
+    }
+
+    /// Write at the current position, growing the file as needed
+    pub async fn write(&mut self, file: &mut File, data: &[u8]) -> Result<(), D::Error> {
+        if file.pos as u64 + data.len() as u64 > u32::MAX as u64 {
+            return Err(FatError::FileTooLarge);
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let Some((lba, offset, sectors)) = self.locate(file, true).await? else {
+                return Err(FatError::Corrupt);
+            };
+            let rest = data.len() - done;
+            let n = if offset == 0 && rest >= BLOCK_LEN {
+                let count = sectors.min((rest / BLOCK_LEN) as u32);
+                self.data.evict(&mut self.dev, lba, count, false).await?;
+                let bytes = count as usize * BLOCK_LEN;
+                self.dev
+                    .write(lba, as_blocks(&data[done..done + bytes]))
+                    .await?;
+                bytes
+            } else {
+                // nothing worth keeping past the end of the file
+                let sector_start = file.pos - offset as u32;
+                let fresh = sector_start >= file.size;
+                self.data.load(&mut self.dev, lba, fresh).await?;
+                let n = rest.min(BLOCK_LEN - offset);
+                self.data.block[offset..offset + n].copy_from_slice(&data[done..done + n]);
+                self.data.dirty = true;
+                n
+            };
+            done += n;
+            file.pos += n as u32;
+            if file.pos > file.size {
+                file.size = file.pos;
+            }
+            file.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Cut the file off at the current position, the clusters past it are
+    /// freed
+    pub async fn truncate(&mut self, file: &mut File) -> Result<(), D::Error> {
+        if file.pos >= file.size {
+            return Ok(());
+        }
+        let keep = file.pos.div_ceil(self.cluster_bytes());
+        if file.first_cluster != 0 {
+            if keep == 0 {
+                self.free_chain(file.first_cluster).await?;
+                file.first_cluster = 0;
+            } else {
+                let mut last = file.first_cluster;
+                for _ in 1..keep {
+                    last = self.next_cluster(last).await?.ok_or(FatError::Corrupt)?;
+                }
+                if let Some(rest) = self.next_cluster(last).await? {
+                    self.set_fat_entry(last, self.end_of_chain()).await?;
+                    self.free_chain(rest).await?;
+                }
+            }
+        }
+        file.size = file.pos;
+        // the chain may be shorter than where the file was
+        file.cluster = 0;
+        file.cluster_index = 0;
+        file.dirty = true;
+        Ok(())
+    }
+
+    /// Store the size and first cluster in the directory entry and flush
+    pub async fn flush_file(&mut self, file: &mut File, time: FatTime) -> Result<(), D::Error> {
+        if file.dirty {
+            self.data.load(&mut self.dev, file.slot.lba, false).await?;
+            let raw = &mut self.data.block[file.slot.index * ENTRY_LEN..][..ENTRY_LEN];
+            raw[20..22].copy_from_slice(&((file.first_cluster >> 16) as u16).to_le_bytes());
+            raw[22..24].copy_from_slice(&time.time.to_le_bytes());
+            raw[24..26].copy_from_slice(&time.date.to_le_bytes());
+            raw[26..28].copy_from_slice(&(file.first_cluster as u16).to_le_bytes());
+            raw[28..32].copy_from_slice(&file.size.to_le_bytes());
+            self.data.dirty = true;
+            file.dirty = false;
+        }
+        self.flush().await
+    }
+
+    /// Sector, offset in it and sectors left in the cluster for the file position
+    async fn locate(
+        &mut self,
+        file: &mut File,
+        allocate: bool,
+    ) -> Result<Option<(u32, usize, u32)>, D::Error> {
+        let cluster_bytes = self.cluster_bytes();
+        let index = file.pos / cluster_bytes;
+        if file.first_cluster == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            file.first_cluster = self.alloc(None).await?;
+            file.dirty = true;
+        }
+        if file.cluster == 0 || index < file.cluster_index {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < index {
+            file.cluster = match self.next_cluster(file.cluster).await? {
+                Some(next) => next,
+                None if allocate => self.alloc(Some(file.cluster)).await?,
+                None => return Ok(None),
+            };
+            file.cluster_index += 1;
+        }
+        let in_cluster = file.pos % cluster_bytes;
+        let sector = in_cluster / BLOCK_LEN as u32;
+        Ok(Some((
+            self.cluster_lba(file.cluster) + sector,
+            (in_cluster % BLOCK_LEN as u32) as usize,
+            self.sectors_per_cluster - sector,
+        )))
+    }
+
+    async fn check_new(&mut self, dir: Dir, name: &ShortName) -> Result<(), D::Error> {
+        match self.find_slot(dir, name).await {
+            Ok(_) => Err(FatError::Exists),
+            Err(FatError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn find_slot(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+    ) -> Result<(Slot, DirEntry), D::Error> {
+        let mut walk = self.walk(dir);
+        while let Some(lba) = self.next_dir_sector(&mut walk).await? {
+            self.data.load(&mut self.dev, lba, false).await?;
+            for (index, raw) in self.data.block.chunks(ENTRY_LEN).enumerate() {
+                if raw[0] == 0 {
+                    return Err(FatError::NotFound);
+                }
+                if let Some(entry) = DirEntry::parse(raw).filter(|e| e.name == *name) {
+                    return Ok((Slot { lba, index }, entry));
+                }
+            }
+        }
+        Err(FatError::NotFound)
+    }
+
+    /// Write a new entry into the first free slot, growing the directory if needed
+    async fn add_entry(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        attr: u8,
+        cluster: u32,
+        time: FatTime,
+    ) -> Result<Slot, D::Error> {
+        let mut walk = self.walk(dir);
+        let mut slot = None;
+        while let Some(lba) = self.next_dir_sector(&mut walk).await? {
+            self.data.load(&mut self.dev, lba, false).await?;
+            let free = self
+                .data
+                .block
+                .chunks(ENTRY_LEN)
+                .position(|raw| raw[0] == 0 || raw[0] == DELETED);
+            if let Some(index) = free {
+                slot = Some(Slot { lba, index });
+                break;
+            }
+        }
+        let slot = match slot {
+            Some(slot) => slot,
+            None if walk.fixed.is_some() => return Err(FatError::DirectoryFull),
+            None => {
+                let cluster = self.alloc(Some(walk.cluster)).await?;
+                self.zero_cluster(cluster).await?;
+                Slot {
+                    lba: self.cluster_lba(cluster),
+                    index: 0,
+                }
+            }
+        };
+        self.data.load(&mut self.dev, slot.lba, false).await?;
+        let raw = &mut self.data.block[slot.index * ENTRY_LEN..][..ENTRY_LEN];
+        write_entry(raw, &name.0, attr, cluster, time);
+        self.data.dirty = true;
+        Ok(slot)
+    }
+
+    fn dir_at(&self, cluster: u32) -> Dir {
+        // `..` entries point at the root as cluster 0, on FAT32 as well
+        Dir {
+            cluster: if cluster == 0 {
+                self.root_cluster
+            } else {
+                cluster
+            },
+        }
+    }
+
+    fn walk(&self, dir: Dir) -> DirWalk {
+        DirWalk {
+            fixed: (dir.cluster == 0)
+                .then_some((self.root_start, self.root_start + self.root_sectors)),
+            cluster: dir.cluster,
+            sector: 0,
+            steps: 0,
+        }
+    }
+
+    async fn next_dir_sector(&mut self, walk: &mut DirWalk) -> Result<Option<u32>, D::Error> {
+        if let Some((next, end)) = walk.fixed.as_mut() {
+            if next == end {
+                return Ok(None);
+            }
+            *next += 1;
+            return Ok(Some(*next - 1));
+        }
+        if walk.sector == self.sectors_per_cluster {
+            match self.next_cluster(walk.cluster).await? {
+                Some(next) => walk.cluster = next,
+                None => return Ok(None),
+            }
+            walk.sector = 0;
+            walk.steps += 1;
+            if walk.steps > self.clusters {
+                return Err(FatError::Corrupt);
+            }
+        }
+        walk.sector += 1;
+        Ok(Some(self.cluster_lba(walk.cluster) + walk.sector - 1))
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    async fn zero_cluster(&mut self, cluster: u32) -> Result<(), D::Error> {
+        let lba = self.cluster_lba(cluster);
+        self.data
+            .evict(&mut self.dev, lba, self.sectors_per_cluster, false)
+            .await?;
+        let zero = [0; BLOCK_LEN];
+        for sector in 0..self.sectors_per_cluster {
+            self.dev.write(lba + sector, slice::from_ref(&zero)).await?;
+        }
+        Ok(())
+    }
+
+    /// Position of the FAT entry of `cluster`, as sector and byte offset
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.kind {
+            FatKind::Fat16 => cluster * 2,
+            FatKind::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + offset / BLOCK_LEN as u32,
+            (offset % BLOCK_LEN as u32) as usize,
+        )
+    }
+
+    async fn fat_entry(&mut self, cluster: u32) -> Result<u32, D::Error> {
+        let (lba, offset) = self.fat_position(cluster);
+        self.fat.load(&mut self.dev, lba, false).await?;
+        Ok(match self.kind {
+            FatKind::Fat16 => u16le(&self.fat.block, offset) as u32,
+            FatKind::Fat32 => u32le(&self.fat.block, offset) & FAT32_MASK,
+        })
+    }
+
+    async fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), D::Error> {
+        let (lba, offset) = self.fat_position(cluster);
+        self.fat.load(&mut self.dev, lba, false).await?;
+        let block = &mut self.fat.block;
+        match self.kind {
+            FatKind::Fat16 => {
+                block[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes())
+            }
+            FatKind::Fat32 => {
+                // the top four bits are reserved and kept
+                let value = u32le(block, offset) & !FAT32_MASK | value & FAT32_MASK;
+                block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.fat.dirty = true;
+        Ok(())
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => FAT32_MASK,
+        }
+    }
+
+    async fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, D::Error> {
+        let next = self.fat_entry(cluster).await?;
+        let end = match self.kind {
+            FatKind::Fat16 => 0xFFF8,
+            FatKind::Fat32 => 0x0FFF_FFF8,
+        };
+        if next >= end {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(FatError::Corrupt)
+        }
+    }
+
+    /// Take a free cluster and append it to the chain ending in `prev`
+    async fn alloc(&mut self, prev: Option<u32>) -> Result<u32, D::Error> {
+        if self.free == 0 {
+            return Err(FatError::DiskFull);
+        }
+        let mut cluster = if self.is_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        for _ in 0..self.clusters {
+            if self.fat_entry(cluster).await? == 0 {
+                self.set_fat_entry(cluster, self.end_of_chain()).await?;
+                if let Some(prev) = prev {
+                    self.set_fat_entry(prev, cluster).await?;
+                }
+                self.free -= 1;
+                self.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+            cluster = if cluster + 1 == self.clusters + 2 {
+                2
+            } else {
+                cluster + 1
+            };
+        }
+        self.free = 0;
+        Err(FatError::DiskFull)
+    }
+
+    async fn free_chain(&mut self, first: u32) -> Result<(), D::Error> {
+        let mut cluster = Some(first);
+        // a chain over every cluster takes one more step to see its end
+        for _ in 0..=self.clusters {
+            let Some(current) = cluster.filter(|&c| self.is_cluster(c)) else {
+                return Ok(());
+            };
+            cluster = self.next_cluster(current).await?;
+            self.set_fat_entry(current, 0).await?;
+            self.free += 1;
+            self.next_free = self.next_free.min(current);
+        }
+        Err(FatError::Corrupt)
+    }
+}
+
+fn is_boot_sector(block: &Block) -> bool {
+    block[510..512] == [0x55, 0xAA]
+        && matches!(block[0], 0xEB | 0xE9)
+        && u16le(block, 11) == BLOCK_LEN as u16
+        && block[13].is_power_of_two()
+        && matches!(block[16], 1 | 2)
+}
+
+fn write_entry(raw: &mut [u8], name: &[u8; 11], attr: u8, cluster: u32, time: FatTime) {
+    raw.fill(0);
+    raw[..11].copy_from_slice(name);
+    raw[11] = attr;
+    raw[14..16].copy_from_slice(&time.time.to_le_bytes());
+    raw[16..18].copy_from_slice(&time.date.to_le_bytes());
+    raw[18..20].copy_from_slice(&time.date.to_le_bytes());
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[22..24].copy_from_slice(&time.time.to_le_bytes());
+    raw[24..26].copy_from_slice(&time.date.to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn u16le(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn as_blocks(data: &[u8]) -> &[Block] {
+    debug_assert_eq!(data.len() % BLOCK_LEN, 0);
+    // SAFETY: a block is a byte array, no alignment or validity requirements
+    unsafe { slice::from_raw_parts(data.as_ptr().cast(), data.len() / BLOCK_LEN) }
+}
+
+fn as_blocks_mut(data: &mut [u8]) -> &mut [Block] {
+    debug_assert_eq!(data.len() % BLOCK_LEN, 0);
+    // SAFETY: as above
+    unsafe { slice::from_raw_parts_mut(data.as_mut_ptr().cast(), data.len() / BLOCK_LEN) }
+}
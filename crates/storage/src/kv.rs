@@ -0,0 +1,535 @@
+//!
+//! Wear-levelled key/value log on NOR flash
+//!
+//! Records are appended to the sectors of a partition in turn, the newest
+//! record of a key wins. When the current sector is full the next one, always
+//! kept erased, takes over, and the records of the oldest sector that are
+//! still current are copied into it before the oldest sector is erased. Every
+//! sector is erased once per trip around the partition.
+//!
+//! A record is one write of an 8 byte header (kind, key length, value length,
+//! CRC-32) followed by the key and the value. One torn by a power loss fails
+//! its CRC and is skipped. A rotation cut short is finished before anything
+//! else is written, the oldest sector is only erased once all of its current
+//! records are copied.
+//!
+//! The format is this crate's own, not ESP-IDF NVS, even though the partition
+//! table gives the `config` partition the `nvs` subtype.
+//!
+
+use crate::block::OutOfRange;
+use alloc::{vec, vec::Vec};
+
+/// Erase unit of the flash
+pub const SECTOR_LEN: u32 = 4096;
+/// Longest key
+pub const MAX_KEY_LEN: usize = 32;
+/// Longest value
+pub const MAX_VALUE_LEN: usize = 512;
+
+const SECTOR_MAGIC: [u8; 4] = *b"ECKV";
+const SECTOR_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: usize = 8;
+const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + MAX_KEY_LEN + MAX_VALUE_LEN;
+const KIND_VALUE: u8 = 0x56;
+const KIND_REMOVED: u8 = 0x44;
+const ERASED: u8 = 0xFF;
+
+/// NOR flash erased in [SECTOR_LEN] sectors and written in 4 byte words
+pub trait Flash {
+    type Error: defmt::Format;
+
+    /// Size in bytes, a multiple of [SECTOR_LEN]
+    fn capacity(&self) -> u32;
+
+    /// Read at any offset
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Program `data` at `offset`, both 4 byte aligned. Bits only go from 1
+    /// to 0.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Set the sector starting at `offset` to 0xFF
+    fn erase(&mut self, offset: u32) -> Result<(), Self::Error>;
+}
+
+/// Flash held in memory, programming only clears bits like NOR flash does
+pub struct RamFlash {
+    data: Vec<u8>,
+}
+
+impl RamFlash {
+    /// An erased flash of `sectors` sectors
+    pub fn new(sectors: u32) -> Self {
+        Self {
+            data: vec![ERASED; (sectors * SECTOR_LEN) as usize],
+        }
+    }
+
+    /// Use a flash image, its length is cut down to whole sectors
+    pub fn from_image(mut data: Vec<u8>) -> Self {
+        data.truncate(data.len() / SECTOR_LEN as usize * SECTOR_LEN as usize);
+        Self { data }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<core::ops::Range<usize>, OutOfRange> {
+        let start = offset as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(OutOfRange),
+        }
+    }
+}
+
+impl Flash for RamFlash {
+    type Error = OutOfRange;
+
+    fn capacity(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), OutOfRange> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), OutOfRange> {
+        let range = self.range(offset, data.len())?;
+        for (byte, new) in self.data[range].iter_mut().zip(data) {
+            *byte &= new;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: u32) -> Result<(), OutOfRange> {
+        let range = self.range(offset, SECTOR_LEN as usize)?;
+        self.data[range].fill(ERASED);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum KvError<E> {
+    Flash(E),
+    /// Empty or longer than [MAX_KEY_LEN]
+    BadKey,
+    /// Longer than [MAX_VALUE_LEN]
+    ValueTooLarge,
+    /// The buffer can't hold the value, which is this long
+    BufferTooSmall(usize),
+    /// The current records don't fit into one sector
+    Full,
+    /// The partition has fewer than 2 sectors
+    TooSmall,
+}
+
+impl<E> From<E> for KvError<E> {
+    fn from(e: E) -> Self {
+        KvError::Flash(e)
+    }
+}
+
+/// A record header as found in a sector
+#[derive(Clone, Copy)]
+struct Record {
+    offset: u32,
+    kind: u8,
+    key_len: usize,
+    value_len: usize,
+    crc: u32,
+}
+
+impl Record {
+    fn parse(offset: u32, header: &[u8; RECORD_HEADER_LEN]) -> Option<Self> {
+        let record = Record {
+            offset,
+            kind: header[0],
+            key_len: header[1] as usize,
+            value_len: u16::from_le_bytes([header[2], header[3]]) as usize,
+            crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        };
+        let valid = matches!(record.kind, KIND_VALUE | KIND_REMOVED)
+            && (1..=MAX_KEY_LEN).contains(&record.key_len)
+            && record.value_len <= MAX_VALUE_LEN
+            && offset % SECTOR_LEN + record.len() <= SECTOR_LEN;
+        valid.then_some(record)
+    }
+
+    /// Bytes taken in the sector, padded to whole words
+    fn len(&self) -> u32 {
+        record_len(self.key_len, self.value_len)
+    }
+}
+
+fn record_len(key_len: usize, value_len: usize) -> u32 {
+    ((RECORD_HEADER_LEN + key_len + value_len + 3) & !3) as u32
+}
+
+/// Key/value store on a [Flash] partition
+pub struct Store<F: Flash> {
+    flash: F,
+    sectors: u32,
+    /// Sector new records go to
+    active: u32,
+    /// Sequence number of the active sector
+    seq: u32,
+    /// Offset of the next record in the partition
+    write_pos: u32,
+    /// Record read from flash
+    buf: Vec<u8>,
+}
+
+impl<F: Flash> Store<F> {
+    /// Open the store on `flash`, a partition without any sector of the log
+    /// is formatted
+    pub fn mount(flash: F) -> Result<Self, KvError<F::Error>> {
+        let sectors = flash.capacity() / SECTOR_LEN;
+        if sectors < 2 {
+            return Err(KvError::TooSmall);
+        }
+        let mut store = Store {
+            flash,
+            sectors,
+            active: 0,
+            seq: 0,
+            write_pos: 0,
+            buf: vec![0; MAX_RECORD_LEN],
+        };
+        let mut newest = None;
+        for sector in 0..sectors {
+            if let Some(seq) = store.sector_seq(sector)? {
+                match newest {
+                    Some((_, newest_seq)) if newest_seq >= seq => {}
+                    _ => newest = Some((sector, seq)),
+                }
+            }
+        }
+        match newest {
+            Some((sector, seq)) => {
+                store.active = sector;
+                store.seq = seq;
+                store.write_pos = store.end_of(sector)?;
+                store.finish_rotation()?;
+            }
+            None => {
+                store.flash.erase(0)?;
+                store.start_sector(0, 1)?;
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Copy the value of `key` into `buf`, returns its length or `None` if
+    /// there is no such key
+    pub fn get(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, KvError<F::Error>> {
+        check_key(key)?;
+        let Some(record) = self.find(key)? else {
+            return Ok(None);
+        };
+        if record.kind == KIND_REMOVED {
+            return Ok(None);
+        }
+        let value = buf
+            .get_mut(..record.value_len)
+            .ok_or(KvError::BufferTooSmall(record.value_len))?;
+        self.flash.read(
+            record.offset + (RECORD_HEADER_LEN + record.key_len) as u32,
+            value,
+        )?;
+        Ok(Some(record.value_len))
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        check_key(key)?;
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLarge);
+        }
+        self.append(KIND_VALUE, key.as_bytes(), value)
+    }
+
+    /// Remove `key`, nothing happens if there is no such key
+    pub fn remove(&mut self, key: &str) -> Result<(), KvError<F::Error>> {
+        check_key(key)?;
+        match self.find(key)? {
+            Some(record) if record.kind == KIND_VALUE => {
+                self.append(KIND_REMOVED, key.as_bytes(), &[])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, kind: u8, key: &[u8], value: &[u8]) -> Result<(), KvError<F::Error>> {
+        // the active sector only takes new records once the rotation into it
+        // is complete
+        self.finish_rotation()?;
+        let len = record_len(key.len(), value.len());
+        let mut rotations = 0;
+        while self.room() < len {
+            // every sector went around once without making room
+            if rotations == self.sectors {
+                return Err(KvError::Full);
+            }
+            self.rotate()?;
+            rotations += 1;
+        }
+        self.write_record(kind, key, value)
+    }
+
+    fn write_record(
+        &mut self,
+        kind: u8,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), KvError<F::Error>> {
+        let len = record_len(key.len(), value.len()) as usize;
+        let buf = &mut self.buf[..len];
+        buf.fill(ERASED);
+        buf[0] = kind;
+        buf[1] = key.len() as u8;
+        buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buf[8..8 + key.len()].copy_from_slice(key);
+        buf[8 + key.len()..8 + key.len() + value.len()].copy_from_slice(value);
+        let crc = record_crc(&buf[..4], &buf[8..8 + key.len() + value.len()]);
+        buf[4..8].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(self.write_pos, buf)?;
+        self.write_pos += len as u32;
+        Ok(())
+    }
+
+    /// Move on to the erased sector after the active one, then free the
+    /// oldest sector
+    fn rotate(&mut self) -> Result<(), KvError<F::Error>> {
+        let next = self.next(self.active);
+        let oldest = self.next(next);
+        let live = match self.sector_seq(oldest)? {
+            Some(_) => Some(self.live_records(oldest)?),
+            None => None,
+        };
+        // nothing is erased unless the oldest sector's records fit the new one
+        if live.as_deref().map_or(0, len_of) > SECTOR_LEN - SECTOR_HEADER_LEN {
+            return Err(KvError::Full);
+        }
+        self.flash.erase(next * SECTOR_LEN)?;
+        self.start_sector(next, self.seq.wrapping_add(1))?;
+        if let Some(live) = live {
+            self.retire(oldest, &live)?;
+        }
+        Ok(())
+    }
+
+    /// Retire the sector after the active one if a rotation stopped before it
+    /// was erased
+    fn finish_rotation(&mut self) -> Result<(), KvError<F::Error>> {
+        let next = self.next(self.active);
+        if self.sector_seq(next)?.is_none() {
+            return Ok(());
+        }
+        let live = self.live_records(next)?;
+        match self.retire(next, &live) {
+            // a torn copy left no room for the rest. The active sector holds
+            // nothing but copies, as the sector they come from is only erased
+            // once all of them are made, so the copying starts over.
+            Err(KvError::Full) => {
+                self.flash.erase(self.active * SECTOR_LEN)?;
+                self.start_sector(self.active, self.seq)?;
+                let live = self.live_records(next)?;
+                self.retire(next, &live)
+            }
+            result => result,
+        }
+    }
+
+    /// Copy `live`, the current records of `sector`, to the active sector and
+    /// erase it
+    ///
+    /// Fails with [KvError::Full] before copying anything if they don't all
+    /// fit, the sector is left as it is then.
+    fn retire(&mut self, sector: u32, live: &[Record]) -> Result<(), KvError<F::Error>> {
+        if len_of(live) > self.room() {
+            return Err(KvError::Full);
+        }
+        for record in live {
+            self.verify(record)?;
+            let len = record.len();
+            let data_len = RECORD_HEADER_LEN + record.key_len + record.value_len;
+            let padded = &mut self.buf[..len as usize];
+            padded[data_len..].fill(ERASED);
+            self.flash.write(self.write_pos, padded)?;
+            self.write_pos += len;
+        }
+        self.flash.erase(sector * SECTOR_LEN)?;
+        Ok(())
+    }
+
+    /// The records of `sector` that are the current one of their key, in
+    /// the order they are in it
+    ///
+    /// The newest intact record of every key is found in one pass over the
+    /// log. Removed keys have nothing to copy, the oldest sector has nothing
+    /// older for them to hide.
+    fn live_records(&mut self, sector: u32) -> Result<Vec<Record>, F::Error> {
+        let mut latest: Vec<([u8; MAX_KEY_LEN], Record)> = Vec::new();
+        for age in 0..self.sectors {
+            let current = (self.active + 1 + age) % self.sectors;
+            if self.sector_seq(current)?.is_none() {
+                continue;
+            }
+            let mut offset = current * SECTOR_LEN + SECTOR_HEADER_LEN;
+            while let Some(record) = self.record_at(current, offset)? {
+                offset += record.len();
+                if !self.verify(&record)? {
+                    continue;
+                }
+                let mut key = [0; MAX_KEY_LEN];
+                key[..record.key_len].copy_from_slice(&self.buf[8..8 + record.key_len]);
+                let same_key = |(k, r): &&mut ([u8; MAX_KEY_LEN], Record)| {
+                    r.key_len == record.key_len && *k == key
+                };
+                match latest.iter_mut().find(same_key) {
+                    Some(entry) => entry.1 = record,
+                    None => latest.push((key, record)),
+                }
+            }
+        }
+        let range = sector * SECTOR_LEN..(sector + 1) * SECTOR_LEN;
+        let mut live: Vec<Record> = latest
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.kind == KIND_VALUE && range.contains(&record.offset))
+            .collect();
+        live.sort_unstable_by_key(|record| record.offset);
+        Ok(live)
+    }
+
+    fn start_sector(&mut self, sector: u32, seq: u32) -> Result<(), KvError<F::Error>> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        header[..4].copy_from_slice(&SECTOR_MAGIC);
+        header[4..].copy_from_slice(&seq.to_le_bytes());
+        self.flash.write(sector * SECTOR_LEN, &header)?;
+        self.active = sector;
+        self.seq = seq;
+        self.write_pos = sector * SECTOR_LEN + SECTOR_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Bytes left in the active sector
+    fn room(&self) -> u32 {
+        (self.active + 1) * SECTOR_LEN - self.write_pos
+    }
+
+    fn next(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+
+    /// Sequence number of a sector of the log, `None` for erased or foreign
+    /// sectors
+    fn sector_seq(&mut self, sector: u32) -> Result<Option<u32>, F::Error> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        self.flash.read(sector * SECTOR_LEN, &mut header)?;
+        let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        // an erased sequence number is a header cut short
+        if header[..4] != SECTOR_MAGIC || seq == u32::MAX {
+            return Ok(None);
+        }
+        Ok(Some(seq))
+    }
+
+    /// Header of the record at `offset` of `sector`, `None` at the end of
+    /// the sector's records
+    fn record_at(&mut self, sector: u32, offset: u32) -> Result<Option<Record>, F::Error> {
+        if offset + RECORD_HEADER_LEN as u32 > (sector + 1) * SECTOR_LEN {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.flash.read(offset, &mut header)?;
+        Ok(Record::parse(offset, &header))
+    }
+
+    /// Where the next record of `sector` goes, the end of the sector if it
+    /// holds something that isn't a record
+    fn end_of(&mut self, sector: u32) -> Result<u32, F::Error> {
+        let mut offset = sector * SECTOR_LEN + SECTOR_HEADER_LEN;
+        while let Some(record) = self.record_at(sector, offset)? {
+            offset += record.len();
+        }
+        // whatever follows the records has to be erased to be written
+        let end = (sector + 1) * SECTOR_LEN;
+        let mut probe = offset;
+        while probe < end {
+            let chunk = &mut self.buf[..(end - probe).min(MAX_RECORD_LEN as u32) as usize];
+            self.flash.read(probe, chunk)?;
+            if chunk.iter().any(|&b| b != ERASED) {
+                return Ok(end);
+            }
+            probe += chunk.len() as u32;
+        }
+        Ok(offset)
+    }
+
+    /// Read a whole record into `buf` and check its CRC
+    fn verify(&mut self, record: &Record) -> Result<bool, F::Error> {
+        let len = RECORD_HEADER_LEN + record.key_len + record.value_len;
+        self.flash.read(record.offset, &mut self.buf[..len])?;
+        Ok(record_crc(&self.buf[..4], &self.buf[8..len]) == record.crc)
+    }
+
+    /// The newest intact record of `key`
+    fn find(&mut self, key: &str) -> Result<Option<Record>, F::Error> {
+        let key = key.as_bytes();
+        let mut found = None;
+        for age in 0..self.sectors {
+            let sector = (self.active + 1 + age) % self.sectors;
+            if self.sector_seq(sector)?.is_none() {
+                continue;
+            }
+            let mut offset = sector * SECTOR_LEN + SECTOR_HEADER_LEN;
+            while let Some(record) = self.record_at(sector, offset)? {
+                offset += record.len();
+                if record.key_len != key.len() || !self.verify(&record)? {
+                    continue;
+                }
+                if &self.buf[8..8 + key.len()] == key {
+                    found = Some(record);
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Bytes `records` take in a sector
+fn len_of(records: &[Record]) -> u32 {
+    records.iter().map(Record::len).sum()
+}
+
+fn check_key<E>(key: &str) -> Result<(), KvError<E>> {
+    match key.len() {
+        1..=MAX_KEY_LEN => Ok(()),
+        _ => Err(KvError::BadKey),
+    }
+}
+
+/// CRC-32 (IEEE) of the first header word and the key and value
+fn record_crc(header: &[u8], data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in header.iter().chain(data) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
@@ -0,0 +1,21 @@
+//!
+//! Block storage and FAT file system for SD card recording, and a key/value
+//! store on NOR flash for settings
+//!
+//! The file system only sees a [block::BlockDevice], so it runs the same on
+//! an SD card and on a disk image held in memory.
+//!
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod block;
+pub mod fat;
+pub mod kv;
+pub mod sdcard;
+
+pub use block::{Block, BlockDevice, RamDisk, BLOCK_LEN};
+pub use fat::{Dir, DirEntry, FatError, FatKind, FatTime, File, ShortName, Volume};
+pub use kv::{Flash, KvError, RamFlash, Store};
+pub use sdcard::{CardKind, SdCard, SdError};
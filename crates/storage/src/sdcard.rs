@@ -0,0 +1,391 @@
+//!
+//! SD card in SPI mode
+//!
+//! Initialize at 100-400 kHz, [SdCard::init] leaves the card ready for the full
+//! bus speed of 25 MHz. Standard (SDSC) and high capacity (SDHC/SDXC) cards
+//! are supported, CRCs are off as usual in SPI mode.
+//!
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::{delay::DelayNs, spi::SpiBus};
+
+use crate::block::{Block, BlockDevice, BLOCK_LEN};
+
+const CMD0_GO_IDLE_STATE: u8 = 0;
+const CMD8_SEND_IF_COND: u8 = 8;
+const CMD9_SEND_CSD: u8 = 9;
+const CMD12_STOP_TRANSMISSION: u8 = 12;
+const CMD16_SET_BLOCKLEN: u8 = 16;
+const CMD17_READ_SINGLE_BLOCK: u8 = 17;
+const CMD18_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD24_WRITE_BLOCK: u8 = 24;
+const CMD25_WRITE_MULTIPLE_BLOCK: u8 = 25;
+const CMD55_APP_CMD: u8 = 55;
+const CMD58_READ_OCR: u8 = 58;
+const ACMD41_SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const TOKEN_START_BLOCK: u8 = 0xFE;
+const TOKEN_START_MULTI_WRITE: u8 = 0xFC;
+const TOKEN_STOP_MULTI_WRITE: u8 = 0xFD;
+const OCR_CCS: u32 = 1 << 30;
+
+/// Polls of the bus while waiting for a data token
+const READ_POLLS: u32 = 100_000;
+/// How long the card may stay busy after a write
+const BUSY_TIMEOUT_MS: u32 = 500;
+/// How long the card may take to leave the idle state
+const INIT_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SdError<E> {
+    Spi(E),
+    ChipSelect,
+    /// The card did not answer, or stayed busy
+    Timeout,
+    /// A command was answered with an error, as command and R1 response
+    Command(u8, u8),
+    /// Not an SD card, or a voltage range it can't run at
+    Unsupported,
+    /// Data was rejected, as the data response token
+    Write(u8),
+    /// A read ended with an error token
+    Read(u8),
+    NotInitialized,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CardKind {
+    /// Byte addressed, up to 2 GB
+    Sdsc,
+    /// Block addressed
+    Sdhc,
+}
+
+pub struct SdCard<SPI, CS, DELAY> {
+    spi: SPI,
+    cs: CS,
+    delay: DELAY,
+    kind: Option<CardKind>,
+    blocks: u32,
+}
+
+impl<SPI, CS, DELAY> SdCard<SPI, CS, DELAY>
+where
+    SPI: SpiBus,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> Self {
+        Self {
+            spi,
+            cs,
+            delay,
+            kind: None,
+            blocks: 0,
+        }
+    }
+
+    /// The bus, to raise its clock after [SdCard::init]
+    pub fn spi(&mut self) -> &mut SPI {
+        &mut self.spi
+    }
+
+    pub fn kind(&self) -> Option<CardKind> {
+        self.kind
+    }
+
+    /// Bring the card from power up into data transfer mode
+    pub async fn init(&mut self) -> Result<CardKind, SdError<SPI::Error>> {
+        self.kind = None;
+        // at least 74 clocks with the card deselected
+        self.cs.set_high().map_err(|_| SdError::ChipSelect)?;
+        self.spi.write(&[0xFF; 10]).await.map_err(SdError::Spi)?;
+
+        self.select()?;
+        let result = self.init_selected().await;
+        self.deselect().await?;
+        let kind = result?;
+        self.kind = Some(kind);
+        Ok(kind)
+    }
+
+    async fn init_selected(&mut self) -> Result<CardKind, SdError<SPI::Error>> {
+        let mut idle = false;
+        for _ in 0..10 {
+            if self.command(CMD0_GO_IDLE_STATE, 0).await? == R1_IDLE {
+                idle = true;
+                break;
+            }
+        }
+        if !idle {
+            return Err(SdError::Timeout);
+        }
+
+        // 2.7-3.6 V and a check pattern, only version 2 cards know the command
+        let version2 = match self.command(CMD8_SEND_IF_COND, 0x1AA).await? {
+            r1 if r1 & R1_ILLEGAL_COMMAND != 0 => false,
+            _ => {
+                let mut r7 = [0xFF; 4];
+                self.transfer(&mut r7).await?;
+                if r7[3] != 0xAA {
+                    return Err(SdError::Unsupported);
+                }
+                true
+            }
+        };
+
+        let hcs = if version2 { OCR_CCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_TIMEOUT_MS / 10 {
+            let r1 = self.app_command(ACMD41_SD_SEND_OP_COND, hcs).await?;
+            if r1 == 0 {
+                ready = true;
+                break;
+            }
+            if r1 & R1_ILLEGAL_COMMAND != 0 {
+                return Err(SdError::Unsupported);
+            }
+            self.delay.delay_ms(10).await;
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        let mut kind = CardKind::Sdsc;
+        if version2 {
+            self.expect(CMD58_READ_OCR, 0).await?;
+            let mut ocr = [0xFF; 4];
+            self.transfer(&mut ocr).await?;
+            if u32::from_be_bytes(ocr) & OCR_CCS != 0 {
+                kind = CardKind::Sdhc;
+            }
+        }
+        if kind == CardKind::Sdsc {
+            self.expect(CMD16_SET_BLOCKLEN, BLOCK_LEN as u32).await?;
+        }
+
+        self.expect(CMD9_SEND_CSD, 0).await?;
+        let mut csd = [0xFF; 16];
+        self.read_data(&mut csd).await?;
+        self.blocks = capacity(&csd);
+        Ok(kind)
+    }
+
+    fn select(&mut self) -> Result<(), SdError<SPI::Error>> {
+        self.cs.set_low().map_err(|_| SdError::ChipSelect)
+    }
+
+    async fn deselect(&mut self) -> Result<(), SdError<SPI::Error>> {
+        self.cs.set_high().map_err(|_| SdError::ChipSelect)?;
+        // one more byte so the card releases the data line
+        self.spi.write(&[0xFF]).await.map_err(SdError::Spi)
+    }
+
+    async fn transfer(&mut self, buf: &mut [u8]) -> Result<(), SdError<SPI::Error>> {
+        buf.fill(0xFF);
+        self.spi.transfer_in_place(buf).await.map_err(SdError::Spi)
+    }
+
+    async fn byte(&mut self) -> Result<u8, SdError<SPI::Error>> {
+        let mut byte = [0xFF];
+        self.transfer(&mut byte).await?;
+        Ok(byte[0])
+    }
+
+    /// Wait until the card releases the data line after a write
+    async fn wait_ready(&mut self) -> Result<(), SdError<SPI::Error>> {
+        for _ in 0..BUSY_TIMEOUT_MS {
+            for _ in 0..100 {
+                if self.byte().await? == 0xFF {
+                    return Ok(());
+                }
+            }
+            self.delay.delay_ms(1).await;
+        }
+        Err(SdError::Timeout)
+    }
+
+    /// Send a command and return its R1 response
+    async fn command(&mut self, cmd: u8, arg: u32) -> Result<u8, SdError<SPI::Error>> {
+        if cmd != CMD0_GO_IDLE_STATE {
+            self.wait_ready().await?;
+        }
+        let crc = match cmd {
+            CMD0_GO_IDLE_STATE => 0x95,
+            CMD8_SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        let arg = arg.to_be_bytes();
+        let frame = [0x40 | cmd, arg[0], arg[1], arg[2], arg[3], crc];
+        self.spi.write(&frame).await.map_err(SdError::Spi)?;
+        if cmd == CMD12_STOP_TRANSMISSION {
+            // stuff byte
+            self.byte().await?;
+        }
+        for _ in 0..10 {
+            let r1 = self.byte().await?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    /// Send a command that has to be answered with R1 0
+    async fn expect(&mut self, cmd: u8, arg: u32) -> Result<(), SdError<SPI::Error>> {
+        match self.command(cmd, arg).await? {
+            0 => Ok(()),
+            r1 => Err(SdError::Command(cmd, r1)),
+        }
+    }
+
+    async fn app_command(&mut self, cmd: u8, arg: u32) -> Result<u8, SdError<SPI::Error>> {
+        self.command(CMD55_APP_CMD, 0).await?;
+        self.command(cmd, arg).await
+    }
+
+    /// Wait for the start token, then read a data block and skip its CRC
+    async fn read_data(&mut self, buf: &mut [u8]) -> Result<(), SdError<SPI::Error>> {
+        let mut token = 0xFF;
+        for _ in 0..READ_POLLS {
+            token = self.byte().await?;
+            if token != 0xFF {
+                break;
+            }
+        }
+        match token {
+            TOKEN_START_BLOCK => {}
+            0xFF => return Err(SdError::Timeout),
+            token => return Err(SdError::Read(token)),
+        }
+        self.transfer(buf).await?;
+        let mut crc = [0xFF; 2];
+        self.transfer(&mut crc).await
+    }
+
+    async fn write_data(&mut self, token: u8, block: &Block) -> Result<(), SdError<SPI::Error>> {
+        self.spi.write(&[0xFF, token]).await.map_err(SdError::Spi)?;
+        self.spi.write(block).await.map_err(SdError::Spi)?;
+        self.spi.write(&[0xFF, 0xFF]).await.map_err(SdError::Spi)?;
+        let response = self.byte().await?;
+        if response & 0x1F != 0x05 {
+            return Err(SdError::Write(response));
+        }
+        self.wait_ready().await
+    }
+
+    async fn read_selected(
+        &mut self,
+        address: u32,
+        blocks: &mut [Block],
+    ) -> Result<(), SdError<SPI::Error>> {
+        match blocks {
+            [] => Ok(()),
+            [block] => {
+                self.expect(CMD17_READ_SINGLE_BLOCK, address).await?;
+                self.read_data(block).await
+            }
+            blocks => {
+                self.expect(CMD18_READ_MULTIPLE_BLOCK, address).await?;
+                let mut result = Ok(());
+                for block in blocks.iter_mut() {
+                    result = self.read_data(block).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                self.command(CMD12_STOP_TRANSMISSION, 0).await?;
+                result
+            }
+        }
+    }
+
+    async fn write_selected(
+        &mut self,
+        address: u32,
+        blocks: &[Block],
+    ) -> Result<(), SdError<SPI::Error>> {
+        match blocks {
+            [] => Ok(()),
+            [block] => {
+                self.expect(CMD24_WRITE_BLOCK, address).await?;
+                self.write_data(TOKEN_START_BLOCK, block).await
+            }
+            blocks => {
+                self.expect(CMD25_WRITE_MULTIPLE_BLOCK, address).await?;
+                let mut result = Ok(());
+                for block in blocks {
+                    result = self.write_data(TOKEN_START_MULTI_WRITE, block).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                self.spi
+                    .write(&[TOKEN_STOP_MULTI_WRITE])
+                    .await
+                    .map_err(SdError::Spi)?;
+                self.byte().await?;
+                self.wait_ready().await?;
+                result
+            }
+        }
+    }
+
+    fn address(&self, lba: u32) -> Result<u32, SdError<SPI::Error>> {
+        match self.kind {
+            Some(CardKind::Sdhc) => Ok(lba),
+            Some(CardKind::Sdsc) => Ok(lba * BLOCK_LEN as u32),
+            None => Err(SdError::NotInitialized),
+        }
+    }
+}
+
+impl<SPI, CS, DELAY> BlockDevice for SdCard<SPI, CS, DELAY>
+where
+    SPI: SpiBus,
+    SPI::Error: defmt::Format,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    type Error = SdError<SPI::Error>;
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    async fn read(&mut self, lba: u32, blocks: &mut [Block]) -> Result<(), Self::Error> {
+        let address = self.address(lba)?;
+        self.select()?;
+        let result = self.read_selected(address, blocks).await;
+        self.deselect().await?;
+        result
+    }
+
+    async fn write(&mut self, lba: u32, blocks: &[Block]) -> Result<(), Self::Error> {
+        let address = self.address(lba)?;
+        self.select()?;
+        let result = self.write_selected(address, blocks).await;
+        self.deselect().await?;
+        result
+    }
+}
+
+/// Number of 512 byte blocks from the CSD register
+fn capacity(csd: &[u8; 16]) -> u32 {
+    match csd[0] >> 6 {
+        // CSD version 2, SDHC and SDXC
+        1 => {
+            let c_size = ((csd[7] as u32 & 0x3F) << 16) | ((csd[8] as u32) << 8) | csd[9] as u32;
+            (c_size + 1).saturating_mul(1024)
+        }
+        _ => {
+            let read_bl_len = (csd[5] & 0x0F) as u32;
+            let c_size =
+                ((csd[6] as u32 & 0x03) << 10) | ((csd[7] as u32) << 2) | ((csd[8] as u32) >> 6);
+            let c_size_mult = ((csd[9] as u32 & 0x03) << 1) | ((csd[10] as u32) >> 7);
+            ((c_size + 1) << (c_size_mult + 2) << read_bl_len) / BLOCK_LEN as u32
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    io::{Cursor, Read, Write},
+    process::Command,
+};
+
+use embassy_futures::block_on;
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use storage::{BlockDevice, FatError, FatKind, FatTime, RamDisk, ShortName, Volume, BLOCK_LEN};
+
+fn name(name: &str) -> ShortName {
+    ShortName::new(name).unwrap()
+}
+
+fn time() -> FatTime {
+    FatTime::from_unix(1_700_000_000)
+}
+
+fn pattern(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(2_654_435_761) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// A volume formatted by the fatfs crate
+fn fatfs_image(kind: FatKind, len: usize, cluster: u32) -> Vec<u8> {
+    let fat_type = match kind {
+        FatKind::Fat16 => FatType::Fat16,
+        FatKind::Fat32 => FatType::Fat32,
+    };
+    let mut image = vec![0; len];
+    let options = FormatVolumeOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(cluster);
+    fatfs::format_volume(Cursor::new(&mut image), options).unwrap();
+    image
+}
+
+/// A volume formatted by mkfs.fat, `None` when it isn't installed
+fn mkfs_image(kind: FatKind, len: usize) -> Option<Vec<u8>> {
+    let path = std::env::temp_dir().join(format!("storage-{}-{:?}.img", std::process::id(), kind));
+    let _ = std::fs::remove_file(&path);
+    let bits = match kind {
+        FatKind::Fat16 => "16",
+        FatKind::Fat32 => "32",
+    };
+    let status = Command::new("mkfs.fat")
+        .args(["-C", "-F", bits, "-S", "512"])
+        .arg(&path)
+        .arg((len / 1024).to_string())
+        .output()
+        .ok()?
+        .status;
+    assert!(status.success(), "mkfs.fat failed");
+    let image = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Some(image)
+}
+
+async fn read_all(volume: &mut Volume<RamDisk>, dir: storage::Dir, file: &str) -> Vec<u8> {
+    let mut file = volume.open(dir, &name(file)).await.unwrap();
+    let mut data = Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        let n = volume.read(&mut file, &mut buf).await.unwrap();
+        if n == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+async fn remount(volume: Volume<RamDisk>) -> Volume<RamDisk> {
+    Volume::mount(volume.into_device()).await.unwrap()
+}
+
+/// Create, write, read back, delete and remount, then check the result with
+/// fatfs, which finds the volume `start` bytes into the image
+fn exercise(image: Vec<u8>, start: usize, kind: FatKind) {
+    let (mut image, free_clusters, still) = block_on(async {
+        let mut volume = Volume::mount(RamDisk::from_image(image)).await.unwrap();
+        assert_eq!(volume.kind(), kind);
+        let cluster = volume.cluster_bytes() as usize;
+        let empty = volume.free_bytes();
+        let root = volume.root();
+
+        let dir = volume
+            .create_dir(root, &name("DCIM"), time())
+            .await
+            .unwrap();
+        // small writes that straddle sectors and clusters
+        let clip = pattern(cluster * 3 + cluster / 2 + 7, 1);
+        let mut file = volume
+            .create(dir, &name("VID00001.AVI"), time())
+            .await
+            .unwrap();
+        for chunk in clip.chunks(700) {
+            volume.write(&mut file, chunk).await.unwrap();
+        }
+        volume.flush_file(&mut file, time()).await.unwrap();
+        // whole clusters at once
+        let still = pattern(cluster * 5, 2);
+        let mut file = volume
+            .create(dir, &name("IMG00001.JPG"), time())
+            .await
+            .unwrap();
+        volume.write(&mut file, &still).await.unwrap();
+        volume.flush_file(&mut file, time()).await.unwrap();
+        let mut file = volume
+            .create(root, &name("NOTE.TXT"), time())
+            .await
+            .unwrap();
+        volume.write(&mut file, b"hello").await.unwrap();
+        volume.flush_file(&mut file, time()).await.unwrap();
+        let used = (1 + 4 + 5 + 1) * cluster as u64;
+        assert_eq!(volume.free_bytes(), empty - used);
+
+        let mut volume = remount(volume).await;
+        assert_eq!(volume.free_bytes(), empty - used);
+        let dir = volume.open_dir(root, &name("DCIM")).await.unwrap();
+        assert_eq!(read_all(&mut volume, dir, "VID00001.AVI").await, clip);
+        assert_eq!(read_all(&mut volume, dir, "IMG00001.JPG").await, still);
+        assert_eq!(read_all(&mut volume, root, "NOTE.TXT").await, b"hello");
+        let entries = volume.list(dir).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, name("VID00001.AVI"));
+        assert_eq!(entries[0].size as usize, clip.len());
+        assert_eq!(entries[0].modified, time());
+        assert!(matches!(
+            volume.create(dir, &name("IMG00001.JPG"), time()).await,
+            Err(FatError::Exists)
+        ));
+
+        volume.delete(dir, &name("VID00001.AVI")).await.unwrap();
+        volume.flush().await.unwrap();
+        let mut volume = remount(volume).await;
+        let dir = volume.open_dir(root, &name("DCIM")).await.unwrap();
+        assert!(matches!(
+            volume.open(dir, &name("VID00001.AVI")).await,
+            Err(FatError::NotFound)
+        ));
+        assert_eq!(volume.free_bytes(), empty - used + 4 * cluster as u64);
+        let free_clusters = volume.free_bytes() / cluster as u64;
+        (volume.into_device().into_image(), free_clusters, still)
+    });
+
+    let fs = FileSystem::new(Cursor::new(&mut image[start..]), FsOptions::new()).unwrap();
+    assert_eq!(fs.stats().unwrap().free_clusters() as u64, free_clusters);
+    let dir = fs.root_dir().open_dir("DCIM").unwrap();
+    let names: Vec<_> = dir.iter().map(|e| e.unwrap().short_file_name()).collect();
+    assert_eq!(names, [".", "..", "IMG00001.JPG"]);
+    let mut data = Vec::new();
+    dir.open_file("IMG00001.JPG")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, still);
+    let mut note = String::new();
+    fs.root_dir()
+        .open_file("NOTE.TXT")
+        .unwrap()
+        .read_to_string(&mut note)
+        .unwrap();
+    assert_eq!(note, "hello");
+}
+
+#[test]
+fn fat16_round_trip() {
+    exercise(
+        fatfs_image(FatKind::Fat16, 16 << 20, 2048),
+        0,
+        FatKind::Fat16,
+    );
+}
+
+#[test]
+fn fat32_round_trip() {
+    exercise(
+        fatfs_image(FatKind::Fat32, 40 << 20, 512),
+        0,
+        FatKind::Fat32,
+    );
+}
+
+#[test]
+fn mkfs_fat_round_trip() {
+    for (kind, len) in [(FatKind::Fat16, 16 << 20), (FatKind::Fat32, 40 << 20)] {
+        let Some(image) = mkfs_image(kind, len) else {
+            eprintln!("mkfs.fat not found, skipped");
+            return;
+        };
+        exercise(image, 0, kind);
+    }
+}
+
+#[test]
+fn partitioned_card() {
+    const START: usize = 2048;
+    let volume = fatfs_image(FatKind::Fat32, 40 << 20, 512);
+    let mut image = vec![0; START * BLOCK_LEN];
+    let entry = &mut image[446..462];
+    entry[4] = 0x0C;
+    entry[8..12].copy_from_slice(&(START as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&((volume.len() / BLOCK_LEN) as u32).to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+    image.extend(volume);
+    exercise(image, START * BLOCK_LEN, FatKind::Fat32);
+}
+
+#[test]
+fn reads_what_fatfs_wrote() {
+    let mut image = fatfs_image(FatKind::Fat16, 16 << 20, 4096);
+    let clip = pattern(10_000, 3);
+    let fs = FileSystem::new(Cursor::new(&mut image), FsOptions::new()).unwrap();
+    let root = fs.root_dir();
+    let dir = root.create_dir("DCIM").unwrap();
+    dir.create_file("CLIP.AVI")
+        .unwrap()
+        .write_all(&clip)
+        .unwrap();
+    dir.create_dir("SUB").unwrap();
+    root.create_file("README.TXT")
+        .unwrap()
+        .write_all(b"fatfs")
+        .unwrap();
+    drop((dir, root));
+    fs.unmount().unwrap();
+    block_on(async {
+        let mut volume = Volume::mount(RamDisk::from_image(image)).await.unwrap();
+        assert_eq!(volume.kind(), FatKind::Fat16);
+        let root = volume.root();
+        assert_eq!(read_all(&mut volume, root, "README.TXT").await, b"fatfs");
+        let dir = volume.open_dir(root, &name("DCIM")).await.unwrap();
+        let entries = volume.list(dir).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[1].is_dir);
+        assert_eq!(read_all(&mut volume, dir, "CLIP.AVI").await, clip);
+        assert!(matches!(
+            volume.open(dir, &name("SUB")).await,
+            Err(FatError::IsADirectory)
+        ));
+    });
+}
+
+#[test]
+fn full_disk_is_reported_and_freed() {
+    let image = fatfs_image(FatKind::Fat16, 4 << 20, 512);
+    block_on(async {
+        let mut volume = Volume::mount(RamDisk::from_image(image)).await.unwrap();
+        let root = volume.root();
+        let empty = volume.free_bytes();
+        let mut file = volume
+            .create(root, &name("FILL.BIN"), time())
+            .await
+            .unwrap();
+        let chunk = pattern(64 * BLOCK_LEN, 4);
+        let result = loop {
+            if let Err(e) = volume.write(&mut file, &chunk).await {
+                break e;
+            }
+        };
+        assert_eq!(result, FatError::DiskFull);
+        assert_eq!(volume.free_bytes(), 0);
+        assert_eq!(file.size() as u64, empty);
+        volume.flush_file(&mut file, time()).await.unwrap();
+
+        let mut volume = remount(volume).await;
+        assert_eq!(volume.free_bytes(), 0);
+        volume.delete(root, &name("FILL.BIN")).await.unwrap();
+        volume.flush().await.unwrap();
+        let volume = remount(volume).await;
+        assert_eq!(volume.free_bytes(), empty);
+        assert_eq!(
+            volume.into_device().block_count() as usize,
+            (4 << 20) / BLOCK_LEN
+        );
+    });
+}
+
+#[test]
+fn truncate_frees_the_clusters_past_the_end() {
+    let image = fatfs_image(FatKind::Fat32, 40 << 20, 1024);
+    block_on(async {
+        let mut volume = Volume::mount(RamDisk::from_image(image)).await.unwrap();
+        let root = volume.root();
+        let empty = volume.free_bytes();
+        let data = pattern(5000, 5);
+        let mut file = volume.create(root, &name("CUT.BIN"), time()).await.unwrap();
+        volume.write(&mut file, &data).await.unwrap();
+        assert_eq!(volume.free_bytes(), empty - 5 * 1024);
+        // past the end nothing changes
+        volume.truncate(&mut file).await.unwrap();
+        assert_eq!(file.size(), 5000);
+
+        // into the middle of a cluster, then write on from there
+        file.seek(2100);
+        volume.truncate(&mut file).await.unwrap();
+        assert_eq!(file.size(), 2100);
+        assert_eq!(volume.free_bytes(), empty - 3 * 1024);
+        volume.write(&mut file, b"tail").await.unwrap();
+        volume.flush_file(&mut file, time()).await.unwrap();
+        let mut volume = remount(volume).await;
+        let mut expected = data[..2100].to_vec();
+        expected.extend_from_slice(b"tail");
+        assert_eq!(read_all(&mut volume, root, "CUT.BIN").await, expected);
+        assert_eq!(volume.free_bytes(), empty - 3 * 1024);
+
+        // down to nothing
+        let mut file = volume.open(root, &name("CUT.BIN")).await.unwrap();
+        volume.truncate(&mut file).await.unwrap();
+        volume.flush_file(&mut file, time()).await.unwrap();
+        let mut volume = remount(volume).await;
+        assert_eq!(read_all(&mut volume, root, "CUT.BIN").await, b"");
+        assert_eq!(volume.free_bytes(), empty);
+    });
+}
+
+#[test]
+fn not_a_file_system() {
+    let result = block_on(Volume::mount(RamDisk::new(64)));
+    assert!(matches!(result, Err(FatError::NoFilesystem)));
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+
+use storage::{
+    block::OutOfRange,
+    kv::{MAX_KEY_LEN, MAX_VALUE_LEN, SECTOR_LEN},
+    Flash, KvError, RamFlash, Store,
+};
+
+type Error = KvError<OutOfRange>;
+
+fn value(key: usize, version: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (key * 31 + version * 7 + i) as u8)
+        .collect()
+}
+
+fn get<F: Flash>(store: &mut Store<F>, key: &str) -> Option<Vec<u8>> {
+    let mut buf = [0; MAX_VALUE_LEN];
+    let len = store.get(key, &mut buf).ok()?;
+    len.map(|len| buf[..len].to_vec())
+}
+
+fn name(key: usize) -> String {
+    format!("key{:02}", key)
+}
+
+/// Check `store` holds exactly `expected` of the first `keys` keys
+fn check<F: Flash>(store: &mut Store<F>, expected: &BTreeMap<String, Vec<u8>>, keys: usize) {
+    for key in (0..keys).map(name) {
+        assert_eq!(get(store, &key).as_ref(), expected.get(&key), "{}", key);
+    }
+}
+
+/// Flash that counts reads and erases and loses power after `budget` writes
+/// and erases, the one it loses power in is done only partly
+struct CutFlash {
+    flash: RamFlash,
+    budget: Option<usize>,
+    /// Writes and erases done
+    operations: usize,
+    reads: usize,
+    erases: Vec<u32>,
+}
+
+impl CutFlash {
+    fn new(flash: RamFlash) -> Self {
+        let sectors = flash.capacity() / SECTOR_LEN;
+        Self {
+            flash,
+            budget: None,
+            operations: 0,
+            reads: 0,
+            erases: vec![0; sectors as usize],
+        }
+    }
+
+    /// Whether the operation goes through, `false` once power is lost
+    fn spend(&mut self) -> bool {
+        self.operations += 1;
+        match &mut self.budget {
+            Some(0) => false,
+            Some(budget) => {
+                *budget -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl Flash for CutFlash {
+    type Error = OutOfRange;
+
+    fn capacity(&self) -> u32 {
+        self.flash.capacity()
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), OutOfRange> {
+        self.reads += 1;
+        self.flash.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), OutOfRange> {
+        if self.spend() {
+            return self.flash.write(offset, data);
+        }
+        // the words programmed before the power went
+        let torn = data.len() / 8 * 4;
+        self.flash.write(offset, &data[..torn])?;
+        Err(OutOfRange)
+    }
+
+    fn erase(&mut self, offset: u32) -> Result<(), OutOfRange> {
+        if self.spend() {
+            self.erases[(offset / SECTOR_LEN) as usize] += 1;
+            return self.flash.erase(offset);
+        }
+        // an erase cut short leaves the start of the sector cleared
+        let half = vec![0xFF; SECTOR_LEN as usize / 2];
+        let mut image = self.flash.image().to_vec();
+        image[offset as usize..][..half.len()].copy_from_slice(&half);
+        self.flash = RamFlash::from_image(image);
+        Err(OutOfRange)
+    }
+}
+
+#[test]
+fn set_get_remove() {
+    let mut store = Store::mount(RamFlash::new(2)).unwrap();
+    assert_eq!(get(&mut store, "ssid"), None);
+    store.set("ssid", b"home").unwrap();
+    store.set("password", b"secret").unwrap();
+    store.set("ssid", b"office").unwrap();
+    store.set("empty", b"").unwrap();
+    assert_eq!(get(&mut store, "ssid").unwrap(), b"office");
+    assert_eq!(get(&mut store, "empty").unwrap(), b"");
+    store.remove("password").unwrap();
+    store.remove("missing").unwrap();
+    assert_eq!(get(&mut store, "password"), None);
+
+    let mut store = Store::mount(store.into_inner()).unwrap();
+    assert_eq!(get(&mut store, "ssid").unwrap(), b"office");
+    assert_eq!(get(&mut store, "password"), None);
+    store.set("password", b"again").unwrap();
+    assert_eq!(get(&mut store, "password").unwrap(), b"again");
+}
+
+#[test]
+fn bad_input() {
+    assert!(matches!(
+        Store::mount(RamFlash::new(1)),
+        Err(Error::TooSmall)
+    ));
+    let mut store = Store::mount(RamFlash::new(2)).unwrap();
+    let long_key = "k".repeat(MAX_KEY_LEN + 1);
+    assert_eq!(store.set("", b"x"), Err(Error::BadKey));
+    assert_eq!(store.set(&long_key, b"x"), Err(Error::BadKey));
+    assert_eq!(store.remove(&long_key), Err(Error::BadKey));
+    let big = vec![1; MAX_VALUE_LEN + 1];
+    assert_eq!(store.set("big", &big), Err(Error::ValueTooLarge));
+    store.set(&"k".repeat(MAX_KEY_LEN), &big[1..]).unwrap();
+    let mut small = [0; 4];
+    assert_eq!(
+        store.get("k".repeat(MAX_KEY_LEN).as_str(), &mut small),
+        Err(Error::BufferTooSmall(MAX_VALUE_LEN))
+    );
+}
+
+#[test]
+fn wraps_around_and_levels_wear() {
+    let mut store = Store::mount(CutFlash::new(RamFlash::new(4))).unwrap();
+    let mut expected = BTreeMap::new();
+    for round in 0..400 {
+        let key = round % 7;
+        let data = value(key, round, 50 + round % 200);
+        store.set(&name(key), &data).unwrap();
+        expected.insert(name(key), data);
+        if round % 13 == 0 {
+            store.remove(&name(3)).unwrap();
+            expected.remove(&name(3));
+        }
+        if round % 50 == 0 {
+            store = Store::mount(store.into_inner()).unwrap();
+        }
+    }
+    check(&mut store, &expected, 7);
+    let flash = store.into_inner();
+    // many trips around the partition, a rotation erases two sectors
+    let (min, max) = (
+        flash.erases.iter().min().unwrap(),
+        flash.erases.iter().max().unwrap(),
+    );
+    assert!(*min >= 5, "{:?}", flash.erases);
+    assert!(max - min <= 2, "{:?}", flash.erases);
+    let mut store = Store::mount(flash.flash).unwrap();
+    check(&mut store, &expected, 7);
+}
+
+#[test]
+fn rotation_reads_the_log_once() {
+    let mut flash = CutFlash::new(RamFlash::new(4));
+    let mut expected = BTreeMap::new();
+    let mut most = 0;
+    // small records of 100 keys, a sector holds about 200 of them
+    for round in 0..2000 {
+        let key = round % 100;
+        let data = value(key, round, 4);
+        let before = flash.reads;
+        let mut store = Store::mount(flash).unwrap();
+        store.set(&name(key), &data).unwrap();
+        flash = store.into_inner();
+        most = most.max(flash.reads - before);
+        expected.insert(name(key), data);
+    }
+    // a header and a record read for each of the about 800 records in the
+    // log, not a search of the log for each of them
+    assert!(most < 4000, "{} reads", most);
+    let mut store = Store::mount(flash).unwrap();
+    check(&mut store, &expected, 100);
+}
+
+#[test]
+fn full_loses_nothing() {
+    let mut store = Store::mount(RamFlash::new(3)).unwrap();
+    let mut expected = BTreeMap::new();
+    let mut key = 0;
+    // unique keys until the current records outgrow a sector
+    let error = loop {
+        let data = value(key, 0, 300);
+        match store.set(&name(key), &data) {
+            Ok(()) => {
+                expected.insert(name(key), data);
+                key += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(error, Error::Full);
+    assert!(key > 10);
+    check(&mut store, &expected, key + 1);
+    // and again, while the store keeps saying it's full
+    for _ in 0..3 {
+        assert_eq!(store.set("more", &[0; 300]), Err(Error::Full));
+    }
+    check(&mut store, &expected, key + 1);
+    let mut store = Store::mount(store.into_inner()).unwrap();
+    check(&mut store, &expected, key + 1);
+
+    // making room lets writes through again
+    for k in 0..key / 2 {
+        store.remove(&name(k)).unwrap();
+        expected.remove(&name(k));
+    }
+    for round in 0..50 {
+        let data = value(key, round, 300);
+        store.set("more", &data).unwrap();
+        expected.insert("more".into(), data);
+    }
+    check(&mut store, &expected, key + 1);
+    assert_eq!(get(&mut store, "more"), expected.get("more").cloned());
+}
+
+/// The write a power cut stopped, the key ends up with its old value or `new`
+struct Cut {
+    key: String,
+    new: Option<Vec<u8>>,
+}
+
+type Run = fn(&mut Store<CutFlash>, &mut BTreeMap<String, Vec<u8>>) -> Result<(), Cut>;
+
+fn set(
+    store: &mut Store<CutFlash>,
+    expected: &mut BTreeMap<String, Vec<u8>>,
+    key: usize,
+    data: Vec<u8>,
+) -> Result<(), Cut> {
+    let key = name(key);
+    match store.set(&key, &data) {
+        Ok(()) => {
+            expected.insert(key, data);
+            Ok(())
+        }
+        Err(_) => Err(Cut {
+            key,
+            new: Some(data),
+        }),
+    }
+}
+
+/// Cut the power at every write and erase of `run`, the store mounts
+/// afterwards with every key at its old or its new value and takes writes
+/// again
+fn cut_everywhere(sectors: u32, keys: usize, run: Run) {
+    let mut store = Store::mount(CutFlash::new(RamFlash::new(sectors))).unwrap();
+    run(&mut store, &mut BTreeMap::new()).unwrap_or_else(|_| panic!("failed without a cut"));
+    let operations = store.into_inner().operations;
+
+    for budget in 0..operations {
+        let mut flash = CutFlash::new(RamFlash::new(sectors));
+        flash.budget = Some(budget);
+        let mut expected = BTreeMap::new();
+        let mut store = match Store::mount(flash) {
+            Ok(store) => store,
+            // cut while formatting
+            Err(_) => continue,
+        };
+        let Err(cut) = run(&mut store, &mut expected) else {
+            panic!("no cut with {} of {} operations", budget, operations);
+        };
+        let image = store.into_inner().flash.into_image();
+        let mut store = Store::mount(RamFlash::from_image(image))
+            .unwrap_or_else(|e| panic!("mount after cut {}: {:?}", budget, e));
+        let found = get(&mut store, &cut.key);
+        assert!(
+            found == cut.new || found.as_ref() == expected.get(&cut.key),
+            "cut {}",
+            budget
+        );
+        match found {
+            Some(data) => expected.insert(cut.key, data),
+            None => expected.remove(&cut.key),
+        };
+        check(&mut store, &expected, keys);
+        for k in 0..keys * 2 {
+            let data = value(k, budget, 200);
+            store.set(&name(k % keys), &data).unwrap();
+            expected.insert(name(k % keys), data);
+        }
+        let mut store = Store::mount(store.into_inner()).unwrap();
+        check(&mut store, &expected, keys);
+    }
+}
+
+#[test]
+fn power_cuts_while_updating() {
+    cut_everywhere(3, 5, |store, expected| {
+        for round in 0..60 {
+            let key = round % 5;
+            if round % 9 == 8 {
+                store.remove(&name(key)).map_err(|_| Cut {
+                    key: name(key),
+                    new: None,
+                })?;
+                expected.remove(&name(key));
+                continue;
+            }
+            set(
+                store,
+                expected,
+                key,
+                value(key, round, 100 + round * 13 % 300),
+            )?;
+        }
+        Ok(())
+    });
+}
+
+/// A sector full of current records is copied into a fresh one, a record torn
+/// on the way leaves too little room to finish the copy in place
+#[test]
+fn power_cuts_while_copying_a_full_sector() {
+    const KEYS: usize = 15;
+    // 15 records of 272 bytes fill a sector to the last word
+    cut_everywhere(3, KEYS + 1, |store, expected| {
+        for key in 0..KEYS {
+            set(store, expected, key, value(key, 0, 259))?;
+        }
+        for round in 0..40 {
+            set(store, expected, KEYS, value(KEYS, round, 300))?;
+        }
+        Ok(())
+    });
+}